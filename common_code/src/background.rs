@@ -0,0 +1,64 @@
+use crate::distribution::Distribution2D;
+use crate::sky::SkyParameters;
+use glam::{Vec3, Vec4};
+use std::f32::consts::PI;
+use std::path::Path;
+
+// what a ray sees when it leaves the scene
+pub enum Background {
+    Solid(Vec3),
+    // blends from bottom to top with the y direction of the ray
+    Gradient { bottom: Vec3, top: Vec3 },
+    Environment(EnvironmentMap),
+    // the Preetham daylight sky, which also brings the sun as a directional light
+    Sky(SkyParameters),
+}
+
+impl Background {
+    // the white to blue sky of the book scenes
+    pub fn sky() -> Self {
+        Background::Gradient { bottom: Vec3::ONE, top: Vec3::new(0.5, 0.7, 1.0) }
+    }
+}
+
+// an equirectangular image of the surroundings; row 0 looks straight up, and the
+// middle column looks down -z
+pub struct EnvironmentMap {
+    width: u32,
+    height: u32,
+    pixels: Vec<Vec4>,
+}
+
+impl EnvironmentMap {
+    pub fn new(width: u32, height: u32, pixels: Vec<Vec4>) -> Self {
+        assert_eq!(pixels.len(), (width * height) as usize, "pixel count does not match the map size");
+        Self { width, height, pixels }
+    }
+
+    // reads a Radiance .hdr or an OpenEXR file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, image::ImageError> {
+        let image = image::open(path)?.into_rgb32f();
+        let (width, height) = image.dimensions();
+        let pixels = image.pixels().map(|p| Vec4::new(p[0], p[1], p[2], 1.0)).collect();
+        Ok(Self { width, height, pixels })
+    }
+
+    // distribution for importance sampling the map, proportional to the luminance of each
+    // texel and to sin(theta), as rows near the poles cover less of the sphere
+    pub fn distribution(&self) -> Distribution2D {
+        let mut func = Vec::with_capacity(self.pixels.len());
+        for y in 0..self.height {
+            let sin_theta = (PI * (y as f32 + 0.5) / self.height as f32).sin();
+            for x in 0..self.width {
+                let texel = self.pixels[(y * self.width + x) as usize];
+                let luminance = 0.2126 * texel.x + 0.7152 * texel.y + 0.0722 * texel.z;
+                func.push(luminance * sin_theta);
+            }
+        }
+        Distribution2D::new(&func, self.width as usize, self.height as usize)
+    }
+
+    pub fn width(&self) -> u32 { self.width }
+    pub fn height(&self) -> u32 { self.height }
+    pub fn pixels(&self) -> &[Vec4] { &self.pixels }
+}
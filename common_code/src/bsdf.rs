@@ -0,0 +1,1029 @@
+use std::f32::consts::{FRAC_1_PI, PI};
+use glam::{Vec2, Vec3, Vec3Swizzles, Vec4};
+use crate::measured::MeasuredBRDF;
+use crate::medium::Medium;
+use crate::spectrum::{cie_xyz, xyz_to_rgb};
+
+// what the CPU tracer needs of a material to scatter light; wo and wi point away from the
+// hit and n is the outward normal of the sphere, so both may be on either side of it
+pub trait BSDF {
+    // the fraction of the light the surface scatters, which an albedo texture replaces
+    fn albedo(&self) -> Vec3;
+
+    // a direction wo scatters into, with the bsdf times |cos(wi)| over the pdf of picking
+    // it, or None when the sample is lost; u is uniform in the unit cube
+    fn sample(&self, wo: Vec3, n: Vec3, albedo: Vec3, u: Vec3) -> Option<(Vec3, Vec3)>;
+
+    // the bsdf times |cos(wi)|, without the specular lobes
+    fn evaluate(&self, wo: Vec3, wi: Vec3, n: Vec3, albedo: Vec3) -> Vec3;
+
+    // the density of sample returning wi, without the specular lobes
+    fn pdf(&self, wo: Vec3, wi: Vec3, n: Vec3, albedo: Vec3) -> f32;
+
+    // whether shadow rays toward the lights are worth tracing from the surface; specular
+    // surfaces only see lights through their few scattered directions
+    fn samples_lights(&self) -> bool {
+        true
+    }
+
+    // radiance leaving an emissive surface, which ends the path
+    fn emitted(&self, _albedo: Vec3) -> Option<Vec3> {
+        None
+    }
+
+    // the medium filling the inside of the surface, which rays that go through it random
+    // walk in until they reach the surface again
+    fn medium(&self, _albedo: Vec3) -> Option<Medium> {
+        None
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Lambertian {
+    pub albedo: Vec3,
+}
+
+impl BSDF for Lambertian {
+    fn albedo(&self) -> Vec3 {
+        self.albedo
+    }
+
+    fn sample(&self, _wo: Vec3, n: Vec3, albedo: Vec3, u: Vec3) -> Option<(Vec3, Vec3)> {
+        Some((cosine_direction(n, u.xy()), albedo))
+    }
+
+    fn evaluate(&self, wo: Vec3, wi: Vec3, n: Vec3, albedo: Vec3) -> Vec3 {
+        albedo * self.pdf(wo, wi, n, albedo)
+    }
+
+    fn pdf(&self, _wo: Vec3, wi: Vec3, n: Vec3, _albedo: Vec3) -> f32 {
+        n.dot(wi).max(0.0) * FRAC_1_PI
+    }
+}
+
+// reflection perturbed toward a uniform point on a sphere of radius fuzz around the tip of
+// the reflected vector
+#[derive(Copy, Clone, Debug)]
+pub struct Metal {
+    pub albedo: Vec3,
+    pub fuzz: f32,
+}
+
+impl BSDF for Metal {
+    fn albedo(&self) -> Vec3 {
+        self.albedo
+    }
+
+    fn sample(&self, wo: Vec3, n: Vec3, albedo: Vec3, u: Vec3) -> Option<(Vec3, Vec3)> {
+        Some((reflect(-wo, n) + self.fuzz * uniform_direction(u.xy()), albedo))
+    }
+
+    fn evaluate(&self, wo: Vec3, wi: Vec3, n: Vec3, albedo: Vec3) -> Vec3 {
+        albedo * self.pdf(wo, wi, n, albedo)
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3, n: Vec3, _albedo: Vec3) -> f32 {
+        // the area density 1 / (4 pi fuzz^2) of the sphere, summed over the points where wi
+        // crosses it, times t^2 / |cos| to turn it into solid angle
+        if self.fuzz <= 0.0 {
+            return 0.0;
+        }
+        let reflected = reflect(-wo, n);
+        let b = wi.dot(reflected);
+        let discrim = b * b - reflected.length_squared() + self.fuzz * self.fuzz;
+        if discrim <= 0.0 {
+            return 0.0;
+        }
+        let root = discrim.sqrt();
+        let mut pdf = 0.0;
+        for t in [b - root, b + root] {
+            if t > 0.0 {
+                pdf += t * t / (4.0 * PI * self.fuzz * root);
+            }
+        }
+        pdf
+    }
+
+    fn samples_lights(&self) -> bool {
+        self.fuzz > 0.0
+    }
+}
+
+// smooth glass, choosing between reflection and refraction with Schlick's approximation, or
+// with the reflectance of its thin film if it has one
+#[derive(Copy, Clone, Debug)]
+pub struct Dielectric {
+    pub refract_index: f32,
+    pub dispersion: Dispersion,
+    pub film: Option<ThinFilm>,
+}
+
+// how the index of refraction of glass changes with the wavelength, with the wavelength in
+// micrometers as glass catalogs give the coefficients
+#[derive(Copy, Clone, Debug)]
+pub enum Dispersion {
+    None,
+    // n = a + b / lambda^2
+    Cauchy { a: f32, b: f32 },
+    // n^2 = 1 + sum of b_i lambda^2 / (lambda^2 - c_i)
+    Sellmeier { b: Vec3, c: Vec3 },
+}
+
+impl Dispersion {
+    // the index at a wavelength in nm, or None for glass that does not disperse
+    pub fn refract_index(&self, lambda: f32) -> Option<f32> {
+        let l2 = (lambda * 1e-3) * (lambda * 1e-3);
+        match *self {
+            Dispersion::None => None,
+            Dispersion::Cauchy { a, b } => Some(a + b / l2),
+            Dispersion::Sellmeier { b, c } => Some((1.0 + (b * l2 / (Vec3::splat(l2) - c)).element_sum()).sqrt()),
+        }
+    }
+}
+
+impl BSDF for Dielectric {
+    fn albedo(&self) -> Vec3 {
+        Vec3::ONE
+    }
+
+    fn sample(&self, wo: Vec3, n: Vec3, albedo: Vec3, u: Vec3) -> Option<(Vec3, Vec3)> {
+        let (norm, eta) = facing(wo, n, self.refract_index);
+        let cos_theta = norm.dot(wo).min(1.0);
+        if let Some(film) = self.film {
+            // the film colors the reflection, so reflecting is picked with the average of its
+            // reflectance and the weights make up for the rest
+            let n1 = if n.dot(wo) < 0.0 { self.refract_index } else { 1.0 };
+            let reflectance = film.reflectance(cos_theta, n1, Vec3::splat(n1 * eta), Vec3::ZERO);
+            let chance = reflectance.element_sum() / 3.0;
+            return match refract(-wo, norm, 1.0 / eta) {
+                Some(refracted) if chance <= u.x => Some((refracted, albedo * (Vec3::ONE - reflectance) / (1.0 - chance))),
+                Some(_) => Some((reflect(-wo, norm), albedo * reflectance / chance)),
+                None => Some((reflect(-wo, norm), albedo)),
+            };
+        }
+        let mut r0 = (1.0 - 1.0 / eta) / (1.0 + 1.0 / eta);
+        r0 = r0 * r0;
+        let reflectance = r0 + (1.0 - r0) * (1.0 - cos_theta).powi(5);
+        match refract(-wo, norm, 1.0 / eta) {
+            Some(refracted) if reflectance <= u.x => Some((refracted, albedo)),
+            _ => Some((reflect(-wo, norm), albedo)),
+        }
+    }
+
+    fn evaluate(&self, _wo: Vec3, _wi: Vec3, _n: Vec3, _albedo: Vec3) -> Vec3 {
+        Vec3::ZERO
+    }
+
+    fn pdf(&self, _wo: Vec3, _wi: Vec3, _n: Vec3, _albedo: Vec3) -> f32 {
+        0.0
+    }
+
+    fn samples_lights(&self) -> bool {
+        false
+    }
+}
+
+// the albedo holds the color of the emission
+#[derive(Copy, Clone, Debug)]
+pub struct Emissive {
+    pub color: Vec3,
+    pub strength: f32,
+}
+
+impl BSDF for Emissive {
+    fn albedo(&self) -> Vec3 {
+        self.color
+    }
+
+    fn sample(&self, _wo: Vec3, _n: Vec3, _albedo: Vec3, _u: Vec3) -> Option<(Vec3, Vec3)> {
+        None
+    }
+
+    fn evaluate(&self, _wo: Vec3, _wi: Vec3, _n: Vec3, _albedo: Vec3) -> Vec3 {
+        Vec3::ZERO
+    }
+
+    fn pdf(&self, _wo: Vec3, _wi: Vec3, _n: Vec3, _albedo: Vec3) -> f32 {
+        0.0
+    }
+
+    fn samples_lights(&self) -> bool {
+        false
+    }
+
+    fn emitted(&self, albedo: Vec3) -> Option<Vec3> {
+        Some(albedo * self.strength)
+    }
+}
+
+// Cook-Torrance with the GGX distribution, the height-correlated Smith shadowing and the
+// Fresnel reflectance of the complex index of refraction (eta, k); roughness is the GGX
+// alpha, and the albedo scales the reflectance
+#[derive(Copy, Clone, Debug)]
+pub struct Conductor {
+    pub eta: Vec3,
+    pub k: Vec3,
+    pub roughness: f32,
+    pub film: Option<ThinFilm>,
+}
+
+impl Conductor {
+    fn fresnel(&self, cos_theta: f32) -> Vec3 {
+        match self.film {
+            Some(film) => film.reflectance(cos_theta, 1.0, self.eta, self.k),
+            None => fresnel_conductor(cos_theta, self.eta, self.k),
+        }
+    }
+}
+
+impl BSDF for Conductor {
+    fn albedo(&self) -> Vec3 {
+        Vec3::ONE
+    }
+
+    fn sample(&self, wo: Vec3, n: Vec3, albedo: Vec3, u: Vec3) -> Option<(Vec3, Vec3)> {
+        let m = sample_microfacet_normal(wo, n, self.roughness, u.xy());
+        let wi = reflect(-wo, m);
+        if n.dot(wo) <= 0.0 || n.dot(wi) <= 0.0 {
+            return None;
+        }
+        let fresnel = self.fresnel(wo.dot(m));
+        Some((wi, albedo * fresnel * microfacet_weight(wo, wi, n, self.roughness)))
+    }
+
+    fn evaluate(&self, wo: Vec3, wi: Vec3, n: Vec3, albedo: Vec3) -> Vec3 {
+        let cos_o = n.dot(wo);
+        let cos_i = n.dot(wi);
+        if cos_o <= 0.0 || cos_i <= 0.0 || self.roughness <= 0.0 {
+            return Vec3::ZERO;
+        }
+        let m = (wo + wi).normalize();
+        let g2 = 1.0 / (1.0 + smith_lambda(cos_o, self.roughness) + smith_lambda(cos_i, self.roughness));
+        let fresnel = self.fresnel(wo.dot(m));
+        albedo * fresnel * ggx_d(n.dot(m), self.roughness) * g2 / (4.0 * cos_o)
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3, n: Vec3, _albedo: Vec3) -> f32 {
+        if self.roughness <= 0.0 {
+            return 0.0;
+        }
+        ggx_reflection_pdf(wo, wi, n, self.roughness)
+    }
+
+    fn samples_lights(&self) -> bool {
+        self.roughness > 0.0
+    }
+}
+
+// GGX glass (Walter et al. 2007), reflecting with the exact Fresnel reflectance and
+// refracting otherwise; a roughness of 0 gives smooth glass
+#[derive(Copy, Clone, Debug)]
+pub struct RoughDielectric {
+    pub refract_index: f32,
+    pub roughness: f32,
+}
+
+impl BSDF for RoughDielectric {
+    fn albedo(&self) -> Vec3 {
+        Vec3::ONE
+    }
+
+    fn sample(&self, wo: Vec3, n: Vec3, albedo: Vec3, u: Vec3) -> Option<(Vec3, Vec3)> {
+        let (norm, eta) = facing(wo, n, self.refract_index);
+        let m = sample_microfacet_normal(wo, norm, self.roughness, u.xy());
+        // picking reflection with the Fresnel probability cancels it out of the weight
+        let reflected = u.z < fresnel_dielectric(wo.dot(m), eta);
+        let wi = match refract(-wo, m, 1.0 / eta) {
+            Some(refracted) if !reflected => refracted,
+            _ => reflect(-wo, m),
+        };
+        if (norm.dot(wi) > 0.0) != reflected {
+            return None;
+        }
+        Some((wi, albedo * microfacet_weight(wo, wi, norm, self.roughness)))
+    }
+
+    fn evaluate(&self, wo: Vec3, wi: Vec3, n: Vec3, albedo: Vec3) -> Vec3 {
+        let (n, eta) = facing(wo, n, self.refract_index);
+        let alpha = self.roughness.max(1e-4);
+        let cos_o = n.dot(wo);
+        let cos_i = n.dot(wi);
+        if cos_o <= 0.0 || cos_i == 0.0 {
+            return Vec3::ZERO;
+        }
+        let g2 = 1.0 / (1.0 + smith_lambda(cos_o, alpha) + smith_lambda(cos_i.abs(), alpha));
+        if cos_i > 0.0 {
+            let m = (wo + wi).normalize();
+            let fresnel = fresnel_dielectric(wo.dot(m), eta);
+            return albedo * fresnel * ggx_d(n.dot(m), alpha) * g2 / (4.0 * cos_o);
+        }
+        match refraction_half_vector(wo, wi, n, eta) {
+            Some(m) => albedo * (1.0 - fresnel_dielectric(wo.dot(m), eta)) * ggx_transmission(wo, wi, n, m, eta, alpha) * g2,
+            None => Vec3::ZERO,
+        }
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3, n: Vec3, _albedo: Vec3) -> f32 {
+        let (n, eta) = facing(wo, n, self.refract_index);
+        let alpha = self.roughness.max(1e-4);
+        let cos_i = n.dot(wi);
+        if n.dot(wo) <= 0.0 || cos_i == 0.0 {
+            return 0.0;
+        }
+        if cos_i > 0.0 {
+            let m = (wo + wi).normalize();
+            return fresnel_dielectric(wo.dot(m), eta) * ggx_reflection_pdf(wo, wi, n, alpha);
+        }
+        match refraction_half_vector(wo, wi, n, eta) {
+            Some(m) => (1.0 - fresnel_dielectric(wo.dot(m), eta)) * ggx_refraction_pdf(wo, wi, n, m, eta, alpha),
+            None => 0.0,
+        }
+    }
+
+    // mostly transmits, and shadow rays only leave through the outside
+    fn samples_lights(&self) -> bool {
+        false
+    }
+}
+
+// uber-material with the parameters of the glTF metallic-roughness model: lambertian diffuse
+// with a sheen at grazing angles and GGX specular reflection and transmission, under a GGX
+// clearcoat that takes its Fresnel share of the light off everything below it; the
+// roughnesses are squared into alpha, and a specular of 0.5 keeps the reflectance of the ior
+#[derive(Copy, Clone, Debug)]
+pub struct Principled {
+    pub base_color: Vec3,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub specular: f32,
+    pub ior: f32,
+    pub transmission: f32,
+    pub clearcoat: f32,
+    pub clearcoat_roughness: f32,
+    pub sheen: f32,
+}
+
+impl Principled {
+    fn alpha(&self) -> f32 {
+        (self.roughness * self.roughness).max(1e-4)
+    }
+
+    fn clearcoat_alpha(&self) -> f32 {
+        (self.clearcoat_roughness * self.clearcoat_roughness).max(1e-4)
+    }
+
+    // chances of sampling the diffuse and sheen, specular, transmission and clearcoat
+    // lobes, roughly in proportion to what each of them reflects toward wo
+    fn lobes(&self, wo: Vec3, n: Vec3, base_color: Vec3) -> Vec4 {
+        let luminance = Vec3::new(0.2126, 0.7152, 0.0722);
+        let cos_o = n.dot(wo);
+        let coat = self.clearcoat * fresnel_dielectric(cos_o, 1.5);
+        let dielectric = (1.0 - self.metallic) * (1.0 - coat);
+        let r = (self.ior - 1.0) / (self.ior + 1.0);
+        let f0 = Vec3::splat((2.0 * self.specular * r * r).min(1.0)).lerp(base_color, self.metallic);
+        let specular = (f0 + (Vec3::ONE - f0) * (1.0 - cos_o).powi(5)).dot(luminance) * (1.0 - coat);
+        let diffuse = dielectric * (1.0 - self.transmission) * (base_color.dot(luminance) + self.sheen);
+        let transmission = dielectric * self.transmission * base_color.dot(luminance);
+        let lobes = Vec4::new(diffuse, specular, transmission, coat);
+        let total = lobes.element_sum();
+        if total <= 0.0 {
+            return Vec4::X;
+        }
+        lobes / total
+    }
+}
+
+impl BSDF for Principled {
+    fn albedo(&self) -> Vec3 {
+        self.base_color
+    }
+
+    fn sample(&self, wo: Vec3, n: Vec3, albedo: Vec3, u: Vec3) -> Option<(Vec3, Vec3)> {
+        // u.z picks the lobe and u.xy samples it
+        let (norm, eta) = facing(wo, n, self.ior);
+        let lobes = self.lobes(wo, norm, albedo);
+        let wi = if u.z < lobes.x {
+            cosine_direction(norm, u.xy())
+        } else if u.z < lobes.x + lobes.y {
+            reflect(-wo, sample_microfacet_normal(wo, norm, self.alpha(), u.xy()))
+        } else if u.z < lobes.x + lobes.y + lobes.z {
+            refract(-wo, sample_microfacet_normal(wo, norm, self.alpha(), u.xy()), 1.0 / eta)?
+        } else {
+            reflect(-wo, sample_microfacet_normal(wo, norm, self.clearcoat_alpha(), u.xy()))
+        };
+        let pdf = self.pdf(wo, wi, n, albedo);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some((wi, self.evaluate(wo, wi, n, albedo) / pdf))
+    }
+
+    fn evaluate(&self, wo: Vec3, wi: Vec3, n: Vec3, albedo: Vec3) -> Vec3 {
+        let (n, eta) = facing(wo, n, self.ior);
+        let cos_o = n.dot(wo);
+        let cos_i = n.dot(wi);
+        if cos_o <= 0.0 || cos_i == 0.0 {
+            return Vec3::ZERO;
+        }
+        let alpha = self.alpha();
+        let dielectric = 1.0 - self.metallic;
+        let below = 1.0 - self.clearcoat * fresnel_dielectric(cos_o, 1.5);
+        let g2 = 1.0 / (1.0 + smith_lambda(cos_o, alpha) + smith_lambda(cos_i.abs(), alpha));
+
+        if cos_i < 0.0 {
+            return match refraction_half_vector(wo, wi, n, eta) {
+                Some(m) => albedo * (1.0 - fresnel_dielectric(wo.dot(m), eta)) * ggx_transmission(wo, wi, n, m, eta, alpha)
+                    * g2 * dielectric * self.transmission * below,
+                None => Vec3::ZERO,
+            };
+        }
+
+        let m = (wo + wi).normalize();
+        let cos_om = wo.dot(m);
+        let sheen = self.sheen * (1.0 - wi.dot(m)).powi(5);
+        let diffuse = (albedo * FRAC_1_PI + Vec3::splat(sheen)) * cos_i * dielectric * (1.0 - self.transmission);
+        let metal_fresnel = albedo + (Vec3::ONE - albedo) * (1.0 - cos_om).powi(5);
+        let dielectric_fresnel = (2.0 * self.specular * fresnel_dielectric(cos_om, eta)).min(1.0);
+        let fresnel = Vec3::splat(dielectric_fresnel).lerp(metal_fresnel, self.metallic);
+        let specular = fresnel * ggx_d(n.dot(m), alpha) * g2 / (4.0 * cos_o);
+
+        let coat_alpha = self.clearcoat_alpha();
+        let coat_g2 = 1.0 / (1.0 + smith_lambda(cos_o, coat_alpha) + smith_lambda(cos_i, coat_alpha));
+        let coat = self.clearcoat * fresnel_dielectric(cos_om, 1.5) * ggx_d(n.dot(m), coat_alpha) * coat_g2 / (4.0 * cos_o);
+        (diffuse + specular) * below + Vec3::splat(coat)
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3, n: Vec3, albedo: Vec3) -> f32 {
+        // the lobe pdfs weighted by the chances of picking them
+        let (n, eta) = facing(wo, n, self.ior);
+        let cos_i = n.dot(wi);
+        if n.dot(wo) <= 0.0 || cos_i == 0.0 {
+            return 0.0;
+        }
+        let lobes = self.lobes(wo, n, albedo);
+        if cos_i < 0.0 {
+            return match refraction_half_vector(wo, wi, n, eta) {
+                Some(m) => lobes.z * ggx_refraction_pdf(wo, wi, n, m, eta, self.alpha()),
+                None => 0.0,
+            };
+        }
+        lobes.x * cos_i * FRAC_1_PI
+            + lobes.y * ggx_reflection_pdf(wo, wi, n, self.alpha())
+            + lobes.w * ggx_reflection_pdf(wo, wi, n, self.clearcoat_alpha())
+    }
+}
+
+// a smooth dielectric boundary with the exact Fresnel reflectance around a scattering medium;
+// the albedo is the color that the medium takes after many scatterings, and light travels
+// mean_free_path between two of them on average
+#[derive(Copy, Clone, Debug)]
+pub struct Subsurface {
+    pub albedo: Vec3,
+    pub mean_free_path: Vec3,
+    pub refract_index: f32,
+}
+
+impl BSDF for Subsurface {
+    fn albedo(&self) -> Vec3 {
+        self.albedo
+    }
+
+    fn sample(&self, wo: Vec3, n: Vec3, _albedo: Vec3, u: Vec3) -> Option<(Vec3, Vec3)> {
+        // the boundary itself is clear, and the medium gives the color
+        let (norm, eta) = facing(wo, n, self.refract_index);
+        match refract(-wo, norm, 1.0 / eta) {
+            Some(refracted) if fresnel_dielectric(norm.dot(wo), eta) <= u.x => Some((refracted, Vec3::ONE)),
+            _ => Some((reflect(-wo, norm), Vec3::ONE)),
+        }
+    }
+
+    fn evaluate(&self, _wo: Vec3, _wi: Vec3, _n: Vec3, _albedo: Vec3) -> Vec3 {
+        Vec3::ZERO
+    }
+
+    fn pdf(&self, _wo: Vec3, _wi: Vec3, _n: Vec3, _albedo: Vec3) -> f32 {
+        0.0
+    }
+
+    fn samples_lights(&self) -> bool {
+        false
+    }
+
+    fn medium(&self, albedo: Vec3) -> Option<Medium> {
+        Some(Medium::from_albedo(albedo, self.mean_free_path))
+    }
+}
+
+// wo and wi in the frame of the surface, where measured tables are indexed
+fn measured_local(v: Vec3, n: Vec3) -> Vec3 {
+    let (tangent, bitangent) = orthonormal_basis(n);
+    Vec3::new(v.dot(tangent), v.dot(bitangent), v.dot(n))
+}
+
+// a tabulated brdf such as a MERL measurement, to compare the analytic materials with; half
+// of the samples follow its specular peak and half are cosine distributed, and the albedo is
+// its reflectance at normal incidence, which albedo textures do not change
+impl BSDF for MeasuredBRDF {
+    fn albedo(&self) -> Vec3 {
+        MeasuredBRDF::albedo(self)
+    }
+
+    fn sample(&self, wo: Vec3, n: Vec3, albedo: Vec3, u: Vec3) -> Option<(Vec3, Vec3)> {
+        if n.dot(wo) <= 0.0 {
+            return None;
+        }
+        let wi = if u.z < 0.5 {
+            cosine_direction(n, u.xy())
+        } else {
+            let (tangent, bitangent) = orthonormal_basis(n);
+            let half = self.sample_half_vector(u.xy());
+            reflect(-wo, half.x * tangent + half.y * bitangent + half.z * n)
+        };
+        let pdf = self.pdf(wo, wi, n, albedo);
+        if n.dot(wi) <= 0.0 || pdf <= 0.0 {
+            return None;
+        }
+        Some((wi, self.evaluate(wo, wi, n, albedo) / pdf))
+    }
+
+    fn evaluate(&self, wo: Vec3, wi: Vec3, n: Vec3, _albedo: Vec3) -> Vec3 {
+        self.value(measured_local(wo, n), measured_local(wi, n)) * n.dot(wi).max(0.0)
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3, n: Vec3, _albedo: Vec3) -> f32 {
+        let cos_i = n.dot(wi);
+        if n.dot(wo) <= 0.0 || cos_i <= 0.0 {
+            return 0.0;
+        }
+        let half = (wo + wi).normalize();
+        let half_pdf = self.half_vector_pdf(measured_local(half, n)) / (4.0 * wo.dot(half));
+        0.5 * cos_i * FRAC_1_PI + 0.5 * half_pdf
+    }
+}
+
+// what sits under the coat of a layered material
+#[derive(Copy, Clone, Debug)]
+pub enum LayerBase {
+    Lambertian(Lambertian),
+    Conductor(Conductor),
+}
+
+impl LayerBase {
+    fn bsdf(&self) -> &dyn BSDF {
+        match self {
+            LayerBase::Lambertian(bsdf) => bsdf,
+            LayerBase::Conductor(bsdf) => bsdf,
+        }
+    }
+
+    // roughly what the base reflects toward wo, to pick between it and the coat
+    fn reflectance(&self, cos_o: f32, albedo: Vec3) -> Vec3 {
+        match self {
+            LayerBase::Lambertian(_) => albedo,
+            LayerBase::Conductor(conductor) => albedo * conductor.fresnel(cos_o),
+        }
+    }
+}
+
+// a GGX dielectric coat over a lambertian or conductor base (Weidlich and Wilkie 2007): the
+// coat reflects its Fresnel share of the light, and the base sees what the coat transmits
+// on the way in and on the way out; coat_roughness is the GGX alpha
+#[derive(Copy, Clone, Debug)]
+pub struct Layered {
+    pub base: LayerBase,
+    pub coat_ior: f32,
+    pub coat_roughness: f32,
+}
+
+impl Layered {
+    fn coat_alpha(&self) -> f32 {
+        self.coat_roughness.max(1e-4)
+    }
+
+    // chance of sampling the coat rather than the base, in proportion to what each of
+    // them reflects toward wo
+    fn coat_chance(&self, wo: Vec3, n: Vec3, albedo: Vec3) -> f32 {
+        let luminance = Vec3::new(0.2126, 0.7152, 0.0722);
+        let cos_o = n.dot(wo);
+        let coat = fresnel_dielectric(cos_o, self.coat_ior);
+        let base = (1.0 - coat) * self.base.reflectance(cos_o, albedo).dot(luminance);
+        if coat + base <= 0.0 {
+            return 1.0;
+        }
+        coat / (coat + base)
+    }
+}
+
+impl BSDF for Layered {
+    fn albedo(&self) -> Vec3 {
+        self.base.bsdf().albedo()
+    }
+
+    fn sample(&self, wo: Vec3, n: Vec3, albedo: Vec3, u: Vec3) -> Option<(Vec3, Vec3)> {
+        // u.z picks the coat or the base and u.xy samples it
+        if n.dot(wo) <= 0.0 {
+            return None;
+        }
+        let wi = if u.z < self.coat_chance(wo, n, albedo) {
+            reflect(-wo, sample_microfacet_normal(wo, n, self.coat_alpha(), u.xy()))
+        } else {
+            self.base.bsdf().sample(wo, n, albedo, u)?.0
+        };
+        let pdf = self.pdf(wo, wi, n, albedo);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some((wi, self.evaluate(wo, wi, n, albedo) / pdf))
+    }
+
+    fn evaluate(&self, wo: Vec3, wi: Vec3, n: Vec3, albedo: Vec3) -> Vec3 {
+        let cos_o = n.dot(wo);
+        let cos_i = n.dot(wi);
+        if cos_o <= 0.0 || cos_i <= 0.0 {
+            return Vec3::ZERO;
+        }
+        let alpha = self.coat_alpha();
+        let m = (wo + wi).normalize();
+        let g2 = 1.0 / (1.0 + smith_lambda(cos_o, alpha) + smith_lambda(cos_i, alpha));
+        let coat = fresnel_dielectric(wo.dot(m), self.coat_ior) * ggx_d(n.dot(m), alpha) * g2 / (4.0 * cos_o);
+        let transmitted = (1.0 - fresnel_dielectric(cos_o, self.coat_ior)) * (1.0 - fresnel_dielectric(cos_i, self.coat_ior));
+        self.base.bsdf().evaluate(wo, wi, n, albedo) * transmitted + Vec3::splat(coat)
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3, n: Vec3, albedo: Vec3) -> f32 {
+        if n.dot(wo) <= 0.0 || n.dot(wi) <= 0.0 {
+            return 0.0;
+        }
+        let coat = self.coat_chance(wo, n, albedo);
+        coat * ggx_reflection_pdf(wo, wi, n, self.coat_alpha()) + (1.0 - coat) * self.base.bsdf().pdf(wo, wi, n, albedo)
+    }
+}
+
+// the normal on the side of wo, and the index across the surface over the one wo is in
+fn facing(wo: Vec3, n: Vec3, refract_index: f32) -> (Vec3, f32) {
+    if n.dot(wo) < 0.0 {
+        return (-n, 1.0 / refract_index);
+    }
+    (n, refract_index)
+}
+
+fn reflect(r: Vec3, n: Vec3) -> Vec3 {
+    r - 2.0 * r.dot(n) * n
+}
+
+// r refracted through the surface with normal n facing it, with ri the index on the side
+// of r over the index across; None under total internal reflection
+fn refract(r: Vec3, n: Vec3, ri: f32) -> Option<Vec3> {
+    let cos_theta = r.dot(n);
+    let k = 1.0 - ri * ri * (1.0 - cos_theta * cos_theta);
+    if k < 0.0 {
+        return None;
+    }
+    Some(ri * r - (ri * cos_theta + k.sqrt()) * n)
+}
+
+// two unit vectors perpendicular to n and to each other (Duff et al. 2017)
+fn orthonormal_basis(n: Vec3) -> (Vec3, Vec3) {
+    let sign = 1f32.copysign(n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+    (Vec3::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+     Vec3::new(b, sign + n.y * n.y * a, -n.y))
+}
+
+fn cosine_direction(n: Vec3, u: Vec2) -> Vec3 {
+    let (tangent, bitangent) = orthonormal_basis(n);
+    let r = u.x.sqrt();
+    let phi = 2.0 * PI * u.y;
+    r * phi.cos() * tangent + r * phi.sin() * bitangent + (1.0 - u.x).max(0.0).sqrt() * n
+}
+
+fn uniform_direction(u: Vec2) -> Vec3 {
+    let z = 1.0 - 2.0 * u.x;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.y;
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+// Trowbridge-Reitz distribution of microfacet normals
+fn ggx_d(cos_m: f32, alpha: f32) -> f32 {
+    if cos_m <= 0.0 {
+        return 0.0;
+    }
+    // written with sin^2 so that d stays above 0 for the tiny alpha of smooth surfaces
+    let a2 = alpha * alpha;
+    let d = cos_m * cos_m * a2 + (1.0 - cos_m * cos_m).max(0.0);
+    a2 / (PI * d * d)
+}
+
+fn smith_lambda(cos_theta: f32, alpha: f32) -> f32 {
+    let cos2 = (cos_theta * cos_theta).max(1e-8);
+    let tan2 = ((1.0 - cos2) / cos2).max(0.0);
+    0.5 * ((1.0 + alpha * alpha * tan2).sqrt() - 1.0)
+}
+
+// G2 / G1 of visible normal sampling, as the bsdf times the cosine over the pdf
+fn microfacet_weight(wo: Vec3, wi: Vec3, n: Vec3, alpha: f32) -> f32 {
+    let lambda_o = smith_lambda(n.dot(wo).abs(), alpha);
+    let lambda_i = smith_lambda(n.dot(wi).abs(), alpha);
+    (1.0 + lambda_o) / (1.0 + lambda_o + lambda_i)
+}
+
+// visible normal density of the half vector, G1(wo) D(m) (wo.m) / cos(wo), over the
+// 4 (wo.m) of the reflection jacobian
+fn ggx_reflection_pdf(wo: Vec3, wi: Vec3, n: Vec3, alpha: f32) -> f32 {
+    let cos_o = n.dot(wo);
+    if cos_o <= 0.0 || n.dot(wi) <= 0.0 {
+        return 0.0;
+    }
+    let m = (wo + wi).normalize();
+    ggx_d(n.dot(m), alpha) / (4.0 * cos_o * (1.0 + smith_lambda(cos_o, alpha)))
+}
+
+// the microfacet normal that refracts wo into wi, on the side of n; None if no microfacet
+// facing wo does
+fn refraction_half_vector(wo: Vec3, wi: Vec3, n: Vec3, eta: f32) -> Option<Vec3> {
+    let mut m = (wo + eta * wi).normalize();
+    if n.dot(m) < 0.0 {
+        m *= -1.0;
+    }
+    if wo.dot(m) <= 0.0 || wi.dot(m) >= 0.0 {
+        return None;
+    }
+    Some(m)
+}
+
+// the jacobian of the refraction from the half vector to wi
+fn refraction_jacobian(wo: Vec3, wi: Vec3, m: Vec3, eta: f32) -> f32 {
+    let denom = wi.dot(m) + wo.dot(m) / eta;
+    wi.dot(m).abs() / (denom * denom)
+}
+
+// D(m) |wo.m| |wi.m| / (cos(wo) denom^2), the refracted part of the bsdf times |cos(wi)|
+// before the Fresnel transmittance and the shadowing; the 1 / eta^2 radiance scaling is
+// left out, as for the smooth dielectric
+fn ggx_transmission(wo: Vec3, wi: Vec3, n: Vec3, m: Vec3, eta: f32, alpha: f32) -> f32 {
+    ggx_d(n.dot(m), alpha) * wo.dot(m) * refraction_jacobian(wo, wi, m, eta) / n.dot(wo)
+}
+
+fn ggx_refraction_pdf(wo: Vec3, wi: Vec3, n: Vec3, m: Vec3, eta: f32, alpha: f32) -> f32 {
+    let cos_o = n.dot(wo);
+    let visible = ggx_d(n.dot(m), alpha) * wo.dot(m) / (cos_o * (1.0 + smith_lambda(cos_o, alpha)));
+    visible * refraction_jacobian(wo, wi, m, eta)
+}
+
+// Heitz 2018: sample the projected disk of the hemisphere stretched to roughness 1, in the
+// frame where n is z, then unstretch the sampled normal; a tiny alpha stands in for a
+// smooth surface
+fn sample_microfacet_normal(wo: Vec3, n: Vec3, roughness: f32, u: Vec2) -> Vec3 {
+    let alpha = roughness.max(1e-4);
+    let (tangent, bitangent) = orthonormal_basis(n);
+    let v = Vec3::new(alpha * wo.dot(tangent), alpha * wo.dot(bitangent), wo.dot(n)).normalize();
+    let lensq = v.x * v.x + v.y * v.y;
+    let t1 = if lensq > 0.0 { Vec3::new(-v.y, v.x, 0.0) / lensq.sqrt() } else { Vec3::X };
+    let t2 = v.cross(t1);
+    let r = u.x.sqrt();
+    let phi = 2.0 * PI * u.y;
+    let p1 = r * phi.cos();
+    let s = 0.5 * (1.0 + v.z);
+    let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * r * phi.sin();
+    let nh = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * v;
+    let m = Vec3::new(alpha * nh.x, alpha * nh.y, nh.z.max(0.0)).normalize();
+    m.x * tangent + m.y * bitangent + m.z * n
+}
+
+// a film of thickness nm and index ior over a surface, as on soap bubbles, oil slicks or
+// coated lenses; the light reflected at its two boundaries interferes, which makes its
+// reflectance depend on the wavelength
+#[derive(Copy, Clone, Debug)]
+pub struct ThinFilm {
+    pub thickness: f32,
+    pub ior: f32,
+}
+
+// the wavelengths at which the film is evaluated and integrated into rgb
+const FILM_WAVELENGTHS: usize = 16;
+
+impl ThinFilm {
+    // rgb reflectance for light coming from a medium of index n1 at cos_theta, onto a
+    // substrate of complex index (eta, k) given at 650, 550 and 450nm
+    pub fn reflectance(&self, cos_theta: f32, n1: f32, eta: Vec3, k: Vec3) -> Vec3 {
+        let (mut xyz, mut white) = (Vec3::ZERO, Vec3::ZERO);
+        for i in 0..FILM_WAVELENGTHS {
+            let lambda = 380.0 + (i as f32 + 0.5) * 400.0 / FILM_WAVELENGTHS as f32;
+            let substrate = Vec2::new(rgb_index(eta, lambda), rgb_index(k, lambda));
+            let cie = cie_xyz(lambda);
+            xyz += self.airy(cos_theta, n1, substrate, lambda) * cie;
+            white += cie;
+        }
+        // a flat reflectance comes out the same in rgb
+        (xyz_to_rgb(xyz) / xyz_to_rgb(white)).max(Vec3::ZERO)
+    }
+
+    // the Airy summation of the reflections inside the film at one wavelength, for
+    // unpolarized light
+    fn airy(&self, cos_theta: f32, n1: f32, substrate: Vec2, lambda: f32) -> f32 {
+        let cos1 = cos_theta.clamp(0.0, 1.0);
+        let sin2 = 1.0 - cos1 * cos1;
+        let n2 = self.ior;
+        let sin2_film = (n1 / n2) * (n1 / n2) * sin2;
+        if sin2_film >= 1.0 {
+            return 1.0;
+        }
+        let cos2 = (1.0 - sin2_film).sqrt();
+        let n3 = substrate;
+        let cos3 = complex_sqrt(Vec2::X - sin2 * complex_div(Vec2::new(n1 * n1, 0.0), complex_mul(n3, n3)));
+
+        let r12_s = (n1 * cos1 - n2 * cos2) / (n1 * cos1 + n2 * cos2);
+        let r12_p = (n2 * cos1 - n1 * cos2) / (n2 * cos1 + n1 * cos2);
+        let (a, b) = (Vec2::new(n2 * cos2, 0.0), complex_mul(n3, cos3));
+        let r23_s = complex_div(a - b, a + b);
+        let (a, b) = (n3 * cos2, n2 * cos3);
+        let r23_p = complex_div(a - b, a + b);
+
+        let phase = 4.0 * PI * n2 * self.thickness * cos2 / lambda;
+        let shift = Vec2::new(phase.cos(), phase.sin());
+        let total = |r12: f32, r23: Vec2| {
+            let r23 = complex_mul(r23, shift);
+            complex_div(Vec2::new(r12, 0.0) + r23, Vec2::X + r12 * r23).length_squared()
+        };
+        0.5 * (total(r12_s, r23_s) + total(r12_p, r23_p))
+    }
+
+    // the film seen from a medium of index outside_index; the optical thickness stays the same
+    pub fn relative_to(self, outside_index: f32) -> Self {
+        Self { thickness: self.thickness * outside_index, ior: self.ior / outside_index }
+    }
+}
+
+// a value given at 650, 550 and 450nm, interpolated to lambda
+fn rgb_index(v: Vec3, lambda: f32) -> f32 {
+    if lambda < 550.0 {
+        v.z + (v.y - v.z) * ((lambda - 450.0) / 100.0).clamp(0.0, 1.0)
+    } else {
+        v.y + (v.x - v.y) * ((lambda - 550.0) / 100.0).clamp(0.0, 1.0)
+    }
+}
+
+// complex numbers as (real, imaginary)
+fn complex_mul(a: Vec2, b: Vec2) -> Vec2 {
+    Vec2::new(a.x * b.x - a.y * b.y, a.x * b.y + a.y * b.x)
+}
+
+fn complex_div(a: Vec2, b: Vec2) -> Vec2 {
+    complex_mul(a, Vec2::new(b.x, -b.y)) / b.length_squared()
+}
+
+// the root with a positive real part
+fn complex_sqrt(z: Vec2) -> Vec2 {
+    let r = z.length();
+    let re = (0.5 * (r + z.x)).max(0.0).sqrt();
+    let im = (0.5 * (r - z.x)).max(0.0).sqrt();
+    Vec2::new(re, if z.y < 0.0 { -im } else { im })
+}
+
+// exact reflectance of a conductor in air for unpolarized light
+fn fresnel_conductor(cos_theta: f32, eta: Vec3, k: Vec3) -> Vec3 {
+    let cos_i = cos_theta.clamp(0.0, 1.0);
+    let cos2 = cos_i * cos_i;
+    let sin2 = 1.0 - cos2;
+    let t0 = eta * eta - k * k - Vec3::splat(sin2);
+    let a2plusb2 = (t0 * t0 + 4.0 * eta * eta * k * k).max(Vec3::ZERO).powf(0.5);
+    let t1 = a2plusb2 + Vec3::splat(cos2);
+    let a = (0.5 * (a2plusb2 + t0)).max(Vec3::ZERO).powf(0.5);
+    let t2 = 2.0 * cos_i * a;
+    let rs = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2plusb2 + Vec3::splat(sin2 * sin2);
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    0.5 * (rp + rs)
+}
+
+// exact reflectance for unpolarized light; eta is the index across the boundary over the
+// index on the side of cos_theta
+fn fresnel_dielectric(cos_theta: f32, eta: f32) -> f32 {
+    let cos_i = cos_theta.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let rs = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let rp = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    0.5 * (rs * rs + rp * rp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::GOLD;
+
+    // xorshift, so the test does not depend on the rand crate
+    fn uniform(state: &mut u32) -> f32 {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        (*state >> 8) as f32 / 16777216.0
+    }
+
+    #[test]
+    fn sampling_matches_evaluation() {
+        // the sampled weights are the evaluated bsdf over the pdf, and their average is
+        // what integrating the evaluated bsdf over the sphere of directions gives; the fuzzy
+        // metal is left out, as its pdf goes to infinity at the edge of its cone
+        let n = Vec3::Y;
+        let wo = Vec3::new(-1.0, 2.0, 0.0).normalize();
+        let principled = |base_color: Vec3, metallic: f32, roughness: f32| Principled {
+            base_color, metallic, roughness, specular: 0.5, ior: 1.5, transmission: 0.0,
+            clearcoat: 0.0, clearcoat_roughness: 0.0, sheen: 0.0,
+        };
+        let bsdfs: Vec<Box<dyn BSDF>> = vec![
+            Box::new(Lambertian { albedo: Vec3::new(0.7, 0.5, 0.3) }),
+            Box::new(Conductor { eta: GOLD.0, k: GOLD.1, roughness: 0.1, film: None }),
+            Box::new(Conductor { eta: GOLD.0, k: GOLD.1, roughness: 0.4, film: None }),
+            Box::new(Conductor { eta: GOLD.0, k: GOLD.1, roughness: 0.8, film: None }),
+            Box::new(RoughDielectric { refract_index: 1.5, roughness: 0.3 }),
+            Box::new(Principled { clearcoat: 1.0, clearcoat_roughness: 0.1, sheen: 0.5, ..principled(Vec3::new(0.8, 0.2, 0.1), 0.0, 0.5) }),
+            Box::new(principled(Vec3::new(0.9, 0.6, 0.3), 1.0, 0.3)),
+            Box::new(Principled { transmission: 1.0, ..principled(Vec3::ONE, 0.0, 0.5) }),
+            Box::new(Principled { transmission: 0.5, specular: 0.8, ior: 1.33, ..principled(Vec3::new(0.3, 0.8, 0.5), 0.3, 0.6) }),
+            Box::new(Layered { base: LayerBase::Lambertian(Lambertian { albedo: Vec3::new(0.8, 0.1, 0.1) }), coat_ior: 1.5, coat_roughness: 0.05 }),
+            Box::new(Layered { base: LayerBase::Conductor(Conductor { eta: GOLD.0, k: GOLD.1, roughness: 0.4, film: None }), coat_ior: 1.5, coat_roughness: 0.2 }),
+            Box::new(Conductor { eta: GOLD.0, k: GOLD.1, roughness: 0.3, film: Some(ThinFilm { thickness: 400.0, ior: 1.4 }) }),
+        ];
+        let mut state = 7u32;
+        for (i, bsdf) in bsdfs.iter().enumerate() {
+            let albedo = bsdf.albedo();
+            let samples = 200_000;
+            let mut sampled = Vec3::ZERO;
+            let mut integrated = Vec3::ZERO;
+            for _i in 0..samples {
+                let u = Vec3::new(uniform(&mut state), uniform(&mut state), uniform(&mut state));
+                if let Some((wi, weight)) = bsdf.sample(wo, n, albedo, u) {
+                    sampled += weight;
+                    let wi = wi.normalize();
+                    let expected = bsdf.evaluate(wo, wi, n, albedo) / bsdf.pdf(wo, wi, n, albedo);
+                    assert!((expected - weight).abs().max_element() < 1e-3 * weight.max_element().max(1.0),
+                            "weight {} but evaluate / pdf {}", weight, expected);
+                }
+                let direction = uniform_direction(Vec2::new(uniform(&mut state), uniform(&mut state)));
+                integrated += bsdf.evaluate(wo, direction, n, albedo) * 4.0 * PI;
+            }
+            sampled /= samples as f32;
+            integrated /= samples as f32;
+            assert!((sampled - integrated).abs().max_element() < 0.03,
+                    "bsdf {}: sampling gives {} but integration gives {}", i, sampled, integrated);
+            assert!(sampled.max_element() < 1.01, "bsdf {} reflects {}", i, sampled);
+        }
+    }
+
+    #[test]
+    fn measured_sampling_matches_its_source() {
+        // the table of a rough gold conductor reflects about as much light as the conductor,
+        // whether it is sampled or integrated; single samples are not compared with evaluate
+        // over pdf, as the table steps from one bin to the next
+        let conductor = Conductor { eta: GOLD.0, k: GOLD.1, roughness: 0.3, film: None };
+        let measured = MeasuredBRDF::tabulate(|wo, wi| conductor.evaluate(wo, wi, Vec3::Z, Vec3::ONE) / wi.z);
+        let n = Vec3::Y;
+        let wo = Vec3::new(-1.0, 2.0, 0.0).normalize();
+        let mut state = 7u32;
+        let samples = 200_000;
+        let (mut sampled, mut integrated, mut reference) = (Vec3::ZERO, Vec3::ZERO, Vec3::ZERO);
+        for _i in 0..samples {
+            let u = Vec3::new(uniform(&mut state), uniform(&mut state), uniform(&mut state));
+            if let Some((_wi, weight)) = measured.sample(wo, n, Vec3::ONE, u) {
+                sampled += weight;
+            }
+            if let Some((_wi, weight)) = conductor.sample(wo, n, Vec3::ONE, u) {
+                reference += weight;
+            }
+            let direction = uniform_direction(Vec2::new(uniform(&mut state), uniform(&mut state)));
+            integrated += measured.evaluate(wo, direction, n, Vec3::ONE) * 4.0 * PI;
+        }
+        sampled /= samples as f32;
+        integrated /= samples as f32;
+        reference /= samples as f32;
+        assert!((sampled - integrated).abs().max_element() < 0.03, "sampling gives {} but integration gives {}", sampled, integrated);
+        assert!((sampled - reference).abs().max_element() < 0.03, "the table reflects {} but the conductor {}", sampled, reference);
+    }
+
+    #[test]
+    fn thin_film_reduces_to_fresnel() {
+        // without thickness, or with the index of what is under it, the film leaves the
+        // reflectance of the bare surface
+        let bare = ThinFilm { thickness: 0.0, ior: 1.38 };
+        for cos_theta in [1.0, 0.7, 0.3, 0.05] {
+            let glass = bare.airy(cos_theta, 1.0, Vec2::new(1.5, 0.0), 550.0);
+            assert!((glass - fresnel_dielectric(cos_theta, 1.5)).abs() < 1e-4, "{} at {}", glass, cos_theta);
+            let gold = bare.airy(cos_theta, 1.0, Vec2::new(GOLD.0.y, GOLD.1.y), 550.0);
+            let expected = fresnel_conductor(cos_theta, Vec3::splat(GOLD.0.y), Vec3::splat(GOLD.1.y)).x;
+            assert!((gold - expected).abs() < 1e-4, "{} instead of {} at {}", gold, expected, cos_theta);
+            let matched = ThinFilm { thickness: 300.0, ior: 1.5 }.reflectance(cos_theta, 1.0, Vec3::splat(1.5), Vec3::ZERO);
+            assert!((matched - Vec3::splat(fresnel_dielectric(cos_theta, 1.5))).abs().max_element() < 1e-3, "{} at {}", matched, cos_theta);
+        }
+        // a soap film in air is colored, and reflects no more than a tenth of the light head on
+        let bubble = ThinFilm { thickness: 350.0, ior: 1.33 }.reflectance(1.0, 1.0, Vec3::ONE, Vec3::ZERO);
+        assert!(bubble.max_element() - bubble.min_element() > 0.02, "{}", bubble);
+        assert!(bubble.max_element() < 0.1 && bubble.min_element() >= 0.0, "{}", bubble);
+    }
+}
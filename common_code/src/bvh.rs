@@ -1,7 +1,12 @@
+use crate::parameters::BVHParameters;
 use crate::sphere::Sphere;
 use glam::{Vec3};
+use std::fmt;
 
 const BINS: usize = 4096;
+// the traversal stacks of both tracers hold this many nodes, one per level below the root
+// at most, so builders keep their trees within this many levels
+pub const TRAVERSAL_STACK_SIZE: usize = 32;
 
 pub struct Bin {
     aabb_min: Vec3,
@@ -140,15 +145,94 @@ impl BVHNode {
 }
 
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BVHBuilder {
+    // binned object partitioning with the SAH
+    Sah,
+    // spatial split bvh; see BVHTree::build_sbvh_tree for the overlap threshold
+    Sbvh { overlap_threshold: f32 },
+    // linear bvh built on the GPU by gpu_tracer, one sphere per leaf
+    GpuLbvh,
+}
+
 pub struct BVHTree {
     pub nodes: Vec<BVHNode>,
 }
 
+// summary of a built tree; the sah cost uses unit traversal and intersection costs and
+// is relative to the surface area of the root
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct BVHStatistics {
+    pub node_count: usize,
+    pub leaf_count: usize,
+    pub max_depth: usize,
+    pub max_leaf_size: u32,
+    pub sah_cost: f32,
+}
+
+impl fmt::Display for BVHStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} nodes, {} leaves, depth {}, max leaf size {}, sah cost {:.3}",
+               self.node_count, self.leaf_count, self.max_depth, self.max_leaf_size, self.sah_cost)
+    }
+}
+
 impl BVHTree {
     pub fn new(num_primitives: usize) -> Self {
         Self { nodes: Vec::<BVHNode>::with_capacity(2 * num_primitives) }
     }
 
+    // builds the tree with the builder selected in the bvh parameters; spheres is reordered
+    // to match the leaves, and may grow if the builder duplicates references
+    pub fn build(&mut self, spheres: &mut Vec<Sphere>, bvh_parameters: &BVHParameters) {
+        match bvh_parameters.builder {
+            BVHBuilder::Sah => self.build_bvh_tree(spheres),
+            BVHBuilder::Sbvh { overlap_threshold } => self.build_sbvh_tree(spheres, overlap_threshold),
+            // there is no GPU to build on here, so fall back to the binned SAH builder
+            BVHBuilder::GpuLbvh => self.build_bvh_tree(spheres),
+        }
+
+        if let Some(budget) = bvh_parameters.optimization {
+            let (before, after) = self.optimize(budget);
+            log::info!("bvh optimization: before {}; after {}", before, after);
+        }
+    }
+
+    pub fn statistics(&self) -> BVHStatistics {
+        let mut statistics = BVHStatistics::default();
+        // the root of an empty scene is an interior node without children
+        if self.nodes.is_empty() || (self.nodes[0].prim_count == 0 && self.nodes.len() <= 2) {
+            return statistics;
+        }
+
+        let root = &self.nodes[0];
+        let extent = root.aabb_max - root.aabb_min;
+        let root_area = extent.x * extent.y + extent.y * extent.z + extent.z * extent.x;
+        let mut cost = 0.0;
+
+        // (node index, depth)
+        let mut stack = vec![(0usize, 1usize)];
+        while let Some((idx, depth)) = stack.pop() {
+            let node = &self.nodes[idx];
+            statistics.node_count += 1;
+            statistics.max_depth = statistics.max_depth.max(depth);
+            if node.prim_count > 0 {
+                statistics.leaf_count += 1;
+                statistics.max_leaf_size = statistics.max_leaf_size.max(node.prim_count);
+                cost += node.find_node_cost();
+            } else {
+                let extent = node.aabb_max - node.aabb_min;
+                cost += extent.x * extent.y + extent.y * extent.z + extent.z * extent.x;
+                stack.push((node.left_first as usize, depth + 1));
+                stack.push((node.left_first as usize + 1, depth + 1));
+            }
+        }
+        if root_area > 0.0 {
+            statistics.sah_cost = cost / root_area;
+        }
+        statistics
+    }
+
     pub fn build_bvh_tree(&mut self, spheres: &mut [Sphere]) {
         let prim_count = spheres.len() as u32;
         let mut node = BVHNode::default();
@@ -160,7 +244,10 @@ impl BVHTree {
         // push an empty node at index 1 as a placeholder that will never be used
         self.nodes.push(BVHNode::default());
 
-        self.subdivide(0, spheres);
+        // an empty scene keeps a root with nothing to split
+        if prim_count > 0 {
+            self.subdivide(0, spheres);
+        }
     }
 
     fn subdivide(&mut self, index: usize, spheres: &mut [Sphere]) {
@@ -208,4 +295,203 @@ impl BVHTree {
         self.subdivide(node_idx, spheres);
         self.subdivide(node_idx + 1, spheres);
     }
+
+    // packs the tree into the quantized layout; node i of the packed buffer corresponds to
+    // node i of self.nodes, so left_first and prim_count keep their meaning
+    pub fn quantize(&self, layout: BVHLayout) -> Vec<u32> {
+        let bits = layout.quantization_bits();
+        assert!(bits > 0, "the full precision layout uses the BVHNodes directly");
+        let stride = layout.node_stride();
+        let max_q = ((1u32 << bits) - 1) as f32;
+        let values_per_word = (32 / bits) as usize;
+
+        let mut packed = vec![0u32; stride * self.nodes.len()];
+        for (idx, node) in self.nodes.iter().enumerate() {
+            let words = &mut packed[idx * stride .. (idx + 1) * stride];
+            words[3] = node.left_first;
+            // leaves have no children to decode, so their first child word holds the count
+            if node.prim_count > 0 {
+                words[4] = BVHLayout::LEAF_FLAG;
+                words[BVHLayout::HEADER_WORDS] = node.prim_count;
+                continue;
+            }
+            // an empty tree has an inverted root box; leave it zeroed
+            if !node.aabb_max.is_finite() || node.aabb_min.cmpgt(node.aabb_max).any() {
+                continue;
+            }
+            let origin = node.aabb_min;
+            words[0] = origin.x.to_bits();
+            words[1] = origin.y.to_bits();
+            words[2] = origin.z.to_bits();
+            let mut scale = Vec3::ZERO;
+            for axis in 0..3 {
+                let exponent = Self::quantization_exponent(origin[axis], node.aabb_max[axis], max_q);
+                words[4] |= exponent << (8 * axis);
+                scale[axis] = f32::from_bits(exponent << 23);
+            }
+
+            // quantized values are ordered left min, left max, right min, right max
+            let mut values = [0u32; 12];
+            for child in 0..2 {
+                let child_node = &self.nodes[node.left_first as usize + child];
+                for axis in 0..3 {
+                    let (q_min, q_max) = Self::quantize_interval(
+                        child_node.aabb_min[axis], child_node.aabb_max[axis],
+                        origin[axis], scale[axis], max_q);
+                    values[6 * child + axis] = q_min;
+                    values[6 * child + 3 + axis] = q_max;
+                }
+            }
+            for (k, value) in values.iter().enumerate() {
+                words[BVHLayout::HEADER_WORDS + k / values_per_word] |=
+                    value << ((k % values_per_word) as u32 * bits);
+            }
+        }
+        packed
+    }
+
+    // the biased f32 exponent of the smallest power of two scale for which
+    // origin + max_q * scale reaches max; 0 when the box is flat along the axis
+    fn quantization_exponent(origin: f32, max: f32, max_q: f32) -> u32 {
+        if max <= origin {
+            return 0;
+        }
+        let mut exponent = (((max - origin) / max_q).log2().ceil() as i32 + 127).clamp(1, 254) as u32;
+        while exponent < 254 && origin + max_q * f32::from_bits(exponent << 23) < max {
+            exponent += 1;
+        }
+        exponent
+    }
+
+    // rounds the child interval outwards so the decoded box (origin + q * scale)
+    // always contains the original one
+    fn quantize_interval(min: f32, max: f32, origin: f32, scale: f32, max_q: f32) -> (u32, u32) {
+        if scale <= 0.0 {
+            return (0, 0);
+        }
+        let mut q_min = ((min - origin) / scale).floor().clamp(0.0, max_q);
+        let mut q_max = ((max - origin) / scale).ceil().clamp(0.0, max_q);
+        if q_min > 0.0 && origin + q_min * scale > min {
+            q_min -= 1.0;
+        }
+        if q_max < max_q && origin + q_max * scale < max {
+            q_max += 1.0;
+        }
+        (q_min as u32, q_max as u32)
+    }
+}
+
+// the GPU can read the tree either as the full precision BVHNodes or as a compact
+// u32 buffer where each interior node stores its own origin and a power of two scale
+// per axis, and the bounds of both children quantized relative to them; traversal then
+// only has to fetch one node (instead of both children) to test the two child boxes
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BVHLayout {
+    Full,
+    Quantized8,
+    Quantized16,
+}
+
+impl BVHLayout {
+    // words 0..3 origin, 3 left_first, 4 the biased exponents of the scale in its low three
+    // bytes and LEAF_FLAG for leaves, then the child bounds, or the prim_count of a leaf
+    const HEADER_WORDS: usize = 5;
+    const LEAF_FLAG: u32 = 1 << 24;
+
+    // 0 means the full precision layout
+    pub fn quantization_bits(&self) -> u32 {
+        match self {
+            BVHLayout::Full => 0,
+            BVHLayout::Quantized8 => 8,
+            BVHLayout::Quantized16 => 16,
+        }
+    }
+
+    // number of u32 words per node; the GPU reads the quantized nodes from an array of
+    // u32, so they need no padding. an 8 bit node is as large as a full BVHNode, and a 16
+    // bit one (44 bytes) is still smaller than the two full children it replaces
+    pub fn node_stride(&self) -> usize {
+        match self {
+            BVHLayout::Full => 8,
+            BVHLayout::Quantized8 => Self::HEADER_WORDS + 3,
+            BVHLayout::Quantized16 => Self::HEADER_WORDS + 6,
+        }
+    }
+
+    pub fn node_size_bytes(&self) -> usize {
+        self.node_stride() * size_of::<u32>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvh_optimizer::OptimizationBudget;
+    use std::time::Duration;
+
+    // decodes the child boxes of an interior node the way compute_megakernel.wgsl does
+    fn decode_children(packed: &[u32], layout: BVHLayout, idx: usize) -> [(Vec3, Vec3); 2] {
+        let words = &packed[idx * layout.node_stride()..(idx + 1) * layout.node_stride()];
+        let bits = layout.quantization_bits();
+        let values_per_word = (32 / bits) as usize;
+        let origin = Vec3::new(f32::from_bits(words[0]), f32::from_bits(words[1]), f32::from_bits(words[2]));
+        let scale = Vec3::from_array([0, 1, 2].map(|axis| f32::from_bits(((words[4] >> (8 * axis)) & 0xff) << 23)));
+        let value = |k: usize| {
+            let word = words[BVHLayout::HEADER_WORDS + k / values_per_word];
+            ((word >> ((k % values_per_word) as u32 * bits)) & ((1u32 << bits) - 1)) as f32
+        };
+        let corner = |first: usize| origin + scale * Vec3::new(value(first), value(first + 1), value(first + 2));
+        [(corner(0), corner(3)), (corner(6), corner(9))]
+    }
+
+    #[test]
+    fn quantized_children_contain_the_original_boxes() {
+        // spheres of very different sizes, far from the origin, so the scales cover a wide range
+        let mut state = 1u32;
+        let mut uniform = || {
+            state = state.wrapping_mul(747796405).wrapping_add(2891336453);
+            (state >> 8) as f32 / 16777216.0
+        };
+        let mut spheres: Vec<Sphere> = (0..500).map(|_i| {
+            let center = Vec3::new(uniform(), uniform(), uniform()) * 200.0 + Vec3::new(1000.0, -40.0, 3.0);
+            Sphere::new(center, 0.001 + 5.0 * uniform() * uniform(), 0)
+        }).collect();
+        let mut tree = BVHTree::new(spheres.len());
+        tree.build_bvh_tree(&mut spheres);
+
+        for layout in [BVHLayout::Quantized8, BVHLayout::Quantized16] {
+            let packed = tree.quantize(layout);
+            let stride = layout.node_stride();
+            for (idx, node) in tree.nodes.iter().enumerate() {
+                assert_eq!(packed[idx * stride + 3], node.left_first);
+                let is_leaf = packed[idx * stride + 4] & BVHLayout::LEAF_FLAG != 0;
+                assert_eq!(is_leaf, node.prim_count > 0);
+                if node.prim_count > 0 {
+                    assert_eq!(packed[idx * stride + BVHLayout::HEADER_WORDS], node.prim_count);
+                    continue;
+                }
+                // the unused placeholder at index 1 has no children
+                if idx == 1 {
+                    continue;
+                }
+                for (child, (min, max)) in decode_children(&packed, layout, idx).into_iter().enumerate() {
+                    let original = &tree.nodes[node.left_first as usize + child];
+                    assert!(min.cmple(original.aabb_min).all() && max.cmpge(original.aabb_max).all(),
+                            "{:?} node {}: decoded {} {} does not contain {} {}",
+                            layout, idx, min, max, original.aabb_min, original.aabb_max);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn empty_scene_has_empty_statistics() {
+        let mut spheres: Vec<Sphere> = Vec::new();
+        let mut tree = BVHTree::new(spheres.len());
+        tree.build_bvh_tree(&mut spheres);
+
+        assert_eq!(tree.statistics(), BVHStatistics::default());
+        let (before, after) = tree.optimize(OptimizationBudget::new(10, Duration::from_secs(1)));
+        assert_eq!((before, after), (BVHStatistics::default(), BVHStatistics::default()));
+    }
 }
@@ -0,0 +1,323 @@
+use crate::bvh::{BVHNode, BVHStatistics, BVHTree, TRAVERSAL_STACK_SIZE};
+use glam::Vec3;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::time::{Duration, Instant};
+
+// the pass stops at whichever limit is reached first, or when a sweep stops paying off
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OptimizationBudget {
+    pub max_iterations: u32,
+    pub max_time: Duration,
+}
+
+impl OptimizationBudget {
+    pub fn new(max_iterations: u32, max_time: Duration) -> Self {
+        Self {
+            max_iterations,
+            max_time
+        }
+    }
+}
+
+// a sweep that lowers the SAH by less than this fraction ends the pass
+const MIN_IMPROVEMENT: f32 = 0.001;
+const NONE: usize = usize::MAX;
+
+// while optimizing the tree is held with explicit child and parent links, as the
+// flat layout needs siblings to be adjacent
+#[derive(Copy, Clone)]
+struct LinkedNode {
+    aabb_min: Vec3,
+    aabb_max: Vec3,
+    parent: usize,
+    left: usize,
+    right: usize,
+    // only meaningful for leaves, which are never split or merged
+    left_first: u32,
+    prim_count: u32,
+    // levels of the subtree, 1 for a leaf
+    height: usize,
+}
+
+impl LinkedNode {
+    fn is_leaf(&self) -> bool {
+        self.prim_count > 0
+    }
+
+    fn area(&self) -> f32 {
+        area(self.aabb_min, self.aabb_max)
+    }
+}
+
+fn area(aabb_min: Vec3, aabb_max: Vec3) -> f32 {
+    let extent = aabb_max - aabb_min;
+    extent.x * extent.y + extent.y * extent.z + extent.z * extent.x
+}
+
+// min heap entry for the branch and bound search
+struct Candidate {
+    induced_cost: f32,
+    node: usize,
+    // level of the node, the root being 1
+    depth: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.induced_cost == other.induced_cost
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.induced_cost.total_cmp(&self.induced_cost)
+    }
+}
+
+impl BVHTree {
+    // lowers the SAH cost of an already built tree by removing subtrees and reinserting
+    // them where they increase the surface area the least (Bittner et al. 2013); leaves
+    // keep their primitive ranges so the spheres do not have to be reordered
+    // no reinsertion takes the tree past TRAVERSAL_STACK_SIZE levels
+    // returns the statistics of the tree before and after the pass
+    pub fn optimize(&mut self, budget: OptimizationBudget) -> (BVHStatistics, BVHStatistics) {
+        let before = self.statistics();
+        // a single leaf, or the root of an empty scene, has nothing to reinsert
+        if self.nodes.len() <= 2 {
+            return (before, before);
+        }
+
+        let start = Instant::now();
+        let (mut linked, mut root) = self.to_linked();
+        let mut cost = before.sah_cost;
+
+        'sweeps: for _iteration in 0..budget.max_iterations {
+            // reinsert the largest nodes first, they are the most likely to be misplaced
+            let mut order: Vec<usize> = (0..linked.len())
+                .filter(|&idx| linked[idx].parent != NONE)
+                .collect();
+            order.sort_by(|&a, &b| linked[b].area().total_cmp(&linked[a].area()));
+
+            for node in order {
+                if start.elapsed() > budget.max_time {
+                    break 'sweeps;
+                }
+                Self::reinsert(&mut linked, &mut root, node);
+            }
+
+            let new_cost = Self::linked_sah_cost(&linked, root);
+            let improvement = (cost - new_cost) / cost;
+            cost = new_cost;
+            if improvement < MIN_IMPROVEMENT {
+                break;
+            }
+        }
+
+        self.store_linked(&linked, root);
+        (before, self.statistics())
+    }
+
+    fn to_linked(&self) -> (Vec<LinkedNode>, usize) {
+        let mut linked: Vec<LinkedNode> = self.nodes.iter().map(|node| LinkedNode {
+            aabb_min: node.aabb_min,
+            aabb_max: node.aabb_max,
+            parent: NONE,
+            left: NONE,
+            right: NONE,
+            left_first: node.left_first,
+            prim_count: node.prim_count,
+            height: 1,
+        }).collect();
+
+        // walk down from the root so the unused placeholder at index 1 never gets linked;
+        // without a parent it is also left out of the sweeps
+        let mut order = Vec::with_capacity(linked.len());
+        let mut stack = vec![0usize];
+        while let Some(idx) = stack.pop() {
+            order.push(idx);
+            if linked[idx].is_leaf() {
+                continue;
+            }
+            let left = self.nodes[idx].left_first as usize;
+            linked[idx].left = left;
+            linked[idx].right = left + 1;
+            linked[left].parent = idx;
+            linked[left + 1].parent = idx;
+            stack.push(left);
+            stack.push(left + 1);
+        }
+        // children come after their parents in the walk
+        for &idx in order.iter().rev() {
+            if !linked[idx].is_leaf() {
+                linked[idx].height = 1 + linked[linked[idx].left].height.max(linked[linked[idx].right].height);
+            }
+        }
+        (linked, 0)
+    }
+
+    fn store_linked(&mut self, linked: &[LinkedNode], root: usize) {
+        let mut nodes = Vec::<BVHNode>::with_capacity(self.nodes.len());
+        nodes.push(BVHNode::default());
+        // push an empty node at index 1 as a placeholder that will never be used
+        nodes.push(BVHNode::default());
+
+        // (linked node, flat index)
+        let mut stack = vec![(root, 0usize)];
+        while let Some((idx, flat_idx)) = stack.pop() {
+            let node = &linked[idx];
+            nodes[flat_idx].aabb_min = node.aabb_min;
+            nodes[flat_idx].aabb_max = node.aabb_max;
+            if node.is_leaf() {
+                nodes[flat_idx].left_first = node.left_first;
+                nodes[flat_idx].prim_count = node.prim_count;
+            } else {
+                let child_idx = nodes.len();
+                nodes.push(BVHNode::default());
+                nodes.push(BVHNode::default());
+                nodes[flat_idx].left_first = child_idx as u32;
+                nodes[flat_idx].prim_count = 0;
+                stack.push((node.left, child_idx));
+                stack.push((node.right, child_idx + 1));
+            }
+        }
+        self.nodes = nodes;
+    }
+
+    fn reinsert(linked: &mut [LinkedNode], root: &mut usize, node: usize) {
+        let parent = linked[node].parent;
+        if parent == NONE || linked[parent].parent == NONE {
+            // children of the root have nowhere better to go
+            return;
+        }
+
+        // remove the node; its sibling takes the place of the parent, and the parent is
+        // kept to become the new interior node at the insertion point
+        let grandparent = linked[parent].parent;
+        let sibling = if linked[parent].left == node { linked[parent].right } else { linked[parent].left };
+        Self::replace_child(linked, grandparent, parent, sibling);
+        Self::refit(linked, grandparent);
+
+        let target = Self::find_best_sibling(linked, *root, node, sibling);
+
+        let target_parent = linked[target].parent;
+        linked[parent].parent = target_parent;
+        linked[parent].left = target;
+        linked[parent].right = node;
+        linked[target].parent = parent;
+        linked[node].parent = parent;
+        if target_parent == NONE {
+            *root = parent;
+        } else {
+            Self::replace_child(linked, target_parent, target, parent);
+        }
+        Self::refit(linked, parent);
+    }
+
+    // branch and bound search for the node that, as the new sibling, adds the least
+    // surface area to the tree without making it deeper than the traversal stacks allow;
+    // the old sibling is kept when no other node fits
+    fn find_best_sibling(linked: &[LinkedNode], root: usize, node: usize, sibling: usize) -> usize {
+        let (node_min, node_max) = (linked[node].aabb_min, linked[node].aabb_max);
+        let node_area = linked[node].area();
+        let node_height = linked[node].height;
+
+        let mut best = sibling;
+        let mut best_cost = f32::INFINITY;
+        let mut heap = BinaryHeap::new();
+        heap.push(Candidate { induced_cost: 0.0, node: root, depth: 1 });
+
+        while let Some(Candidate { induced_cost, node: candidate, depth }) = heap.pop() {
+            if induced_cost + node_area >= best_cost {
+                break;
+            }
+            let direct_cost = area(linked[candidate].aabb_min.min(node_min),
+                                   linked[candidate].aabb_max.max(node_max));
+            let cost = induced_cost + direct_cost;
+            // the new parent takes the candidate's level, and both subtrees move one below it
+            let fits = depth + node_height.max(linked[candidate].height) <= TRAVERSAL_STACK_SIZE;
+            if fits && cost < best_cost {
+                best_cost = cost;
+                best = candidate;
+            }
+
+            // going further down, this node's box still has to grow to hold the subtree
+            let child_induced_cost = cost - linked[candidate].area();
+            if !linked[candidate].is_leaf() && child_induced_cost + node_area < best_cost {
+                heap.push(Candidate { induced_cost: child_induced_cost, node: linked[candidate].left, depth: depth + 1 });
+                heap.push(Candidate { induced_cost: child_induced_cost, node: linked[candidate].right, depth: depth + 1 });
+            }
+        }
+        best
+    }
+
+    fn replace_child(linked: &mut [LinkedNode], parent: usize, old_child: usize, new_child: usize) {
+        if linked[parent].left == old_child {
+            linked[parent].left = new_child;
+        } else {
+            linked[parent].right = new_child;
+        }
+        linked[new_child].parent = parent;
+    }
+
+    fn refit(linked: &mut [LinkedNode], from: usize) {
+        let mut idx = from;
+        while idx != NONE {
+            let (left, right) = (linked[idx].left, linked[idx].right);
+            linked[idx].aabb_min = linked[left].aabb_min.min(linked[right].aabb_min);
+            linked[idx].aabb_max = linked[left].aabb_max.max(linked[right].aabb_max);
+            linked[idx].height = 1 + linked[left].height.max(linked[right].height);
+            idx = linked[idx].parent;
+        }
+    }
+
+    fn linked_sah_cost(linked: &[LinkedNode], root: usize) -> f32 {
+        let mut cost = 0.0;
+        let mut stack = vec![root];
+        while let Some(idx) = stack.pop() {
+            let node = &linked[idx];
+            if node.is_leaf() {
+                cost += node.prim_count as f32 * node.area();
+            } else {
+                cost += node.area();
+                stack.push(node.left);
+                stack.push(node.right);
+            }
+        }
+        cost / linked[root].area()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sphere::Sphere;
+
+    #[test]
+    fn optimized_tree_fits_the_traversal_stack() {
+        // spheres doubling in size and distance along a line, where the cheapest trees are
+        // long chains with a sphere peeled off at every level
+        let mut spheres: Vec<Sphere> = (0..60)
+            .map(|i| {
+                let x = 2.0f32.powi(i);
+                Sphere::new(Vec3::new(x, 0.0, 0.0), 0.01 * x, 0)
+            })
+            .collect();
+        let mut bvh_tree = BVHTree::new(spheres.len());
+        bvh_tree.build_bvh_tree(&mut spheres);
+
+        let (before, after) = bvh_tree.optimize(OptimizationBudget::new(100, Duration::from_secs(10)));
+        assert!(before.max_depth <= TRAVERSAL_STACK_SIZE);
+        assert!(after.max_depth <= TRAVERSAL_STACK_SIZE, "{}", after);
+        assert!(after.sah_cost <= before.sah_cost);
+        assert_eq!(after.leaf_count, before.leaf_count);
+    }
+}
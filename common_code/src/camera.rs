@@ -29,6 +29,12 @@ impl Camera {
         Self::new(look_from, look_at)
     }
 
+    pub fn cornell_box_camera() -> Self {
+        let look_at = Vec3::new(0.0, 1.0, 0.0);
+        let look_from = Vec3::new(0.0, 1.0, 6.5);
+        Self::new(look_from, look_at)
+    }
+
     pub fn get_camera(&self) -> (Vec3, f32, f32) {
         (self.position, self.pitch, self.yaw)
     }
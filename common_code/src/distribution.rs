@@ -0,0 +1,98 @@
+use glam::Vec2;
+
+// piecewise constant distribution over [0, 1), proportional to func
+pub struct Distribution1D {
+    func: Vec<f32>,
+    cdf: Vec<f32>,
+    func_int: f32,
+}
+
+impl Distribution1D {
+    pub fn new(func: &[f32]) -> Self {
+        let n = func.len();
+        let mut cdf = vec![0.0f32; n + 1];
+        for i in 0..n {
+            cdf[i + 1] = cdf[i] + func[i] / n as f32;
+        }
+        let func_int = cdf[n];
+        if func_int == 0.0 {
+            // nothing to prefer, so fall back to uniform
+            for (i, c) in cdf.iter_mut().enumerate().skip(1) {
+                *c = i as f32 / n as f32;
+            }
+        } else {
+            for c in cdf.iter_mut().skip(1) {
+                *c /= func_int;
+            }
+        }
+        Self { func: func.to_vec(), cdf, func_int }
+    }
+
+    pub fn count(&self) -> usize { self.func.len() }
+    pub fn func_int(&self) -> f32 { self.func_int }
+
+    // returns the sampled point, its pdf and the index of the segment it fell in
+    pub fn sample(&self, u: f32) -> (f32, f32, usize) {
+        // last cdf entry that is <= u
+        let offset = self.cdf.partition_point(|&c| c <= u).clamp(1, self.count()) - 1;
+        let mut du = u - self.cdf[offset];
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        if width > 0.0 {
+            du /= width;
+        }
+        let pdf = if self.func_int > 0.0 { self.func[offset] / self.func_int } else { 1.0 };
+        ((offset as f32 + du) / self.count() as f32, pdf, offset)
+    }
+
+    pub fn pdf(&self, x: f32) -> f32 {
+        let offset = ((x * self.count() as f32) as usize).min(self.count() - 1);
+        if self.func_int > 0.0 { self.func[offset] / self.func_int } else { 1.0 }
+    }
+}
+
+// piecewise constant distribution over [0, 1)^2: the row is picked with the marginal
+// distribution, then the column with that row's conditional one
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    // func holds height rows of width values each
+    pub fn new(func: &[f32], width: usize, height: usize) -> Self {
+        assert_eq!(func.len(), width * height, "function size does not match the distribution size");
+        let conditional: Vec<Distribution1D> = func.chunks(width).map(Distribution1D::new).collect();
+        let row_integrals: Vec<f32> = conditional.iter().map(|row| row.func_int()).collect();
+        let marginal = Distribution1D::new(&row_integrals);
+        Self { conditional, marginal }
+    }
+
+    // returns the sampled (u, v) point and its pdf
+    pub fn sample(&self, u: Vec2) -> (Vec2, f32) {
+        let (v, pdf_v, row) = self.marginal.sample(u.y);
+        let (u, pdf_u, _) = self.conditional[row].sample(u.x);
+        (Vec2::new(u, v), pdf_u * pdf_v)
+    }
+
+    pub fn pdf(&self, p: Vec2) -> f32 {
+        let row = ((p.y * self.marginal.count() as f32) as usize).min(self.marginal.count() - 1);
+        self.conditional[row].pdf(p.x) * self.marginal.pdf(p.y)
+    }
+
+    // flattened for the shaders, which find each part from the width and height:
+    // the conditional functions (height * width), the conditional cdfs (height * (width + 1)),
+    // the marginal function (height), the marginal cdf (height + 1) and its integral
+    pub fn to_gpu(&self) -> Vec<f32> {
+        let mut data = Vec::new();
+        for row in &self.conditional {
+            data.extend_from_slice(&row.func);
+        }
+        for row in &self.conditional {
+            data.extend_from_slice(&row.cdf);
+        }
+        data.extend_from_slice(&self.marginal.func);
+        data.extend_from_slice(&self.marginal.cdf);
+        data.push(self.marginal.func_int);
+        data
+    }
+}
@@ -51,14 +51,11 @@ impl GPUBuffer {
 
     pub fn layout(&self, visibility: ShaderStages, read_only: bool) -> BindGroupLayoutEntry {
         let mut buffer_binding_type: BufferBindingType = Default::default();
-        match self.usage {
-            BufferUsages::STORAGE => {
-                buffer_binding_type = BufferBindingType::Storage { read_only };
-            }
-            BufferUsages::UNIFORM => {
-                buffer_binding_type = BufferBindingType::Uniform;
-            }
-            _ => {}
+        // the usage may also carry copy flags, e.g. to read a buffer back
+        if self.usage.contains(BufferUsages::STORAGE) {
+            buffer_binding_type = BufferBindingType::Storage { read_only };
+        } else if self.usage.contains(BufferUsages::UNIFORM) {
+            buffer_binding_type = BufferBindingType::Uniform;
         }
         BindGroupLayoutEntry {
             binding: self.binding_idx,
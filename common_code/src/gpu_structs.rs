@@ -1,7 +1,13 @@
-use glam::{Vec4};
+use glam::{Vec3, Vec4};
 use crate::parameters::SamplingParameters;
 use crate::camera::Camera;
 use crate::camera_controller::CameraController;
+use crate::background::Background;
+use crate::light::{GPULight, Light};
+use crate::material::GPUMaterial;
+use crate::sky::{PreethamSky, SkyParameters};
+use crate::texture::{GPUTexture, Texture};
+use crate::scene::Scene;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
@@ -9,7 +15,8 @@ pub struct GPUSamplingParameters {
     samples_per_frame: u32,
     num_bounces: u32,
     clear_image_buffer: u32,
-    _buffer: u32,
+    // 1 to trace the path at four wavelengths
+    spectral: u32,
 }
 
 // sampling parameters are set at initialization but can also be changed by the user via GUI
@@ -21,12 +28,191 @@ impl GPUSamplingParameters {
             samples_per_frame: sampling_parameters.samples_per_frame,
             num_bounces: sampling_parameters.num_bounces,
             clear_image_buffer: sampling_parameters.clear_image_buffer,
-            _buffer: 0u32
+            spectral: sampling_parameters.spectral as u32,
         }
     }
     pub fn spf(&self) -> u32 { self. samples_per_frame}
     pub fn num_bounces(&self) -> u32 { self.num_bounces }
     pub fn clear_image(&self) -> u32 { self.clear_image_buffer }
+    pub fn spectral(&self) -> u32 { self.spectral }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct GPUSceneParameters {
+    // 0 solid color, 1 gradient, 2 environment map, 3 Preetham sky
+    background_type: u32,
+    environment_width: u32,
+    environment_height: u32,
+    // 1 to importance sample the environment map at every diffuse bounce
+    environment_sampling: u32,
+    // the solid color, or the bottom and top of the gradient
+    background_bottom: Vec4,
+    background_top: Vec4,
+    // number of entries in the lights buffer
+    light_count: u32,
+    _buffer: [u32; 3],
+    // the Perez coefficients A to E and the zenith values of the sky, each holding
+    // (Y, x, y), and the direction to the sun, as laid out by PreethamSky::to_gpu
+    sky: [Vec4; 7],
+}
+
+unsafe impl bytemuck::Pod for GPUSceneParameters {}
+unsafe impl bytemuck::Zeroable for GPUSceneParameters {}
+
+// scene wide settings that are fixed once the scene is loaded, apart from the sky
+impl GPUSceneParameters {
+    pub fn get_gpu_scene_params(scene: &Scene) -> GPUSceneParameters {
+        let mut scene_parameters = GPUSceneParameters {
+            background_type: 0,
+            environment_width: 0,
+            environment_height: 0,
+            environment_sampling: 0,
+            background_bottom: Vec4::ZERO,
+            background_top: Vec4::ZERO,
+            light_count: Self::get_gpu_lights(scene).len() as u32,
+            _buffer: [0u32; 3],
+            sky: [Vec4::ZERO; 7],
+        };
+        match &scene.background {
+            Background::Solid(color) => {
+                scene_parameters.background_bottom = color.extend(1.0);
+            }
+            Background::Gradient { bottom, top } => {
+                scene_parameters.background_type = 1;
+                scene_parameters.background_bottom = bottom.extend(1.0);
+                scene_parameters.background_top = top.extend(1.0);
+            }
+            Background::Environment(map) => {
+                scene_parameters.background_type = 2;
+                scene_parameters.environment_width = map.width();
+                scene_parameters.environment_height = map.height();
+                scene_parameters.environment_sampling = 1;
+            }
+            Background::Sky(sky) => scene_parameters.set_sky(sky),
+        }
+        scene_parameters
+    }
+
+    // the texels of the environment map, or a single placeholder texel if there is none
+    pub fn get_gpu_environment_map(scene: &Scene) -> Vec<Vec4> {
+        match &scene.background {
+            Background::Environment(map) => map.pixels().to_vec(),
+            _ => vec![Vec4::ZERO],
+        }
+    }
+
+    pub fn get_gpu_materials(scene: &Scene) -> Vec<GPUMaterial> {
+        scene.materials.iter().map(|material| GPUMaterial::from_material(material, &scene.measured)).collect()
+    }
+
+    // every light for light sampling: the emissive spheres, the lights of the scene, and
+    // last the sun of a sky background, so it can be swapped when the sky changes; the bvh
+    // builders may have copied a sphere into several leaves, and each emissive sphere is
+    // only listed once
+    pub fn get_gpu_lights(scene: &Scene) -> Vec<GPULight> {
+        let mut sphere_lights: Vec<u32> = Vec::new();
+        for (idx, sphere) in scene.spheres.iter().enumerate() {
+            if !scene.materials[sphere.material_idx() as usize].is_emissive() {
+                continue;
+            }
+            let listed = sphere_lights.iter().any(|&light| {
+                let other = &scene.spheres[light as usize];
+                other.center == sphere.center && other.radius() == sphere.radius()
+            });
+            if !listed {
+                sphere_lights.push(idx as u32);
+            }
+        }
+        let mut lights: Vec<GPULight> = sphere_lights.into_iter().map(GPULight::emissive_sphere).collect();
+        let mut profile_offset = 0u32;
+        for light in &scene.lights {
+            lights.push(GPULight::from_light(light, profile_offset));
+            if let Light::Photometric { profile, .. } = light {
+                profile_offset += profile.to_gpu().len() as u32;
+            }
+        }
+        if let Background::Sky(sky) = &scene.background {
+            lights.push(GPULight::from_light(&sky.sun(), 0));
+        }
+        lights
+    }
+
+    // the profiles of the photometric lights one after the other, as laid out by
+    // IesProfile::to_gpu, or a single placeholder value if there are none
+    pub fn get_gpu_photometric_profiles(scene: &Scene) -> Vec<f32> {
+        let mut profiles = Vec::new();
+        for light in &scene.lights {
+            if let Light::Photometric { profile, .. } = light {
+                profiles.extend(profile.to_gpu());
+            }
+        }
+        if profiles.is_empty() {
+            profiles.push(0.0);
+        }
+        profiles
+    }
+
+    // every texture of the scene in the order the materials index them, or a single
+    // placeholder if there are none
+    pub fn get_gpu_textures(scene: &Scene) -> Vec<GPUTexture> {
+        let mut texel_offset = 0u32;
+        let mut textures = Vec::new();
+        for texture in &scene.textures {
+            textures.push(GPUTexture::from_texture(texture, texel_offset));
+            if let Texture::Image(image) = texture {
+                texel_offset += image.pixels().len() as u32;
+            }
+        }
+        if textures.is_empty() {
+            textures.push(GPUTexture::from_texture(&Texture::Solid(Vec3::ONE), 0));
+        }
+        textures
+    }
+
+    // the texels of the image textures one after the other, or a single placeholder texel
+    // if there are none
+    pub fn get_gpu_texels(scene: &Scene) -> Vec<Vec4> {
+        let mut texels = Vec::new();
+        for texture in &scene.textures {
+            if let Texture::Image(image) = texture {
+                texels.extend_from_slice(image.pixels());
+            }
+        }
+        if texels.is_empty() {
+            texels.push(Vec4::ZERO);
+        }
+        texels
+    }
+
+    // the flattened importance sampling distribution of the environment map, laid out as
+    // described in Distribution2D::to_gpu, or a single placeholder value if there is none
+    pub fn get_gpu_environment_distribution(scene: &Scene) -> Vec<f32> {
+        match &scene.background {
+            Background::Environment(map) => map.distribution().to_gpu(),
+            _ => vec![0.0],
+        }
+    }
+
+    // turning sampling off leaves the environment to be found by bounce rays alone
+    pub fn set_environment_sampling(&mut self, enabled: bool) {
+        self.environment_sampling = enabled as u32;
+    }
+
+    // moves the sun or changes the haze of a sky background; the sun light at the end of
+    // the lights buffer has to follow
+    pub fn set_sky(&mut self, sky: &SkyParameters) {
+        self.background_type = 3;
+        self.sky = PreethamSky::new(sky).to_gpu();
+    }
+
+    pub fn background_type(&self) -> u32 { self.background_type }
+    pub fn environment_size(&self) -> (u32, u32) { (self.environment_width, self.environment_height) }
+    pub fn environment_sampling(&self) -> u32 { self.environment_sampling }
+    pub fn light_count(&self) -> u32 { self.light_count }
+    pub fn background_bottom(&self) -> Vec4 { self.background_bottom }
+    pub fn background_top(&self) -> Vec4 { self.background_top }
+    pub fn sky(&self) -> &[Vec4; 7] { &self.sky }
 }
 
 #[repr(C)]
@@ -1,8 +1,8 @@
 use std::time::{Duration, Instant};
-use imgui::{FontSource, MouseCursor};
-use imgui_wgpu::{Renderer, RendererConfig};
+use imgui::{FontSource, MouseCursor, TextureId};
+use imgui_wgpu::{Renderer, RendererConfig, Texture, TextureConfig};
 use imgui_winit_support::WinitPlatform;
-use wgpu::{Queue, SurfaceConfiguration};
+use wgpu::{Device, Extent3d, Queue, SurfaceConfiguration, TextureFormat};
 use winit::window::Window;
 use crate::parameters::RenderParameters;
 
@@ -11,6 +11,14 @@ pub struct GUI {
     pub imgui: imgui::Context,
     pub imgui_renderer: Renderer,
     last_cursor: Option<MouseCursor>,
+    material_previews: Vec<MaterialPreview>,
+}
+
+// a thumbnail of a material in the material list, and the label shown next to it
+struct MaterialPreview {
+    label: String,
+    texture: TextureId,
+    size: f32,
 }
 
 impl GUI {
@@ -52,9 +60,26 @@ impl GUI {
             imgui,
             imgui_renderer,
             last_cursor: None,
+            material_previews: Vec::new(),
         })
     }
 
+    // uploads a square rgba8 thumbnail of size x size pixels and adds it to the material
+    // list, which is only shown once it has an entry
+    pub fn add_material_preview(&mut self, device: &Device, queue: &Queue,
+                                label: String, rgba: &[u8], size: u32) {
+        let texture_config = TextureConfig {
+            size: Extent3d { width: size, height: size, depth_or_array_layers: 1 },
+            label: Some("material preview"),
+            format: Some(TextureFormat::Rgba8Unorm),
+            ..Default::default()
+        };
+        let texture = Texture::new(device, &self.imgui_renderer, texture_config);
+        texture.write(queue, rgba, size, size);
+        let texture = self.imgui_renderer.textures.insert(texture);
+        self.material_previews.push(MaterialPreview { label, texture, size: size as f32 });
+    }
+
     pub fn display_ui(&mut self, window: &Window, progress: f32, rp: & mut RenderParameters,
                       avg_fps:f32, compute_kernel_time: f32, dt: Duration) {
         self.imgui.io_mut().update_delta_time(dt);
@@ -175,6 +200,49 @@ impl GUI {
                             100,
                             &mut rp.sampling_parameters.num_bounces,
                         );
+
+                        ui.checkbox(
+                            "spectral",
+                            &mut rp.sampling_parameters.spectral,
+                        );
+
+                        if let Some(sky) = rp.sky_parameters.as_mut() {
+                            ui.separator();
+                            ui.text("Sky parameters");
+                            ui.slider(
+                                "sun elevation",
+                                0.0,
+                                90.0,
+                                &mut sky.sun_elevation,
+                            );
+
+                            ui.slider(
+                                "sun azimuth",
+                                -180.0,
+                                180.0,
+                                &mut sky.sun_azimuth,
+                            );
+
+                            ui.slider(
+                                "turbidity",
+                                2.0,
+                                10.0,
+                                &mut sky.turbidity,
+                            );
+                        }
+                    });
+            }
+
+            if !self.material_previews.is_empty() {
+                let window = ui.window("Materials");
+                window
+                    .size([220.0, 400.0], imgui::Condition::FirstUseEver)
+                    .build(|| {
+                        for preview in &self.material_previews {
+                            imgui::Image::new(preview.texture, [preview.size, preview.size]).build(ui);
+                            ui.same_line();
+                            ui.text(&preview.label);
+                        }
                     });
             }
 
@@ -0,0 +1,220 @@
+use std::fmt;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum IesError {
+    Io(std::io::Error),
+    Format(String),
+}
+
+impl fmt::Display for IesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IesError::Io(error) => write!(f, "could not read the IES file: {}", error),
+            IesError::Format(message) => write!(f, "malformed IES file: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for IesError {}
+
+impl From<std::io::Error> for IesError {
+    fn from(error: std::io::Error) -> Self {
+        IesError::Io(error)
+    }
+}
+
+// candela distribution of a luminaire, read from an IES LM-63 file with type C photometry:
+// vertical angles go from 0 (straight down) to 180 (straight up), and horizontal angles
+// turn counterclockwise, seen from above, from the length of the luminaire
+// the horizontal angles are expanded from the symmetry of the file to cover 0 to 360,
+// and the candela table holds the vertical values of one horizontal angle after the other
+#[derive(Clone, Debug)]
+pub struct IesProfile {
+    vertical_angles: Vec<f32>,
+    horizontal_angles: Vec<f32>,
+    candela: Vec<f32>,
+}
+
+impl IesProfile {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, IesError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, IesError> {
+        // the header is made of keyword lines and ends with the TILT line
+        let mut lines = text.lines();
+        let tilt = loop {
+            match lines.next() {
+                Some(line) if line.trim_start().starts_with("TILT=") => break line.trim()[5..].to_string(),
+                Some(_) => continue,
+                None => return Err(IesError::Format("no TILT line".to_string())),
+            }
+        };
+
+        // everything after it is a list of numbers separated by spaces, commas or line breaks
+        let rest: Vec<&str> = lines.collect();
+        let mut values = rest.iter()
+            .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|token| !token.is_empty())
+            .map(|token| token.parse::<f32>().map_err(|_| IesError::Format(format!("'{}' is not a number", token))));
+        let mut next = || values.next().unwrap_or_else(|| Err(IesError::Format("the file ends early".to_string())));
+
+        // tilt data only changes the output with the lamp's inclination, which is not modelled
+        if tilt == "INCLUDE" {
+            let _geometry = next()?;
+            let pairs = next()? as usize;
+            for _ in 0..2 * pairs {
+                next()?;
+            }
+        }
+
+        let _lamps = next()?;
+        let _lumens_per_lamp = next()?;
+        let multiplier = next()?;
+        let vertical_count = next()? as usize;
+        let horizontal_count = next()? as usize;
+        let photometric_type = next()? as u32;
+        let _units = next()?;
+        let _width = next()?;
+        let _length = next()?;
+        let _height = next()?;
+        let ballast_factor = next()?;
+        let ballast_lamp_factor = next()?;
+        let _input_watts = next()?;
+
+        if photometric_type != 1 {
+            return Err(IesError::Format(format!("photometric type {} is not supported, only type C", photometric_type)));
+        }
+        if vertical_count == 0 || horizontal_count == 0 {
+            return Err(IesError::Format("the candela table is empty".to_string()));
+        }
+
+        let scale = multiplier * ballast_factor * ballast_lamp_factor;
+        let vertical_angles = (0..vertical_count).map(|_| next()).collect::<Result<Vec<f32>, IesError>>()?;
+        let horizontal_angles = (0..horizontal_count).map(|_| next()).collect::<Result<Vec<f32>, IesError>>()?;
+        let candela = (0..vertical_count * horizontal_count)
+            .map(|_| next().map(|value| value * scale))
+            .collect::<Result<Vec<f32>, IesError>>()?;
+
+        let profile = Self { vertical_angles, horizontal_angles, candela };
+        profile.expand_symmetry()
+    }
+
+    // mirrors the horizontal planes given in the file until they cover 0 to 360
+    fn expand_symmetry(mut self) -> Result<Self, IesError> {
+        let first = self.horizontal_angles[0];
+        let last = *self.horizontal_angles.last().unwrap();
+        if first != 0.0 {
+            return Err(IesError::Format(format!("horizontal angles starting at {} are not supported", first)));
+        }
+        if self.horizontal_angles.len() == 1 {
+            // the same in every direction around the vertical axis
+            self.horizontal_angles = vec![0.0, 360.0];
+            self.candela.extend_from_within(..);
+            return Ok(self);
+        }
+        match last as u32 {
+            90 => {
+                self.mirror_horizontal(90.0);
+                self.mirror_horizontal(180.0);
+            }
+            180 => self.mirror_horizontal(180.0),
+            360 => {}
+            _ => return Err(IesError::Format(format!("horizontal angles ending at {} are not supported", last))),
+        }
+        Ok(self)
+    }
+
+    // appends the planes reflected about the plane at angle, which is the last one
+    fn mirror_horizontal(&mut self, angle: f32) {
+        let vertical_count = self.vertical_angles.len();
+        let count = self.horizontal_angles.len();
+        for h in (0..count - 1).rev() {
+            self.horizontal_angles.push(2.0 * angle - self.horizontal_angles[h]);
+            self.candela.extend_from_within(h * vertical_count..(h + 1) * vertical_count);
+        }
+    }
+
+    // candela in the direction given by the two angles, in degrees; 0 outside the vertical range
+    pub fn candela(&self, vertical: f32, horizontal: f32) -> f32 {
+        let vertical_count = self.vertical_angles.len();
+        if vertical < self.vertical_angles[0] || vertical > self.vertical_angles[vertical_count - 1] {
+            return 0.0;
+        }
+        let horizontal = horizontal.rem_euclid(360.0);
+        let (v, tv) = interval(&self.vertical_angles, vertical);
+        let (h, th) = interval(&self.horizontal_angles, horizontal);
+        let v1 = (v + 1).min(vertical_count - 1);
+        let h1 = (h + 1).min(self.horizontal_angles.len() - 1);
+        let value = |h: usize, v: usize| self.candela[h * vertical_count + v];
+        let lower = value(h, v) * (1.0 - tv) + value(h, v1) * tv;
+        let upper = value(h1, v) * (1.0 - tv) + value(h1, v1) * tv;
+        lower * (1.0 - th) + upper * th
+    }
+
+    pub fn vertical_angles(&self) -> &[f32] { &self.vertical_angles }
+    pub fn horizontal_angles(&self) -> &[f32] { &self.horizontal_angles }
+
+    // flattened for the shaders: the vertical and horizontal counts, the vertical angles,
+    // the horizontal angles and the candela table
+    pub fn to_gpu(&self) -> Vec<f32> {
+        let mut data = vec![self.vertical_angles.len() as f32, self.horizontal_angles.len() as f32];
+        data.extend_from_slice(&self.vertical_angles);
+        data.extend_from_slice(&self.horizontal_angles);
+        data.extend_from_slice(&self.candela);
+        data
+    }
+}
+
+// the segment of the ascending angles that holds x, and how far along it x lies
+fn interval(angles: &[f32], x: f32) -> (usize, f32) {
+    if angles.len() == 1 {
+        return (0, 0.0);
+    }
+    let i = angles.partition_point(|&angle| angle <= x).clamp(1, angles.len() - 1) - 1;
+    let t = (x - angles[i]) / (angles[i + 1] - angles[i]);
+    (i, t.clamp(0.0, 1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUADRANT: &str = "IESNA:LM-63-2002
+[TEST] quadrant symmetric test fixture
+[MANUFAC] none
+TILT=NONE
+1 -1 2.0 3 2 1 2 0.0 0.0 0.0
+1.0 1.0 20
+0 45 90
+0 90
+100 80 10
+50 40 5
+";
+
+    #[test]
+    fn parses_and_mirrors_a_quadrant_profile() {
+        let profile = IesProfile::parse(QUADRANT).unwrap();
+        assert_eq!(profile.horizontal_angles(), &[0.0, 90.0, 180.0, 270.0, 360.0]);
+        // the multiplier doubles every value
+        assert_eq!(profile.candela(0.0, 0.0), 200.0);
+        assert_eq!(profile.candela(45.0, 90.0), 80.0);
+        // mirrored planes repeat the ones they reflect
+        assert_eq!(profile.candela(45.0, 180.0), 160.0);
+        assert_eq!(profile.candela(45.0, 270.0), 80.0);
+        assert_eq!(profile.candela(45.0, -90.0), 80.0);
+        // halfway in both angles
+        assert!((profile.candela(22.5, 45.0) - 0.25 * (200.0 + 160.0 + 100.0 + 80.0)).abs() < 1e-3);
+        // nothing above the horizon
+        assert_eq!(profile.candela(120.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn rejects_unsupported_photometry() {
+        let type_b = QUADRANT.replace("1 -1 2.0 3 2 1 2", "1 -1 2.0 3 2 2 2");
+        assert!(matches!(IesProfile::parse(&type_b), Err(IesError::Format(_))));
+        let short = &QUADRANT[..QUADRANT.len() - 10];
+        assert!(matches!(IesProfile::parse(short), Err(IesError::Format(_))));
+    }
+}
@@ -2,8 +2,20 @@ pub mod projection_matrix;
 pub mod camera;
 pub mod sphere;
 pub mod material;
+pub mod bsdf;
+pub mod measured;
+pub mod medium;
+pub mod spectrum;
+pub mod texture;
+pub mod light;
+pub mod ies;
 pub mod scene;
+pub mod background;
+pub mod sky;
+pub mod distribution;
 pub mod bvh;
+pub mod sbvh;
+pub mod bvh_optimizer;
 pub mod util_funcs;
 pub mod gpu_buffer;
 pub mod parameters;
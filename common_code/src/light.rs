@@ -0,0 +1,113 @@
+use crate::ies::IesProfile;
+use glam::{Vec3, Vec4};
+
+// light_type will be indexed as follows:
+// 0 emissive sphere; 1 point; 2 spot; 3 directional; 4 photometric
+// emissive spheres come from the materials of the scene, the others from Scene::lights
+
+// lights that are not part of the geometry; they are only found through shadow rays,
+// never by bounce rays or by the camera
+pub enum Light {
+    // intensity is the radiant intensity, so the irradiance falls off with the square of the distance
+    Point { position: Vec3, intensity: Vec3 },
+    // full intensity inside inner_angle, none outside outer_angle (both half angles, in
+    // radians); in between it fades as t^falloff, with t going from 0 to 1
+    Spot { position: Vec3, direction: Vec3, intensity: Vec3, inner_angle: f32, outer_angle: f32, falloff: f32 },
+    // light travelling along direction, with the irradiance it gives a surface facing it;
+    // a non zero angular diameter (in radians) spreads it over a disk to soften the shadows
+    Directional { direction: Vec3, irradiance: Vec3, angular_diameter: f32 },
+    // a point light whose intensity in each direction is the candela of the profile times
+    // color; the profile points straight down -y, with its horizontal angle 0 along +x
+    // only traced by the CPU tracer for now
+    Photometric { position: Vec3, profile: IesProfile, color: Vec3 },
+}
+
+impl Light {
+    // the sun is about half a degree across
+    pub fn sun(direction: Vec3, irradiance: Vec3) -> Self {
+        Light::Directional { direction, irradiance, angular_diameter: 0.53f32.to_radians() }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct GPULight {
+    position: Vec4,
+    direction: Vec4,
+    // intensity, or irradiance for directional lights
+    intensity: Vec4,
+    light_type: u32,
+    sphere_idx: u32,
+    // cosines of the spot angles; directional lights keep the cosine of half their
+    // angular diameter in cos_outer
+    cos_inner: f32,
+    cos_outer: f32,
+    falloff: f32,
+    // where the profile of a photometric light starts in the photometric table
+    profile_offset: u32,
+    _buffer: [u32; 2],
+}
+
+unsafe impl bytemuck::Pod for GPULight {}
+unsafe impl bytemuck::Zeroable for GPULight {}
+
+impl GPULight {
+    pub fn emissive_sphere(sphere_idx: u32) -> Self {
+        Self {
+            position: Vec4::ZERO,
+            direction: Vec4::ZERO,
+            intensity: Vec4::ZERO,
+            light_type: 0,
+            sphere_idx,
+            cos_inner: 0.0,
+            cos_outer: 0.0,
+            falloff: 0.0,
+            profile_offset: 0,
+            _buffer: [0u32; 2],
+        }
+    }
+
+    // profile_offset is only used by photometric lights
+    pub fn from_light(light: &Light, profile_offset: u32) -> Self {
+        let mut gpu_light = Self::emissive_sphere(0);
+        match *light {
+            Light::Point { position, intensity } => {
+                gpu_light.light_type = 1;
+                gpu_light.position = position.extend(1.0);
+                gpu_light.intensity = intensity.extend(1.0);
+            }
+            Light::Spot { position, direction, intensity, inner_angle, outer_angle, falloff } => {
+                gpu_light.light_type = 2;
+                gpu_light.position = position.extend(1.0);
+                gpu_light.direction = direction.normalize().extend(0.0);
+                gpu_light.intensity = intensity.extend(1.0);
+                gpu_light.cos_inner = inner_angle.cos();
+                gpu_light.cos_outer = outer_angle.cos();
+                gpu_light.falloff = falloff;
+            }
+            Light::Directional { direction, irradiance, angular_diameter } => {
+                gpu_light.light_type = 3;
+                gpu_light.direction = direction.normalize().extend(0.0);
+                gpu_light.intensity = irradiance.extend(1.0);
+                gpu_light.cos_outer = (0.5 * angular_diameter).cos();
+            }
+            Light::Photometric { position, color, .. } => {
+                gpu_light.light_type = 4;
+                gpu_light.position = position.extend(1.0);
+                gpu_light.intensity = color.extend(1.0);
+                gpu_light.profile_offset = profile_offset;
+            }
+        }
+        gpu_light
+    }
+
+    pub fn position(&self) -> Vec4 { self.position }
+    pub fn direction(&self) -> Vec4 { self.direction }
+    pub fn intensity(&self) -> Vec4 { self.intensity }
+    pub fn light_type(&self) -> u32 { self.light_type }
+    pub fn sphere_idx(&self) -> u32 { self.sphere_idx }
+    pub fn cos_inner(&self) -> f32 { self.cos_inner }
+    pub fn cos_outer(&self) -> f32 { self.cos_outer }
+    pub fn falloff(&self) -> f32 { self.falloff }
+    pub fn profile_offset(&self) -> u32 { self.profile_offset }
+}
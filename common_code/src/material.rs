@@ -1,53 +1,480 @@
+use crate::bsdf::{Conductor, Dielectric, Dispersion, Emissive, Lambertian, LayerBase, Layered, Metal, Principled, RoughDielectric, Subsurface, ThinFilm, BSDF};
+use crate::measured::MeasuredBRDF;
+use std::sync::Arc;
+use crate::texture::NO_TEXTURE;
 use glam::{Vec3, Vec4};
 
-// material_type will be indexed as follows:
-// 0 Lambertian; 1 Metal; 2 Dielectric
+// complex indices of refraction (eta, k) of common metals at the red, green and blue
+// wavelengths, 650, 550 and 450nm
+pub const GOLD: (Vec3, Vec3) = (Vec3::new(0.143, 0.374, 1.442), Vec3::new(3.983, 2.385, 1.603));
+pub const SILVER: (Vec3, Vec3) = (Vec3::new(0.155, 0.116, 0.138), Vec3::new(4.828, 3.122, 2.147));
+pub const COPPER: (Vec3, Vec3) = (Vec3::new(0.200, 0.924, 1.102), Vec3::new(3.912, 2.452, 2.142));
+pub const ALUMINIUM: (Vec3, Vec3) = (Vec3::new(1.657, 0.880, 0.521), Vec3::new(9.224, 6.270, 4.837));
+
+// Sellmeier coefficients (B, C) of common glasses from the Schott catalog, with C in um^2
+pub const BK7: (Vec3, Vec3) = (Vec3::new(1.0396122, 0.23179235, 1.0104694), Vec3::new(0.0060006985, 0.020017914, 103.56065));
+pub const SF11: (Vec3, Vec3) = (Vec3::new(1.737597, 0.31374735, 1.8987811), Vec3::new(0.013188707, 0.062306814, 155.2363));
+// the sodium D line, at which glasses quote their index of refraction
+const SODIUM_D: f32 = 589.3;
 
-enum MaterialType {
-    Lambertian = 0,
-    Metal = 1,
-    Dielectric = 2,
+// a new material is a struct implementing BSDF in bsdf.rs, a variant here, and its packing
+// in GPUMaterial::from_material
+#[derive(Copy, Clone, Debug)]
+pub enum Surface {
+    Lambertian(Lambertian),
+    Metal(Metal),
+    Dielectric(Dielectric),
+    Emissive(Emissive),
+    Conductor(Conductor),
+    RoughDielectric(RoughDielectric),
+    Principled(Principled),
+    Layered(Layered),
+    Subsurface(Subsurface),
+    // index into the measured tables of the scene, which are too large to copy around
+    Measured(u32),
 }
 
-#[repr(C)]
+// tilts the shading normal away from the geometric one; the tangent frame follows the uvs,
+// with the tangent along increasing u and the bitangent along increasing v
 #[derive(Copy, Clone, Debug)]
-pub struct Material {
-    albedo: Vec4,
-    fuzz: f32,
-    refract_index: f32,
-    material_type: u32,
-    _buffer: u32,
+pub enum NormalMap {
+    // the texture stores the tangent-space normal as rgb = 0.5 * (xyz + 1), as OpenGL does;
+    // strength scales the tilt
+    TangentSpace { texture: u32, strength: f32 },
+    // the average of the texture's color is a height above the surface, times scale in
+    // world units
+    Bump { texture: u32, scale: f32 },
 }
 
-unsafe impl bytemuck::Pod for Material {}
-unsafe impl bytemuck::Zeroable for Material {}
+#[derive(Copy, Clone, Debug)]
+pub struct Material {
+    pub surface: Surface,
+    // index into the scene textures that replaces the albedo
+    pub albedo_texture: Option<u32>,
+    pub normal_map: Option<NormalMap>,
+    // where dielectrics overlap, the one with the highest priority fills the overlap
+    pub priority: u32,
+    // the chance that a ray stops at the surface rather than passing through it, times the
+    // average of the alpha texture's color if there is one
+    pub opacity: f32,
+    pub alpha_texture: Option<u32>,
+}
 
 impl Material {
+    fn new(surface: Surface) -> Self {
+        Self { surface, albedo_texture: None, normal_map: None, priority: 0, opacity: 1.0, alpha_texture: None }
+    }
+
     pub fn Lambertian(albedo: Vec3) -> Self {
-        Self { albedo: albedo.extend(1.0), fuzz:0.0, refract_index:0.0, material_type: 0, _buffer: 0 }
+        Self::new(Surface::Lambertian(Lambertian { albedo }))
     }
 
     pub fn Metal(albedo: Vec3, fuzz: f32) -> Self {
-        Self { albedo: albedo.extend(1.0), fuzz: fuzz.clamp(0.0, 1.0), refract_index:0.0, material_type: 1, _buffer: 0 }
+        Self::new(Surface::Metal(Metal { albedo, fuzz: fuzz.clamp(0.0, 1.0) }))
     }
 
     pub fn Dielectric(refract_index: f32) -> Self {
-        Self { albedo: Vec4::ONE, fuzz:0.0, refract_index, material_type: 2, _buffer: 0 }
+        Self::new(Surface::Dielectric(Dielectric { refract_index, dispersion: Dispersion::None, film: None }))
+    }
+
+    // emissive materials add color * strength when hit and end the path
+    pub fn Emissive(color: Vec3, strength: f32) -> Self {
+        Self::new(Surface::Emissive(Emissive { color, strength: strength.max(0.0) }))
     }
 
-    pub fn albedo(&self) -> Vec4 {
-        self.albedo
+    // GGX microfacet metal; the albedo scales the Fresnel reflectance and is left white
+    // for a physically measured metal
+    pub fn Conductor(eta: Vec3, k: Vec3, roughness: f32) -> Self {
+        Self::new(Surface::Conductor(Conductor { eta, k, roughness: roughness.clamp(0.0, 1.0), film: None }))
     }
 
-    pub fn fuzz(&self) -> f32 {
-        self.fuzz
+    // GGX microfacet glass; a roughness of 0 gives smooth glass with exact Fresnel
+    pub fn RoughDielectric(refract_index: f32, roughness: f32) -> Self {
+        Self::new(Surface::RoughDielectric(RoughDielectric { refract_index, roughness: roughness.clamp(0.0, 1.0) }))
     }
 
-    pub fn refract_index(&self) -> f32 {
-        self.refract_index
+    // glass-like boundary around a medium that light random walks through, as in skin, wax or
+    // marble; the albedo is the color after many scatterings, and mean_free_path how far light
+    // gets between two of them, per channel. only the CPU tracer walks the medium so far
+    pub fn Subsurface(albedo: Vec3, mean_free_path: Vec3, refract_index: f32) -> Self {
+        Self::new(Surface::Subsurface(Subsurface { albedo, mean_free_path, refract_index }))
     }
 
-    pub fn material_type(&self) -> u32 {
-        self.material_type
+    // a tabulated brdf, such as one of the MERL database read with MeasuredBRDF::load, by
+    // its index in Scene::measured. only the CPU tracer looks it up, and the GPU tracer
+    // shades it as lambertian of the same albedo
+    pub fn Measured(table: u32) -> Self {
+        Self::new(Surface::Measured(table))
     }
-}
\ No newline at end of file
+
+    // uber-material with the parameters of the glTF metallic-roughness model; the with_
+    // methods add the other lobes
+    pub fn Principled(base_color: Vec3, metallic: f32, roughness: f32) -> Self {
+        Self::new(Surface::Principled(Principled {
+            base_color,
+            metallic: metallic.clamp(0.0, 1.0),
+            roughness: roughness.clamp(0.0, 1.0),
+            specular: 0.5,
+            ior: 1.5,
+            transmission: 0.0,
+            clearcoat: 0.0,
+            clearcoat_roughness: 0.0,
+            sheen: 0.0,
+        }))
+    }
+
+    // the with_ methods below only apply to the principled material
+    pub fn with_specular(mut self, specular: f32, ior: f32) -> Self {
+        if let Surface::Principled(principled) = &mut self.surface {
+            principled.specular = specular.clamp(0.0, 1.0);
+            principled.ior = ior;
+        }
+        self
+    }
+
+    pub fn with_transmission(mut self, transmission: f32) -> Self {
+        if let Surface::Principled(principled) = &mut self.surface {
+            principled.transmission = transmission.clamp(0.0, 1.0);
+        }
+        self
+    }
+
+    pub fn with_clearcoat(mut self, clearcoat: f32, clearcoat_roughness: f32) -> Self {
+        if let Surface::Principled(principled) = &mut self.surface {
+            principled.clearcoat = clearcoat.clamp(0.0, 1.0);
+            principled.clearcoat_roughness = clearcoat_roughness.clamp(0.0, 1.0);
+        }
+        self
+    }
+
+    pub fn with_sheen(mut self, sheen: f32) -> Self {
+        if let Surface::Principled(principled) = &mut self.surface {
+            principled.sheen = sheen.clamp(0.0, 1.0);
+        }
+        self
+    }
+
+    // puts a GGX dielectric coat over a lambertian or conductor material, as on car paint,
+    // varnished wood or glossy plastic; roughness is the GGX alpha of the coat, and other
+    // materials are left as they are
+    pub fn with_coat(mut self, ior: f32, roughness: f32) -> Self {
+        let base = match self.surface {
+            Surface::Lambertian(lambertian) => LayerBase::Lambertian(lambertian),
+            // a smooth base under the coat is treated as a very sharp rough one
+            Surface::Conductor(conductor) => LayerBase::Conductor(Conductor { roughness: conductor.roughness.max(1e-4), ..conductor }),
+            _ => return self,
+        };
+        self.surface = Surface::Layered(Layered { base, coat_ior: ior, coat_roughness: roughness.clamp(0.0, 1.0) });
+        self
+    }
+
+    // a film of the given thickness in nm and index of refraction over a dielectric or
+    // conductor, which gives soap bubbles, oil slicks and lens coatings their colors
+    pub fn with_thin_film(mut self, thickness: f32, ior: f32) -> Self {
+        let film = (thickness > 0.0).then_some(ThinFilm { thickness, ior });
+        match &mut self.surface {
+            Surface::Dielectric(dielectric) => dielectric.film = film,
+            Surface::Conductor(conductor) => conductor.film = film,
+            _ => {}
+        }
+        self
+    }
+
+    // the with_ methods below only apply to the dielectric material, whose index of refraction
+    // then follows the wavelength in spectral mode; rgb mode keeps the index at 589.3nm
+    pub fn with_cauchy(self, a: f32, b: f32) -> Self {
+        self.with_dispersion(Dispersion::Cauchy { a, b })
+    }
+
+    pub fn with_sellmeier(self, b: Vec3, c: Vec3) -> Self {
+        self.with_dispersion(Dispersion::Sellmeier { b, c })
+    }
+
+    fn with_dispersion(mut self, dispersion: Dispersion) -> Self {
+        if let Surface::Dielectric(dielectric) = &mut self.surface {
+            dielectric.dispersion = dispersion;
+            dielectric.refract_index = dispersion.refract_index(SODIUM_D).unwrap_or(dielectric.refract_index);
+        }
+        self
+    }
+
+    // the material as seen by light of a single wavelength in nm
+    pub fn at_wavelength(mut self, lambda: f32) -> Self {
+        if let Surface::Dielectric(dielectric) = &mut self.surface {
+            dielectric.refract_index = dielectric.dispersion.refract_index(lambda).unwrap_or(dielectric.refract_index);
+        }
+        self
+    }
+
+    // nested glass such as a bubble in glass, or water in a glass, is modeled with overlapping
+    // spheres: the bubble or the water gets the higher priority, and the surfaces of the other
+    // inside it are ignored
+    pub fn with_priority(mut self, priority: u32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    // the index of refraction of the dielectrics that a path can be inside of
+    pub fn refract_index(&self) -> Option<f32> {
+        match self.surface {
+            Surface::Dielectric(dielectric) => Some(dielectric.refract_index),
+            Surface::RoughDielectric(dielectric) => Some(dielectric.refract_index),
+            _ => None,
+        }
+    }
+
+    // the material seen from a medium of index outside_index instead of from a vacuum
+    pub fn relative_to(mut self, outside_index: f32) -> Self {
+        match &mut self.surface {
+            Surface::Dielectric(dielectric) => {
+                dielectric.refract_index /= outside_index;
+                dielectric.film = dielectric.film.map(|film| film.relative_to(outside_index));
+            }
+            Surface::RoughDielectric(dielectric) => dielectric.refract_index /= outside_index,
+            _ => {}
+        }
+        self
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        match self.surface {
+            Surface::Dielectric(dielectric) => !matches!(dielectric.dispersion, Dispersion::None),
+            _ => false,
+        }
+    }
+
+    // the texture is looked up at every hit instead of the constant albedo; for emissive
+    // materials it gives the color of the emission
+    pub fn with_albedo_texture(mut self, texture_idx: u32) -> Self {
+        self.albedo_texture = Some(texture_idx);
+        self
+    }
+
+    // data textures such as normal maps want ImageTexture::load_linear rather than load
+    pub fn with_normal_map(mut self, texture_idx: u32, strength: f32) -> Self {
+        self.normal_map = Some(NormalMap::TangentSpace { texture: texture_idx, strength });
+        self
+    }
+
+    pub fn with_bump_map(mut self, texture_idx: u32, scale: f32) -> Self {
+        self.normal_map = Some(NormalMap::Bump { texture: texture_idx, scale });
+        self
+    }
+
+    // a partly transparent surface, such as a decal or tinted film; shadows pass through it too
+    pub fn with_opacity(mut self, opacity: f32) -> Self {
+        self.opacity = opacity.clamp(0.0, 1.0);
+        self
+    }
+
+    // cuts the surface out where the texture is dark, as for leaves or fences; the alpha
+    // channel of an image is read with ImageTexture::load_alpha
+    pub fn with_alpha_texture(mut self, texture_idx: u32) -> Self {
+        self.alpha_texture = Some(texture_idx);
+        self
+    }
+
+    pub fn is_opaque(&self) -> bool {
+        self.opacity >= 1.0 && self.alpha_texture.is_none()
+    }
+
+    // measured holds the tables of the scene that measured materials refer to
+    pub fn bsdf<'a>(&'a self, measured: &'a [Arc<MeasuredBRDF>]) -> &'a dyn BSDF {
+        match &self.surface {
+            Surface::Lambertian(bsdf) => bsdf,
+            Surface::Metal(bsdf) => bsdf,
+            Surface::Dielectric(bsdf) => bsdf,
+            Surface::Emissive(bsdf) => bsdf,
+            Surface::Conductor(bsdf) => bsdf,
+            Surface::RoughDielectric(bsdf) => bsdf,
+            Surface::Principled(bsdf) => bsdf,
+            Surface::Layered(bsdf) => bsdf,
+            Surface::Subsurface(bsdf) => bsdf,
+            Surface::Measured(table) => measured[*table as usize].as_ref(),
+        }
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        matches!(self.surface, Surface::Emissive(_))
+    }
+
+    // the kind of surface, as the material list of the gui shows it
+    pub fn name(&self) -> &'static str {
+        match self.surface {
+            Surface::Lambertian(_) => "lambertian",
+            Surface::Metal(_) => "metal",
+            Surface::Dielectric(_) => "dielectric",
+            Surface::Emissive(_) => "emissive",
+            Surface::Conductor(_) => "conductor",
+            Surface::RoughDielectric(_) => "rough dielectric",
+            Surface::Principled(_) => "principled",
+            Surface::Layered(_) => "layered",
+            Surface::Subsurface(_) => "subsurface",
+            Surface::Measured(_) => "measured",
+        }
+    }
+}
+
+// material_type will be indexed as follows:
+// 0 Lambertian; 1 Metal; 2 Dielectric; 3 Emissive; 4 Conductor; 5 RoughDielectric; 6 Principled;
+// 7 Layered
+// Subsurface and Measured have no GPU counterpart and fall back to 0 with their albedo; thin
+// films are not a type of their own, but a film_thickness on a 2, 4 or conductor based 7
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct GPUMaterial {
+    albedo: Vec4,
+    fuzz: f32,
+    refract_index: f32,
+    material_type: u32,
+    emission_strength: f32,
+    // index into the scene textures that replaces the albedo, or NO_TEXTURE
+    albedo_texture: u32,
+    // GGX alpha of the conductor and rough dielectric, as in Mitsuba and pbrt; the
+    // principled material squares it into alpha like glTF does
+    roughness: f32,
+    metallic: f32,
+    specular: f32,
+    // complex index of refraction of conductors; dispersive dielectrics keep a and b of
+    // Cauchy's equation in eta.xy with eta.w = 1, or the B and C of Sellmeier's in eta.xyz
+    // and k.xyz with eta.w = 2
+    eta: Vec4,
+    k: Vec4,
+    // the remaining lobes of the principled material; the layered material keeps the index
+    // and alpha of its coat in refract_index and clearcoat_roughness, and a metallic of 1
+    // for a conductor base
+    transmission: f32,
+    clearcoat: f32,
+    clearcoat_roughness: f32,
+    sheen: f32,
+    // the normal or bump map, or NO_TEXTURE; normal_type is 0 for a tangent-space normal
+    // map and 1 for a bump map, and normal_strength is its strength or scale
+    normal_texture: u32,
+    normal_type: u32,
+    normal_strength: f32,
+    // the priority of dielectrics that overlap
+    priority: u32,
+    // the alpha mask, or NO_TEXTURE, and the opacity that scales it
+    opacity: f32,
+    alpha_texture: u32,
+    // the thin film over a dielectric or conductor, with a thickness of 0 for none
+    film_thickness: f32,
+    film_ior: f32,
+}
+
+unsafe impl bytemuck::Pod for GPUMaterial {}
+unsafe impl bytemuck::Zeroable for GPUMaterial {}
+
+impl GPUMaterial {
+    pub fn from_material(material: &Material, measured: &[Arc<MeasuredBRDF>]) -> Self {
+        let mut gpu_material = Self {
+            albedo: material.bsdf(measured).albedo().extend(1.0),
+            fuzz: 0.0,
+            refract_index: 0.0,
+            material_type: 0,
+            emission_strength: 0.0,
+            albedo_texture: material.albedo_texture.unwrap_or(NO_TEXTURE),
+            roughness: 0.0,
+            metallic: 0.0,
+            specular: 0.0,
+            eta: Vec4::ZERO,
+            k: Vec4::ZERO,
+            transmission: 0.0,
+            clearcoat: 0.0,
+            clearcoat_roughness: 0.0,
+            sheen: 0.0,
+            normal_texture: NO_TEXTURE,
+            normal_type: 0,
+            normal_strength: 0.0,
+            priority: material.priority,
+            opacity: material.opacity,
+            alpha_texture: material.alpha_texture.unwrap_or(NO_TEXTURE),
+            film_thickness: 0.0,
+            film_ior: 0.0,
+        };
+        match material.normal_map {
+            Some(NormalMap::TangentSpace { texture, strength }) => {
+                gpu_material.normal_texture = texture;
+                gpu_material.normal_strength = strength;
+            }
+            Some(NormalMap::Bump { texture, scale }) => {
+                gpu_material.normal_texture = texture;
+                gpu_material.normal_type = 1;
+                gpu_material.normal_strength = scale;
+            }
+            None => {}
+        }
+        match material.surface {
+            Surface::Lambertian(_) => {}
+            Surface::Metal(metal) => {
+                gpu_material.material_type = 1;
+                gpu_material.fuzz = metal.fuzz;
+            }
+            Surface::Dielectric(dielectric) => {
+                gpu_material.material_type = 2;
+                gpu_material.refract_index = dielectric.refract_index;
+                gpu_material.set_film(dielectric.film);
+                match dielectric.dispersion {
+                    Dispersion::None => {}
+                    Dispersion::Cauchy { a, b } => gpu_material.eta = Vec4::new(a, b, 0.0, 1.0),
+                    Dispersion::Sellmeier { b, c } => {
+                        gpu_material.eta = b.extend(2.0);
+                        gpu_material.k = c.extend(0.0);
+                    }
+                }
+            }
+            Surface::Emissive(emissive) => {
+                gpu_material.material_type = 3;
+                gpu_material.emission_strength = emissive.strength;
+            }
+            Surface::Conductor(conductor) => {
+                gpu_material.material_type = 4;
+                gpu_material.roughness = conductor.roughness;
+                gpu_material.eta = conductor.eta.extend(0.0);
+                gpu_material.k = conductor.k.extend(0.0);
+                gpu_material.set_film(conductor.film);
+            }
+            Surface::RoughDielectric(dielectric) => {
+                gpu_material.material_type = 5;
+                gpu_material.refract_index = dielectric.refract_index;
+                gpu_material.roughness = dielectric.roughness;
+            }
+            Surface::Principled(principled) => {
+                gpu_material.material_type = 6;
+                gpu_material.refract_index = principled.ior;
+                gpu_material.roughness = principled.roughness;
+                gpu_material.metallic = principled.metallic;
+                gpu_material.specular = principled.specular;
+                gpu_material.transmission = principled.transmission;
+                gpu_material.clearcoat = principled.clearcoat;
+                gpu_material.clearcoat_roughness = principled.clearcoat_roughness;
+                gpu_material.sheen = principled.sheen;
+            }
+            Surface::Layered(layered) => {
+                gpu_material.material_type = 7;
+                gpu_material.refract_index = layered.coat_ior;
+                gpu_material.clearcoat_roughness = layered.coat_roughness;
+                if let LayerBase::Conductor(conductor) = layered.base {
+                    gpu_material.metallic = 1.0;
+                    gpu_material.roughness = conductor.roughness;
+                    gpu_material.eta = conductor.eta.extend(0.0);
+                    gpu_material.k = conductor.k.extend(0.0);
+                    gpu_material.set_film(conductor.film);
+                }
+            }
+            // the GPU tracer has no random walk yet, and shades it as lambertian
+            Surface::Subsurface(_) => {}
+            // nor tabulated brdfs, which keep the albedo they reflect at normal incidence
+            Surface::Measured(_) => {}
+        }
+        gpu_material
+    }
+
+    fn set_film(&mut self, film: Option<ThinFilm>) {
+        if let Some(film) = film {
+            self.film_thickness = film.thickness;
+            self.film_ior = film.ior;
+        }
+    }
+}
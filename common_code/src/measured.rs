@@ -0,0 +1,248 @@
+use std::f32::consts::{FRAC_PI_2, PI};
+use std::fmt;
+use std::path::Path;
+use glam::{Vec2, Vec3};
+use crate::distribution::Distribution1D;
+
+// the MERL tables are indexed by the half vector angle theta_h, the difference angles
+// theta_d and phi_d of Rusinkiewicz's parameterization; phi_d only covers [0, pi), as the
+// brdf is reciprocal
+const THETA_H_COUNT: usize = 90;
+const THETA_D_COUNT: usize = 90;
+const PHI_D_COUNT: usize = 180;
+const TABLE_SIZE: usize = THETA_H_COUNT * THETA_D_COUNT * PHI_D_COUNT;
+// the stored values are scaled per channel, as in the reference code of the database
+const CHANNEL_SCALE: Vec3 = Vec3::new(1.0 / 1500.0, 1.15 / 1500.0, 1.66 / 1500.0);
+
+#[derive(Debug)]
+pub enum MerlError {
+    Io(std::io::Error),
+    Format(String),
+}
+
+impl fmt::Display for MerlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MerlError::Io(error) => write!(f, "could not read the MERL file: {}", error),
+            MerlError::Format(message) => write!(f, "malformed MERL file: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for MerlError {}
+
+impl From<std::io::Error> for MerlError {
+    fn from(error: std::io::Error) -> Self {
+        MerlError::Io(error)
+    }
+}
+
+// an isotropic brdf tabulated the way the MERL database measures it; directions are given
+// in the frame of the surface, with the normal along z
+// sampling picks the half vector from a tabulated distribution of theta_h, which follows
+// the specular peak, and mixes it with cosine sampling for the diffuse part
+pub struct MeasuredBRDF {
+    values: Vec<Vec3>,
+    theta_h_distribution: Distribution1D,
+    albedo: Vec3,
+}
+
+impl MeasuredBRDF {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, MerlError> {
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    // the binary format holds the three table dimensions as 32 bit integers, then the red,
+    // green and blue tables one after the other as doubles, all little endian
+    pub fn parse(bytes: &[u8]) -> Result<Self, MerlError> {
+        if bytes.len() < 12 {
+            return Err(MerlError::Format("the file ends before the table dimensions".to_string()));
+        }
+        let dimensions: Vec<usize> = bytes[..12].chunks_exact(4)
+            .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]).max(0) as usize)
+            .collect();
+        if dimensions.iter().product::<usize>() != TABLE_SIZE {
+            return Err(MerlError::Format(format!("a table of {:?} is not 90 x 90 x 180", dimensions)));
+        }
+        if bytes.len() != 12 + 3 * TABLE_SIZE * 8 {
+            return Err(MerlError::Format(format!("{} bytes of data instead of {}", bytes.len() - 12, 3 * TABLE_SIZE * 8)));
+        }
+
+        let value = |channel: usize, i: usize| {
+            let start = 12 + 8 * (channel * TABLE_SIZE + i);
+            f64::from_le_bytes(bytes[start..start + 8].try_into().unwrap()) as f32
+        };
+        // directions the gonioreflectometer could not reach are stored as negative values
+        let values = (0..TABLE_SIZE)
+            .map(|i| (Vec3::new(value(0, i), value(1, i), value(2, i)) * CHANNEL_SCALE).max(Vec3::ZERO))
+            .collect();
+        Ok(Self::from_values(values))
+    }
+
+    // tabulates an analytic brdf f(wo, wi) at the centers of the MERL bins, to check how a
+    // material compares with its own measured version
+    pub fn tabulate(f: impl Fn(Vec3, Vec3) -> Vec3) -> Self {
+        let mut values = Vec::with_capacity(TABLE_SIZE);
+        for i in 0..THETA_H_COUNT {
+            let s = (i as f32 + 0.5) / THETA_H_COUNT as f32;
+            let theta_h = s * s * FRAC_PI_2;
+            let half = Vec3::new(theta_h.sin(), 0.0, theta_h.cos());
+            for j in 0..THETA_D_COUNT {
+                let theta_d = (j as f32 + 0.5) / THETA_D_COUNT as f32 * FRAC_PI_2;
+                for k in 0..PHI_D_COUNT {
+                    let phi_d = (k as f32 + 0.5) / PHI_D_COUNT as f32 * PI;
+                    let difference = Vec3::new(theta_d.sin() * phi_d.cos(), theta_d.sin() * phi_d.sin(), theta_d.cos());
+                    let wi = rotate(difference, Vec3::Y, theta_h);
+                    let wo = 2.0 * half.dot(wi) * half - wi;
+                    values.push(if wi.z > 0.0 && wo.z > 0.0 { f(wo, wi) } else { Vec3::ZERO });
+                }
+            }
+        }
+        Self::from_values(values)
+    }
+
+    fn from_values(values: Vec<Vec3>) -> Self {
+        // the density of half vectors in s = sqrt(theta_h / (pi / 2)), the coordinate the
+        // bins are uniform in, follows the average brdf times cos(theta_h) and the solid
+        // angle of the ring, 2 pi sin(theta_h) pi s ds
+        let per_theta_h = THETA_D_COUNT * PHI_D_COUNT;
+        let func: Vec<f32> = (0..THETA_H_COUNT).map(|i| {
+            let s = (i as f32 + 0.5) / THETA_H_COUNT as f32;
+            let theta_h = s * s * FRAC_PI_2;
+            let average = values[i * per_theta_h..(i + 1) * per_theta_h].iter().sum::<Vec3>().element_sum()
+                / (3 * per_theta_h) as f32;
+            average * theta_h.cos() * theta_h.sin() * s
+        }).collect();
+        let mut brdf = Self { values, theta_h_distribution: Distribution1D::new(&func), albedo: Vec3::ZERO };
+
+        // the reflectance at normal incidence, over a grid of cosine distributed directions
+        let n = 64;
+        let mut sum = Vec3::ZERO;
+        for a in 0..n {
+            for b in 0..n {
+                let u = Vec2::new((a as f32 + 0.5) / n as f32, (b as f32 + 0.5) / n as f32);
+                let r = u.x.sqrt();
+                let phi = 2.0 * PI * u.y;
+                let wi = Vec3::new(r * phi.cos(), r * phi.sin(), (1.0 - u.x).sqrt());
+                sum += brdf.value(Vec3::Z, wi);
+            }
+        }
+        brdf.albedo = PI * sum / (n * n) as f32;
+        brdf
+    }
+
+    // the brdf for the directions wo and wi, 0 when either is below the surface
+    pub fn value(&self, wo: Vec3, wi: Vec3) -> Vec3 {
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return Vec3::ZERO;
+        }
+        let half = (wo + wi).normalize();
+        let theta_h = half.z.clamp(-1.0, 1.0).acos();
+        let phi_h = half.y.atan2(half.x);
+        // wi seen from the frame of the half vector
+        let difference = rotate(rotate(wi, Vec3::Z, -phi_h), Vec3::Y, -theta_h);
+        let theta_d = difference.z.clamp(-1.0, 1.0).acos();
+        let mut phi_d = difference.y.atan2(difference.x);
+        if phi_d < 0.0 {
+            phi_d += PI;
+        }
+
+        let i = ((theta_h / FRAC_PI_2).sqrt() * THETA_H_COUNT as f32) as usize;
+        let j = (theta_d / FRAC_PI_2 * THETA_D_COUNT as f32) as usize;
+        let k = (phi_d / PI * PHI_D_COUNT as f32) as usize;
+        let index = (i.min(THETA_H_COUNT - 1) * THETA_D_COUNT + j.min(THETA_D_COUNT - 1)) * PHI_D_COUNT
+            + k.min(PHI_D_COUNT - 1);
+        self.values[index]
+    }
+
+    // a half vector around z from the tabulated distribution; u is uniform in the unit square
+    pub fn sample_half_vector(&self, u: Vec2) -> Vec3 {
+        let (s, _pdf, _offset) = self.theta_h_distribution.sample(u.x);
+        let theta_h = s * s * FRAC_PI_2;
+        let phi_h = 2.0 * PI * u.y;
+        Vec3::new(theta_h.sin() * phi_h.cos(), theta_h.sin() * phi_h.sin(), theta_h.cos())
+    }
+
+    // the density of sample_half_vector returning half, per solid angle
+    pub fn half_vector_pdf(&self, half: Vec3) -> f32 {
+        let theta_h = half.z.clamp(-1.0, 1.0).acos();
+        let s = (theta_h / FRAC_PI_2).sqrt();
+        let jacobian = 2.0 * PI * theta_h.sin() * PI * s;
+        if half.z <= 0.0 || jacobian <= 1e-8 {
+            return 0.0;
+        }
+        self.theta_h_distribution.pdf(s) / jacobian
+    }
+
+    // the reflectance at normal incidence
+    pub fn albedo(&self) -> Vec3 {
+        self.albedo
+    }
+}
+
+impl fmt::Debug for MeasuredBRDF {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MeasuredBRDF").field("albedo", &self.albedo).finish_non_exhaustive()
+    }
+}
+
+// v turned by angle around the unit axis
+fn rotate(v: Vec3, axis: Vec3, angle: f32) -> Vec3 {
+    let (sin, cos) = angle.sin_cos();
+    v * cos + axis * axis.dot(v) * (1.0 - cos) + axis.cross(v) * sin
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_1_PI;
+
+    fn merl_file(dimensions: [i32; 3], red: f64, green: f64, blue: f64) -> Vec<u8> {
+        let mut bytes: Vec<u8> = dimensions.iter().flat_map(|d| d.to_le_bytes()).collect();
+        for value in [red, green, blue] {
+            for _ in 0..TABLE_SIZE {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn parses_and_scales_a_constant_table() {
+        let brdf = MeasuredBRDF::parse(&merl_file([90, 90, 180], 150.0, 150.0, -1.0)).unwrap();
+        let wo = Vec3::new(0.3, -0.2, 0.9).normalize();
+        let wi = Vec3::new(-0.6, 0.1, 0.5).normalize();
+        assert_eq!(brdf.value(wo, wi), Vec3::new(0.1, 0.115, 0.0));
+        assert_eq!(brdf.value(wo, -wi), Vec3::ZERO);
+        // a constant brdf reflects pi times itself
+        assert!((brdf.albedo() - Vec3::new(0.1, 0.115, 0.0) * PI).abs().max_element() < 1e-4);
+        assert!(MeasuredBRDF::parse(&merl_file([90, 90, 90], 1.0, 1.0, 1.0)).is_err());
+        assert!(MeasuredBRDF::parse(&[0u8; 20]).is_err());
+    }
+
+    #[test]
+    fn lookup_finds_the_tabulated_bins() {
+        // a brdf that only depends on the angles of the parameterization comes back from the
+        // table within the width of a bin
+        let angles = |wo: Vec3, wi: Vec3| {
+            let half = (wo + wi).normalize();
+            Vec3::new(half.z, half.dot(wi), 1.0 - wo.z * wi.z)
+        };
+        let brdf = MeasuredBRDF::tabulate(angles);
+        let mut state = 1u32;
+        let mut uniform = || {
+            state = state.wrapping_mul(747796405).wrapping_add(2891336453);
+            (state >> 8) as f32 / 16777216.0
+        };
+        // bins that reach below the horizon are left at 0, so grazing directions are skipped
+        for _i in 0..1000 {
+            let wo = Vec3::new(uniform() - 0.5, uniform() - 0.5, uniform() + 0.2).normalize();
+            let wi = Vec3::new(uniform() - 0.5, uniform() - 0.5, uniform() + 0.2).normalize();
+            let error = (brdf.value(wo, wi) - angles(wo, wi)).abs();
+            assert!(error.max_element() < 0.05, "{} instead of {} for {} and {}", brdf.value(wo, wi), angles(wo, wi), wo, wi);
+        }
+        // the lambertian of albedo 1 reflects all of the light
+        let white = MeasuredBRDF::tabulate(|_wo, _wi| Vec3::splat(FRAC_1_PI));
+        assert!((white.albedo() - Vec3::ONE).abs().max_element() < 1e-3);
+    }
+}
@@ -0,0 +1,47 @@
+use std::f32::consts::PI;
+use glam::{Vec2, Vec3};
+
+// homogeneous medium that scatters isotropically, filling the inside of a closed surface;
+// sigma_s and sigma_t are the scattering and extinction coefficients per world unit
+#[derive(Copy, Clone, Debug)]
+pub struct Medium {
+    pub sigma_s: Vec3,
+    pub sigma_t: Vec3,
+}
+
+impl Medium {
+    // the coefficients that give a thick slab the multiple scattering albedo, with the
+    // van de Hulst inversion as fitted by Christensen and Burley 2015; light travels
+    // mean_free_path between two scatterings on average
+    pub fn from_albedo(albedo: Vec3, mean_free_path: Vec3) -> Self {
+        let albedo = albedo.clamp(Vec3::ZERO, Vec3::ONE);
+        let s = Vec3::splat(4.09712) + 4.20863 * albedo
+            - (Vec3::splat(9.59217) + 41.6808 * albedo + 17.7126 * albedo * albedo).powf(0.5);
+        let single_scattering = (Vec3::ONE - s * s).clamp(Vec3::ZERO, Vec3::ONE);
+        let sigma_t = Vec3::ONE / mean_free_path.max(Vec3::splat(1e-4));
+        Self { sigma_s: single_scattering * sigma_t, sigma_t }
+    }
+
+    // a distance to the next scattering, following the exponential of one channel
+    pub fn sample_distance(&self, channel: usize, u: f32) -> f32 {
+        -(1.0 - u).ln() / self.sigma_t[channel]
+    }
+
+    // the throughput of a step of length t, which either ends in a scattering or reaches
+    // the surface, and the density of sampling it when following each of the channels
+    pub fn step(&self, t: f32, scattered: bool) -> (Vec3, Vec3) {
+        let transmittance = (-self.sigma_t * t).exp();
+        if scattered {
+            return (self.sigma_s * transmittance, self.sigma_t * transmittance);
+        }
+        (transmittance, transmittance)
+    }
+
+    // the isotropic phase function picks any direction
+    pub fn sample_direction(u: Vec2) -> Vec3 {
+        let z = 1.0 - 2.0 * u.x;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * PI * u.y;
+        Vec3::new(r * phi.cos(), r * phi.sin(), z)
+    }
+}
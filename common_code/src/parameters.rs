@@ -1,12 +1,17 @@
+use crate::bvh::{BVHBuilder, BVHLayout};
+use crate::bvh_optimizer::OptimizationBudget;
 use crate::camera_controller::CameraController;
 use crate::gpu_structs::GPUFrameBuffer;
+use crate::sky::SkyParameters;
 
 #[derive(Copy, Clone, PartialEq)]
 pub struct SamplingParameters {
     pub samples_per_frame: u32,
     pub num_bounces: u32,
     pub clear_image_buffer: u32,
-    pub samples_per_pixel: u32
+    pub samples_per_pixel: u32,
+    // traces a few wavelengths per path instead of rgb, so that glass can disperse light
+    pub spectral: bool,
 }
 
 impl SamplingParameters {
@@ -15,7 +20,32 @@ impl SamplingParameters {
             samples_per_frame,
             num_bounces,
             clear_image_buffer,
-            samples_per_pixel
+            samples_per_pixel,
+            spectral: false,
+        }
+    }
+
+    pub fn with_spectral(mut self, spectral: bool) -> Self {
+        self.spectral = spectral;
+        self
+    }
+}
+
+// bvh parameters are only read when the tree is built and uploaded
+#[derive(Copy, Clone, PartialEq)]
+pub struct BVHParameters {
+    pub builder: BVHBuilder,
+    // reinsertion pass run after the build, if any
+    pub optimization: Option<OptimizationBudget>,
+    pub layout: BVHLayout,
+}
+
+impl BVHParameters {
+    pub fn new(builder: BVHBuilder, optimization: Option<OptimizationBudget>, layout: BVHLayout) -> Self {
+        Self {
+            builder,
+            optimization,
+            layout
         }
     }
 }
@@ -24,15 +54,21 @@ impl SamplingParameters {
 pub struct RenderParameters {
     camera_controller: CameraController,
     pub sampling_parameters: SamplingParameters,
-    viewport_size: (u32, u32)
+    bvh_parameters: BVHParameters,
+    viewport_size: (u32, u32),
+    // only set when the scene has a sky background, which the path tracer fills in
+    pub sky_parameters: Option<SkyParameters>,
 }
 
 impl RenderParameters {
-    pub fn new(camera_controller: CameraController, sampling_parameters: SamplingParameters, viewport_size: (u32, u32)) -> Self {
+    pub fn new(camera_controller: CameraController, sampling_parameters: SamplingParameters,
+               bvh_parameters: BVHParameters, viewport_size: (u32, u32)) -> Self {
         Self {
             camera_controller,
             sampling_parameters,
+            bvh_parameters,
             viewport_size,
+            sky_parameters: None,
         }
     }
 
@@ -50,6 +86,10 @@ impl RenderParameters {
 
     pub fn sampling_parameters(&self) -> &SamplingParameters { &self.sampling_parameters }
 
+    pub fn bvh_parameters(&self) -> &BVHParameters { &self.bvh_parameters }
+
+    pub fn sky_parameters(&self) -> Option<&SkyParameters> { self.sky_parameters.as_ref() }
+
     pub fn update_camera_controller(&mut self, camera_controller: CameraController) {
         self.camera_controller = camera_controller
     }
@@ -114,7 +154,7 @@ impl RenderProgress {
                 self.num_bounces,
                 1,
                 self.samples_per_pixel
-            );
+            ).with_spectral(rp.sampling_parameters.spectral);
             frame = 1;
             self.frame = 1;
             accumulated_samples = delta_samples;
@@ -125,7 +165,7 @@ impl RenderProgress {
               self.num_bounces,
               0,
               self.samples_per_pixel
-            );
+            ).with_spectral(rp.sampling_parameters.spectral);
             self.frame += 1;
             frame = self.frame;
             accumulated_samples = current_progress;
@@ -137,7 +177,7 @@ impl RenderProgress {
                 self.num_bounces,
                 0,
                 self.samples_per_pixel
-            );
+            ).with_spectral(rp.sampling_parameters.spectral);
             self.frame += 1;
             frame = self.frame;
             self.accumulated_samples = updated_progress;
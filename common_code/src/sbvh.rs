@@ -0,0 +1,388 @@
+use crate::bvh::{BVHNode, BVHTree, TRAVERSAL_STACK_SIZE};
+use crate::sphere::Sphere;
+use glam::{Vec3, Vec4Swizzles};
+
+// spatial splits are binned more coarsely than object splits as every bin a reference
+// straddles has to be clipped
+const OBJECT_BINS: usize = 64;
+const SPATIAL_BINS: usize = 32;
+// spatial splits make deeper trees; leaves below the root at depth MAX_DEPTH keep the
+// tree within the traversal stacks of both tracers
+const MAX_DEPTH: u32 = TRAVERSAL_STACK_SIZE as u32 - 1;
+
+// a reference is one sphere together with the part of its bounds that falls inside the
+// node holding it; a sphere cut by a spatial split ends up with a reference on both sides
+#[derive(Copy, Clone)]
+struct Reference {
+    sphere_idx: usize,
+    aabb_min: Vec3,
+    aabb_max: Vec3,
+}
+
+impl Reference {
+    fn centroid(&self, axis: usize) -> f32 {
+        0.5 * (self.aabb_min[axis] + self.aabb_max[axis])
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Aabb {
+    min: Vec3,
+    max: Vec3,
+}
+
+impl Default for Aabb {
+    fn default() -> Self {
+        Self { min: Vec3::INFINITY, max: Vec3::NEG_INFINITY }
+    }
+}
+
+impl Aabb {
+    fn grow(&mut self, min: Vec3, max: Vec3) {
+        self.min = self.min.min(min);
+        self.max = self.max.max(max);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.min.cmpgt(self.max).any()
+    }
+
+    fn area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let extent = self.max - self.min;
+        extent.x * extent.y + extent.y * extent.z + extent.z * extent.x
+    }
+
+    fn intersection(&self, other: &Aabb) -> Aabb {
+        Aabb { min: self.min.max(other.min), max: self.max.min(other.max) }
+    }
+}
+
+// a bounding box and the number of references in it
+type BinSum = (Aabb, u32);
+
+enum Split {
+    Object { axis: usize, plane: f32 },
+    Spatial { axis: usize, plane: f32 },
+}
+
+impl BVHTree {
+    // builds a spatial split bvh (Stich et al. 2009); spheres that straddle a spatial split
+    // are duplicated in the sphere array so that leaves still reference contiguous ranges
+    // and the traversal code in both tracers is unchanged
+    // overlap_threshold is the fraction of the root surface area two object split children
+    // have to overlap before spatial splits are considered; 1.0 disables them entirely
+    pub fn build_sbvh_tree(&mut self, spheres: &mut Vec<Sphere>, overlap_threshold: f32) {
+        let refs: Vec<Reference> = spheres.iter().enumerate().map(|(sphere_idx, sphere)| {
+            let (aabb_min, aabb_max) = sphere.get_aabb();
+            Reference { sphere_idx, aabb_min, aabb_max }
+        }).collect();
+
+        let mut root = BVHNode::default();
+        let bounds = Self::reference_bounds(&refs);
+        root.aabb_min = bounds.min;
+        root.aabb_max = bounds.max;
+        self.nodes.push(root);
+
+        // push an empty node at index 1 as a placeholder that will never be used
+        self.nodes.push(BVHNode::default());
+
+        let min_overlap = overlap_threshold * bounds.area();
+        let mut ordered_spheres = Vec::<Sphere>::with_capacity(spheres.len());
+        self.subdivide_spatial(0, refs, spheres, &mut ordered_spheres, min_overlap, 0);
+
+        *spheres = ordered_spheres;
+    }
+
+    fn subdivide_spatial(&mut self, index: usize, refs: Vec<Reference>, spheres: &[Sphere],
+                         ordered_spheres: &mut Vec<Sphere>, min_overlap: f32, depth: u32) {
+        let bounds = Aabb { min: self.nodes[index].aabb_min, max: self.nodes[index].aabb_max };
+        let leaf_cost = refs.len() as f32 * bounds.area();
+
+        let mut best_cost = f32::INFINITY;
+        let mut best_split = None;
+        if refs.len() > 1 && depth < MAX_DEPTH {
+            if let Some((cost, axis, plane, overlap)) = Self::find_object_split(&refs) {
+                best_cost = cost;
+                best_split = Some(Split::Object { axis, plane });
+
+                if overlap.area() > min_overlap {
+                    if let Some((cost, axis, plane)) =
+                        Self::find_spatial_split(&refs, &bounds, spheres) {
+                        if cost < best_cost {
+                            best_cost = cost;
+                            best_split = Some(Split::Spatial { axis, plane });
+                        }
+                    }
+                }
+            }
+        }
+
+        let (left_refs, right_refs) = match best_split {
+            Some(Split::Object { axis, plane }) if best_cost < leaf_cost => {
+                refs.into_iter().partition(|r| r.centroid(axis) < plane)
+            }
+            Some(Split::Spatial { axis, plane }) if best_cost < leaf_cost => {
+                Self::partition_spatial(refs, axis, plane, spheres)
+            }
+            _ => {
+                self.make_leaf(index, &refs, spheres, ordered_spheres);
+                return;
+            }
+        };
+
+        // a leaf is identified by a non zero prim_count, so children can never be empty
+        if left_refs.is_empty() || right_refs.is_empty() {
+            let refs = [left_refs, right_refs].concat();
+            self.make_leaf(index, &refs, spheres, ordered_spheres);
+            return;
+        }
+
+        let node_idx = self.nodes.len();
+        for child_refs in [&left_refs, &right_refs] {
+            let child_bounds = Self::reference_bounds(child_refs);
+            self.nodes.push(BVHNode { aabb_min: child_bounds.min, aabb_max: child_bounds.max, ..Default::default() });
+        }
+        self.nodes[index].left_first = node_idx as u32;
+        self.nodes[index].prim_count = 0;
+
+        self.subdivide_spatial(node_idx, left_refs, spheres, ordered_spheres, min_overlap, depth + 1);
+        self.subdivide_spatial(node_idx + 1, right_refs, spheres, ordered_spheres, min_overlap, depth + 1);
+    }
+
+    fn make_leaf(&mut self, index: usize, refs: &[Reference], spheres: &[Sphere],
+                 ordered_spheres: &mut Vec<Sphere>) {
+        self.nodes[index].left_first = ordered_spheres.len() as u32;
+        self.nodes[index].prim_count = refs.len() as u32;
+        for r in refs {
+            ordered_spheres.push(spheres[r.sphere_idx]);
+        }
+    }
+
+    fn reference_bounds(refs: &[Reference]) -> Aabb {
+        let mut bounds = Aabb::default();
+        for r in refs {
+            bounds.grow(r.aabb_min, r.aabb_max);
+        }
+        bounds
+    }
+
+    // prefix and suffix sums over the bins: entry i holds the bins left of plane i and
+    // the bins right of it
+    fn sweep(bins: &[BinSum; OBJECT_BINS]) -> ([BinSum; OBJECT_BINS - 1], [BinSum; OBJECT_BINS - 1]) {
+        let mut left = [(Aabb::default(), 0u32); OBJECT_BINS - 1];
+        let mut right = [(Aabb::default(), 0u32); OBJECT_BINS - 1];
+        let mut left_sum = (Aabb::default(), 0u32);
+        let mut right_sum = (Aabb::default(), 0u32);
+        for idx in 0..OBJECT_BINS - 1 {
+            left_sum.0.grow(bins[idx].0.min, bins[idx].0.max);
+            left_sum.1 += bins[idx].1;
+            left[idx] = left_sum;
+            right_sum.0.grow(bins[OBJECT_BINS - 1 - idx].0.min, bins[OBJECT_BINS - 1 - idx].0.max);
+            right_sum.1 += bins[OBJECT_BINS - 1 - idx].1;
+            right[OBJECT_BINS - 2 - idx] = right_sum;
+        }
+        (left, right)
+    }
+
+    // binned SAH over the reference centroids; returns (cost, axis, plane, overlap of the children)
+    fn find_object_split(refs: &[Reference]) -> Option<(f32, usize, f32, Aabb)> {
+        let mut centroid_bounds = Aabb::default();
+        for r in refs {
+            let centroid = 0.5 * (r.aabb_min + r.aabb_max);
+            centroid_bounds.grow(centroid, centroid);
+        }
+        let extent = centroid_bounds.max - centroid_bounds.min;
+
+        let mut best = None;
+        let mut low_cost = f32::INFINITY;
+        for axis in 0..3 {
+            if extent[axis] < 0.00001 {
+                continue;
+            }
+            let mut bins = [(Aabb::default(), 0u32); OBJECT_BINS];
+            let scale = OBJECT_BINS as f32 / extent[axis];
+            for r in refs {
+                let bin_idx = (OBJECT_BINS - 1).min(
+                    ((r.centroid(axis) - centroid_bounds.min[axis]) * scale) as usize);
+                bins[bin_idx].0.grow(r.aabb_min, r.aabb_max);
+                bins[bin_idx].1 += 1;
+            }
+
+            let (left, right) = Self::sweep(&bins);
+            for idx in 0..OBJECT_BINS - 1 {
+                let cost = left[idx].1 as f32 * left[idx].0.area() +
+                    right[idx].1 as f32 * right[idx].0.area();
+                if cost < low_cost {
+                    low_cost = cost;
+                    let plane = centroid_bounds.min[axis] + extent[axis] * (idx + 1) as f32 / OBJECT_BINS as f32;
+                    best = Some((cost, axis, plane, left[idx].0.intersection(&right[idx].0)));
+                }
+            }
+        }
+        best
+    }
+
+    // bins the clipped references along each axis of the node bounds; a reference adds its
+    // clipped box to every bin it overlaps, but is only counted where it enters and exits
+    fn find_spatial_split(refs: &[Reference], bounds: &Aabb, spheres: &[Sphere])
+                          -> Option<(f32, usize, f32)> {
+        let extent = bounds.max - bounds.min;
+        let mut best = None;
+        let mut low_cost = f32::INFINITY;
+        for axis in 0..3 {
+            if extent[axis] < 0.00001 {
+                continue;
+            }
+            let bin_width = extent[axis] / SPATIAL_BINS as f32;
+            let bin_of = |x: f32| -> usize {
+                (((x - bounds.min[axis]) / bin_width) as usize).min(SPATIAL_BINS - 1)
+            };
+            let mut bins = [Aabb::default(); SPATIAL_BINS];
+            let mut entries = [0u32; SPATIAL_BINS];
+            let mut exits = [0u32; SPATIAL_BINS];
+
+            for r in refs {
+                let first_bin = bin_of(r.aabb_min[axis]);
+                let last_bin = bin_of(r.aabb_max[axis]);
+                entries[first_bin] += 1;
+                exits[last_bin] += 1;
+
+                let mut remainder = *r;
+                for (bin_idx, bin) in bins.iter_mut().enumerate().take(last_bin).skip(first_bin) {
+                    let plane = bounds.min[axis] + bin_width * (bin_idx + 1) as f32;
+                    let (left, right) = Self::clip_reference(&remainder, axis, plane, spheres);
+                    bin.grow(left.aabb_min, left.aabb_max);
+                    remainder = right;
+                }
+                bins[last_bin].grow(remainder.aabb_min, remainder.aabb_max);
+            }
+
+            let mut left_bounds = [Aabb::default(); SPATIAL_BINS - 1];
+            let mut left_box = Aabb::default();
+            let mut left_count = 0u32;
+            for idx in 0..SPATIAL_BINS - 1 {
+                left_box.grow(bins[idx].min, bins[idx].max);
+                left_bounds[idx] = left_box;
+            }
+            let mut right_box = Aabb::default();
+            let mut right_count = 0u32;
+            let mut right_counts = [0u32; SPATIAL_BINS - 1];
+            let mut right_bounds = [Aabb::default(); SPATIAL_BINS - 1];
+            for idx in (1..SPATIAL_BINS).rev() {
+                right_box.grow(bins[idx].min, bins[idx].max);
+                right_count += exits[idx];
+                right_bounds[idx - 1] = right_box;
+                right_counts[idx - 1] = right_count;
+            }
+
+            for idx in 0..SPATIAL_BINS - 1 {
+                left_count += entries[idx];
+                // a split that keeps every reference on one side makes no progress
+                if left_count as usize == refs.len() || right_counts[idx] as usize == refs.len() {
+                    continue;
+                }
+                let cost = left_count as f32 * left_bounds[idx].area() +
+                    right_counts[idx] as f32 * right_bounds[idx].area();
+                if cost < low_cost {
+                    low_cost = cost;
+                    best = Some((cost, axis, bounds.min[axis] + bin_width * (idx + 1) as f32));
+                }
+            }
+        }
+        best
+    }
+
+    fn partition_spatial(refs: Vec<Reference>, axis: usize, plane: f32, spheres: &[Sphere])
+                         -> (Vec<Reference>, Vec<Reference>) {
+        let mut left_refs = Vec::<Reference>::new();
+        let mut right_refs = Vec::<Reference>::new();
+        let mut straddling = Vec::<Reference>::new();
+        let mut left_box = Aabb::default();
+        let mut right_box = Aabb::default();
+        for r in refs {
+            if r.aabb_max[axis] <= plane {
+                left_box.grow(r.aabb_min, r.aabb_max);
+                left_refs.push(r);
+            } else if r.aabb_min[axis] >= plane {
+                right_box.grow(r.aabb_min, r.aabb_max);
+                right_refs.push(r);
+            } else {
+                straddling.push(r);
+            }
+        }
+
+        for r in straddling {
+            let (left, right) = Self::clip_reference(&r, axis, plane, spheres);
+            // reference unsplitting: moving the whole reference to one side can be cheaper
+            // than duplicating it
+            let n_left = left_refs.len() as f32;
+            let n_right = right_refs.len() as f32;
+            let mut split_left = left_box;
+            split_left.grow(left.aabb_min, left.aabb_max);
+            let mut split_right = right_box;
+            split_right.grow(right.aabb_min, right.aabb_max);
+            let mut all_left = left_box;
+            all_left.grow(r.aabb_min, r.aabb_max);
+            let mut all_right = right_box;
+            all_right.grow(r.aabb_min, r.aabb_max);
+
+            let split_cost = split_left.area() * (n_left + 1.0) + split_right.area() * (n_right + 1.0);
+            let left_cost = all_left.area() * (n_left + 1.0) + right_box.area() * n_right;
+            let right_cost = left_box.area() * n_left + all_right.area() * (n_right + 1.0);
+
+            if left_cost < split_cost && left_cost <= right_cost {
+                left_box = all_left;
+                left_refs.push(r);
+            } else if right_cost < split_cost {
+                right_box = all_right;
+                right_refs.push(r);
+            } else {
+                left_box = split_left;
+                right_box = split_right;
+                left_refs.push(left);
+                right_refs.push(right);
+            }
+        }
+        (left_refs, right_refs)
+    }
+
+    // splits a reference at the plane; the bounds of each side are those of the sphere cap
+    // on that side, intersected with the reference's current bounds
+    fn clip_reference(r: &Reference, axis: usize, plane: f32, spheres: &[Sphere])
+                      -> (Reference, Reference) {
+        let sphere = spheres[r.sphere_idx];
+        let center = sphere.center().xyz();
+        let radius = sphere.radius();
+        let d = plane - center[axis];
+        // radius of the circle where the plane cuts the sphere
+        let cut_radius = (radius * radius - d * d).max(0.0).sqrt();
+
+        // the cap containing the center is as wide as the sphere, the other as wide as the cut
+        let left_radius = if d >= 0.0 { radius } else { cut_radius };
+        let right_radius = if d <= 0.0 { radius } else { cut_radius };
+
+        let mut left_min = center - Vec3::splat(left_radius);
+        let mut left_max = center + Vec3::splat(left_radius);
+        left_min[axis] = center[axis] - radius;
+        left_max[axis] = plane;
+        let mut right_min = center - Vec3::splat(right_radius);
+        let mut right_max = center + Vec3::splat(right_radius);
+        right_min[axis] = plane;
+        right_max[axis] = center[axis] + radius;
+
+        let left = Reference {
+            sphere_idx: r.sphere_idx,
+            aabb_min: left_min.max(r.aabb_min),
+            aabb_max: left_max.min(r.aabb_max),
+        };
+        let right = Reference {
+            sphere_idx: r.sphere_idx,
+            aabb_min: right_min.max(r.aabb_min),
+            aabb_max: right_max.min(r.aabb_max),
+        };
+        (left, right)
+    }
+}
@@ -1,20 +1,41 @@
+use std::path::Path;
+use std::sync::Arc;
 use glam::{Vec3};
-use crate::material::Material;
+use crate::background::Background;
+use crate::light::Light;
+use crate::material::{Material, ALUMINIUM, BK7, COPPER, GOLD, SF11};
+use crate::measured::{MeasuredBRDF, MerlError};
 use crate::sphere::Sphere;
+use crate::texture::{ImageTexture, Texture, WrapMode};
 use crate::util_funcs::{random_f32, random_range_f32, random_vec3, random_vec3_range};
 
 pub struct Scene {
     pub spheres: Vec<Sphere>,
     pub materials: Vec<Material>,
+    // what rays that leave the scene pick up; indoor scenes use black and are lit by
+    // emissive spheres only
+    pub background: Background,
+    // point, spot and directional lights; emissive spheres are lights too, through their material
+    pub lights: Vec<Light>,
+    // referenced by the materials through their texture indices
+    pub textures: Vec<Texture>,
+    // tabulated brdfs, referenced by measured materials through their indices
+    pub measured: Vec<Arc<MeasuredBRDF>>,
 }
 
 impl Scene {
+    // a scene of the spheres alone, without lights, textures or measured tables; scenes that
+    // need them fill them in over it
+    pub fn from_spheres(spheres: Vec<Sphere>, materials: Vec<Material>, background: Background) -> Self {
+        Self { spheres, materials, background, lights: Vec::new(), textures: Vec::new(), measured: Vec::new() }
+    }
+
     pub fn new() -> Self {
         let mat_ground = Material::Lambertian(Vec3::new(0.8, 0.8, 0.0));
         let mat_center = Material::Lambertian(Vec3::new(0.1, 0.2, 0.5));
         let mat_left = Material::Dielectric(1.50);
         // let mat_left = Material::Metal(Vec3::new(0.8, 0.8, 0.8), 0.3);
-        let mat_bubble = Material::Dielectric(1.00/1.50);
+        let mat_bubble = Material::Dielectric(1.00).with_priority(1);
         let mat_right = Material::Metal(Vec3::new(0.8, 0.6, 0.2), 1.0);
 
         let mut materials = vec![mat_ground, mat_center, mat_left, mat_right, mat_bubble];
@@ -42,7 +63,7 @@ impl Scene {
 
         let mut spheres = vec![ground, center, right, left, bubble];
 
-        Self { spheres, materials }
+        Self::from_spheres(spheres, materials, Background::sky())
     }
 
     pub fn book_one_final() -> Self {
@@ -103,7 +124,300 @@ impl Scene {
         materials.push(met_mat);
         spheres.push(Sphere::new(Vec3::new(4.0, 1.0, 0.0), 1.0, (materials.len() - 1) as u32));
 
-        Self { spheres, materials }
+        Self::from_spheres(spheres, materials, Background::sky())
+    }
+
+    // a cornell box in the spirit of smallpt: the walls are large spheres, the box spans
+    // [-1, 1] x [0, 2] x [-1, 1] and is open towards the camera, and the only light is a lamp
+    // poking through the ceiling; use with Camera::cornell_box_camera
+    pub fn cornell_box() -> Self {
+        let white = Material::Lambertian(Vec3::new(0.73, 0.73, 0.73));
+        let red = Material::Lambertian(Vec3::new(0.65, 0.05, 0.05));
+        let green = Material::Lambertian(Vec3::new(0.12, 0.45, 0.15));
+        let lamp = Material::Emissive(Vec3::new(1.0, 0.85, 0.6), 12.0);
+        let mirror = Material::Metal(Vec3::new(0.9, 0.9, 0.9), 0.0);
+        let glass = Material::Dielectric(1.5);
+
+        let materials = vec![white, red, green, lamp, mirror, glass];
+
+        let wall_radius = 1000.0;
+        let spheres = vec![
+            // floor, ceiling and back wall
+            Sphere::new(Vec3::new(0.0, -wall_radius, 0.0), wall_radius, 0),
+            Sphere::new(Vec3::new(0.0, 2.0 + wall_radius, 0.0), wall_radius, 0),
+            Sphere::new(Vec3::new(0.0, 1.0, -1.0 - wall_radius), wall_radius, 0),
+            // left and right walls
+            Sphere::new(Vec3::new(-1.0 - wall_radius, 1.0, 0.0), wall_radius, 1),
+            Sphere::new(Vec3::new(1.0 + wall_radius, 1.0, 0.0), wall_radius, 2),
+            // the lamp only shows a small cap below the ceiling
+            Sphere::new(Vec3::new(0.0, 3.96, 0.0), 2.0, 3),
+            Sphere::new(Vec3::new(-0.45, 0.35, -0.35), 0.35, 4),
+            Sphere::new(Vec3::new(0.45, 0.35, 0.3), 0.35, 5),
+        ];
+
+        Self::from_spheres(spheres, materials, Background::Solid(Vec3::ZERO))
+    }
+
+    // the layout of Scene::new with the procedural textures of "The Next Week": a checkered
+    // ground, and noise, marble and turbulence spheres
+    pub fn textured() -> Self {
+        let textures = vec![
+            Texture::Checker { scale: 0.25, even: Vec3::new(0.2, 0.3, 0.1), odd: Vec3::new(0.9, 0.9, 0.9) },
+            Texture::Noise { scale: 8.0, color: Vec3::ONE },
+            Texture::Marble { scale: 12.0, color: Vec3::ONE },
+            Texture::Turbulence { scale: 6.0, color: Vec3::new(0.8, 0.6, 0.2), depth: 7 },
+        ];
+        let materials = vec![
+            Material::Lambertian(Vec3::ONE).with_albedo_texture(0),
+            Material::Lambertian(Vec3::ONE).with_albedo_texture(1),
+            Material::Lambertian(Vec3::ONE).with_albedo_texture(2),
+            Material::Metal(Vec3::ONE, 0.3).with_albedo_texture(3),
+        ];
+        let spheres = vec![
+            Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0, 0),
+            Sphere::new(Vec3::new(-1.0, 0.0, -1.0), 0.5, 1),
+            Sphere::new(Vec3::new(0.0, 0.0, -1.2), 0.5, 2),
+            Sphere::new(Vec3::new(1.0, 0.0, -1.0), 0.5, 3),
+        ];
+
+        Self { textures, ..Self::from_spheres(spheres, materials, Background::sky()) }
     }
 
-}
\ No newline at end of file
+    // microfacet metals of increasing roughness next to a frosted glass sphere
+    pub fn microfacet() -> Self {
+        let materials = vec![
+            Material::Lambertian(Vec3::new(0.5, 0.5, 0.5)),
+            Material::Conductor(GOLD.0, GOLD.1, 0.05),
+            Material::Conductor(COPPER.0, COPPER.1, 0.25),
+            Material::Conductor(ALUMINIUM.0, ALUMINIUM.1, 0.6),
+            Material::RoughDielectric(1.5, 0.2),
+        ];
+        let spheres = vec![
+            Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0, 0),
+            Sphere::new(Vec3::new(-1.65, 0.0, -1.0), 0.5, 1),
+            Sphere::new(Vec3::new(-0.55, 0.0, -1.0), 0.5, 2),
+            Sphere::new(Vec3::new(0.55, 0.0, -1.0), 0.5, 3),
+            Sphere::new(Vec3::new(1.65, 0.0, -1.0), 0.5, 4),
+        ];
+
+        Self::from_spheres(spheres, materials, Background::sky())
+    }
+
+    // the principled material standing in for plastic, brushed metal, glass, car paint
+    // and velvet
+    pub fn principled() -> Self {
+        let materials = vec![
+            Material::Lambertian(Vec3::new(0.5, 0.5, 0.5)),
+            Material::Principled(Vec3::new(0.1, 0.3, 0.8), 0.0, 0.4),
+            Material::Principled(Vec3::new(0.95, 0.64, 0.54), 1.0, 0.35),
+            Material::Principled(Vec3::ONE, 0.0, 0.05).with_transmission(1.0),
+            Material::Principled(Vec3::new(0.6, 0.02, 0.02), 0.5, 0.5).with_clearcoat(1.0, 0.05),
+            Material::Principled(Vec3::new(0.3, 0.05, 0.3), 0.0, 0.9).with_sheen(1.0),
+        ];
+        let spheres = vec![
+            Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0, 0),
+            Sphere::new(Vec3::new(-2.2, 0.0, -1.0), 0.5, 1),
+            Sphere::new(Vec3::new(-1.1, 0.0, -1.0), 0.5, 2),
+            Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, 3),
+            Sphere::new(Vec3::new(1.1, 0.0, -1.0), 0.5, 4),
+            Sphere::new(Vec3::new(2.2, 0.0, -1.0), 0.5, 5),
+        ];
+
+        Self::from_spheres(spheres, materials, Background::sky())
+    }
+
+    // noise and marble bump maps, and a normal map of rivets on brushed aluminium
+    pub fn bumpy() -> Self {
+        let textures = vec![
+            Texture::Noise { scale: 10.0, color: Vec3::ONE },
+            Texture::Marble { scale: 12.0, color: Vec3::ONE },
+            Texture::Image(rivet_normal_map()),
+        ];
+        let materials = vec![
+            Material::Lambertian(Vec3::new(0.5, 0.5, 0.5)),
+            Material::Lambertian(Vec3::new(0.8, 0.4, 0.1)).with_bump_map(0, 0.05),
+            Material::Principled(Vec3::new(0.9, 0.9, 0.85), 0.0, 0.3).with_bump_map(1, 0.02),
+            Material::Conductor(ALUMINIUM.0, ALUMINIUM.1, 0.2).with_normal_map(2, 1.0),
+        ];
+        let spheres = vec![
+            Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0, 0),
+            Sphere::new(Vec3::new(-1.1, 0.0, -1.0), 0.5, 1),
+            Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, 2),
+            Sphere::new(Vec3::new(1.1, 0.0, -1.0), 0.5, 3),
+        ];
+
+        Self { textures, ..Self::from_spheres(spheres, materials, Background::sky()) }
+    }
+
+    // coated materials: glossy plastic, varnished wood, and car paint over red and over
+    // copper flakes
+    pub fn layered() -> Self {
+        let textures = vec![
+            Texture::Marble { scale: 4.0, color: Vec3::new(0.55, 0.3, 0.12) },
+        ];
+        let materials = vec![
+            Material::Lambertian(Vec3::new(0.5, 0.5, 0.5)),
+            Material::Lambertian(Vec3::new(0.05, 0.2, 0.7)).with_coat(1.5, 0.0),
+            Material::Lambertian(Vec3::ONE).with_albedo_texture(0).with_coat(1.5, 0.1),
+            Material::Lambertian(Vec3::new(0.6, 0.02, 0.02)).with_coat(1.5, 0.02),
+            Material::Conductor(COPPER.0, COPPER.1, 0.35).with_coat(1.5, 0.02),
+        ];
+        let spheres = vec![
+            Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0, 0),
+            Sphere::new(Vec3::new(-1.65, 0.0, -1.0), 0.5, 1),
+            Sphere::new(Vec3::new(-0.55, 0.0, -1.0), 0.5, 2),
+            Sphere::new(Vec3::new(0.55, 0.0, -1.0), 0.5, 3),
+            Sphere::new(Vec3::new(1.65, 0.0, -1.0), 0.5, 4),
+        ];
+
+        Self { textures, ..Self::from_spheres(spheres, materials, Background::sky()) }
+    }
+
+    // skin, wax and marble for the random walk of the CPU tracer; light in skin goes
+    // furthest in the red
+    pub fn subsurface() -> Self {
+        let textures = vec![
+            Texture::Marble { scale: 6.0, color: Vec3::ONE },
+        ];
+        let materials = vec![
+            Material::Lambertian(Vec3::new(0.5, 0.5, 0.5)),
+            Material::Subsurface(Vec3::new(0.85, 0.55, 0.45), Vec3::new(0.12, 0.05, 0.03), 1.4),
+            Material::Subsurface(Vec3::new(0.95, 0.85, 0.6), Vec3::splat(0.1), 1.45),
+            Material::Subsurface(Vec3::ONE, Vec3::splat(0.03), 1.5).with_albedo_texture(0),
+        ];
+        let spheres = vec![
+            Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0, 0),
+            Sphere::new(Vec3::new(-1.1, 0.0, -1.0), 0.5, 1),
+            Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, 2),
+            Sphere::new(Vec3::new(1.1, 0.0, -1.0), 0.5, 3),
+        ];
+
+        Self { textures, ..Self::from_spheres(spheres, materials, Background::sky()) }
+    }
+
+    // nested dielectrics: a glass ball filled with water that holds air bubbles, and a glass
+    // ball with a bubble; the spheres inside get the higher priorities
+    pub fn nested() -> Self {
+        let materials = vec![
+            Material::Lambertian(Vec3::new(0.5, 0.5, 0.5)),
+            Material::Dielectric(1.5).with_priority(1),
+            Material::Dielectric(1.33).with_priority(2),
+            Material::Dielectric(1.0).with_priority(3),
+        ];
+        let spheres = vec![
+            Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0, 0),
+            Sphere::new(Vec3::new(-0.6, 0.0, -1.0), 0.5, 1),
+            Sphere::new(Vec3::new(-0.6, 0.0, -1.0), 0.45, 2),
+            Sphere::new(Vec3::new(-0.7, 0.15, -0.9), 0.1, 3),
+            Sphere::new(Vec3::new(-0.45, -0.1, -0.85), 0.06, 3),
+            Sphere::new(Vec3::new(0.6, 0.0, -1.0), 0.5, 1),
+            Sphere::new(Vec3::new(0.6, 0.0, -1.0), 0.25, 3),
+        ];
+
+        Self::from_spheres(spheres, materials, Background::sky())
+    }
+
+    // a ball cut into a lattice by a checker alpha texture, and a translucent ball, under a
+    // small light; their shadows are masked and dimmed the same way
+    pub fn alpha() -> Self {
+        let textures = vec![Texture::Checker { scale: 0.12, even: Vec3::ONE, odd: Vec3::ZERO }];
+        let materials = vec![
+            Material::Lambertian(Vec3::new(0.5, 0.5, 0.5)),
+            Material::Emissive(Vec3::ONE, 30.0),
+            Material::Lambertian(Vec3::new(0.2, 0.6, 0.2)).with_alpha_texture(0),
+            Material::Lambertian(Vec3::new(0.8, 0.2, 0.1)).with_opacity(0.4),
+        ];
+        let spheres = vec![
+            Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0, 0),
+            Sphere::new(Vec3::new(0.0, 3.0, -1.0), 0.3, 1),
+            Sphere::new(Vec3::new(-0.6, 0.0, -1.0), 0.5, 2),
+            Sphere::new(Vec3::new(0.6, 0.0, -1.0), 0.5, 3),
+        ];
+
+        Self { textures, ..Self::from_spheres(spheres, materials, Background::Solid(Vec3::splat(0.05))) }
+    }
+
+    // a MERL measurement on the left, next to a lambertian of the same albedo on the right;
+    // the analytic material to compare it with goes in place of the lambertian
+    pub fn measured<P: AsRef<Path>>(path: P) -> Result<Self, MerlError> {
+        let brdf = MeasuredBRDF::load(path)?;
+        let albedo = brdf.albedo();
+        let materials = vec![
+            Material::Lambertian(Vec3::new(0.5, 0.5, 0.5)),
+            Material::Emissive(Vec3::ONE, 30.0),
+            Material::Measured(0),
+            Material::Lambertian(albedo),
+        ];
+        let spheres = vec![
+            Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0, 0),
+            Sphere::new(Vec3::new(-1.5, 3.0, 0.5), 0.3, 1),
+            Sphere::new(Vec3::new(-0.6, 0.0, -1.0), 0.5, 2),
+            Sphere::new(Vec3::new(0.6, 0.0, -1.0), 0.5, 3),
+        ];
+
+        Ok(Self { measured: vec![Arc::new(brdf)], ..Self::from_spheres(spheres, materials, Background::sky()) })
+    }
+
+    // a soap bubble, a coated lens and oil on a metal ball, colored by their thin films; the
+    // quarter wave coating of the lens cuts its reflections rather than coloring them
+    pub fn thin_film() -> Self {
+        let materials = vec![
+            Material::Lambertian(Vec3::new(0.5, 0.5, 0.5)),
+            Material::Dielectric(1.0).with_thin_film(450.0, 1.33),
+            Material::Dielectric(1.5).with_thin_film(100.0, 1.38),
+            Material::Conductor(ALUMINIUM.0, ALUMINIUM.1, 0.05).with_thin_film(350.0, 1.45),
+        ];
+        let spheres = vec![
+            Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0, 0),
+            Sphere::new(Vec3::new(-1.1, 0.0, -1.0), 0.5, 1),
+            Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, 2),
+            Sphere::new(Vec3::new(1.1, 0.0, -1.0), 0.5, 3),
+        ];
+
+        Self::from_spheres(spheres, materials, Background::sky())
+    }
+
+    // glass spheres in a dark room lit by a small light, which only disperse in spectral mode;
+    // the flint glass on the right spreads colors the most
+    pub fn dispersion() -> Self {
+        let materials = vec![
+            Material::Lambertian(Vec3::new(0.7, 0.7, 0.7)),
+            Material::Emissive(Vec3::ONE, 40.0),
+            Material::Dielectric(1.5).with_sellmeier(BK7.0, BK7.1),
+            Material::Dielectric(1.5).with_cauchy(1.5, 0.02),
+            Material::Dielectric(1.5).with_sellmeier(SF11.0, SF11.1),
+        ];
+        let spheres = vec![
+            Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0, 0),
+            Sphere::new(Vec3::new(-2.0, 2.5, -1.5), 0.25, 1),
+            Sphere::new(Vec3::new(-1.1, 0.0, -1.0), 0.5, 2),
+            Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, 3),
+            Sphere::new(Vec3::new(1.1, 0.0, -1.0), 0.5, 4),
+        ];
+
+        Self::from_spheres(spheres, materials, Background::Solid(Vec3::splat(0.02)))
+    }
+
+}
+
+// rows of domed rivets, as a tangent-space normal map twice as wide as it is high so that
+// its texels come out square on a sphere
+fn rivet_normal_map() -> ImageTexture {
+    let (width, height, cell, radius) = (256u32, 128u32, 16.0, 5.0);
+    let mut pixels = Vec::with_capacity((width * height) as usize);
+    for row in 0..height {
+        for column in 0..width {
+            // offset from the center of the cell, with y up as in the uvs
+            let x = (column as f32 + 0.5) % cell - 0.5 * cell;
+            let y = 0.5 * cell - (row as f32 + 0.5) % cell;
+            let d2 = x * x + y * y;
+            let normal = if d2 < radius * radius {
+                Vec3::new(x, y, (radius * radius - d2).sqrt()).normalize()
+            } else {
+                Vec3::Z
+            };
+            pixels.push((0.5 * (normal + Vec3::ONE)).extend(1.0));
+        }
+    }
+    ImageTexture::new(width, height, pixels, WrapMode::Repeat)
+}
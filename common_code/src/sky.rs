@@ -0,0 +1,169 @@
+use crate::light::Light;
+use glam::{Vec3, Vec4};
+
+// Preetham sky luminance is in kcd/m^2; this brings a clear zenith to about 1
+const SKY_LUMINANCE_SCALE: f32 = 0.1;
+// illuminance of the sun above the atmosphere, in klux
+const SOLAR_ILLUMINANCE: f32 = 128.0;
+// wavelengths, in micrometers, standing in for the red, green and blue channels
+const WAVELENGTHS: [f32; 3] = [0.680, 0.550, 0.440];
+
+// the sun position and the haziness of the sky; angles are in degrees, with the azimuth
+// turning from -z towards +x, and turbidity going from 2 (very clear) to 10 (hazy)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SkyParameters {
+    pub sun_elevation: f32,
+    pub sun_azimuth: f32,
+    pub turbidity: f32,
+}
+
+impl SkyParameters {
+    pub fn new(sun_elevation: f32, sun_azimuth: f32, turbidity: f32) -> Self {
+        Self {
+            sun_elevation,
+            sun_azimuth,
+            turbidity,
+        }
+    }
+
+    // unit vector pointing towards the sun
+    pub fn sun_direction(&self) -> Vec3 {
+        let elevation = self.sun_elevation.to_radians();
+        let azimuth = self.sun_azimuth.to_radians();
+        Vec3::new(elevation.cos() * azimuth.sin(), elevation.sin(), -elevation.cos() * azimuth.cos())
+    }
+
+    // the sun disk seen through the atmosphere: the light outside of it is dimmed by
+    // rayleigh and aerosol scattering along the air mass the sunlight crosses
+    pub fn sun(&self) -> Light {
+        let irradiance = if self.sun_elevation <= 0.0 {
+            Vec3::ZERO
+        } else {
+            // Kasten's relative optical air mass
+            let zenith = 90.0 - self.sun_elevation;
+            let air_mass = 1.0 / (zenith.to_radians().cos() + 0.15 * (93.885 - zenith).powf(-1.253));
+            // Angstrom's turbidity coefficient, with alpha = 1.3
+            let beta = 0.04608 * self.turbidity - 0.04586;
+            let transmittance = WAVELENGTHS.map(|lambda| {
+                let rayleigh = 0.008735 * lambda.powf(-4.08);
+                let aerosol = beta * lambda.powf(-1.3);
+                (-(rayleigh + aerosol) * air_mass).exp()
+            });
+            SKY_LUMINANCE_SCALE * SOLAR_ILLUMINANCE * Vec3::from_array(transmittance)
+        };
+        Light::sun(-self.sun_direction(), irradiance)
+    }
+}
+
+// the Preetham, Shirley and Smits analytic sky: each of the luminance Y and the
+// chromaticities x and y is its zenith value times the ratio of the Perez function
+// in the view direction to the Perez function at the zenith
+// the coefficients are stored as (Y, x, y) so they line up with the shaders
+#[derive(Copy, Clone, Debug)]
+pub struct PreethamSky {
+    perez: [Vec3; 5],
+    zenith: Vec3,
+    sun_direction: Vec3,
+}
+
+impl PreethamSky {
+    pub fn new(sky: &SkyParameters) -> Self {
+        let t = sky.turbidity;
+        let perez = [
+            Vec3::new(0.1787 * t - 1.4630, -0.0193 * t - 0.2592, -0.0167 * t - 0.2608),
+            Vec3::new(-0.3554 * t + 0.4275, -0.0665 * t + 0.0008, -0.0950 * t + 0.0092),
+            Vec3::new(-0.0227 * t + 5.3251, -0.0004 * t + 0.2125, -0.0079 * t + 0.2102),
+            Vec3::new(0.1206 * t - 2.5771, -0.0641 * t - 0.8989, -0.0441 * t - 1.6537),
+            Vec3::new(-0.0670 * t + 0.3703, -0.0033 * t + 0.0452, -0.0109 * t + 0.0529),
+        ];
+
+        // the model is not defined with the sun below the horizon
+        let theta_s = (90.0 - sky.sun_elevation.max(0.0)).to_radians();
+        let chi = (4.0 / 9.0 - t / 120.0) * (std::f32::consts::PI - 2.0 * theta_s);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let theta = Vec3::new(theta_s.powi(3), theta_s.powi(2), theta_s);
+        let zenith_x = t * t * theta.dot(Vec3::new(0.00166, -0.00375, 0.00209))
+            + t * (theta.dot(Vec3::new(-0.02903, 0.06377, -0.03202)) + 0.00394)
+            + theta.dot(Vec3::new(0.11693, -0.21196, 0.06052)) + 0.25886;
+        let zenith_y = t * t * theta.dot(Vec3::new(0.00275, -0.00610, 0.00317))
+            + t * (theta.dot(Vec3::new(-0.04214, 0.08970, -0.04153)) + 0.00516)
+            + theta.dot(Vec3::new(0.15346, -0.26756, 0.06670)) + 0.26688;
+
+        // folding the Perez function at the zenith into the zenith values leaves the
+        // shaders a single evaluation per ray
+        let zenith = Vec3::new(SKY_LUMINANCE_SCALE * zenith_luminance, zenith_x, zenith_y)
+            / perez_function(&perez, 1.0, theta_s.cos());
+        Self { perez, zenith, sun_direction: sky.sun_direction() }
+    }
+
+    // linear sRGB radiance in the given direction; the sky below the horizon repeats the horizon
+    pub fn radiance(&self, direction: Vec3) -> Vec3 {
+        let d = direction.normalize();
+        let cos_theta = d.y.max(0.001);
+        let cos_gamma = d.dot(self.sun_direction).clamp(-1.0, 1.0);
+        let yxy = self.zenith * perez_function(&self.perez, cos_theta, cos_gamma);
+        xyy_to_rgb(yxy)
+    }
+
+    // flattened for the scene parameters: the five Perez coefficients, the zenith values
+    // and the direction to the sun
+    pub fn to_gpu(&self) -> [Vec4; 7] {
+        [
+            self.perez[0].extend(0.0),
+            self.perez[1].extend(0.0),
+            self.perez[2].extend(0.0),
+            self.perez[3].extend(0.0),
+            self.perez[4].extend(0.0),
+            self.zenith.extend(0.0),
+            self.sun_direction.extend(0.0),
+        ]
+    }
+}
+
+// (1 + A exp(B / cos theta)) (1 + C exp(D gamma) + E cos^2 gamma), for the three channels at once
+fn perez_function(perez: &[Vec3; 5], cos_theta: f32, cos_gamma: f32) -> Vec3 {
+    let gamma = cos_gamma.acos();
+    (Vec3::ONE + perez[0] * (perez[1] / cos_theta).exp())
+        * (Vec3::ONE + perez[2] * (perez[3] * gamma).exp() + perez[4] * cos_gamma * cos_gamma)
+}
+
+// (Y, x, y) to linear sRGB through XYZ
+fn xyy_to_rgb(yxy: Vec3) -> Vec3 {
+    let (luminance, x, y) = (yxy.x, yxy.y, yxy.z);
+    let xyz = Vec3::new(x / y * luminance, luminance, (1.0 - x - y) / y * luminance);
+    Vec3::new(
+        3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+        -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+        0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z,
+    ).max(Vec3::ZERO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zenith_has_the_zenith_luminance() {
+        let sky = SkyParameters::new(40.0, 0.0, 3.0);
+        let model = PreethamSky::new(&sky);
+        let zenith = model.radiance(Vec3::Y);
+        let luminance = 0.2126 * zenith.x + 0.7152 * zenith.y + 0.0722 * zenith.z;
+        // Y_z from the paper, for theta_s = 50 degrees and T = 3
+        let chi = (4.0 / 9.0 - 3.0 / 120.0) * (std::f32::consts::PI - 2.0 * 50f32.to_radians());
+        let expected = SKY_LUMINANCE_SCALE * ((4.0453 * 3.0 - 4.9710) * chi.tan() - 0.2155 * 3.0 + 2.4192);
+        assert!((luminance - expected).abs() < 0.02 * expected, "{} != {}", luminance, expected);
+    }
+
+    #[test]
+    fn sky_is_brightest_around_the_sun_and_reddens_at_sunset() {
+        let sky = SkyParameters::new(30.0, 60.0, 3.0);
+        let model = PreethamSky::new(&sky);
+        let near_sun = model.radiance(sky.sun_direction() + Vec3::new(0.0, 0.05, 0.0));
+        let away = model.radiance(-sky.sun_direction() + Vec3::new(0.0, 1.0, 0.0));
+        assert!(near_sun.length() > 2.0 * away.length());
+
+        let Light::Directional { irradiance: noon, .. } = SkyParameters::new(80.0, 0.0, 3.0).sun() else { unreachable!() };
+        let Light::Directional { irradiance: dusk, .. } = SkyParameters::new(3.0, 0.0, 3.0).sun() else { unreachable!() };
+        assert!(dusk.x < noon.x && dusk.z / dusk.x < noon.z / noon.x);
+    }
+}
@@ -0,0 +1,266 @@
+use std::sync::OnceLock;
+use glam::{Mat3, Vec3, Vec4};
+
+// the visible range that spectral rendering samples, in nm
+pub const LAMBDA_MIN: f32 = 360.0;
+pub const LAMBDA_MAX: f32 = 830.0;
+// the integral of the CIE y curve over the visible range, which scales the estimate of a
+// spectrum into XYZ so that a flat spectrum of 1 has Y = 1
+pub const CIE_Y_INTEGRAL: f32 = 106.92207;
+// linear sRGB of a flat spectrum of 1; rgb is divided by it, which white balances the
+// equal energy illuminant so that a flat spectrum is white
+pub const WHITE_BALANCE: Vec3 = Vec3::new(1.2002837, 0.9497977, 0.9081533);
+// the table of rgb to spectrum coefficients has this many entries along each axis
+pub const RGB_TO_SPECTRUM_RESOLUTION: usize = 16;
+
+// the CIE 1931 matching functions, with the multi-lobe gaussian fit of Wyman, Sloan and
+// Shirley 2013
+pub fn cie_xyz(lambda: f32) -> Vec3 {
+    let lobe = |mu: f32, sigma_below: f32, sigma_above: f32| {
+        let t = (lambda - mu) / if lambda < mu { sigma_below } else { sigma_above };
+        (-0.5 * t * t).exp()
+    };
+    Vec3::new(
+        1.056 * lobe(599.8, 37.9, 31.0) + 0.362 * lobe(442.0, 16.0, 26.7) - 0.065 * lobe(501.1, 20.4, 26.2),
+        0.821 * lobe(568.8, 46.9, 40.5) + 0.286 * lobe(530.9, 16.3, 31.1),
+        1.217 * lobe(437.0, 11.8, 36.0) + 0.681 * lobe(459.0, 26.0, 13.8),
+    )
+}
+
+pub fn xyz_to_rgb(xyz: Vec3) -> Vec3 {
+    Vec3::new(
+        3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+        -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+        0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z,
+    ) / WHITE_BALANCE
+}
+
+// the hero wavelength picked by u, and three more spread evenly over the visible range
+// after it; each of them is uniform over the range
+pub fn sample_wavelengths(u: f32) -> Vec4 {
+    let range = LAMBDA_MAX - LAMBDA_MIN;
+    let hero = u * range;
+    Vec4::new(0.0, 0.25, 0.5, 0.75).map(|offset| LAMBDA_MIN + (hero + offset * range) % range)
+}
+
+// rgb from the values of a spectrum at the wavelengths of sample_wavelengths
+pub fn spectrum_to_rgb(values: Vec4, wavelengths: Vec4) -> Vec3 {
+    let mut xyz = Vec3::ZERO;
+    for i in 0..4 {
+        xyz += values[i] * cie_xyz(wavelengths[i]);
+    }
+    xyz_to_rgb(xyz * 0.25 * (LAMBDA_MAX - LAMBDA_MIN) / CIE_Y_INTEGRAL)
+}
+
+// the smooth spectrum of Jakob and Hanika 2019, a sigmoid of a quadratic in the wavelength
+// scaled to [0, 1] over the visible range
+pub fn sigmoid_polynomial(coefficients: Vec4, lambda: f32) -> f32 {
+    let t = (lambda - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN);
+    let x = (coefficients.x * t + coefficients.y) * t + coefficients.z;
+    0.5 + 0.5 * x / (1.0 + x * x).sqrt()
+}
+
+// the spectrum of an rgb color at four wavelengths; colors brighter than 1, such as lights,
+// are scaled down to fit the table and back up, as pbrt does
+pub fn rgb_to_spectrum(rgb: Vec3, wavelengths: Vec4) -> Vec4 {
+    let rgb = rgb.max(Vec3::ZERO);
+    let scale = rgb.max_element();
+    if scale <= 0.0 {
+        return Vec4::ZERO;
+    }
+    let scale = if scale > 1.0 { 2.0 * scale } else { 1.0 };
+    let coefficients = RGBToSpectrumTable::get().lookup(rgb / scale);
+    scale * wavelengths.map(|lambda| sigmoid_polynomial(coefficients, lambda))
+}
+
+// sigmoid polynomial coefficients fitted to rgb colors: for each channel that can be the
+// largest, a cube indexed by the two other channels over it and by the largest channel on
+// a grid that is denser toward 0 and 1
+pub struct RGBToSpectrumTable {
+    coefficients: Vec<Vec4>,
+}
+
+impl RGBToSpectrumTable {
+    // the table is fitted the first time it is needed
+    pub fn get() -> &'static Self {
+        static TABLE: OnceLock<RGBToSpectrumTable> = OnceLock::new();
+        TABLE.get_or_init(Self::fit)
+    }
+
+    pub fn coefficients(&self) -> &[Vec4] {
+        &self.coefficients
+    }
+
+    pub fn lookup(&self, rgb: Vec3) -> Vec4 {
+        let rgb = rgb.clamp(Vec3::ZERO, Vec3::ONE);
+        let largest = if rgb.x >= rgb.y && rgb.x >= rgb.z { 0 } else if rgb.y >= rgb.z { 1 } else { 2 };
+        let z = rgb[largest];
+        if z <= 0.0 {
+            return Vec4::new(0.0, 0.0, -1e4, 0.0);
+        }
+        let res = RGB_TO_SPECTRUM_RESOLUTION;
+        let cell = |v: f32| {
+            let v = v.clamp(0.0, 1.0) * (res - 1) as f32;
+            let i = (v as usize).min(res - 2);
+            (i, v - i as f32)
+        };
+        let (xi, fx) = cell(rgb[(largest + 1) % 3] / z);
+        let (yi, fy) = cell(rgb[(largest + 2) % 3] / z);
+        let (zi, fz) = cell(inverse_z_scale(z));
+        let entry = |x: usize, y: usize, z: usize| self.coefficients[((largest * res + z) * res + y) * res + x];
+        let lerp_x = |y: usize, z: usize| entry(xi, y, z).lerp(entry(xi + 1, y, z), fx);
+        let lerp_y = |z: usize| lerp_x(yi, z).lerp(lerp_x(yi + 1, z), fy);
+        lerp_y(zi).lerp(lerp_y(zi + 1), fz)
+    }
+
+    fn fit() -> Self {
+        // every entry starts from the solutions of the neighbors already fitted, walking out
+        // from the grays and from a largest channel of 0.5, and keeps the closest fit
+        let res = RGB_TO_SPECTRUM_RESOLUTION;
+        let mut coefficients = vec![Vec4::ZERO; 3 * res * res * res];
+        let index = |largest: usize, x: usize, y: usize, z: usize| ((largest * res + z) * res + y) * res + x;
+        let middle = (0..res).find(|&z| z_scale(z as f32 / (res - 1) as f32) >= 0.5).unwrap_or(0);
+        let order: Vec<usize> = (middle..res).chain((0..middle).rev()).collect();
+        let colors = integration_colors();
+        for largest in 0..3 {
+            for &z in &order {
+                let scale = z_scale(z as f32 / (res - 1) as f32);
+                for y in (0..res).rev() {
+                    for x in (0..res).rev() {
+                        let mut guesses = vec![Vec3::ZERO];
+                        if x + 1 < res {
+                            guesses.push(coefficients[index(largest, x + 1, y, z)].truncate());
+                        }
+                        if y + 1 < res {
+                            guesses.push(coefficients[index(largest, x, y + 1, z)].truncate());
+                        }
+                        if z != middle {
+                            let toward_middle = if z > middle { z - 1 } else { z + 1 };
+                            guesses.push(coefficients[index(largest, x, y, toward_middle)].truncate());
+                        }
+                        let mut target = Vec3::ZERO;
+                        target[largest] = scale;
+                        target[(largest + 1) % 3] = x as f32 / (res - 1) as f32 * scale;
+                        target[(largest + 2) % 3] = y as f32 / (res - 1) as f32 * scale;
+                        let error = |c: Vec3| (spectrum_rgb(c, &colors).0 - target).length();
+                        let best = guesses.into_iter()
+                            .map(|guess| fit_coefficients(target, guess, &colors))
+                            .min_by(|a, b| error(*a).total_cmp(&error(*b)))
+                            .unwrap_or(Vec3::ZERO);
+                        coefficients[index(largest, x, y, z)] = best.extend(0.0);
+                    }
+                }
+            }
+        }
+        Self { coefficients }
+    }
+}
+
+// the grid of the largest channel, smoothstep applied twice
+fn z_scale(t: f32) -> f32 {
+    let smoothstep = |x: f32| x * x * (3.0 - 2.0 * x);
+    smoothstep(smoothstep(t))
+}
+
+// where z falls on [0, 1] before z_scale
+pub fn inverse_z_scale(z: f32) -> f32 {
+    let inverse_smoothstep = |y: f32| 0.5 - ((1.0 - 2.0 * y.clamp(0.0, 1.0)).asin() / 3.0).sin();
+    inverse_smoothstep(inverse_smoothstep(z))
+}
+
+// coefficients whose spectrum has the rgb color target, from a first guess, with
+// Levenberg-Marquardt; colors that no spectrum between 0 and 1 reaches, such as saturated
+// ones that are almost 1, get the closest that does
+fn fit_coefficients(target: Vec3, mut c: Vec3, colors: &[Vec3]) -> Vec3 {
+    let (mut rgb, mut jacobian) = spectrum_rgb(c, colors);
+    let mut damping = 1e-3;
+    for _i in 0..100 {
+        let residual = rgb - target;
+        if residual.length() < 1e-5 {
+            break;
+        }
+        let normal = jacobian.transpose() * jacobian;
+        let diagonal = Vec3::new(normal.x_axis.x, normal.y_axis.y, normal.z_axis.z);
+        let step = (normal + Mat3::from_diagonal(damping * diagonal + Vec3::splat(1e-12))).inverse()
+            * (jacobian.transpose() * residual);
+        let candidate = c - step;
+        let (candidate_rgb, candidate_jacobian) = spectrum_rgb(candidate, colors);
+        if (candidate_rgb - target).length() < residual.length() {
+            c = candidate;
+            rgb = candidate_rgb;
+            jacobian = candidate_jacobian;
+            damping *= 0.3;
+        } else {
+            damping *= 10.0;
+            if damping > 1e8 {
+                break;
+            }
+        }
+    }
+    c
+}
+
+// the rgb that each 5nm step of the visible range adds to the color of a spectrum
+fn integration_colors() -> Vec<Vec3> {
+    let steps = 94;
+    let step = (LAMBDA_MAX - LAMBDA_MIN) / steps as f32;
+    (0..steps)
+        .map(|i| xyz_to_rgb(cie_xyz(LAMBDA_MIN + (i as f32 + 0.5) * step) * step / CIE_Y_INTEGRAL))
+        .collect()
+}
+
+// the rgb of the spectrum of the coefficients and its derivatives
+fn spectrum_rgb(c: Vec3, colors: &[Vec3]) -> (Vec3, Mat3) {
+    let mut rgb = Vec3::ZERO;
+    let mut jacobian = Mat3::ZERO;
+    for (i, &color) in colors.iter().enumerate() {
+        let t = (i as f32 + 0.5) / colors.len() as f32;
+        let x = (c.x * t + c.y) * t + c.z;
+        let sqrt = (1.0 + x * x).sqrt();
+        let slope = 0.5 / (sqrt * sqrt * sqrt);
+        rgb += (0.5 + 0.5 * x / sqrt) * color;
+        jacobian += Mat3::from_cols(color * slope * t * t, color * slope * t, color * slope);
+    }
+    (rgb, jacobian)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgb_of(rgb: Vec3) -> Vec3 {
+        // integrates the upsampled spectrum with the estimator of the path tracers
+        let mut sum = Vec3::ZERO;
+        let samples = 2000;
+        for i in 0..samples {
+            let wavelengths = sample_wavelengths((i as f32 + 0.5) / samples as f32);
+            sum += spectrum_to_rgb(rgb_to_spectrum(rgb, wavelengths), wavelengths);
+        }
+        sum / samples as f32
+    }
+
+    #[test]
+    fn constants_match_the_matching_functions() {
+        let mut xyz = Vec3::ZERO;
+        for i in 0..4700 {
+            xyz += cie_xyz(LAMBDA_MIN + (i as f32 + 0.5) * 0.1) * 0.1;
+        }
+        assert!((xyz.y - CIE_Y_INTEGRAL).abs() < 1e-2, "y integrates to {}", xyz.y);
+        let white = xyz_to_rgb(xyz / CIE_Y_INTEGRAL);
+        assert!((white - Vec3::ONE).abs().max_element() < 1e-3, "a flat spectrum is {}", white);
+    }
+
+    #[test]
+    fn upsampled_colors_keep_their_rgb() {
+        let colors = [
+            Vec3::ONE, Vec3::splat(0.18), Vec3::new(0.8, 0.1, 0.1), Vec3::new(0.1, 0.6, 0.2),
+            Vec3::new(0.05, 0.2, 0.7), Vec3::new(0.95, 0.64, 0.54), Vec3::new(0.5, 0.5, 0.05),
+            Vec3::Y, Vec3::new(0.8, 1.0, 0.3), Vec3::new(3.0, 2.0, 1.0), Vec3::new(20.0, 18.0, 15.0),
+        ];
+        for rgb in colors {
+            let roundtrip = rgb_of(rgb);
+            assert!((roundtrip - rgb).abs().max_element() < 0.02 * rgb.max_element(),
+                    "{} comes back as {}", rgb, roundtrip);
+        }
+    }
+}
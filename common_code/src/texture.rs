@@ -0,0 +1,317 @@
+use glam::{Vec2, Vec3, Vec4};
+use std::path::Path;
+
+// texture_type will be indexed as follows:
+// 0 solid; 1 checker; 2 noise; 3 turbulence; 4 marble; 5 image
+// the shaders and Texture::value share the same definitions, so the CPU and GPU tracers agree
+
+// a material references a texture through its index in Scene::textures
+pub const NO_TEXTURE: u32 = u32::MAX;
+
+// how image lookups outside of [0, 1] are brought back in
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WrapMode {
+    Repeat = 0,
+    Clamp = 1,
+    Mirror = 2,
+}
+
+pub enum Texture {
+    Solid(Vec3),
+    // a 3D checkerboard of cubes of side scale, which follows the hit point rather than the uvs
+    Checker { scale: f32, even: Vec3, odd: Vec3 },
+    // Perlin noise of the hit point times scale, remapped to [0, 1]
+    Noise { scale: f32, color: Vec3 },
+    // depth octaves of the absolute value of the noise, each at twice the frequency and half the weight
+    Turbulence { scale: f32, color: Vec3, depth: u32 },
+    // the marble of "The Next Week": stripes along z, bent by seven octaves of turbulence
+    Marble { scale: f32, color: Vec3 },
+    Image(ImageTexture),
+}
+
+impl Texture {
+    // the color at the uv coordinates and hit point p; the shaders follow the same steps
+    pub fn value(&self, uv: Vec2, p: Vec3) -> Vec3 {
+        match self {
+            Texture::Solid(color) => *color,
+            Texture::Checker { scale, even, odd } => {
+                let cell = (p / *scale).floor();
+                if (cell.x + cell.y + cell.z).rem_euclid(2.0) < 1.0 { *even } else { *odd }
+            }
+            Texture::Noise { scale, color } => *color * 0.5 * (1.0 + perlin(*scale * p)),
+            Texture::Turbulence { scale, color, depth } => *color * turbulence(*scale * p, *depth),
+            Texture::Marble { scale, color } => {
+                *color * 0.5 * (1.0 + (*scale * p.z + 10.0 * turbulence(p, 7)).sin())
+            }
+            Texture::Image(image) => image.value(uv),
+        }
+    }
+}
+
+// an image stored in linear color, looked up bilinearly; v = 0 is the bottom row
+pub struct ImageTexture {
+    width: u32,
+    height: u32,
+    pixels: Vec<Vec4>,
+    wrap: WrapMode,
+}
+
+impl ImageTexture {
+    pub fn new(width: u32, height: u32, pixels: Vec<Vec4>, wrap: WrapMode) -> Self {
+        assert_eq!(pixels.len(), (width * height) as usize, "pixel count does not match the image size");
+        Self { width, height, pixels, wrap }
+    }
+
+    // reads a PNG or JPEG image, whose sRGB values are taken to linear color
+    pub fn load<P: AsRef<Path>>(path: P, wrap: WrapMode) -> Result<Self, image::ImageError> {
+        let image = image::open(path)?.into_rgb32f();
+        let (width, height) = image.dimensions();
+        let pixels = image.pixels()
+            .map(|p| Vec4::new(srgb_to_linear(p[0]), srgb_to_linear(p[1]), srgb_to_linear(p[2]), 1.0))
+            .collect();
+        Ok(Self { width, height, pixels, wrap })
+    }
+
+    // reads an image that holds data rather than color, such as a normal or bump map,
+    // keeping its values as they are stored
+    pub fn load_linear<P: AsRef<Path>>(path: P, wrap: WrapMode) -> Result<Self, image::ImageError> {
+        let image = image::open(path)?.into_rgb32f();
+        let (width, height) = image.dimensions();
+        let pixels = image.pixels().map(|p| Vec4::new(p[0], p[1], p[2], 1.0)).collect();
+        Ok(Self { width, height, pixels, wrap })
+    }
+
+    // reads the alpha channel of an image into a gray mask, for Material::with_alpha_texture
+    pub fn load_alpha<P: AsRef<Path>>(path: P, wrap: WrapMode) -> Result<Self, image::ImageError> {
+        let image = image::open(path)?.into_rgba32f();
+        let (width, height) = image.dimensions();
+        let pixels = image.pixels().map(|p| Vec4::new(p[3], p[3], p[3], 1.0)).collect();
+        Ok(Self { width, height, pixels, wrap })
+    }
+
+    pub fn value(&self, uv: Vec2) -> Vec3 {
+        // texel centers sit at half integers; row 0 is the top of the image
+        let x = uv.x * self.width as f32 - 0.5;
+        let y = (1.0 - uv.y) * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let texel = |x: f32, y: f32| {
+            let column = wrap_texel(x as i32, self.width, self.wrap);
+            let row = wrap_texel(y as i32, self.height, self.wrap);
+            self.pixels[(row * self.width + column) as usize].truncate()
+        };
+        let top = texel(x0, y0).lerp(texel(x0 + 1.0, y0), tx);
+        let bottom = texel(x0, y0 + 1.0).lerp(texel(x0 + 1.0, y0 + 1.0), tx);
+        top.lerp(bottom, ty)
+    }
+
+    pub fn width(&self) -> u32 { self.width }
+    pub fn height(&self) -> u32 { self.height }
+    pub fn pixels(&self) -> &[Vec4] { &self.pixels }
+    pub fn wrap(&self) -> WrapMode { self.wrap }
+}
+
+// brings a texel index that may fall outside of the image back into [0, size)
+fn wrap_texel(i: i32, size: u32, wrap: WrapMode) -> u32 {
+    let size = size as i32;
+    let i = match wrap {
+        WrapMode::Repeat => i.rem_euclid(size),
+        WrapMode::Clamp => i.clamp(0, size - 1),
+        WrapMode::Mirror => {
+            let period = i.rem_euclid(2 * size);
+            if period < size { period } else { 2 * size - 1 - period }
+        }
+    };
+    i as u32
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+// Perlin gradient noise in [-1, 1]; the gradient of each lattice point is hashed from its
+// coordinates rather than read from a permutation table, so the GPU needs no extra buffer
+pub fn perlin(p: Vec3) -> f32 {
+    let cell = p.floor();
+    let f = p - cell;
+    // Hermite smoothing of the weights
+    let w = f * f * (Vec3::splat(3.0) - 2.0 * f);
+    let (i, j, k) = (cell.x as i32, cell.y as i32, cell.z as i32);
+    let mut accum = 0.0;
+    for dk in 0..2 {
+        for dj in 0..2 {
+            for di in 0..2 {
+                let corner = Vec3::new(di as f32, dj as f32, dk as f32);
+                let gradient = lattice_gradient(i + di, j + dj, k + dk);
+                let weight = (corner * w + (Vec3::ONE - corner) * (Vec3::ONE - w)).element_product();
+                accum += weight * gradient.dot(f - corner);
+            }
+        }
+    }
+    accum
+}
+
+pub fn turbulence(p: Vec3, depth: u32) -> f32 {
+    let mut accum = 0.0;
+    let mut point = p;
+    let mut weight = 1.0;
+    for _ in 0..depth {
+        accum += weight * perlin(point);
+        weight *= 0.5;
+        point *= 2.0;
+    }
+    accum.abs()
+}
+
+// one of the twelve edge directions of the cube of the improved Perlin noise
+fn lattice_gradient(i: i32, j: i32, k: i32) -> Vec3 {
+    let hash = jenkins_hash(i as u32 ^ jenkins_hash(j as u32 ^ jenkins_hash(k as u32)));
+    match hash % 12 {
+        0 => Vec3::new(1.0, 1.0, 0.0),
+        1 => Vec3::new(-1.0, 1.0, 0.0),
+        2 => Vec3::new(1.0, -1.0, 0.0),
+        3 => Vec3::new(-1.0, -1.0, 0.0),
+        4 => Vec3::new(1.0, 0.0, 1.0),
+        5 => Vec3::new(-1.0, 0.0, 1.0),
+        6 => Vec3::new(1.0, 0.0, -1.0),
+        7 => Vec3::new(-1.0, 0.0, -1.0),
+        8 => Vec3::new(0.0, 1.0, 1.0),
+        9 => Vec3::new(0.0, -1.0, 1.0),
+        10 => Vec3::new(0.0, 1.0, -1.0),
+        _ => Vec3::new(0.0, -1.0, -1.0),
+    }
+}
+
+// the same hash as the shaders' jenkinsHash
+fn jenkins_hash(input: u32) -> u32 {
+    let mut x = input;
+    x = x.wrapping_add(x.wrapping_shl(10));
+    x ^= x >> 6;
+    x = x.wrapping_add(x.wrapping_shl(3));
+    x ^= x >> 11;
+    x = x.wrapping_add(x.wrapping_shl(15));
+    x
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct GPUTexture {
+    texture_type: u32,
+    // image size, and where its texels start in the texel buffer
+    width: u32,
+    height: u32,
+    texel_offset: u32,
+    // the solid or noise color, or the even checker color
+    color: Vec4,
+    // the odd checker color
+    odd_color: Vec4,
+    scale: f32,
+    depth: u32,
+    wrap: u32,
+    _buffer: u32,
+}
+
+unsafe impl bytemuck::Pod for GPUTexture {}
+unsafe impl bytemuck::Zeroable for GPUTexture {}
+
+impl GPUTexture {
+    // texel_offset is only used by image textures
+    pub fn from_texture(texture: &Texture, texel_offset: u32) -> Self {
+        let mut gpu_texture = Self {
+            texture_type: 0,
+            width: 0,
+            height: 0,
+            texel_offset: 0,
+            color: Vec4::ONE,
+            odd_color: Vec4::ZERO,
+            scale: 1.0,
+            depth: 0,
+            wrap: 0,
+            _buffer: 0,
+        };
+        match texture {
+            Texture::Solid(color) => gpu_texture.color = color.extend(1.0),
+            Texture::Checker { scale, even, odd } => {
+                gpu_texture.texture_type = 1;
+                gpu_texture.scale = *scale;
+                gpu_texture.color = even.extend(1.0);
+                gpu_texture.odd_color = odd.extend(1.0);
+            }
+            Texture::Noise { scale, color } => {
+                gpu_texture.texture_type = 2;
+                gpu_texture.scale = *scale;
+                gpu_texture.color = color.extend(1.0);
+            }
+            Texture::Turbulence { scale, color, depth } => {
+                gpu_texture.texture_type = 3;
+                gpu_texture.scale = *scale;
+                gpu_texture.color = color.extend(1.0);
+                gpu_texture.depth = *depth;
+            }
+            Texture::Marble { scale, color } => {
+                gpu_texture.texture_type = 4;
+                gpu_texture.scale = *scale;
+                gpu_texture.color = color.extend(1.0);
+            }
+            Texture::Image(image) => {
+                gpu_texture.texture_type = 5;
+                gpu_texture.width = image.width();
+                gpu_texture.height = image.height();
+                gpu_texture.texel_offset = texel_offset;
+                gpu_texture.wrap = image.wrap() as u32;
+            }
+        }
+        gpu_texture
+    }
+
+    pub fn texture_type(&self) -> u32 { self.texture_type }
+    pub fn size(&self) -> (u32, u32) { (self.width, self.height) }
+    pub fn texel_offset(&self) -> u32 { self.texel_offset }
+    pub fn color(&self) -> Vec4 { self.color }
+    pub fn odd_color(&self) -> Vec4 { self.odd_color }
+    pub fn scale(&self) -> f32 { self.scale }
+    pub fn depth(&self) -> u32 { self.depth }
+    pub fn wrap(&self) -> u32 { self.wrap }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_lookups_wrap_and_filter() {
+        // a 2x1 image, black on the left and white on the right
+        let pixels = vec![Vec4::new(0.0, 0.0, 0.0, 1.0), Vec4::ONE];
+        let repeat = ImageTexture::new(2, 1, pixels.clone(), WrapMode::Repeat);
+        let clamp = ImageTexture::new(2, 1, pixels.clone(), WrapMode::Clamp);
+        let mirror = ImageTexture::new(2, 1, pixels, WrapMode::Mirror);
+
+        // texel centers return the texel, and halfway between them is the average
+        assert_eq!(repeat.value(Vec2::new(0.25, 0.5)), Vec3::ZERO);
+        assert_eq!(repeat.value(Vec2::new(0.5, 0.5)), Vec3::splat(0.5));
+        // at the right edge, repeat blends with the left texel, clamp and mirror do not
+        assert_eq!(repeat.value(Vec2::new(1.0, 0.5)), Vec3::splat(0.5));
+        assert_eq!(clamp.value(Vec2::new(1.0, 0.5)), Vec3::ONE);
+        assert_eq!(mirror.value(Vec2::new(1.0, 0.5)), Vec3::ONE);
+        // past the edge, repeat starts over while mirror walks back
+        assert_eq!(repeat.value(Vec2::new(1.25, 0.5)), Vec3::ZERO);
+        assert_eq!(mirror.value(Vec2::new(1.25, 0.5)), Vec3::ONE);
+        assert_eq!(clamp.value(Vec2::new(-3.0, 0.5)), Vec3::ZERO);
+    }
+
+    #[test]
+    fn noise_is_smooth_and_zero_on_the_lattice() {
+        for &p in &[Vec3::ZERO, Vec3::new(3.0, -2.0, 7.0), Vec3::new(-5.0, 1.0, 0.0)] {
+            assert_eq!(perlin(p), 0.0);
+        }
+        let mut max: f32 = 0.0;
+        for i in 0..1000 {
+            let p = Vec3::new(i as f32 * 0.0137, i as f32 * 0.0071 - 2.0, i as f32 * 0.0193 + 1.0);
+            let value = perlin(p);
+            assert!(value.abs() <= 1.1);
+            assert!((perlin(p + Vec3::splat(1e-3)) - value).abs() < 0.02);
+            max = max.max(value.abs());
+        }
+        assert!(max > 0.3);
+    }
+}
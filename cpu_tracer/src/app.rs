@@ -1,4 +1,5 @@
 use crate::gui::GUI;
+use crate::material_preview::{MaterialPreviews, PREVIEW_SIZE};
 use crate::path_tracer::PathTracer;
 use common_code::camera_controller::CameraController;
 use common_code::gpu_structs::{GPUSamplingParameters};
@@ -21,6 +22,7 @@ pub struct App<'a> {
     gui: Option<GUI>,
     cursor_position: winit::dpi::PhysicalPosition<f64>,
     scene: Scene,
+    material_previews: MaterialPreviews,
     render_parameters: RenderParameters,
     last_render_time: Instant,
     frames_per_second: FramesPerSecond,
@@ -28,6 +30,7 @@ pub struct App<'a> {
 
 impl<'a> App<'a> {
     pub fn new(scene: Scene, render_parameters: RenderParameters) -> Self {
+        let material_previews = MaterialPreviews::new(&scene);
         Self {
             window: None,
             wgpu_state: None,
@@ -35,6 +38,7 @@ impl<'a> App<'a> {
             gui: None,
             cursor_position: Default::default(),
             scene,
+            material_previews,
             render_parameters,
             last_render_time: Instant::now(),
             frames_per_second: FramesPerSecond::new()
@@ -127,6 +131,12 @@ impl ApplicationHandler for App<'_> {
                     self.frames_per_second.update(dt);
                     let avg_fps= self.frames_per_second.get_avg_fps();
 
+                    // upload the material previews their thread has finished since the last frame
+                    for (idx, thumbnail) in self.material_previews.finished() {
+                        let label = format!("{}: {}", idx, self.scene.materials[idx].name());
+                        gui.add_material_preview(&state.device, &state.queue, label, &thumbnail, PREVIEW_SIZE);
+                    }
+
                     gui.display_ui(window.as_ref(), path_tracer.progress(), & mut rp, avg_fps, 0.0, dt);
                     path_tracer.update_render_parameters(rp);
                     path_tracer.update_buffers(&state.queue);
@@ -1,15 +1,31 @@
-use crate::bvh::BVHNode;
+use crate::bvh::{BVHNode, TRAVERSAL_STACK_SIZE};
 use crate::gpu_buffer::GPUBuffer;
-use crate::gpu_structs::{GPUSamplingParameters};
-use crate::material::Material;
+use crate::gpu_structs::{GPUSamplingParameters, GPUSceneParameters};
+use crate::light::GPULight;
+use crate::material::{Material, NormalMap};
+use crate::medium::Medium;
+use crate::sky::SkyParameters;
 use crate::sphere::Sphere;
+use crate::spectrum::{rgb_to_spectrum, sample_wavelengths, spectrum_to_rgb};
+use crate::texture::{perlin, turbulence, GPUTexture};
 use common_code::gpu_structs::GPUFrameBuffer;
 use common_code::camera_controller::{GPUCamera};
+use common_code::measured::MeasuredBRDF;
 use glam::{Mat4, UVec2, UVec3, Vec2, Vec3, Vec3Swizzles, Vec4, Vec4Swizzles};
 use rayon::iter::{ParallelIterator, IntoParallelIterator, IntoParallelRefIterator};
+use std::sync::Arc;
 use wgpu::Queue;
 
 const EPSILON: f32 = 0.001;
+// world space step of the finite differences of bump maps
+const BUMP_DELTA: f32 = 0.001;
+// longest random walk through a subsurface medium before the path is taken as absorbed
+const MAX_WALK_STEPS: u32 = 256;
+// most dielectrics a path can be inside of at once
+const MAX_NESTING: usize = 4;
+// most surfaces a path can pass through without them changing its medium; these crossings
+// are not bounces, but a bound keeps a ray from passing through them forever
+const MAX_NULL_CROSSINGS: u32 = 64;
 
 const PI: f32 = 3.1415927;
 const FRAC_1_PI: f32 = 0.31830987;
@@ -19,22 +35,32 @@ const USE_BVH: bool = true;
 pub struct ComputeShader {
     spheres: Vec<Sphere>,
     materials: Vec<Material>,
+    measured: Vec<Arc<MeasuredBRDF>>,
     bvh_tree: Vec<BVHNode>,
+    scene_parameters: GPUSceneParameters,
+    environment_map: Vec<Vec4>,
+    environment_distribution: Vec<f32>,
+    lights: Vec<GPULight>,
+    photometric_profiles: Vec<f32>,
+    textures: Vec<GPUTexture>,
+    texels: Vec<Vec4>,
     camera_data: GPUCamera,
     sampling_parameters: GPUSamplingParameters,
     inv_proj_matrix: [[f32;4];4],
     view_matrix: [[f32;4];4],
     frame_buffer: [u32;4],
     pixels: Vec<[f32;3]>,
-    rngState: GPURNG,
 }
 
 #[derive(Copy, Clone, Default)]
 struct HitPayload {
     t: f32,
     p: Vec3,
+    // the shading normal, which starts out as the geometric normal ng until a normal map tilts it
     n: Vec3,
     idx: u32,
+    uv: Vec2,
+    ng: Vec3,
 }
 
 // Frame buffer
@@ -46,10 +72,51 @@ struct Ray {
     direction: Vec3,
 }
 
+// TraceRay has no random numbers to draw from, so the alpha test hashes the ray and the
+// intersection into a threshold in [0, 1), as pbrt does
+fn alphaThreshold(ray: Ray, sphereIdx: u32, t: f32) -> f32 {
+    let mut hash = GPURNG::jenkinsHash(sphereIdx ^ t.to_bits());
+    for x in ray.origin.to_array().into_iter().chain(ray.direction.to_array()) {
+        hash = GPURNG::jenkinsHash(hash ^ x.to_bits());
+    }
+    (hash >> 8) as f32 / 16777216.0
+}
+
+// the dielectrics a path is inside of, in the order it entered them
+#[derive(Clone, Copy, Default)]
+struct MediumStack {
+    materials: [u32; MAX_NESTING],
+    count: usize,
+}
+
+impl MediumStack {
+    fn push(&mut self, mat_idx: u32) {
+        if self.count < MAX_NESTING {
+            self.materials[self.count] = mat_idx;
+            self.count += 1;
+        }
+    }
+
+    fn remove(&mut self, mat_idx: u32) {
+        if let Some(i) = (0..self.count).rev().find(|&i| self.materials[i] == mat_idx) {
+            self.materials.copy_within(i + 1..self.count, i);
+            self.count -= 1;
+        }
+    }
+}
+
 impl ComputeShader {
     pub fn new(spheres: Vec<Sphere>,
                materials: Vec<Material>,
+               measured: Vec<Arc<MeasuredBRDF>>,
                bvh_tree: Vec<BVHNode>,
+               scene_parameters: GPUSceneParameters,
+               environment_map: Vec<Vec4>,
+               environment_distribution: Vec<f32>,
+               lights: Vec<GPULight>,
+               photometric_profiles: Vec<f32>,
+               textures: Vec<GPUTexture>,
+               texels: Vec<Vec4>,
                camera_data: GPUCamera,
                inv_proj_matrix: [[f32;4];4],
                view_matrix: [[f32;4];4],
@@ -61,14 +128,21 @@ impl ComputeShader {
         Self { 
             spheres,
             materials,
+            measured,
             bvh_tree,
+            scene_parameters,
+            environment_map,
+            environment_distribution,
+            lights,
+            photometric_profiles,
+            textures,
+            texels,
             camera_data,
             sampling_parameters,
             inv_proj_matrix,
             view_matrix,
             frame_buffer: frame_buffer.into_array(),
             pixels,
-            rngState: GPURNG::default(),
         }
     }
 
@@ -88,6 +162,14 @@ impl ComputeShader {
         self.sampling_parameters = sampling_parameters
     }
 
+    // the sun is the last light whenever the scene has a sky
+    pub fn queue_sky(&mut self, sky: &SkyParameters) {
+        self.scene_parameters.set_sky(sky);
+        if let Some(sun) = self.lights.last_mut() {
+            *sun = GPULight::from_light(&sky.sun(), 0);
+        }
+    }
+
     pub fn queue_frame(&mut self, frame: GPUFrameBuffer) {
         self.frame_buffer = frame.into_array();
     }
@@ -118,45 +200,34 @@ impl ComputeShader {
         image_buffer.queue_for_gpu(queue, bytemuck::cast_slice(self.pixels.as_slice()));
     }
 
+    // the sum of the samples of the queued frame over an image of the given size, for
+    // renders that are not shown in the window, such as the material previews
+    pub fn render_frame(&self, size: (u32, u32)) -> Vec<[f32;3]> {
+        let image_size = (size.0 as usize, size.1 as usize);
+        let mut image = vec![[0f32;3]; image_size.0 * image_size.1];
+
+        let bands: Vec<(usize, &mut [[f32;3]])> = image.chunks_mut(image_size.0).enumerate().collect();
+        bands.into_par_iter().for_each(|(i, row)| {
+            let screen_pos = UVec2::new(0u32, i as u32);
+            let mut rngState = GPURNG::initRng(screen_pos, image_size, self.frame_buffer[2]);
+            self.main_cs_parallel(row, i, &mut rngState);
+        });
+        image
+    }
+
     pub fn main_cs_parallel(&self, pixel_row: &mut [[f32;3]], row: usize, rngState: &mut GPURNG) {
         for x in 0..pixel_row.iter().len() {
             let mut pixel_color = Vec3::from_array(pixel_row[x]);
 
             for _i in 0..self.sampling_parameters.spf() {
-                let ray = self.getRay_parallel(x as u32, row as u32, rngState);
-                pixel_color += self.rayColor_parallel(ray, rngState);
+                let ray = self.getRay(x as u32, row as u32, rngState);
+                pixel_color += self.rayColor(ray, rngState);
             }
 
             pixel_row[x] = pixel_color.to_array();
         }
     }
 
-    fn rayColor_parallel(&self, primaryRay: Ray, rngState: &mut GPURNG) -> Vec3 {
-        // for every ray, we want to trace the ray through num_bounces
-        // rayColor calls traceRay to get a hit, then calls it again
-        // with new bounce ray
-        let mut nextRay = primaryRay.clone();
-        let mut throughput = Vec3::ONE;
-        let mut pixel_color = Vec3::ZERO;
-        for _i in 0 .. self.sampling_parameters.num_bounces() {
-            let mut payLoad = HitPayload::default();
-
-            if self.TraceRay(nextRay, &mut payLoad) {
-                // depending on what kind of material, I need to find the scatter ray and the attenuation
-                let mat_idx:u32 = self.spheres[payLoad.idx as usize].material_idx();
-                nextRay = self.getScatterRay_parallel(nextRay, mat_idx, payLoad, rngState);
-
-                throughput *= self.materials[mat_idx as usize].albedo().xyz();
-            } else {
-                let a: f32 = 0.5 * (primaryRay.direction.y + 1.0);
-                pixel_color = throughput * ((1.0 - a) * Vec3::ONE + a * Vec3::new(0.5, 0.7, 1.0));
-                break;
-            }
-        }
-
-        return pixel_color;
-    }
-
     pub fn run_render(&mut self, queue: &Queue, size: (u32, u32), image_buffer: &mut GPUBuffer) {
         for y in 0..size.1 {
             for x in 0..size.0 {
@@ -172,7 +243,7 @@ impl ComputeShader {
 
         let image_size = (self.frame_buffer[0] as usize, self.frame_buffer[1] as usize);
         let screen_pos = id.xy();
-        self.rngState = GPURNG::initRng(screen_pos, image_size, self.frame_buffer[2]);
+        let mut rngState = GPURNG::initRng(screen_pos, image_size, self.frame_buffer[2]);
 
         // if the accumulator = 0, zero out the image buffer
         if self.sampling_parameters.clear_image() == 1 {
@@ -181,37 +252,528 @@ impl ComputeShader {
         let mut pixel_color = Vec3::from_array(self.pixels[idx]);
 
         for _i in 0..self.sampling_parameters.spf() {
-            let ray = self.getRay(id.x, id.y);
-            pixel_color += self.rayColor(ray);
+            let ray = self.getRay(id.x, id.y, &mut rngState);
+            pixel_color += self.rayColor(ray, &mut rngState);
         }
 
         self.pixels[idx] = pixel_color.to_array();
     }
 
-    fn rayColor(&mut self, primaryRay: Ray) -> Vec3 {
+    // the path loop of both the serial and the parallel renders, which each keep the
+    // random state of the pixel or row they work on
+    fn rayColor(&self, primaryRay: Ray, rngState: &mut GPURNG) -> Vec3 {
         // for every ray, we want to trace the ray through num_bounces
         // rayColor calls traceRay to get a hit, then calls it again
         // with new bounce ray
         let mut nextRay = primaryRay.clone();
-        let mut throughput = Vec3::ONE;
-        let mut pixel_color = Vec3::ZERO;
-        for _i in 0 .. self.sampling_parameters.num_bounces() {
+        // in spectral mode the path carries four wavelengths, the hero first; in rgb mode the
+        // first three entries are the channels
+        let mut wavelengths = Vec4::ZERO;
+        if self.isSpectral() {
+            wavelengths = sample_wavelengths(rngState.rngNextFloat());
+        }
+        let mut throughput = Vec4::ONE;
+        let mut pixel_color = Vec4::ZERO;
+        // set once dispersive glass has split the wavelengths, leaving only the hero
+        let mut heroOnly = false;
+        // the dielectrics the path is inside of
+        let mut media = MediumStack::default();
+        // pdf of the last bounce direction and where it started; the pdf is 0 for camera rays
+        // and specular bounces, which light sampling could never have produced
+        let mut bouncePdf: f32 = 0.0;
+        let mut bounceOrigin = primaryRay.origin;
+        // the medium the ray travels through, when it is inside a subsurface material
+        let mut medium: Option<Medium> = None;
+        let mut i = 0;
+        let mut nullCrossings = 0;
+        while i < self.sampling_parameters.num_bounces() {
+            if let Some(inside) = medium {
+                let walk = self.randomWalk(nextRay, inside, rngState);
+                match walk {
+                    Some((walkRay, weight)) => {
+                        nextRay = walkRay;
+                        throughput *= self.toPathColor(weight, wavelengths);
+                    }
+                    None => break,
+                }
+            }
             let mut payLoad = HitPayload::default();
 
             if self.TraceRay(nextRay, &mut payLoad) {
                 // depending on what kind of material, I need to find the scatter ray and the attenuation
                 let mat_idx:u32 = self.spheres[payLoad.idx as usize].material_idx();
-                nextRay = self.getScatterRay(nextRay, mat_idx, payLoad);
-
-                throughput *= self.materials[mat_idx as usize].albedo().xyz();
+                let mut material = self.materials[mat_idx as usize];
+                // emissive spheres add their radiance and end the path; a bounce that hits one
+                // shares it with the light sample taken at the previous vertex
+                if let Some(emitted) = material.bsdf(&self.measured).emitted(self.materialAlbedo(material, payLoad)) {
+                    let mut weight: f32 = 1.0;
+                    if bouncePdf > 0.0 {
+                        weight = self.powerHeuristic(bouncePdf, self.sphereLightPdf(bounceOrigin, payLoad.idx));
+                    }
+                    pixel_color += throughput * self.toPathColor(weight * emitted, wavelengths);
+                    break;
+                }
+                // dispersive glass bends each wavelength its own way, so the path follows the
+                // hero alone from here, which carries the share of the others
+                if self.isSpectral() && material.is_dispersive() {
+                    material = material.at_wavelength(wavelengths.x);
+                    if !heroOnly {
+                        throughput = Vec4::new(4.0 * throughput.x, 0.0, 0.0, 0.0);
+                        heroOnly = true;
+                    }
+                }
+                // the surface of a dielectric is only there when crossing it changes the
+                // medium, which is the one with the highest priority the path is inside of
+                let mut crossed = media;
+                if material.refract_index().is_some() {
+                    let entering = payLoad.ng.dot(nextRay.direction) < 0.0;
+                    if entering {
+                        crossed.push(mat_idx);
+                    } else {
+                        crossed.remove(mat_idx);
+                    }
+                    let current = self.currentMedium(&media);
+                    let next = self.currentMedium(&crossed);
+                    if current == next {
+                        media = crossed;
+                        nextRay = Ray { origin: payLoad.p, direction: nextRay.direction };
+                        nullCrossings += 1;
+                        if nullCrossings > MAX_NULL_CROSSINGS {
+                            break;
+                        }
+                        continue;
+                    }
+                    let outside = if entering { current } else { next };
+                    material = material.relative_to(self.mediumIndex(outside, wavelengths.x));
+                }
+                payLoad.n = self.shadingNormal(material, payLoad, -nextRay.direction);
+                // the last vertex has no bounce left to reach a light, so it does not sample one either
+                if material.bsdf(&self.measured).samples_lights() && i + 1 < self.sampling_parameters.num_bounces() {
+                    let u = Vec3::new(rngState.rngNextFloat(), rngState.rngNextFloat(), rngState.rngNextFloat());
+                    pixel_color += throughput * self.toPathColor(self.directLight(nextRay, payLoad, material, u), wavelengths);
+                    if self.samplesEnvironment() {
+                        let u = Vec2::new(rngState.rngNextFloat(), rngState.rngNextFloat());
+                        pixel_color += throughput * self.toPathColor(self.environmentLight(nextRay, payLoad, material, u), wavelengths);
+                    }
+                }
+                let inRay = nextRay;
+                let (scatterRay, weight) = self.getScatterRay(nextRay, material, payLoad, rngState);
+                nextRay = scatterRay;
+                bouncePdf = self.bsdfPdf(inRay, payLoad, material, nextRay.direction);
+                bounceOrigin = payLoad.p;
+                // a refraction takes the path into the dielectric or out of it
+                if material.refract_index().is_some() && (payLoad.ng.dot(nextRay.direction) < 0.0) == (payLoad.ng.dot(inRay.direction) < 0.0) {
+                    media = crossed;
+                }
+                // a bounce into a subsurface material random walks through its medium
+                medium = material.bsdf(&self.measured).medium(self.materialAlbedo(material, payLoad))
+                    .filter(|_| payLoad.ng.dot(nextRay.direction) < 0.0);
+
+                throughput *= self.toPathColor(weight, wavelengths);
+                // microfacet samples that leave on the wrong side of the surface carry nothing
+                if throughput == Vec4::ZERO {
+                    break;
+                }
             } else {
-                let a: f32 = 0.5 * (primaryRay.direction.y + 1.0);
-                pixel_color = throughput * ((1.0 - a) * Vec3::ONE + a * Vec3::new(0.5, 0.7, 1.0));
+                // a bounce that reaches the environment shares it with the light sample
+                let mut weight: f32 = 1.0;
+                if bouncePdf > 0.0 && self.samplesEnvironment() {
+                    weight = self.powerHeuristic(bouncePdf, self.environmentPdf(nextRay.direction));
+                }
+                pixel_color += throughput * self.toPathColor(weight * self.backgroundColor(nextRay.direction), wavelengths);
                 break;
             }
+            i += 1;
+        }
+
+        return self.toRgb(pixel_color, wavelengths);
+    }
+
+    fn isSpectral(&self) -> bool {
+        self.sampling_parameters.spectral() == 1
+    }
+
+    fn toPathColor(&self, rgb: Vec3, wavelengths: Vec4) -> Vec4 {
+        // an rgb quantity as the path carries it, upsampled to the wavelengths in spectral mode
+        if self.isSpectral() {
+            return rgb_to_spectrum(rgb, wavelengths);
+        }
+        rgb.extend(0.0)
+    }
+
+    fn toRgb(&self, color: Vec4, wavelengths: Vec4) -> Vec3 {
+        // what the path brought back, through XYZ in spectral mode
+        if self.isSpectral() {
+            return spectrum_to_rgb(color, wavelengths);
+        }
+        color.xyz()
+    }
+
+    fn currentMedium(&self, media: &MediumStack) -> Option<u32> {
+        // the dielectric with the highest priority, the last one entered among equals
+        let mut current: Option<u32> = None;
+        for &mat_idx in &media.materials[..media.count] {
+            let priority = self.materials[mat_idx as usize].priority;
+            if current.map_or(true, |c| priority >= self.materials[c as usize].priority) {
+                current = Some(mat_idx);
+            }
+        }
+        current
+    }
+
+    fn mediumIndex(&self, medium: Option<u32>, wavelength: f32) -> f32 {
+        // the index of refraction of a medium, at the hero wavelength in spectral mode, and 1
+        // outside of any
+        let Some(mat_idx) = medium else {
+            return 1.0;
+        };
+        let mut material = self.materials[mat_idx as usize];
+        if self.isSpectral() {
+            material = material.at_wavelength(wavelength);
+        }
+        material.refract_index().unwrap_or(1.0)
+    }
+
+    fn backgroundColor(&self, direction: Vec3) -> Vec3 {
+        // what a ray leaving the scene in this direction picks up
+        let bottom = self.scene_parameters.background_bottom().xyz();
+        match self.scene_parameters.background_type() {
+            1 => {
+                let a: f32 = 0.5 * (direction.normalize().y + 1.0);
+                (1.0 - a) * bottom + a * self.scene_parameters.background_top().xyz()
+            }
+            2 => self.environmentLookup(direction),
+            3 => self.skyRadiance(direction),
+            _ => bottom,
+        }
+    }
+
+    fn skyRadiance(&self, direction: Vec3) -> Vec3 {
+        // Preetham sky: the Perez function of the view and sun angles scales the zenith
+        // (Y, x, y), which is then taken to linear sRGB; below the horizon the horizon repeats
+        let sky = self.scene_parameters.sky();
+        let d = direction.normalize();
+        let cosTheta = d.y.max(0.001);
+        let cosGamma = d.dot(sky[6].xyz()).clamp(-1.0, 1.0);
+        let gamma = cosGamma.acos();
+        let perez = (Vec3::ONE + sky[0].xyz() * (sky[1].xyz() / cosTheta).exp())
+            * (Vec3::ONE + sky[2].xyz() * (sky[3].xyz() * gamma).exp() + sky[4].xyz() * cosGamma * cosGamma);
+        let Yxy = sky[5].xyz() * perez;
+
+        let XYZ = Vec3::new(Yxy.y / Yxy.z * Yxy.x, Yxy.x, (1.0 - Yxy.y - Yxy.z) / Yxy.z * Yxy.x);
+        Vec3::new(
+            3.2406 * XYZ.x - 1.5372 * XYZ.y - 0.4986 * XYZ.z,
+            -0.9689 * XYZ.x + 1.8758 * XYZ.y + 0.0415 * XYZ.z,
+            0.0557 * XYZ.x - 0.2040 * XYZ.y + 1.0570 * XYZ.z,
+        ).max(Vec3::ZERO)
+    }
+
+    fn environmentLookup(&self, direction: Vec3) -> Vec3 {
+        // bilinear lookup in the equirectangular map, wrapping around horizontally;
+        // v = 0 looks straight up and u = 0.5 looks down -z
+        let (width, height) = self.scene_parameters.environment_size();
+        let d = direction.normalize();
+        let u = 0.5 + d.x.atan2(-d.z) * 0.5 * FRAC_1_PI;
+        let v = d.y.clamp(-1.0, 1.0).acos() * FRAC_1_PI;
+
+        let x = u * width as f32 - 0.5;
+        let y = (v * height as f32 - 0.5).clamp(0.0, (height - 1) as f32);
+        let fx = x - x.floor();
+        let fy = y - y.floor();
+        let x0 = (x.floor() as i32).rem_euclid(width as i32) as u32;
+        let x1 = (x0 + 1) % width;
+        let y0 = y.floor() as u32;
+        let y1 = (y0 + 1).min(height - 1);
+
+        let texel = |x: u32, y: u32| self.environment_map[(y * width + x) as usize].xyz();
+        let top = texel(x0, y0).lerp(texel(x1, y0), fx);
+        let bottom = texel(x0, y1).lerp(texel(x1, y1), fx);
+        top.lerp(bottom, fy)
+    }
+
+    fn samplesEnvironment(&self) -> bool {
+        self.scene_parameters.background_type() == 2 && self.scene_parameters.environment_sampling() == 1
+    }
+
+    fn bsdfPdf(&self, inRay: Ray, hit: HitPayload, material: Material, direction: Vec3) -> f32 {
+        // pdf of getScatterRay producing this direction; it is 0 for the materials light
+        // sampling skips, so that their bounces keep the whole of what they hit, and light
+        // samples only leave through the outside of both the shading and geometric surfaces
+        if !material.bsdf(&self.measured).samples_lights() || hit.n.dot(direction) <= 0.0 || hit.ng.dot(direction) <= 0.0 {
+            return 0.0;
+        }
+        material.bsdf(&self.measured).pdf(-inRay.direction.normalize(), direction.normalize(), hit.n, self.materialAlbedo(material, hit))
+    }
+
+    fn bsdfEval(&self, inRay: Ray, hit: HitPayload, material: Material, direction: Vec3) -> Vec3 {
+        // the bsdf times the cosine toward direction
+        material.bsdf(&self.measured).evaluate(-inRay.direction.normalize(), direction.normalize(), hit.n, self.materialAlbedo(material, hit))
+    }
+
+    fn directLight(&self, inRay: Ray, hit: HitPayload, material: Material, u: Vec3) -> Vec3 {
+        // light sample toward one of the lights, picked uniformly with u.z
+        let lightCount = self.scene_parameters.light_count();
+        if lightCount == 0 {
+            return Vec3::ZERO;
+        }
+        let light = self.lights[((u.z * lightCount as f32) as u32).min(lightCount - 1) as usize];
+        match light.light_type() {
+            0 => self.sphereLight(inRay, hit, material, light.sphere_idx(), u.xy()),
+            3 => self.directionalLight(inRay, hit, material, light, u.xy()),
+            _ => self.pointLight(inRay, hit, material, light),
+        }
+    }
+
+    fn pointLight(&self, inRay: Ray, hit: HitPayload, material: Material, light: GPULight) -> Vec3 {
+        // point, spot and photometric lights are only ever reached by shadow rays, so the
+        // sample needs no weight; scaling by the light count makes up for picking a single light
+        let toLight = light.position().xyz() - hit.p;
+        let distanceSquared = toLight.length_squared();
+        let distance = distanceSquared.sqrt();
+        let direction = toLight / distance;
+
+        let mut intensity = light.intensity().xyz();
+        if light.light_type() == 2 {
+            intensity *= self.spotFalloff(light, -direction);
+        }
+        if light.light_type() == 4 {
+            intensity *= self.photometricCandela(light.profile_offset() as usize, -direction);
+        }
+        let pdf = self.bsdfPdf(inRay, hit, material, direction);
+        if pdf <= 0.0 || hit.n.dot(direction) <= 0.0 || intensity == Vec3::ZERO {
+            return Vec3::ZERO;
+        }
+        if self.occluded(Ray { origin: hit.p, direction }, distance - EPSILON) {
+            return Vec3::ZERO;
+        }
+        let lightCount = self.scene_parameters.light_count() as f32;
+        self.bsdfEval(inRay, hit, material, direction) * intensity / distanceSquared * lightCount
+    }
+
+    fn spotFalloff(&self, light: GPULight, direction: Vec3) -> f32 {
+        // direction points away from the light
+        let cosTheta = direction.dot(light.direction().xyz());
+        if cosTheta < light.cos_outer() {
+            return 0.0;
+        }
+        let t = (cosTheta - light.cos_outer()) / (light.cos_inner() - light.cos_outer()).max(1e-6);
+        t.min(1.0).powf(light.falloff())
+    }
+
+    fn photometricCandela(&self, profile: usize, direction: Vec3) -> f32 {
+        // bilinear lookup in the candela table of the profile starting at profile, laid out as
+        // in IesProfile::to_gpu; direction points away from the light, vertical angle 0 is
+        // straight down and horizontal angles turn from +x toward -z
+        let table = &self.photometric_profiles;
+        let verticalCount = table[profile] as usize;
+        let horizontalCount = table[profile + 1] as usize;
+        let verticalAngles = profile + 2;
+        let horizontalAngles = verticalAngles + verticalCount;
+        let candela = horizontalAngles + horizontalCount;
+
+        let vertical = (-direction.y).clamp(-1.0, 1.0).acos().to_degrees();
+        let horizontal = (-direction.z).atan2(direction.x).to_degrees().rem_euclid(360.0);
+        if vertical < table[verticalAngles] || vertical > table[verticalAngles + verticalCount - 1] {
+            return 0.0;
+        }
+        let (v, tv) = self.tableInterval(verticalAngles, verticalCount, vertical);
+        let (h, th) = self.tableInterval(horizontalAngles, horizontalCount, horizontal);
+        let v1 = (v + 1).min(verticalCount - 1);
+        let h1 = (h + 1).min(horizontalCount - 1);
+
+        let value = |h: usize, v: usize| table[candela + h * verticalCount + v];
+        let lower = value(h, v) * (1.0 - tv) + value(h, v1) * tv;
+        let upper = value(h1, v) * (1.0 - tv) + value(h1, v1) * tv;
+        lower * (1.0 - th) + upper * th
+    }
+
+    fn tableInterval(&self, start: usize, count: usize, x: f32) -> (usize, f32) {
+        // the segment of the count ascending angles from start that holds x, and how far
+        // along it x lies
+        let angles = &self.photometric_profiles[start..start + count];
+        if count == 1 {
+            return (0, 0.0);
+        }
+        let mut i = 0;
+        while i + 2 < count && angles[i + 1] <= x {
+            i += 1;
+        }
+        let t = (x - angles[i]) / (angles[i + 1] - angles[i]);
+        (i, t.clamp(0.0, 1.0))
+    }
+
+    fn directionalLight(&self, inRay: Ray, hit: HitPayload, material: Material, light: GPULight, u: Vec2) -> Vec3 {
+        // uniform over the disk of the sun; its radiance times its solid angle is the
+        // irradiance, so the estimate does not depend on the size of the disk
+        let axis = -light.direction().xyz();
+        let cosTheta = 1.0 - u.x * (1.0 - light.cos_outer());
+        let sinTheta = (1.0 - cosTheta * cosTheta).max(0.0).sqrt();
+        let phi = 2.0 * PI * u.y;
+        let (tangent, bitangent) = self.orthonormalBasis(axis);
+        let direction = sinTheta * phi.cos() * tangent + sinTheta * phi.sin() * bitangent + cosTheta * axis;
+
+        let pdf = self.bsdfPdf(inRay, hit, material, direction);
+        if pdf <= 0.0 || hit.n.dot(direction) <= 0.0 {
+            return Vec3::ZERO;
+        }
+        if self.occluded(Ray { origin: hit.p, direction }, 1e29) {
+            return Vec3::ZERO;
+        }
+        let lightCount = self.scene_parameters.light_count() as f32;
+        self.bsdfEval(inRay, hit, material, direction) * light.intensity().xyz() * lightCount
+    }
+
+    fn sphereLight(&self, inRay: Ray, hit: HitPayload, material: Material, lightIdx: u32, u: Vec2) -> Vec3 {
+        // light sample in the cone the emissive sphere subtends, weighted against the chance
+        // of the bounce ray hitting it
+        let light = self.spheres[lightIdx as usize];
+        let toCenter = light.center.xyz() - hit.p;
+        let distanceSquared = toCenter.length_squared();
+        let radiusSquared = light.radius() * light.radius();
+        if distanceSquared <= radiusSquared {
+            return Vec3::ZERO;
+        }
+
+        let cosThetaMax = (1.0 - radiusSquared / distanceSquared).max(0.0).sqrt();
+        let cosTheta = 1.0 - u.x * (1.0 - cosThetaMax);
+        let sinTheta = (1.0 - cosTheta * cosTheta).max(0.0).sqrt();
+        let phi = 2.0 * PI * u.y;
+        let axis = toCenter / distanceSquared.sqrt();
+        let (tangent, bitangent) = self.orthonormalBasis(axis);
+        let direction = sinTheta * phi.cos() * tangent + sinTheta * phi.sin() * bitangent + cosTheta * axis;
+
+        let bsdfPdf = self.bsdfPdf(inRay, hit, material, direction);
+        if bsdfPdf <= 0.0 || hit.n.dot(direction) <= 0.0 {
+            return Vec3::ZERO;
+        }
+        // stop the shadow ray just short of the near side of the light
+        let b = direction.dot(toCenter);
+        let tLight = b - (b * b - distanceSquared + radiusSquared).max(0.0).sqrt();
+        if self.occluded(Ray { origin: hit.p, direction }, tLight - EPSILON) {
+            return Vec3::ZERO;
+        }
+
+        let lightPdf = self.sphereLightPdf(hit.p, lightIdx);
+        // the emission is looked up where the shadow ray meets the light
+        let lightPoint = hit.p + tLight * direction;
+        let lightNormal = (lightPoint - light.center.xyz()).normalize();
+        let lightHit = HitPayload { t: tLight, p: lightPoint, n: lightNormal, idx: lightIdx, uv: self.sphereUV(lightNormal), ng: lightNormal };
+        let lightMaterial = self.materials[light.material_idx() as usize];
+        let emitted = lightMaterial.bsdf(&self.measured).emitted(self.materialAlbedo(lightMaterial, lightHit)).unwrap_or(Vec3::ZERO);
+        self.bsdfEval(inRay, hit, material, direction) * emitted * self.powerHeuristic(lightPdf, bsdfPdf) / lightPdf
+    }
+
+    fn sphereLightPdf(&self, origin: Vec3, sphereIdx: u32) -> f32 {
+        // uniform over the cone the sphere subtends from origin, and over the lights
+        let sphere = self.spheres[sphereIdx as usize];
+        let distanceSquared = (sphere.center.xyz() - origin).length_squared();
+        let radiusSquared = sphere.radius() * sphere.radius();
+        let lightCount = self.scene_parameters.light_count();
+        if distanceSquared <= radiusSquared || lightCount == 0 {
+            return 0.0;
+        }
+        let cosThetaMax = (1.0 - radiusSquared / distanceSquared).max(0.0).sqrt();
+        1.0 / (2.0 * PI * (1.0 - cosThetaMax) * lightCount as f32)
+    }
+
+    fn orthonormalBasis(&self, n: Vec3) -> (Vec3, Vec3) {
+        // two unit vectors perpendicular to n and to each other (Duff et al. 2017)
+        let sign = 1f32.copysign(n.z);
+        let a = -1.0 / (sign + n.z);
+        let b = n.x * n.y * a;
+        (Vec3::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+         Vec3::new(b, sign + n.y * n.y * a, -n.y))
+    }
+
+    fn environmentLight(&self, inRay: Ray, hit: HitPayload, material: Material, u: Vec2) -> Vec3 {
+        // light sample of the environment, weighted against the chance of the bounce ray
+        // finding the same direction
+        let (direction, lightPdf) = self.sampleEnvironment(u);
+        if lightPdf <= 0.0 || hit.n.dot(direction) <= 0.0 {
+            return Vec3::ZERO;
+        }
+        let bsdfPdf = self.bsdfPdf(inRay, hit, material, direction);
+        if bsdfPdf <= 0.0 || self.occluded(Ray { origin: hit.p, direction }, 1e29) {
+            return Vec3::ZERO;
+        }
+        let weight = self.powerHeuristic(lightPdf, bsdfPdf);
+        self.bsdfEval(inRay, hit, material, direction) * self.environmentLookup(direction) * weight / lightPdf
+    }
+
+    fn sampleEnvironment(&self, u: Vec2) -> (Vec3, f32) {
+        // picks a row with the marginal cdf and a column with that row's conditional cdf,
+        // then maps the point to a direction; the layout is the one of Distribution2D::to_gpu
+        let (width, height) = self.scene_parameters.environment_size();
+        let (width, height) = (width as usize, height as usize);
+        let conditionalCdf = width * height;
+        let marginalCdf = conditionalCdf + height * (width + 1) + height;
+        let integral = self.environment_distribution[marginalCdf + height + 1];
+        if integral <= 0.0 {
+            return (Vec3::ZERO, 0.0);
+        }
+
+        let row = self.findInterval(marginalCdf, height + 1, u.y);
+        let dv = self.segmentOffset(marginalCdf + row, u.y);
+        let rowCdf = conditionalCdf + row * (width + 1);
+        let col = self.findInterval(rowCdf, width + 1, u.x);
+        let du = self.segmentOffset(rowCdf + col, u.x);
+
+        let theta = PI * (row as f32 + dv) / height as f32;
+        let phi = 2.0 * PI * ((col as f32 + du) / width as f32 - 0.5);
+        let sinTheta = theta.sin();
+        if sinTheta <= 0.0 {
+            return (Vec3::ZERO, 0.0);
+        }
+        let direction = Vec3::new(sinTheta * phi.sin(), theta.cos(), -sinTheta * phi.cos());
+        // from the (u, v) square to solid angle
+        let pdf = self.environment_distribution[row * width + col] / integral / (2.0 * PI * PI * sinTheta);
+        (direction, pdf)
+    }
+
+    fn environmentPdf(&self, direction: Vec3) -> f32 {
+        let (width, height) = self.scene_parameters.environment_size();
+        let (width, height) = (width as usize, height as usize);
+        let integral = self.environment_distribution[width * height + height * (width + 1) + 2 * height + 1];
+        let d = direction.normalize();
+        let sinTheta = (1.0 - d.y * d.y).max(0.0).sqrt();
+        if integral <= 0.0 || sinTheta <= 0.0 {
+            return 0.0;
+        }
+        let u = 0.5 + d.x.atan2(-d.z) * 0.5 * FRAC_1_PI;
+        let v = d.y.clamp(-1.0, 1.0).acos() * FRAC_1_PI;
+        let col = ((u * width as f32) as usize).min(width - 1);
+        let row = ((v * height as f32) as usize).min(height - 1);
+        self.environment_distribution[row * width + col] / integral / (2.0 * PI * PI * sinTheta)
+    }
+
+    fn findInterval(&self, cdf: usize, size: usize, u: f32) -> usize {
+        // index of the last of the size cdf entries starting at cdf that is <= u, kept
+        // within the segments
+        let mut first = 0usize;
+        let mut length = size;
+        while length > 0 {
+            let half = length >> 1;
+            let middle = first + half;
+            if self.environment_distribution[cdf + middle] <= u {
+                first = middle + 1;
+                length -= half + 1;
+            } else {
+                length = half;
+            }
         }
+        first.saturating_sub(1).min(size - 2)
+    }
+
+    fn segmentOffset(&self, segment: usize, u: f32) -> f32 {
+        // where u lies between the cdf values at both ends of a segment
+        let start = self.environment_distribution[segment];
+        let width = self.environment_distribution[segment + 1] - start;
+        if width > 0.0 { ((u - start) / width).clamp(0.0, 1.0) } else { 0.0 }
+    }
 
-        return pixel_color;
+    fn powerHeuristic(&self, pdf: f32, otherPdf: f32) -> f32 {
+        let a = pdf * pdf;
+        a / (a + otherPdf * otherPdf)
     }
 
     fn TraceRay(&self, ray: Ray, hit: &mut HitPayload) -> bool {
@@ -224,7 +786,7 @@ impl ComputeShader {
 
         if USE_BVH {
             // this is where I will implement the BVH tree search rather than using a full primitive search
-            let mut stack = [0usize; 32];
+            let mut stack = [0usize; TRAVERSAL_STACK_SIZE];
             let mut stack_pointer = 0usize;
             let mut node_index = 0usize;
 
@@ -302,6 +864,49 @@ impl ComputeShader {
         return false;
     }
 
+    fn occluded(&self, ray: Ray, t_max: f32) -> bool {
+        // any hit query for shadow rays; returns true as soon as any sphere is hit between
+        // 0.001 and t_max, without looking for the closest one
+
+        if !USE_BVH {
+            return (0..self.spheres.len() as u32).any(|i| self.occludes(ray, i, 0.001, t_max));
+        }
+
+        // children are visited in any order, so there is no need to sort them by distance
+        let mut stack = [0usize; TRAVERSAL_STACK_SIZE];
+        let mut stack_pointer = 0usize;
+        let mut node_index = 0usize;
+
+        loop {
+            let node = &self.bvh_tree[node_index];
+            if node.prim_count > 0 {
+                for idx in 0..node.prim_count {
+                    if self.occludes(ray, node.left_first + idx, 0.001, t_max) {
+                        return true;
+                    }
+                }
+            } else {
+                let left_idx = node.left_first as usize;
+                let hit_left = self.hit_bvh_node(&ray, &self.bvh_tree[left_idx]) < t_max;
+                let hit_right = self.hit_bvh_node(&ray, &self.bvh_tree[left_idx + 1]) < t_max;
+                if hit_left || hit_right {
+                    node_index = if hit_left { left_idx } else { left_idx + 1 };
+                    if hit_left && hit_right {
+                        stack[stack_pointer] = left_idx + 1;
+                        stack_pointer += 1;
+                    }
+                    continue;
+                }
+            }
+
+            if stack_pointer == 0 {
+                return false;
+            }
+            stack_pointer -= 1;
+            node_index = stack[stack_pointer];
+        }
+    }
+
     fn hit_bvh_node(&self, ray: &Ray, node: &BVHNode) -> f32 {
         let t_x_min = (node.aabb_min.x - ray.origin.x) / ray.direction.x;
         let t_x_max = (node.aabb_max.x - ray.origin.x) / ray.direction.x;
@@ -337,13 +942,13 @@ impl ComputeShader {
 
         if (discrim >= 0.0) {
             let mut t = (-b - discrim.sqrt()) / a;
-            if (t > t_min && t < t_nearest) {
+            if (t > t_min && t < t_nearest && self.anyHit(ray, sphere, sphereIdx, t)) {
                 *payload = self.hitSphere(t, ray, sphere, sphereIdx);
                 return true;
             }
 
             t = (-b + discrim.sqrt()) / a;
-            if (t > t_min && t < t_nearest) {
+            if (t > t_min && t < t_nearest && self.anyHit(ray, sphere, sphereIdx, t)) {
                 *payload = self.hitSphere(t, ray, sphere, sphereIdx);
                 return true;
             }
@@ -351,6 +956,40 @@ impl ComputeShader {
         return false;
     }
 
+    fn occludes(&self, ray: Ray, sphereIdx: u32, t_min: f32, t_max: f32) -> bool {
+        // same test as hit, but only reports whether either intersection lies in (t_min, t_max)
+        let sphere: Sphere = self.spheres[sphereIdx as usize];
+        let oc = ray.origin - sphere.center.xyz();
+        let a: f32 = ray.direction.dot(ray.direction);
+        let b: f32 = ray.direction.dot(oc);
+        let c: f32 = oc.dot(oc) - sphere.radius() * sphere.radius();
+        let discrim: f32 = b * b - a * c;
+
+        if discrim < 0.0 {
+            return false;
+        }
+        let t_near = (-b - discrim.sqrt()) / a;
+        let t_far = (-b + discrim.sqrt()) / a;
+        (t_near > t_min && t_near < t_max && self.anyHit(ray, sphere, sphereIdx, t_near)) ||
+            (t_far > t_min && t_far < t_max && self.anyHit(ray, sphere, sphereIdx, t_far))
+    }
+
+    fn anyHit(&self, ray: Ray, sphere: Sphere, sphereIdx: u32, t: f32) -> bool {
+        // called for every intersection found during traversal; a surface with an opacity or
+        // alpha texture is kept with a chance equal to its alpha, and skipped otherwise, so
+        // that shadow rays pass through it as well
+        let material = self.materials[sphere.material_idx() as usize];
+        if material.is_opaque() {
+            return true;
+        }
+        let mut alpha = material.opacity;
+        if let Some(textureIdx) = material.alpha_texture {
+            let hit = self.hitSphere(t, ray, sphere, sphereIdx);
+            alpha *= self.textureValue(textureIdx, hit.uv, hit.p).dot(Vec3::splat(1.0 / 3.0));
+        }
+        alpha > alphaThreshold(ray, sphereIdx, t)
+    }
+
     fn hitSphere(&self, t: f32, ray: Ray, sphere: Sphere, idx: u32) -> HitPayload {
         // make the hitPayload struct
         // note that decision here is that normals ALWAYS point out of the sphere
@@ -359,100 +998,119 @@ impl ComputeShader {
         // from outside; if positive, ray comes from within
         let p = ray.origin + t * ray.direction;
         let mut n = (p - sphere.center.xyz()).normalize();
+        let uv = self.sphereUV(n);
 
-        return HitPayload {t, p, n, idx}
+        return HitPayload {t, p, n, idx, uv, ng: n}
     }
 
-    fn getRay_parallel(&self, x: u32, y: u32, rngState: &mut GPURNG) -> Ray {
-        let mut offset = rngState.rngNextVec3InUnitDisk();
-
-        let mut point = Vec2::new((x as f32 + offset.x) / self.frame_buffer[0] as f32,
-                                  1.0 - (y as f32 + offset.y) / self.frame_buffer[1] as f32);
-        point = 2.0 * point - 1.0;
-        let mut projPoint = Mat4::from_cols_array_2d(&self.inv_proj_matrix) * Vec4::new(point.x, point.y, 1.0, 1.0);
-        projPoint = projPoint / projPoint.w;
-        projPoint = projPoint.xyz().extend(0.0);
-
-        let mut origin = self.camera_data.position().xyz();
-
-        if self.camera_data.defocus_radius() > 0.0 {
-            offset = rngState.rngNextVec3InUnitDisk();
-
-            let pLens= (self.camera_data.defocus_radius() * offset).extend(1.0);
-            let mut lensOrigin = Mat4::from_cols_array_2d(&self.view_matrix) * pLens;
-            lensOrigin = lensOrigin / lensOrigin.w;
-            origin = lensOrigin.xyz();
+    fn sphereUV(&self, n: Vec3) -> Vec2 {
+        // u turns around the y axis starting from -x, v goes from the bottom (0) to the top (1)
+        let theta = (-n.y).clamp(-1.0, 1.0).acos();
+        let phi = (-n.z).atan2(n.x) + PI;
+        Vec2::new(phi * 0.5 * FRAC_1_PI, theta * FRAC_1_PI)
+    }
 
-            let tf = self.camera_data.focus_distance() / projPoint.z;
-            projPoint = tf * projPoint - pLens;
+    fn materialAlbedo(&self, material: Material, hit: HitPayload) -> Vec3 {
+        // the albedo texture, if the material has one, replaces the constant albedo
+        match material.albedo_texture {
+            Some(textureIdx) => self.textureValue(textureIdx, hit.uv, hit.p),
+            None => material.bsdf(&self.measured).albedo(),
         }
-
-        let rayDir = Mat4::from_cols_array_2d(&self.view_matrix) * projPoint.with_w(0.0);
-        let direction = rayDir.xyz().normalize();
-
-        Ray { origin, direction }
     }
 
-    fn getScatterRay_parallel(&self, inRay: Ray,
-                     mat_idx: u32,
-                     hit: HitPayload, rngState: &mut GPURNG)
-                     -> Ray {
-
-        let origin = hit.p;
-        let mat_type: u32 = self.materials[mat_idx as usize].material_type();
-        let mut direction = Vec3::ZERO;
+    fn shadingNormal(&self, material: Material, hit: HitPayload, wo: Vec3) -> Vec3 {
+        // the normal or bump map tilts the normal in the tangent frame of the sphere's uvs; a
+        // tilt that turns the surface away from wo is dropped, as the bsdf would then see wo
+        // on the other side of the surface than the ray came from
+        let Some(normalMap) = material.normal_map else {
+            return hit.n;
+        };
+        // u turns around the y axis, so the frame degenerates at the poles
+        let sinTheta = (hit.n.x * hit.n.x + hit.n.z * hit.n.z).sqrt();
+        if sinTheta < 1e-4 {
+            return hit.n;
+        }
+        let tangent = Vec3::new(hit.n.z, 0.0, -hit.n.x) / sinTheta;
+        let bitangent = hit.n.cross(tangent);
+        let shading = match normalMap {
+            NormalMap::TangentSpace { texture, strength } => {
+                let t = 2.0 * self.textureValue(texture, hit.uv, hit.p) - Vec3::ONE;
+                (strength * (t.x * tangent + t.y * bitangent) + t.z * hit.n).normalize()
+            }
+            NormalMap::Bump { texture, scale } => {
+                // slopes of the height along the tangent and bitangent; a step of BUMP_DELTA
+                // covers BUMP_DELTA / (2 pi r sinTheta) in u and BUMP_DELTA / (pi r) in v
+                let radius = self.spheres[hit.idx as usize].radius().abs();
+                let du = Vec2::new(BUMP_DELTA / (2.0 * PI * radius * sinTheta), 0.0);
+                let dv = Vec2::new(0.0, BUMP_DELTA / (PI * radius));
+                let height = self.bumpHeight(texture, hit.uv, hit.p);
+                let slopeU = (self.bumpHeight(texture, hit.uv + du, hit.p + BUMP_DELTA * tangent) - height) / BUMP_DELTA;
+                let slopeV = (self.bumpHeight(texture, hit.uv + dv, hit.p + BUMP_DELTA * bitangent) - height) / BUMP_DELTA;
+                (hit.n - scale * (slopeU * tangent + slopeV * bitangent)).normalize()
+            }
+        };
+        if shading.dot(wo) * hit.ng.dot(wo) <= 0.0 {
+            return hit.ng;
+        }
+        shading
+    }
 
-        match mat_type {
-            0 => {
-                let randomBounce= rngState.rngNextVec3InUnitSphere().normalize();
+    fn bumpHeight(&self, textureIdx: u32, uv: Vec2, p: Vec3) -> f32 {
+        self.textureValue(textureIdx, uv, p).dot(Vec3::splat(1.0 / 3.0))
+    }
 
-                direction = hit.n + randomBounce;
-                if direction.length() < 0.0001 {
-                    direction = hit.n;
-                }
-            }
+    fn textureValue(&self, textureIdx: u32, uv: Vec2, p: Vec3) -> Vec3 {
+        // the same steps as Texture::value, from the flattened textures
+        let texture = self.textures[textureIdx as usize];
+        let color = texture.color().xyz();
+        match texture.texture_type() {
             1 => {
-                let randomBounce= rngState.rngNextVec3InUnitSphere().normalize();
-
-                let fuzz: f32 = self.materials[mat_idx as usize].fuzz();
-                direction = self.reflect(inRay.direction, hit.n) + fuzz * randomBounce;
+                let cell = (p / texture.scale()).floor();
+                if (cell.x + cell.y + cell.z).rem_euclid(2.0) < 1.0 { color } else { texture.odd_color().xyz() }
             }
-            2 => {
-                let refract_idx: f32 = self.materials[mat_idx as usize].refract_index();
-                let mut norm= hit.n.clone();
-                let uv = inRay.direction.normalize();
-                let mut cosTheta = norm.dot(-uv).min(1.0); // as uv represents incoming, -uv is outgoing direction
-                let mut etaOverEtaPrime: f32 = 0.0;
-
-                if cosTheta >= 0.0 {
-                    etaOverEtaPrime = 1.0 / refract_idx;
-                } else {
-                    etaOverEtaPrime = refract_idx;
-                    norm *= -1.0;
-                    cosTheta *= -1.0;
-                }
+            2 => color * 0.5 * (1.0 + perlin(texture.scale() * p)),
+            3 => color * turbulence(texture.scale() * p, texture.depth()),
+            4 => color * 0.5 * (1.0 + (texture.scale() * p.z + 10.0 * turbulence(p, 7)).sin()),
+            5 => self.imageLookup(texture, uv),
+            _ => color,
+        }
+    }
 
-                let reflectance: f32 = self.schlick(cosTheta, etaOverEtaPrime);
-                let mut refractDirection = Vec3::ZERO;
-                let cond= rngState.rngNextFloat();
+    fn imageLookup(&self, texture: GPUTexture, uv: Vec2) -> Vec3 {
+        // bilinear lookup; texel centers sit at half integers and row 0 is the top of the image
+        let (width, height) = texture.size();
+        let x = uv.x * width as f32 - 0.5;
+        let y = (1.0 - uv.y) * height as f32 - 0.5;
+        let x0 = x.floor() as i32;
+        let y0 = y.floor() as i32;
+        let tx = x - x0 as f32;
+        let ty = y - y0 as f32;
+        let texel = |i: i32, j: i32| {
+            let column = self.wrapTexel(i, width, texture.wrap());
+            let row = self.wrapTexel(j, height, texture.wrap());
+            self.texels[(texture.texel_offset() + row * width + column) as usize].xyz()
+        };
+        let top = texel(x0, y0).lerp(texel(x0 + 1, y0), tx);
+        let bottom = texel(x0, y0 + 1).lerp(texel(x0 + 1, y0 + 1), tx);
+        top.lerp(bottom, ty)
+    }
 
-                if self.refract(uv, norm, etaOverEtaPrime, &mut refractDirection) {
-                    if reflectance > cond {
-                        direction = self.reflect(uv, norm);
-                    } else {
-                        direction = refractDirection;
-                    }
-                } else {
-                    direction = self.reflect(uv, norm);
-                }
+    fn wrapTexel(&self, i: i32, size: u32, wrap: u32) -> u32 {
+        // 0 repeat, 1 clamp, 2 mirror
+        let size = size as i32;
+        let wrapped = match wrap {
+            1 => i.clamp(0, size - 1),
+            2 => {
+                let period = i.rem_euclid(2 * size);
+                if period < size { period } else { 2 * size - 1 - period }
             }
-            _ => {}
-        }
-        Ray { origin, direction }
+            _ => i.rem_euclid(size),
+        };
+        wrapped as u32
     }
 
-    fn getRay(&mut self, x: u32, y: u32) -> Ray {
-        let mut offset = self.rngState.rngNextVec3InUnitDisk();
+    fn getRay(&self, x: u32, y: u32, rngState: &mut GPURNG) -> Ray {
+        let mut offset = rngState.rngNextVec3InUnitDisk();
 
         let mut point = Vec2::new((x as f32 + offset.x) / self.frame_buffer[0] as f32,
                                   1.0 - (y as f32 + offset.y) / self.frame_buffer[1] as f32);
@@ -464,7 +1122,7 @@ impl ComputeShader {
         let mut origin = self.camera_data.position().xyz();
 
         if self.camera_data.defocus_radius() > 0.0 {
-            offset = self.rngState.rngNextVec3InUnitDisk();
+            offset = rngState.rngNextVec3InUnitDisk();
 
             let pLens= (self.camera_data.defocus_radius() * offset).extend(1.0);
             let mut lensOrigin = Mat4::from_cols_array_2d(&self.view_matrix) * pLens;
@@ -481,82 +1139,55 @@ impl ComputeShader {
         Ray { origin, direction }
     }
 
-    fn getScatterRay(&mut self, inRay: Ray,
-                     mat_idx: u32,
-                     hit: HitPayload)
-        -> Ray {
-
-        let origin = hit.p;
-        let mat_type: u32 = self.materials[mat_idx as usize].material_type();
-        let mut direction = Vec3::ZERO;
-
-        match mat_type {
-            0 => {
-                let randomBounce= self.rngState.rngNextVec3InUnitSphere().normalize();
-
-                direction = hit.n + randomBounce;
-                if direction.length() < 0.0001 {
-                    direction = hit.n;
-                }
-            }
-            1 => {
-                let randomBounce= self.rngState.rngNextVec3InUnitSphere().normalize();
+    fn getScatterRay(&self, inRay: Ray,
+                     material: Material,
+                     hit: HitPayload, rngState: &mut GPURNG)
+                     -> (Ray, Vec3) {
+        let u = Vec3::new(rngState.rngNextFloat(), rngState.rngNextFloat(), rngState.rngNextFloat());
+        self.scatter(inRay, material, hit, u)
+    }
 
-                let fuzz: f32 = self.materials[mat_idx as usize].fuzz();
-                direction = self.reflect(inRay.direction, hit.n) + fuzz * randomBounce;
+    fn randomWalk(&self, ray: Ray, medium: Medium, rngState: &mut GPURNG) -> Option<(Ray, Vec3)> {
+        // scatters the ray through the medium until it heads for the surface without
+        // scattering again, and returns that last ray with the throughput of the walk; the
+        // walk is lost if it leaves the scene or runs too long
+        // one channel picks all the distances, and the throughput is divided by the average
+        // of the densities that following each of the channels gives the walk
+        let channel = ((3.0 * rngState.rngNextFloat()) as usize).min(2);
+        let mut walkRay = Ray { origin: ray.origin, direction: ray.direction.normalize() };
+        let mut throughput = Vec3::ONE;
+        let mut pdfs = Vec3::ONE;
+        for _i in 0..MAX_WALK_STEPS {
+            let mut hit = HitPayload::default();
+            if !self.TraceRay(walkRay, &mut hit) {
+                return None;
             }
-            2 => {
-                let refract_idx: f32 = self.materials[mat_idx as usize].refract_index();
-                let mut norm= hit.n.clone();
-                let uv = inRay.direction.normalize();
-                let mut cosTheta = norm.dot(-uv).min(1.0); // as uv represents incoming, -uv is outgoing direction
-                let mut etaOverEtaPrime: f32 = 0.0;
-
-                if cosTheta >= 0.0 {
-                    etaOverEtaPrime = 1.0 / refract_idx;
-                } else {
-                    etaOverEtaPrime = refract_idx;
-                    norm *= -1.0;
-                    cosTheta *= -1.0;
-                }
-
-                let reflectance: f32 = self.schlick(cosTheta, etaOverEtaPrime);
-                let mut refractDirection = Vec3::ZERO;
-                let cond= self.rngState.rngNextFloat();
-
-                if self.refract(uv, norm, etaOverEtaPrime, &mut refractDirection) {
-                       if reflectance > cond {
-                           direction = self.reflect(uv, norm);
-                       } else {
-                            direction = refractDirection;
-                        }
-                } else {
-                    direction = self.reflect(uv, norm);
-                }
+            let t = medium.sample_distance(channel, rngState.rngNextFloat());
+            if t >= hit.t {
+                let (f, pdf) = medium.step(hit.t, false);
+                return Some((walkRay, throughput * f / ((pdfs * pdf).element_sum() / 3.0)));
             }
-            _ => {}
-    }
-        Ray { origin, direction }
-    }
-
-    fn schlick(&self, cosine: f32, refractionIndex: f32) -> f32 {
-        let mut r0 = (1f32 - refractionIndex) / (1f32 + refractionIndex);
-        r0 = r0 * r0;
-        return r0 + (1f32 - r0) * (1f32 - cosine).powi(5)
-    }
-
-    fn reflect(&self, r: Vec3, n: Vec3) -> Vec3 {
-        return r - 2.0 * r.dot(n) * n;
+            let (f, pdf) = medium.step(t, true);
+            // rescaled at every step so that the products stay in range
+            let scale = (pdfs * pdf).element_sum() / 3.0;
+            throughput *= f / scale;
+            pdfs *= pdf / scale;
+            let direction = Medium::sample_direction(Vec2::new(rngState.rngNextFloat(), rngState.rngNextFloat()));
+            walkRay = Ray { origin: walkRay.origin + t * walkRay.direction, direction };
+        }
+        None
     }
 
-    fn refract(&self, uv: Vec3, n: Vec3, ri: f32, dir: &mut Vec3) -> bool {
-        let cosTheta: f32 = uv.dot(n);
-        let k: f32 = 1.0 - ri * ri * (1.0 - cosTheta * cosTheta);
-        if k >= 0.0 {
-            *dir = ri * uv - (ri * cosTheta + k.sqrt()) * n;
-            return true;
+    fn scatter(&self, inRay: Ray, material: Material, hit: HitPayload, u: Vec3) -> (Ray, Vec3) {
+        // the bounce ray and what the throughput is scaled by; a lost sample carries nothing
+        let albedo = self.materialAlbedo(material, hit);
+        let (direction, mut weight) = material.bsdf(&self.measured).sample(-inRay.direction.normalize(), hit.n, albedo, u)
+            .unwrap_or((hit.n, Vec3::ZERO));
+        // a bounce the shading normal sends through the geometric surface would leak light
+        if (hit.ng.dot(direction) > 0.0) != (hit.n.dot(direction) > 0.0) {
+            weight = Vec3::ZERO;
         }
-        return false;
+        (Ray { origin: hit.p, direction }, weight)
     }
 }
 
@@ -661,3 +1292,182 @@ impl GPURNG {
         x
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common_code::background::{Background, EnvironmentMap};
+    use common_code::bvh::BVHTree;
+    use common_code::camera::Camera;
+    use common_code::material::SF11;
+    use common_code::parameters::SamplingParameters;
+    use common_code::scene::Scene;
+    use common_code::texture::Texture;
+
+    // a diffuse sphere on a diffuse floor, under a dim sky with a small, bright sun
+    fn sun_scene() -> Scene {
+        let (width, height) = (32u32, 16u32);
+        let mut pixels = vec![Vec4::new(0.2, 0.3, 0.5, 1.0); (width * height) as usize];
+        for y in 3..5 {
+            for x in 20..23 {
+                pixels[(y * width + x) as usize] = Vec4::new(40.0, 36.0, 30.0, 1.0);
+            }
+        }
+        Scene::from_spheres(vec![Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 999.0, 0),
+                                 Sphere::new(Vec3::ZERO, 1.0, 1)],
+                            vec![Material::Lambertian(Vec3::splat(0.5)),
+                                 Material::Lambertian(Vec3::new(0.7, 0.3, 0.3))],
+                            Background::Environment(EnvironmentMap::new(width, height, pixels)))
+    }
+
+    fn shader(scene: &mut Scene, environment_sampling: bool) -> ComputeShader {
+        let mut tree = BVHTree::new(scene.spheres.len());
+        tree.build_bvh_tree(&mut scene.spheres);
+        let mut scene_parameters = GPUSceneParameters::get_gpu_scene_params(scene);
+        scene_parameters.set_environment_sampling(environment_sampling);
+        let camera = GPUCamera::new(&Camera::new(Vec3::new(0.0, 0.0, 5.0), Vec3::ZERO), 0.0, 10.0);
+        let sampling = GPUSamplingParameters::get_gpu_sampling_params(&SamplingParameters::new(1, 4, 1, 1));
+        ComputeShader::new(scene.spheres.clone(), scene.materials.clone(), scene.measured.clone(), tree.nodes,
+                           scene_parameters,
+                           GPUSceneParameters::get_gpu_environment_map(scene),
+                           GPUSceneParameters::get_gpu_environment_distribution(scene),
+                           GPUSceneParameters::get_gpu_lights(scene),
+                           GPUSceneParameters::get_gpu_photometric_profiles(scene),
+                           GPUSceneParameters::get_gpu_textures(scene),
+                           GPUSceneParameters::get_gpu_texels(scene),
+                           camera, Mat4::IDENTITY.to_cols_array_2d(), Mat4::IDENTITY.to_cols_array_2d(),
+                           sampling, GPUFrameBuffer::new(1, 1, 1, 0), 1)
+    }
+
+    fn mean_color(shader: &ComputeShader, ray: Ray, samples: u32) -> Vec3 {
+        let mut rng = GPURNG::initRng(UVec2::ZERO, (1, 1), 1);
+        let mut sum = Vec3::ZERO;
+        for _i in 0..samples {
+            sum += shader.rayColor(ray, &mut rng);
+        }
+        sum / samples as f32
+    }
+
+    #[test]
+    fn environment_pdf_matches_sampled_pdf() {
+        let mut scene = sun_scene();
+        let shader = shader(&mut scene, true);
+        let mut rng = GPURNG::initRng(UVec2::ZERO, (1, 1), 7);
+        for _i in 0..1000 {
+            let (direction, pdf) = shader.sampleEnvironment(Vec2::new(rng.rngNextFloat(), rng.rngNextFloat()));
+            if pdf > 0.0 {
+                let lookup = shader.environmentPdf(direction);
+                assert!((lookup - pdf).abs() <= 1e-3 * pdf, "sampled pdf {} but lookup gives {}", pdf, lookup);
+            }
+        }
+    }
+
+    #[test]
+    fn environment_sampling_is_unbiased() {
+        // the sphere's shaded side faces the camera, so most of its light comes from
+        // bounces that are hard to find without sampling the sun
+        let ray = Ray { origin: Vec3::new(0.0, 0.3, 5.0), direction: Vec3::new(0.0, 0.0, -1.0) };
+        let mut scene = sun_scene();
+        let naive = mean_color(&shader(&mut scene, false), ray, 400_000);
+        let sampled = mean_color(&shader(&mut scene, true), ray, 40_000);
+        assert!((naive - sampled).abs().max_element() < 0.03 * naive.max_element(),
+                "bounce sampling gives {} but environment sampling gives {}", naive, sampled);
+    }
+
+    #[test]
+    fn normal_maps_do_not_leak_light() {
+        // a constant normal map tilted 60 degrees toward the tangent: the shading normal has
+        // to face the camera ray, and no bounce that carries light may cross the sphere
+        let mut scene = sun_scene();
+        scene.textures.push(Texture::Solid(Vec3::new(0.5 + 0.5 * 0.866, 0.5, 0.75)));
+        scene.materials[1] = Material::Lambertian(Vec3::splat(0.8)).with_normal_map(0, 1.0);
+        let shader = shader(&mut scene, false);
+        let mut rng = GPURNG::initRng(UVec2::ZERO, (1, 1), 1);
+        for i in 0..199 {
+            let ray = Ray { origin: Vec3::new(-0.995 + 0.01 * i as f32, 0.05, 5.0), direction: Vec3::new(0.0, 0.0, -1.0) };
+            let mut hit = HitPayload::default();
+            assert!(shader.TraceRay(ray, &mut hit));
+            let material = shader.materials[shader.spheres[hit.idx as usize].material_idx() as usize];
+            hit.n = shader.shadingNormal(material, hit, -ray.direction);
+            assert!(hit.n.dot(-ray.direction) > 0.0, "shading normal {} faces away from the ray", hit.n);
+            for _j in 0..50 {
+                let (bounce, weight) = shader.getScatterRay(ray, material, hit, &mut rng);
+                if weight != Vec3::ZERO {
+                    assert!(hit.ng.dot(bounce.direction) > 0.0, "bounce {} goes through the surface", bounce.direction);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn random_walk_conserves_energy() {
+        // a white medium under a white sky absorbs nothing, so every walk comes out with the
+        // color of the sky, even when each channel scatters at its own rate
+        let mut scene = Scene::from_spheres(vec![Sphere::new(Vec3::ZERO, 1.0, 0)],
+            vec![Material::Subsurface(Vec3::ONE, Vec3::new(0.4, 0.2, 0.1), 1.0)],
+            Background::Solid(Vec3::ONE));
+        let ray = Ray { origin: Vec3::new(0.0, 0.3, 5.0), direction: Vec3::new(0.0, 0.0, -1.0) };
+        let color = mean_color(&shader(&mut scene, false), ray, 20_000);
+        assert!((color - Vec3::ONE).abs().max_element() < 0.02, "a white medium reflects {}", color);
+    }
+
+    #[test]
+    fn dispersive_glass_keeps_a_white_sky_white() {
+        // glass absorbs nothing, so whichever way it bends the hero wavelength, the
+        // wavelengths it drops are made up for and the sky comes back white on average
+        let mut scene = Scene::from_spheres(vec![Sphere::new(Vec3::ZERO, 1.0, 0)],
+            vec![Material::Dielectric(1.5).with_sellmeier(SF11.0, SF11.1)],
+            Background::Solid(Vec3::ONE));
+        let mut shader = shader(&mut scene, false);
+        shader.queue_sampling(GPUSamplingParameters::get_gpu_sampling_params(&SamplingParameters::new(1, 8, 1, 1).with_spectral(true)));
+        let ray = Ray { origin: Vec3::new(0.0, 0.3, 5.0), direction: Vec3::new(0.0, 0.0, -1.0) };
+        let color = mean_color(&shader, ray, 200_000);
+        assert!((color - Vec3::ONE).abs().max_element() < 0.02, "the glass turns the sky into {}", color);
+    }
+
+    #[test]
+    fn translucent_sphere_lets_through_the_rest_of_the_light() {
+        // a black sphere of opacity 0.25 against a white sky stops a ray at either of its
+        // two surfaces with that chance, which leaves 0.75^2 of the sky and of the shadow rays
+        let mut scene = Scene::from_spheres(vec![Sphere::new(Vec3::ZERO, 1.0, 0)],
+            vec![Material::Lambertian(Vec3::ZERO).with_opacity(0.25)],
+            Background::Solid(Vec3::ONE));
+        let shader = shader(&mut scene, false);
+        let mut rng = GPURNG::initRng(UVec2::ZERO, (1, 1), 3);
+        let (mut color, mut occluded) = (Vec3::ZERO, 0);
+        let samples = 40_000;
+        for _i in 0..samples {
+            let origin = Vec3::new(0.6 * rng.rngNextFloat() - 0.3, 0.6 * rng.rngNextFloat() - 0.3, 5.0);
+            let ray = Ray { origin, direction: Vec3::new(0.0, 0.0, -1.0) };
+            color += shader.rayColor(ray, &mut rng);
+            occluded += shader.occluded(ray, 10.0) as u32;
+        }
+        color /= samples as f32;
+        let unoccluded = 1.0 - occluded as f32 / samples as f32;
+        assert!((color - Vec3::splat(0.5625)).abs().max_element() < 0.01, "the sky shows through as {}", color);
+        assert!((unoccluded - 0.5625).abs() < 0.01, "{} of the shadow rays get through", unoccluded);
+    }
+
+    #[test]
+    fn glass_inside_higher_priority_glass_is_invisible() {
+        // the surfaces of the diamond are all inside the glass around it, which has the
+        // higher priority, so rays go through the glass as if the diamond was not there; three
+        // bounces take a ray through the glass to the sky, as crossing the diamond takes none
+        let scene = |spheres: Vec<Sphere>| Scene::from_spheres(spheres,
+            vec![Material::Dielectric(1.5).with_priority(1), Material::Dielectric(2.4)],
+            Background::Gradient { bottom: Vec3::ZERO, top: Vec3::ONE });
+        let mut nested = scene(vec![Sphere::new(Vec3::ZERO, 1.0, 0), Sphere::new(Vec3::new(0.0, 0.2, 0.0), 0.5, 1)]);
+        let mut single = scene(vec![Sphere::new(Vec3::ZERO, 1.0, 0)]);
+        let sampling = GPUSamplingParameters::get_gpu_sampling_params(&SamplingParameters::new(1, 3, 1, 1));
+        let mut nested = shader(&mut nested, false);
+        let mut single = shader(&mut single, false);
+        nested.queue_sampling(sampling);
+        single.queue_sampling(sampling);
+        for i in 0..10 {
+            let ray = Ray { origin: Vec3::new(0.0, -0.9 + 0.2 * i as f32, 5.0), direction: Vec3::new(0.0, 0.0, -1.0) };
+            let expected = mean_color(&single, ray, 100);
+            let color = mean_color(&nested, ray, 100);
+            assert!((color - expected).abs().max_element() < 1e-3, "the diamond shows as {} instead of {}", color, expected);
+        }
+    }
+}
@@ -1,18 +1,25 @@
 mod app;
 mod path_tracer;
 mod compute_shader;
+mod material_preview;
 
 use common_code::bvh;
+use common_code::bvh::{BVHBuilder, BVHLayout};
 use common_code::camera::Camera;
 use common_code::camera_controller::CameraController;
 use common_code::gpu_buffer;
 use common_code::gpu_structs;
 use common_code::gui;
+use common_code::light;
 use common_code::material;
+use common_code::medium;
 use common_code::parameters;
-use common_code::parameters::{RenderParameters, SamplingParameters};
+use common_code::parameters::{BVHParameters, RenderParameters, SamplingParameters};
 use common_code::scene;
+use common_code::sky;
+use common_code::spectrum;
 use common_code::sphere;
+use common_code::texture;
 use glam::Vec3;
 
 use crate::app::App;
@@ -24,10 +31,27 @@ fn main() -> Result<(), EventLoopError> {
     env_logger::init();
 
     let scene = Scene::book_one_final();
+    // let scene = Scene::cornell_box();
+    // let scene = Scene::textured();
+    // let scene = Scene::microfacet();
+    // let scene = Scene::principled();
+    // let scene = Scene::bumpy();
+    // let scene = Scene::layered();
+    // let scene = Scene::subsurface();
+    // let scene = Scene::nested();
+    // let scene = Scene::alpha();
+    // let scene = Scene::measured("gold-metallic-paint.binary").unwrap();
+    // let scene = Scene::thin_film();
+    // let scene = Scene::dispersion();
+    // scene.background = Background::Environment(
+    //     EnvironmentMap::load("assets/environment.hdr").expect("could not load the environment map"));
+    // scene.lights.push(Light::sun(Vec3::new(0.3, -1.0, -0.4), Vec3::splat(3.0)));
+    // scene.background = Background::Sky(SkyParameters::new(35.0, 20.0, 3.0));
     let camera = Camera::new(
         Vec3::new(0.0, 0.0, 1.0),       //look from
         Vec3::new(0.0, 0.0, -1.0));     //look at
     let camera = Camera::book_one_final_camera();
+    // let camera = Camera::cornell_box_camera();
     let camera_controller
         = CameraController::new(camera,
                                 20.0,
@@ -42,7 +66,8 @@ fn main() -> Result<(), EventLoopError> {
                                                       50,
                                                       1,
                                                       100);
-    let render_parameters = RenderParameters::new(camera_controller, sampling_parameters, screen_size);
+    let bvh_parameters = BVHParameters::new(BVHBuilder::Sah, None, BVHLayout::Full);
+    let render_parameters = RenderParameters::new(camera_controller, sampling_parameters, bvh_parameters, screen_size);
 
     let event_loop = EventLoop::new()?;
     event_loop.set_control_flow(ControlFlow::Poll);
@@ -0,0 +1,147 @@
+use crate::bvh::BVHTree;
+use crate::compute_shader::ComputeShader;
+use crate::gpu_structs::{GPUSamplingParameters, GPUSceneParameters};
+use crate::light::Light;
+use crate::material::Material;
+use crate::parameters::SamplingParameters;
+use crate::scene::Scene;
+use crate::sphere::Sphere;
+use crate::texture::{GPUTexture, Texture};
+use common_code::background::Background;
+use common_code::camera::Camera;
+use common_code::camera_controller::CameraController;
+use common_code::gpu_structs::GPUFrameBuffer;
+use common_code::measured::MeasuredBRDF;
+use common_code::projection_matrix::ProjectionMatrix;
+use glam::{Vec3, Vec4};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+
+// the thumbnails are square, and rendered in a single frame
+pub const PREVIEW_SIZE: u32 = 64;
+const PREVIEW_SAMPLES: u32 = 32;
+const PREVIEW_BOUNCES: u32 = 8;
+
+// small renders of the materials of a scene through the cpu tracer, each on a sphere over a
+// checker floor under the sky of the book scenes and a sun; a thread renders them one at a
+// time, so the event loop only picks up the finished thumbnails
+pub struct MaterialPreviews {
+    // rgba8 thumbnails with the indices of their materials, in the order they finish
+    receiver: Receiver<(usize, Vec<u8>)>,
+}
+
+impl MaterialPreviews {
+    pub fn new(scene: &Scene) -> Self {
+        let mut textures = GPUSceneParameters::get_gpu_textures(scene);
+        let checker = Texture::Checker { scale: 0.5, even: Vec3::splat(0.8), odd: Vec3::splat(0.2) };
+        textures.push(GPUTexture::from_texture(&checker, 0));
+
+        let renderer = PreviewRenderer {
+            measured: scene.measured.clone(),
+            textures,
+            texels: GPUSceneParameters::get_gpu_texels(scene),
+        };
+        let materials = scene.materials.clone();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for (idx, material) in materials.into_iter().enumerate() {
+                // nobody is left to show the rest once the app has closed
+                if sender.send((idx, renderer.render(material))).is_err() {
+                    break;
+                }
+            }
+        });
+        Self { receiver }
+    }
+
+    // the thumbnails finished since the last call, without waiting for the others
+    pub fn finished(&self) -> impl Iterator<Item = (usize, Vec<u8>)> + '_ {
+        self.receiver.try_iter()
+    }
+}
+
+// what the previews share with the scene, moved to the render thread
+struct PreviewRenderer {
+    measured: Vec<Arc<MeasuredBRDF>>,
+    // the textures of the scene, which the materials refer to by index, with the checker of
+    // the floor after them
+    textures: Vec<GPUTexture>,
+    texels: Vec<Vec4>,
+}
+
+impl PreviewRenderer {
+
+    fn render(&self, material: Material) -> Vec<u8> {
+        let floor = Material::Lambertian(Vec3::ONE).with_albedo_texture(self.textures.len() as u32 - 1);
+        let mut scene = Scene {
+            lights: vec![Light::sun(Vec3::new(-0.4, -1.0, -0.6), Vec3::splat(2.0))],
+            measured: self.measured.clone(),
+            ..Scene::from_spheres(vec![Sphere::new(Vec3::new(0.0, -1001.0, 0.0), 1000.0, 1),
+                                       Sphere::new(Vec3::ZERO, 1.0, 0)],
+                                  vec![material, floor],
+                                  Background::sky())
+        };
+        let mut bvh_tree = BVHTree::new(scene.spheres.len());
+        bvh_tree.build_bvh_tree(&mut scene.spheres);
+
+        let camera = Camera::new(Vec3::new(0.0, 1.0, 4.0), Vec3::new(0.0, -0.1, 0.0));
+        let camera_controller = CameraController::new(camera, 35.0, 0.0, 10.0, 0.1, 100.0, 4.0, 0.1);
+        let (z_near, z_far) = camera_controller.get_clip_planes();
+        let projection = ProjectionMatrix::new(camera_controller.vfov_rad(), 1.0, z_near, z_far).p_inv();
+        // every sample in one frame
+        let sampling_parameters = SamplingParameters::new(PREVIEW_SAMPLES, PREVIEW_BOUNCES, 1, PREVIEW_SAMPLES);
+
+        let compute_shader = ComputeShader::new(scene.spheres.clone(),
+                                                scene.materials.clone(),
+                                                scene.measured.clone(),
+                                                bvh_tree.nodes,
+                                                GPUSceneParameters::get_gpu_scene_params(&scene),
+                                                GPUSceneParameters::get_gpu_environment_map(&scene),
+                                                GPUSceneParameters::get_gpu_environment_distribution(&scene),
+                                                GPUSceneParameters::get_gpu_lights(&scene),
+                                                GPUSceneParameters::get_gpu_photometric_profiles(&scene),
+                                                self.textures.clone(),
+                                                self.texels.clone(),
+                                                camera_controller.get_GPU_camera(),
+                                                projection,
+                                                camera_controller.get_view_matrix(),
+                                                GPUSamplingParameters::get_gpu_sampling_params(&sampling_parameters),
+                                                GPUFrameBuffer::new(PREVIEW_SIZE, PREVIEW_SIZE, 1, 0),
+                                                PREVIEW_SIZE * PREVIEW_SIZE);
+
+        // with the same gamma as the display shader
+        compute_shader.render_frame((PREVIEW_SIZE, PREVIEW_SIZE)).iter().flat_map(|pixel| {
+            let color = (Vec3::from_array(*pixel) / PREVIEW_SAMPLES as f32).max(Vec3::ZERO).powf(0.5).min(Vec3::ONE);
+            let [r, g, b] = (color * 255.0).round().to_array().map(|c| c as u8);
+            [r, g, b, 255]
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_every_material_once() {
+        let scene = Scene::from_spheres(vec![Sphere::new(Vec3::ZERO, 1.0, 0)],
+            vec![Material::Lambertian(Vec3::new(0.8, 0.1, 0.1)), Material::Emissive(Vec3::ONE, 4.0)],
+            Background::Solid(Vec3::ZERO));
+        let previews = MaterialPreviews::new(&scene);
+        let center = (4 * (PREVIEW_SIZE * PREVIEW_SIZE / 2 + PREVIEW_SIZE / 2)) as usize;
+
+        // waits for the render thread, which hangs up once it is done
+        let thumbnails: Vec<(usize, Vec<u8>)> = previews.receiver.iter().collect();
+        assert_eq!(thumbnails.len(), 2);
+
+        let (idx, red) = &thumbnails[0];
+        assert_eq!((*idx, red.len()), (0, (4 * PREVIEW_SIZE * PREVIEW_SIZE) as usize));
+        let red = &red[center..center + 4];
+        assert!(red[0] > 2 * red[1] && red[0] > 2 * red[2], "{:?}", red);
+
+        let (idx, light) = &thumbnails[1];
+        assert_eq!((*idx, &light[center..center + 4]), (1, &[255u8, 255, 255, 255][..]));
+        assert!(previews.finished().next().is_none());
+    }
+}
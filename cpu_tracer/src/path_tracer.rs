@@ -1,10 +1,11 @@
 use crate::bvh::BVHTree;
 use crate::compute_shader::ComputeShader;
 use crate::gpu_buffer::GPUBuffer;
-use crate::gpu_structs::{GPUSamplingParameters};
+use crate::gpu_structs::{GPUSamplingParameters, GPUSceneParameters};
 use crate::gui::GUI;
 use crate::parameters::{RenderParameters, RenderProgress};
 use crate::scene::Scene;
+use common_code::background::Background;
 use common_code::camera_controller::{GPUCamera};
 use common_code::gpu_structs::GPUFrameBuffer;
 use common_code::projection_matrix::ProjectionMatrix;
@@ -75,15 +76,26 @@ impl PathTracer {
 
         // create the bvh_tree that corresponds to the scene
         let mut bvh_tree= BVHTree::new(scene.spheres.len());
-        bvh_tree.build_bvh_tree(&mut scene.spheres);
+        bvh_tree.build(&mut scene.spheres, rp.bvh_parameters());
         
         let spheres_buffer = scene.spheres.clone();
         let materials_buffer = scene.materials.clone();
+        let measured_buffer = scene.measured.clone();
         let bvh_buffer = bvh_tree.nodes;
+        let scene_parameters_buffer = GPUSceneParameters::get_gpu_scene_params(scene);
+        let environment_map_buffer = GPUSceneParameters::get_gpu_environment_map(scene);
+        let environment_distribution_buffer = GPUSceneParameters::get_gpu_environment_distribution(scene);
+        let lights_buffer = GPUSceneParameters::get_gpu_lights(scene);
+        let photometric_profiles_buffer = GPUSceneParameters::get_gpu_photometric_profiles(scene);
+        let textures_buffer = GPUSceneParameters::get_gpu_textures(scene);
+        let texels_buffer = GPUSceneParameters::get_gpu_texels(scene);
 
         // create the parameters bind group to interact with GPU during runtime
         // this will include the camera, and the sampling parameters
-        let render_parameters= rp.clone();
+        let mut render_parameters= rp.clone();
+        if let Background::Sky(sky) = &scene.background {
+            render_parameters.sky_parameters = Some(*sky);
+        }
         let camera_controller = render_parameters.camera_controller();
         let (width, height) = render_parameters.get_viewport();
         let ar = width as f32 / height as f32;
@@ -107,7 +119,15 @@ impl PathTracer {
         
         let compute_shader = ComputeShader::new(spheres_buffer,
                                                 materials_buffer,
+                                                measured_buffer,
                                                 bvh_buffer,
+                                                scene_parameters_buffer,
+                                                environment_map_buffer,
+                                                environment_distribution_buffer,
+                                                lights_buffer,
+                                                photometric_profiles_buffer,
+                                                textures_buffer,
+                                                texels_buffer,
                                                 camera_buffer,
                                                 projection_buffer,
                                                 view_buffer,
@@ -223,6 +243,13 @@ impl PathTracer {
         self.compute_shader.queue_proj(self.projection_buffer);
         self.compute_shader.queue_view(self.view_buffer);
 
+        // update the sky and its sun if the user moved them
+        if let Some(sky) = self.render_parameters.sky_parameters() {
+            if self.last_render_parameters.sky_parameters() != Some(sky) {
+                self.compute_shader.queue_sky(sky);
+            }
+        }
+
         self.render_progress.reset();
     }
 
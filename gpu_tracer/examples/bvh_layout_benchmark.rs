@@ -0,0 +1,111 @@
+use common_code::bvh::{BVHBuilder, BVHLayout, BVHTree};
+use common_code::camera::Camera;
+use common_code::camera_controller::CameraController;
+use common_code::parameters::{BVHParameters, RenderParameters, SamplingParameters};
+use common_code::scene::Scene;
+use gpu_tracer::{PathTracer, Queries, QueryResults};
+
+// renders the book one final scene off screen with each bvh layout and reports the
+// average megakernel time next to the size of the tree the kernel traverses
+// cargo run --release -p gpu_tracer --example bvh_layout_benchmark
+
+const WARMUP_FRAMES: u32 = 10;
+const TIMED_FRAMES: u32 = 100;
+
+fn main() {
+    env_logger::init();
+
+    let (device, queue) = pollster::block_on(request_device())
+        .expect("benchmark needs an adapter with timestamp queries");
+    let period = queue.get_timestamp_period() as f64;
+
+    // the scene is random, so build it once and share it between the layouts
+    let mut scene = Scene::book_one_final();
+    let mut bvh_tree = BVHTree::new(scene.spheres.len());
+    bvh_tree.build_bvh_tree(&mut scene.spheres);
+    let node_count = bvh_tree.nodes.len();
+
+    let screen_size = (1920, 1080);
+    let camera_controller
+        = CameraController::new(Camera::book_one_final_camera(),
+                                20.0,
+                                0.6,
+                                10.0,
+                                0.1,
+                                100.0,
+                                4.0,
+                                0.1);
+    // spp is set high enough that the render never completes during the benchmark
+    let sampling_parameters = SamplingParameters::new(1,
+                                                      50,
+                                                      1,
+                                                      100_000);
+
+    for layout in [BVHLayout::Full, BVHLayout::Quantized16, BVHLayout::Quantized8] {
+        let render_parameters = RenderParameters::new(camera_controller,
+                                                      sampling_parameters,
+                                                      BVHParameters::new(BVHBuilder::Sah, None, layout),
+                                                      screen_size);
+        let mut path_tracer = PathTracer::new(&device,
+                                              screen_size.0 * screen_size.1,
+                                              &mut scene,
+                                              &render_parameters)
+            .expect("failed to create path tracer");
+
+        let mut total_ms = 0.0;
+        for frame in 0..WARMUP_FRAMES + TIMED_FRAMES {
+            let mut queries = Queries::new(&device, QueryResults::NUM_QUERIES);
+            path_tracer.run_compute_kernel(&device, &queue, &mut queries);
+            let timestamps = queries.wait_for_results(&device);
+            if frame >= WARMUP_FRAMES {
+                total_ms += timestamps[1].wrapping_sub(timestamps[0]) as f64 * period / 1_000_000.0;
+            }
+        }
+
+        // the full layout reads both 32 byte children for every interior node visited,
+        // the quantized layouts read the one parent node instead
+        let bytes_per_visit = match layout {
+            BVHLayout::Full => 2 * size_of::<common_code::bvh::BVHNode>(),
+            _ => layout.node_size_bytes(),
+        };
+        println!("{:?}: {:.3} ms per frame, {} nodes, {} KiB tree, {} bytes read per interior node",
+                 layout,
+                 total_ms / TIMED_FRAMES as f64,
+                 node_count,
+                 node_count * layout.node_size_bytes() / 1024,
+                 bytes_per_visit);
+    }
+}
+
+async fn request_device() -> Option<(wgpu::Device, wgpu::Queue)> {
+    let instance = wgpu::Instance::new(
+        wgpu::InstanceDescriptor {
+            backends: wgpu::Backends::PRIMARY,
+            ..Default::default()
+        }
+    );
+
+    let adapter = instance.request_adapter(
+        &wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            compatible_surface: None,
+            force_fallback_adapter: false,
+        }
+    ).await?;
+
+    if !adapter.features().contains(wgpu::Features::TIMESTAMP_QUERY) {
+        return None;
+    }
+
+    let required_limits = PathTracer::required_limits(&adapter)
+        .unwrap_or_else(|error| panic!("{}", error));
+    adapter.request_device(
+        &wgpu::DeviceDescriptor {
+            required_features: wgpu::Features::TIMESTAMP_QUERY,
+            required_limits,
+            label: None,
+            memory_hints: Default::default(),
+        },
+        None,
+    ).await.ok()
+}
@@ -208,13 +208,12 @@ impl<'a> WgpuState<'a> {
         //     println!("Adapter does not support timestamp queries within encoders.");
         // }
 
+        let required_limits = PathTracer::required_limits(&adapter)
+            .unwrap_or_else(|error| panic!("{}", error));
         let (device, queue) = adapter.request_device(
             &wgpu::DeviceDescriptor {
                 required_features: features, // wgpu::Features::empty(),
-                required_limits: wgpu::Limits {
-                    max_storage_buffer_binding_size: 512_u32 << 20,
-                    ..Default::default()
-                },
+                required_limits,
                 label: None,
                 memory_hints: Default::default(),
             },
@@ -0,0 +1,508 @@
+use crate::gpu_buffer::GPUBuffer;
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+use wgpu::{BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingType, Buffer, BufferBindingType, BufferUsages, CommandEncoder, ComputePipeline, Device, Maintain, MapMode, ShaderModule, ShaderStages};
+
+const WORKGROUP_SIZE: u32 = 256;
+// 30 bit morton codes sorted 4 bits at a time
+const SORT_PASSES: u32 = 8;
+
+// builds a bvh over the spheres on the GPU and writes it to the bvh buffer in the same
+// layout as BVHTree, so the megakernel can traverse it as it is; each leaf holds one
+// sphere and refers to it by its index in the spheres buffer, which is not reordered
+pub struct LBVH {
+    num_prims: u32,
+    scene_bounds: Buffer,
+    depth_buffer: Buffer,
+    build_bind_group: BindGroup,
+    sort_bind_groups: Vec<BindGroup>,
+    reset_pipeline: ComputePipeline,
+    scene_bounds_pipeline: ComputePipeline,
+    morton_codes_pipeline: ComputePipeline,
+    count_digits_pipeline: ComputePipeline,
+    scan_histogram_pipeline: ComputePipeline,
+    scatter_pipeline: ComputePipeline,
+    hierarchy_pipeline: ComputePipeline,
+    propagate_bounds_pipeline: ComputePipeline,
+    write_bounds_pipeline: ComputePipeline,
+    tree_depth_pipeline: ComputePipeline,
+}
+
+impl LBVH {
+    // bvh_buffer has to hold 2 * num_prims nodes
+    pub fn new(device: &Device, spheres_buffer: &GPUBuffer, bvh_buffer: &GPUBuffer, num_prims: u32) -> Self {
+        let num_blocks = num_prims.div_ceil(WORKGROUP_SIZE);
+
+        // the depth of the tree is copied out of the word after the scene bounds
+        let scene_bounds = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("lbvh scene bounds"),
+            size: 4 * 7,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let depth_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("lbvh depth buffer"),
+            size: 4,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let node_bounds = Self::storage_buffer(device, 12 * num_prims, "lbvh node bounds");
+        let morton_codes = Self::storage_buffer(device, num_prims, "lbvh morton codes");
+        let prim_indices = Self::storage_buffer(device, num_prims, "lbvh primitive indices");
+        let sorted_morton_codes = Self::storage_buffer(device, num_prims, "lbvh sort keys");
+        let sorted_prim_indices = Self::storage_buffer(device, num_prims, "lbvh sort values");
+        let leaf_slots = Self::storage_buffer(device, num_prims, "lbvh leaf slots");
+        let internal_slots = Self::storage_buffer(device, num_prims, "lbvh internal slots");
+        let histogram = Self::storage_buffer(device, 16 * num_blocks, "lbvh radix histogram");
+        let build_parameters = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("lbvh build parameters"),
+            contents: bytemuck::cast_slice(&[num_prims, num_blocks, 0, 0]),
+            usage: BufferUsages::UNIFORM,
+        });
+
+        let build_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("lbvh build bind group layout"),
+            entries: &[spheres_buffer.layout(ShaderStages::COMPUTE, true),
+                Self::storage_entry(1, false),
+                bvh_buffer.layout(ShaderStages::COMPUTE, false),
+                Self::storage_entry(3, false),
+                Self::storage_entry(4, false),
+                Self::storage_entry(5, false),
+                Self::storage_entry(6, false),
+                Self::storage_entry(7, false),
+                Self::uniform_entry(8)],
+        });
+        let build_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("lbvh build bind group"),
+            layout: &build_bind_group_layout,
+            entries: &[spheres_buffer.binding(),
+                Self::entry(1, &scene_bounds),
+                bvh_buffer.binding(),
+                Self::entry(3, &node_bounds),
+                Self::entry(4, &morton_codes),
+                Self::entry(5, &prim_indices),
+                Self::entry(6, &leaf_slots),
+                Self::entry(7, &internal_slots),
+                Self::entry(8, &build_parameters)],
+        });
+
+        // every sort pass has its own bind group, with its own shift, ping-ponging between
+        // the two pairs of buffers; with an even number of passes the result ends up back
+        // in the buffers the hierarchy reads
+        let sort_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("lbvh sort bind group layout"),
+            entries: &[Self::storage_entry(0, true),
+                Self::storage_entry(1, true),
+                Self::storage_entry(2, false),
+                Self::storage_entry(3, false),
+                Self::storage_entry(4, false),
+                Self::uniform_entry(5)],
+        });
+        let sort_bind_groups = (0..SORT_PASSES).map(|pass| {
+            let (keys_in, values_in, keys_out, values_out) = if pass % 2 == 0 {
+                (&morton_codes, &prim_indices, &sorted_morton_codes, &sorted_prim_indices)
+            } else {
+                (&sorted_morton_codes, &sorted_prim_indices, &morton_codes, &prim_indices)
+            };
+            let sort_parameters = device.create_buffer_init(&BufferInitDescriptor {
+                label: Some("lbvh sort parameters"),
+                contents: bytemuck::cast_slice(&[num_prims, num_blocks, 4 * pass, 0]),
+                usage: BufferUsages::UNIFORM,
+            });
+            device.create_bind_group(&BindGroupDescriptor {
+                label: Some("lbvh sort bind group"),
+                layout: &sort_bind_group_layout,
+                entries: &[Self::entry(0, keys_in),
+                    Self::entry(1, values_in),
+                    Self::entry(2, keys_out),
+                    Self::entry(3, values_out),
+                    Self::entry(4, &histogram),
+                    Self::entry(5, &sort_parameters)],
+            })
+        }).collect();
+
+        let build_shader = device.create_shader_module(
+            wgpu::include_wgsl!("shaders/lbvh.wgsl")
+        );
+        let sort_shader = device.create_shader_module(
+            wgpu::include_wgsl!("shaders/radix_sort.wgsl")
+        );
+
+        let pipeline = |layout: &BindGroupLayout, module: &ShaderModule, entry_point: &str| {
+            let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("lbvh pipeline layout"),
+                bind_group_layouts: &[layout],
+                push_constant_ranges: &[],
+            });
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&pipeline_layout),
+                module,
+                entry_point,
+                compilation_options: Default::default(),
+                cache: None,
+            })
+        };
+
+        Self {
+            num_prims,
+            reset_pipeline: pipeline(&build_bind_group_layout, &build_shader, "reset"),
+            scene_bounds_pipeline: pipeline(&build_bind_group_layout, &build_shader, "scene_bounds"),
+            morton_codes_pipeline: pipeline(&build_bind_group_layout, &build_shader, "morton_codes"),
+            count_digits_pipeline: pipeline(&sort_bind_group_layout, &sort_shader, "count_digits"),
+            scan_histogram_pipeline: pipeline(&sort_bind_group_layout, &sort_shader, "scan_histogram"),
+            scatter_pipeline: pipeline(&sort_bind_group_layout, &sort_shader, "scatter"),
+            hierarchy_pipeline: pipeline(&build_bind_group_layout, &build_shader, "hierarchy"),
+            propagate_bounds_pipeline: pipeline(&build_bind_group_layout, &build_shader, "propagate_bounds"),
+            write_bounds_pipeline: pipeline(&build_bind_group_layout, &build_shader, "write_bounds"),
+            tree_depth_pipeline: pipeline(&build_bind_group_layout, &build_shader, "tree_depth"),
+            scene_bounds,
+            depth_buffer,
+            build_bind_group,
+            sort_bind_groups,
+        }
+    }
+
+    // records the whole build; it only reads the spheres buffer, so a scene whose spheres
+    // move can be rebuilt every frame ahead of the megakernel
+    pub fn build(&self, encoder: &mut CommandEncoder) {
+        if self.num_prims == 0 {
+            return;
+        }
+        let prim_groups = self.num_prims.div_ceil(WORKGROUP_SIZE);
+        let node_groups = (2 * self.num_prims).div_ceil(WORKGROUP_SIZE);
+
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("lbvh build pass"),
+            timestamp_writes: None,
+        });
+
+        compute_pass.set_bind_group(0, &self.build_bind_group, &[]);
+        compute_pass.set_pipeline(&self.reset_pipeline);
+        compute_pass.dispatch_workgroups(node_groups, 1, 1);
+        compute_pass.set_pipeline(&self.scene_bounds_pipeline);
+        compute_pass.dispatch_workgroups(prim_groups, 1, 1);
+        compute_pass.set_pipeline(&self.morton_codes_pipeline);
+        compute_pass.dispatch_workgroups(prim_groups, 1, 1);
+
+        for sort_bind_group in &self.sort_bind_groups {
+            compute_pass.set_bind_group(0, sort_bind_group, &[]);
+            compute_pass.set_pipeline(&self.count_digits_pipeline);
+            compute_pass.dispatch_workgroups(prim_groups, 1, 1);
+            compute_pass.set_pipeline(&self.scan_histogram_pipeline);
+            compute_pass.dispatch_workgroups(1, 1, 1);
+            compute_pass.set_pipeline(&self.scatter_pipeline);
+            compute_pass.dispatch_workgroups(prim_groups, 1, 1);
+        }
+
+        compute_pass.set_bind_group(0, &self.build_bind_group, &[]);
+        compute_pass.set_pipeline(&self.hierarchy_pipeline);
+        compute_pass.dispatch_workgroups(prim_groups, 1, 1);
+        compute_pass.set_pipeline(&self.propagate_bounds_pipeline);
+        compute_pass.dispatch_workgroups(prim_groups, 1, 1);
+        compute_pass.set_pipeline(&self.write_bounds_pipeline);
+        compute_pass.dispatch_workgroups(node_groups, 1, 1);
+        compute_pass.set_pipeline(&self.tree_depth_pipeline);
+        compute_pass.dispatch_workgroups(prim_groups, 1, 1);
+        drop(compute_pass);
+
+        encoder.copy_buffer_to_buffer(&self.scene_bounds, 4 * 6, &self.depth_buffer, 0, 4);
+    }
+
+    // the depth of the last tree built, counting the root as the first level; it waits for
+    // the GPU, so the build has to be submitted first
+    pub fn read_depth(&self, device: &Device) -> u32 {
+        if self.num_prims == 0 {
+            return 0;
+        }
+        let slice = self.depth_buffer.slice(..);
+        slice.map_async(MapMode::Read, |result| result.unwrap());
+        device.poll(Maintain::Wait);
+        let depth = bytemuck::cast_slice::<u8, u32>(&slice.get_mapped_range())[0];
+        self.depth_buffer.unmap();
+        depth
+    }
+
+    fn storage_buffer(device: &Device, num_words: u32, label: &str) -> Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: 4 * num_words.max(1) as u64,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn storage_entry(binding: u32, read_only: bool) -> BindGroupLayoutEntry {
+        BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        }
+    }
+
+    fn uniform_entry(binding: u32) -> BindGroupLayoutEntry {
+        BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        }
+    }
+
+    fn entry(binding: u32, buffer: &Buffer) -> BindGroupEntry<'_> {
+        BindGroupEntry {
+            binding,
+            resource: buffer.as_entire_binding(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common_code::bvh::{BVHNode, BVHTree, TRAVERSAL_STACK_SIZE};
+    use common_code::scene::Scene;
+    use common_code::sphere::Sphere;
+    use glam::Vec3;
+
+    fn fallback_device() -> Option<(Device, wgpu::Queue)> {
+        let instance = wgpu::Instance::default();
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            force_fallback_adapter: true,
+            ..Default::default()
+        }))?;
+        pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor {
+            label: None,
+            required_features: wgpu::Features::empty(),
+            required_limits: adapter.limits(),
+            memory_hints: Default::default(),
+        }, None)).ok()
+    }
+
+    // builds the tree over the spheres and reads it back with the depth the build reports
+    fn gpu_build(device: &Device, queue: &wgpu::Queue, spheres: &[Sphere]) -> (Vec<BVHNode>, u32) {
+        let num_prims = spheres.len() as u32;
+        let spheres_buffer = GPUBuffer::new_from_bytes(device, BufferUsages::STORAGE, 0u32,
+                                                       bytemuck::cast_slice(spheres),
+                                                       Some("spheres buffer"));
+        let size = (2 * num_prims as usize * size_of::<BVHNode>()) as u64;
+        let bvh_buffer = GPUBuffer::new(device, BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+                                        size, 2u32, Some("bvh_tree buffer"));
+        let staging_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("bvh_tree staging buffer"),
+            size,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let lbvh = LBVH::new(device, &spheres_buffer, &bvh_buffer, num_prims);
+        let mut encoder = device.create_command_encoder(&Default::default());
+        lbvh.build(&mut encoder);
+        encoder.copy_buffer_to_buffer(bvh_buffer.name(), 0, &staging_buffer, 0, size);
+        queue.submit(Some(encoder.finish()));
+
+        staging_buffer.slice(..).map_async(MapMode::Read, |result| result.unwrap());
+        device.poll(Maintain::Wait);
+        let nodes: Vec<BVHNode> = bytemuck::cast_slice(&staging_buffer.slice(..).get_mapped_range()).to_vec();
+        (nodes, lbvh.read_depth(device))
+    }
+
+    // the depth of the deepest leaf, counting the root as the first level
+    fn depth(nodes: &[BVHNode]) -> u32 {
+        let mut max_depth = 0;
+        let mut stack = vec![(0usize, 1u32)];
+        while let Some((idx, depth)) = stack.pop() {
+            max_depth = max_depth.max(depth);
+            if nodes[idx].prim_count == 0 {
+                stack.push((nodes[idx].left_first as usize, depth + 1));
+                stack.push((nodes[idx].left_first as usize + 1, depth + 1));
+            }
+        }
+        max_depth
+    }
+
+    fn contains(outer: &BVHNode, inner_min: glam::Vec3, inner_max: glam::Vec3) -> bool {
+        outer.aabb_min.cmple(inner_min).all() && outer.aabb_max.cmpge(inner_max).all()
+    }
+
+    // the same as expandBits in lbvh.wgsl
+    fn expand_bits(v: u32) -> u32 {
+        let mut x = v & 0x3ff;
+        x = (x | (x << 16)) & 0x030000ff;
+        x = (x | (x << 8)) & 0x0300f00f;
+        x = (x | (x << 4)) & 0x030c30c3;
+        x = (x | (x << 2)) & 0x09249249;
+        x
+    }
+
+    // the same as the morton_codes kernel, for a point already scaled to the scene bounds
+    fn morton_code(p: glam::Vec3) -> u32 {
+        let q = (p.clamp(glam::Vec3::ZERO, glam::Vec3::ONE) * 1024.0).min(glam::Vec3::splat(1023.0)).as_uvec3();
+        (expand_bits(q.x) << 2) | (expand_bits(q.y) << 1) | expand_bits(q.z)
+    }
+
+    // the same as delta in lbvh.wgsl
+    fn delta(codes: &[u32], i: i32, j: i32) -> i32 {
+        if j < 0 || j >= codes.len() as i32 {
+            return -1;
+        }
+        let (a, b) = (codes[i as usize], codes[j as usize]);
+        if a == b {
+            return 32 + (i as u32 ^ j as u32).leading_zeros() as i32;
+        }
+        (a ^ b).leading_zeros() as i32
+    }
+
+    // the range and split of internal node i, as the hierarchy kernel finds them
+    fn karras_node(codes: &[u32], i: i32) -> (usize, usize, usize) {
+        let d = if delta(codes, i, i + 1) - delta(codes, i, i - 1) > 0 { 1 } else { -1 };
+        let delta_min = delta(codes, i, i - d);
+        let mut l_max = 2;
+        while delta(codes, i, i + l_max * d) > delta_min {
+            l_max *= 2;
+        }
+        let mut l = 0;
+        let mut t = l_max / 2;
+        while t >= 1 {
+            if delta(codes, i, i + (l + t) * d) > delta_min {
+                l += t;
+            }
+            t /= 2;
+        }
+        let j = i + l * d;
+
+        let delta_node = delta(codes, i, j);
+        let mut s = 0;
+        let mut t = l;
+        loop {
+            t = (t + 1) / 2;
+            if delta(codes, i, i + (s + t) * d) > delta_node {
+                s += t;
+            }
+            if t <= 1 {
+                break;
+            }
+        }
+        ((i.min(j)) as usize, (i.max(j)) as usize, (i + s * d + d.min(0)) as usize)
+    }
+
+    #[test]
+    fn morton_codes_interleave_the_axes() {
+        assert_eq!(expand_bits(0x3ff), 0x09249249);
+        assert_eq!(expand_bits(0b101), 0b1000001);
+        assert_eq!(morton_code(glam::Vec3::new(1.0, 0.0, 0.0)), 0x24924924);
+        assert_eq!(morton_code(glam::Vec3::new(0.0, 1.0, 0.0)), 0x12492492);
+        assert_eq!(morton_code(glam::Vec3::new(0.0, 0.0, 1.0)), 0x09249249);
+        assert_eq!(morton_code(glam::Vec3::ONE), 0x3fffffff);
+        // the first cell along x comes after every cell of the lower half in x
+        assert!(morton_code(glam::Vec3::new(0.5, 0.0, 0.0)) > morton_code(glam::Vec3::new(0.49, 0.99, 0.99)));
+    }
+
+    #[test]
+    fn karras_splits_form_a_binary_tree_over_the_sorted_keys() {
+        // points on a coarse grid so that many of the codes are equal
+        let mut state = 7u32;
+        let mut codes: Vec<u32> = (0..1000).map(|_| {
+            let mut coordinate = || {
+                state = state.wrapping_mul(747796405).wrapping_add(2891336453);
+                (state >> 28) as f32 / 16.0
+            };
+            morton_code(glam::Vec3::new(coordinate(), coordinate(), coordinate()))
+        }).collect();
+        codes.sort_unstable();
+        let n = codes.len();
+
+        // every internal node but the root and every leaf has exactly one parent, and the
+        // children of a node split its range in two
+        let mut internal_parents = vec![0u32; n - 1];
+        let mut leaf_parents = vec![0u32; n];
+        let ranges: Vec<_> = (0..n as i32 - 1).map(|i| karras_node(&codes, i)).collect();
+        assert_eq!((ranges[0].0, ranges[0].1), (0, n - 1));
+        for &(first, last, split) in &ranges {
+            assert!(first <= split && split < last);
+            for (child, child_first, child_last) in [(split, first, split), (split + 1, split + 1, last)] {
+                if child_first == child_last {
+                    leaf_parents[child] += 1;
+                } else {
+                    internal_parents[child] += 1;
+                    assert_eq!((ranges[child].0, ranges[child].1), (child_first, child_last));
+                }
+            }
+        }
+        assert_eq!(internal_parents[0], 0);
+        assert!(internal_parents[1..].iter().all(|&count| count == 1));
+        assert!(leaf_parents.iter().all(|&count| count == 1));
+    }
+
+    #[test]
+    fn gpu_tree_covers_the_same_spheres_as_the_cpu_tree() {
+        // skipped where there is no fallback adapter, such as llvmpipe
+        let Some((device, queue)) = fallback_device() else {
+            return;
+        };
+
+        let scene = Scene::book_one_final();
+        let num_prims = scene.spheres.len();
+        let mut cpu_spheres = scene.spheres.clone();
+        let mut cpu_tree = BVHTree::new(cpu_spheres.len());
+        cpu_tree.build_bvh_tree(&mut cpu_spheres);
+
+        let (nodes, reported_depth) = gpu_build(&device, &queue, &scene.spheres);
+
+        // both trees bound the whole scene
+        assert_eq!(nodes[0].aabb_min, cpu_tree.nodes[0].aabb_min);
+        assert_eq!(nodes[0].aabb_max, cpu_tree.nodes[0].aabb_max);
+
+        // every sphere sits in exactly one leaf, and every box holds what is below it
+        let mut seen = vec![0u32; num_prims];
+        let mut stack = vec![0usize];
+        while let Some(idx) = stack.pop() {
+            let node = &nodes[idx];
+            if node.prim_count > 0 {
+                assert_eq!(node.prim_count, 1);
+                let (sphere_min, sphere_max) = scene.spheres[node.left_first as usize].get_aabb();
+                assert!(contains(node, sphere_min, sphere_max));
+                seen[node.left_first as usize] += 1;
+            } else {
+                for child in node.left_first as usize..node.left_first as usize + 2 {
+                    assert!(contains(node, nodes[child].aabb_min, nodes[child].aabb_max));
+                    stack.push(child);
+                }
+            }
+        }
+        assert!(seen.iter().all(|&count| count == 1));
+
+        assert_eq!(reported_depth, depth(&nodes));
+        assert!(reported_depth as usize <= TRAVERSAL_STACK_SIZE);
+    }
+
+    #[test]
+    fn gpu_tree_reports_a_depth_past_the_traversal_stack() {
+        let Some((device, queue)) = fallback_device() else {
+            return;
+        };
+
+        // one sphere for every bit of the morton codes, each splitting off its own level,
+        // over a cluster of spheres that share the lowest code
+        let cell = |bit: u32| {
+            let mut center = Vec3::ZERO;
+            center[2 - bit as usize % 3] = (1u32 << (bit / 3)) as f32;
+            center
+        };
+        let mut spheres: Vec<Sphere> = (0..30).map(|bit| Sphere::new(cell(bit), 1e-4, 0)).collect();
+        spheres.extend((0..16).map(|_i| Sphere::new(Vec3::ZERO, 1e-4, 0)));
+        spheres.push(Sphere::new(Vec3::splat(1023.0), 1e-4, 0));
+
+        let (nodes, reported_depth) = gpu_build(&device, &queue, &spheres);
+        assert_eq!(reported_depth, depth(&nodes));
+        assert!(reported_depth as usize > TRAVERSAL_STACK_SIZE, "{}", reported_depth);
+    }
+}
@@ -1,11 +1,14 @@
 mod app;
 mod path_tracer;
+mod lbvh;
 
 mod query_gpu;
 mod gpu_timing;
 
 pub use app::App;
 pub use path_tracer::PathTracer;
+pub use lbvh::LBVH;
+pub use query_gpu::{Queries, QueryResults};
 
 use common_code::gpu_structs;
 use common_code::projection_matrix;
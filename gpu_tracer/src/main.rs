@@ -1,9 +1,10 @@
 use glam::Vec3;
 use winit::error::EventLoopError;
 use winit::event_loop::{ControlFlow, EventLoop};
+use common_code::bvh::{BVHBuilder, BVHLayout};
 use common_code::camera::Camera;
 use common_code::camera_controller::CameraController;
-use common_code::parameters::{RenderParameters, SamplingParameters};
+use common_code::parameters::{BVHParameters, RenderParameters, SamplingParameters};
 use common_code::scene::Scene;
 use gpu_tracer::App;
 
@@ -11,9 +12,25 @@ fn main() -> Result<(), EventLoopError> {
     env_logger::init();
 
     let scene = Scene::book_one_final();
+    // let scene = Scene::cornell_box();
+    // let scene = Scene::textured();
+    // let scene = Scene::microfacet();
+    // let scene = Scene::principled();
+    // let scene = Scene::bumpy();
+    // let scene = Scene::layered();
+    // let scene = Scene::subsurface();
+    // let scene = Scene::nested();
+    // let scene = Scene::alpha();
+    // let scene = Scene::thin_film();
+    // let scene = Scene::dispersion();
+    // scene.background = Background::Environment(
+    //     EnvironmentMap::load("assets/environment.hdr").expect("could not load the environment map"));
+    // scene.lights.push(Light::sun(Vec3::new(0.3, -1.0, -0.4), Vec3::splat(3.0)));
+    // scene.background = Background::Sky(SkyParameters::new(35.0, 20.0, 3.0));
     // let camera = Camera::new(Vec3::new(0.0, 0.0, 1.0),
     //                          Vec3::new(0.0, 0.0, -1.0));
     let camera = Camera::book_one_final_camera();
+    // let camera = Camera::cornell_box_camera();
     let camera_controller
         = CameraController::new(camera,
                                 20.0,
@@ -28,8 +45,13 @@ fn main() -> Result<(), EventLoopError> {
                                                       50,
                                                       1,
                                                       500);
+    // BVHBuilder::Sbvh { overlap_threshold: 1e-5 } splits the large ground sphere spatially;
+    // BVHBuilder::GpuLbvh builds the tree on the GPU instead (full precision layout only);
+    // Some(OptimizationBudget::new(iterations, duration)) runs a reinsertion pass after the build;
+    // BVHLayout::Quantized8 and Quantized16 trade a little traversal precision for bandwidth
+    let bvh_parameters = BVHParameters::new(BVHBuilder::Sah, None, BVHLayout::Full);
     let render_parameters
-        = RenderParameters::new(camera_controller, sampling_parameters, screen_size);
+        = RenderParameters::new(camera_controller, sampling_parameters, bvh_parameters, screen_size);
     
     let event_loop = EventLoop::new()?;
     event_loop.set_control_flow(ControlFlow::Poll);
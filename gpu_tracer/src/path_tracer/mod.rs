@@ -1,13 +1,19 @@
-use crate::bvh::BVHTree;
+use std::collections::HashMap;
+use crate::bvh::{BVHBuilder, BVHLayout, BVHNode, BVHTree, TRAVERSAL_STACK_SIZE};
 use crate::gpu_buffer::GPUBuffer;
-use crate::gpu_structs::{GPUSamplingParameters};
+use crate::gpu_structs::{GPUSamplingParameters, GPUSceneParameters};
 use crate::gui::GUI;
+use crate::lbvh::LBVH;
 use crate::parameters::{RenderParameters, RenderProgress};
 use crate::projection_matrix::ProjectionMatrix;
 use crate::query_gpu::Queries;
 use crate::scene::Scene;
+use common_code::background::Background;
 use common_code::camera_controller::CameraController;
-use wgpu::{BindGroup, BindGroupDescriptor, BindGroupLayoutDescriptor, BufferAddress, BufferUsages, ComputePassTimestampWrites, Device, Queue, RenderPipeline, ShaderStages, Surface, TextureFormat};
+use common_code::light::{GPULight, Light};
+use common_code::spectrum::RGBToSpectrumTable;
+use common_code::sphere::Sphere;
+use wgpu::{Adapter, BindGroup, BindGroupDescriptor, BindGroupLayoutDescriptor, BufferAddress, BufferUsages, ComputePassTimestampWrites, Device, Limits, PipelineCompilationOptions, Queue, RenderPipeline, ShaderStages, Surface, TextureFormat};
 use winit::event::WindowEvent;
 
 pub struct PathTracer {
@@ -17,6 +23,19 @@ pub struct PathTracer {
     spheres_buffer: GPUBuffer,
     materials_buffer: GPUBuffer,
     bvh_buffer:  GPUBuffer,
+    bvh_quantized_buffer: GPUBuffer,
+    scene_parameters_buffer: GPUBuffer,
+    // kept to rewrite the sky and its sun when the user moves them
+    scene_parameters: GPUSceneParameters,
+    lights: Vec<GPULight>,
+    environment_map_buffer: GPUBuffer,
+    environment_distribution_buffer: GPUBuffer,
+    lights_buffer: GPUBuffer,
+    textures_buffer: GPUBuffer,
+    texels_buffer: GPUBuffer,
+    rgb_to_spectrum_buffer: GPUBuffer,
+    lbvh: Option<LBVH>,
+    rebuild_bvh: bool,
     scene_bind_group: BindGroup,
     camera_buffer: GPUBuffer,
     sampling_parameters_buffer: GPUBuffer,
@@ -32,6 +51,26 @@ pub struct PathTracer {
 }
 
 impl PathTracer {
+    // the device limits the megakernel needs, shared by everything that creates one, or
+    // which of them the adapter falls short of
+    pub fn required_limits(adapter: &Adapter) -> Result<Limits, String> {
+        let limits = Limits {
+            max_storage_buffer_binding_size: 512_u32 << 20,
+            // the megakernel binds the scene, its lights, its textures and the rgb to
+            // spectrum table, next to the image
+            max_storage_buffers_per_shader_stage: 11,
+            ..Default::default()
+        };
+        let mut missing = Vec::new();
+        limits.check_limits_with_fail_fn(&adapter.limits(), false, |name, required, allowed| {
+            missing.push(format!("{} is {}, the megakernel needs {}", name, allowed, required));
+        });
+        if !missing.is_empty() {
+            return Err(format!("the adapter cannot run the megakernel: {}", missing.join(", ")));
+        }
+        Ok(limits)
+    }
+
     pub fn new(device: &Device,
                max_window_size: u32,
                scene: &mut Scene,
@@ -69,22 +108,101 @@ impl PathTracer {
             entries: &[image_buffer.binding(), frame_buffer.binding()],
         });
         
-        // create the scene and the bvh_tree that corresponds to it
+        // create the scene and the bvh_tree that corresponds to it; the GPU builder fills the
+        // bvh buffer on the first frame and leaves the spheres in place, while the CPU builders
+        // may reorder or duplicate them, so they run before the spheres are uploaded
+        let bvh_layout = rp.bvh_parameters().layout;
+        let use_gpu_builder = rp.bvh_parameters().builder == BVHBuilder::GpuLbvh;
         let mut bvh_tree= BVHTree::new(scene.spheres.len());
-        bvh_tree.build_bvh_tree(&mut scene.spheres);
+        if use_gpu_builder {
+            assert_eq!(bvh_layout, BVHLayout::Full, "the GPU builder only writes the full precision layout");
+        } else {
+            bvh_tree.build(&mut scene.spheres, rp.bvh_parameters());
+        }
         
         let spheres_buffer = GPUBuffer::new_from_bytes(device, BufferUsages::STORAGE,
                                                 0u32,
                                                 bytemuck::cast_slice(scene.spheres.as_slice()),
                                                 Some("spheres buffer"));
+        let materials = GPUSceneParameters::get_gpu_materials(scene);
         let materials_buffer = GPUBuffer::new_from_bytes(device, BufferUsages::STORAGE,
                                                   1u32,
-                                                  bytemuck::cast_slice(scene.materials.as_slice()),
+                                                  bytemuck::cast_slice(materials.as_slice()),
                                                   Some("materials buffer"));
-        let bvh_buffer = GPUBuffer::new_from_bytes(device, BufferUsages::STORAGE,
-                                                  2u32,
-                                                  bytemuck::cast_slice(bvh_tree.nodes.as_slice()),
-                                                  Some("bvh_tree buffer"));
+        let (bvh_buffer, lbvh) = if use_gpu_builder {
+            let num_prims = scene.spheres.len() as u32;
+            let bvh_buffer = GPUBuffer::new(device, BufferUsages::STORAGE,
+                                            (2 * num_prims as usize * size_of::<BVHNode>()) as BufferAddress,
+                                            2u32,
+                                            Some("bvh_tree buffer"));
+            let lbvh = LBVH::new(device, &spheres_buffer, &bvh_buffer, num_prims);
+            (bvh_buffer, Some(lbvh))
+        } else {
+            // only the layout the shader is built for is uploaded; the other gets a placeholder
+            let placeholder = [BVHNode::default()];
+            let nodes = match bvh_layout {
+                BVHLayout::Full => bvh_tree.nodes.as_slice(),
+                _ => placeholder.as_slice(),
+            };
+            let bvh_buffer = GPUBuffer::new_from_bytes(device, BufferUsages::STORAGE,
+                                                      2u32,
+                                                      bytemuck::cast_slice(nodes),
+                                                      Some("bvh_tree buffer"));
+            (bvh_buffer, None)
+        };
+
+        let bvh_quantized = match bvh_layout {
+            BVHLayout::Full => vec![0u32; 4],
+            _ => bvh_tree.quantize(bvh_layout),
+        };
+        let bvh_quantized_buffer = GPUBuffer::new_from_bytes(device, BufferUsages::STORAGE,
+                                                  3u32,
+                                                  bytemuck::cast_slice(bvh_quantized.as_slice()),
+                                                  Some("quantized bvh_tree buffer"));
+        let scene_parameters = GPUSceneParameters::get_gpu_scene_params(scene);
+        let scene_parameters_buffer = GPUBuffer::new_from_bytes(device, BufferUsages::UNIFORM,
+                                                  4u32,
+                                                  bytemuck::cast_slice(&[scene_parameters]),
+                                                  Some("scene parameters buffer"));
+        let environment_map: Vec<[f32; 4]> = GPUSceneParameters::get_gpu_environment_map(scene)
+            .iter().map(|texel| texel.to_array()).collect();
+        let environment_map_buffer = GPUBuffer::new_from_bytes(device, BufferUsages::STORAGE,
+                                                  5u32,
+                                                  bytemuck::cast_slice(environment_map.as_slice()),
+                                                  Some("environment map buffer"));
+        let environment_distribution_buffer = GPUBuffer::new_from_bytes(device, BufferUsages::STORAGE,
+                                                  6u32,
+                                                  bytemuck::cast_slice(GPUSceneParameters::get_gpu_environment_distribution(scene).as_slice()),
+                                                  Some("environment distribution buffer"));
+        if scene.lights.iter().any(|light| matches!(light, Light::Photometric { .. })) {
+            log::warn!("photometric lights are only traced by the CPU tracer, they will be dark here");
+        }
+        // a scene without lights still needs something to bind
+        let mut lights = GPUSceneParameters::get_gpu_lights(scene);
+        if lights.is_empty() {
+            lights.push(GPULight::emissive_sphere(0));
+        }
+        let lights_buffer = GPUBuffer::new_from_bytes(device, BufferUsages::STORAGE,
+                                                  7u32,
+                                                  bytemuck::cast_slice(lights.as_slice()),
+                                                  Some("lights buffer"));
+        let textures_buffer = GPUBuffer::new_from_bytes(device, BufferUsages::STORAGE,
+                                                  8u32,
+                                                  bytemuck::cast_slice(GPUSceneParameters::get_gpu_textures(scene).as_slice()),
+                                                  Some("textures buffer"));
+        let texels: Vec<[f32; 4]> = GPUSceneParameters::get_gpu_texels(scene)
+            .iter().map(|texel| texel.to_array()).collect();
+        let texels_buffer = GPUBuffer::new_from_bytes(device, BufferUsages::STORAGE,
+                                                  9u32,
+                                                  bytemuck::cast_slice(texels.as_slice()),
+                                                  Some("texels buffer"));
+        // the spectral mode upsamples rgb colors with it
+        let rgb_to_spectrum: Vec<[f32; 4]> = RGBToSpectrumTable::get().coefficients()
+            .iter().map(|coefficients| coefficients.to_array()).collect();
+        let rgb_to_spectrum_buffer = GPUBuffer::new_from_bytes(device, BufferUsages::STORAGE,
+                                                  10u32,
+                                                  bytemuck::cast_slice(rgb_to_spectrum.as_slice()),
+                                                  Some("rgb to spectrum buffer"));
         
         // the scene bind group will hold the primitives, the materials, and the bvh_tree
         let scene_bind_group_layout = device.create_bind_group_layout(
@@ -92,18 +210,33 @@ impl PathTracer {
                 label: Some("scene bind group layout"),
                 entries: &[spheres_buffer.layout(ShaderStages::COMPUTE, true),
                     materials_buffer.layout(ShaderStages::COMPUTE, true),
-                    bvh_buffer.layout(ShaderStages::COMPUTE, true)],
+                    bvh_buffer.layout(ShaderStages::COMPUTE, true),
+                    bvh_quantized_buffer.layout(ShaderStages::COMPUTE, true),
+                    scene_parameters_buffer.layout(ShaderStages::COMPUTE, true),
+                    environment_map_buffer.layout(ShaderStages::COMPUTE, true),
+                    environment_distribution_buffer.layout(ShaderStages::COMPUTE, true),
+                    lights_buffer.layout(ShaderStages::COMPUTE, true),
+                    textures_buffer.layout(ShaderStages::COMPUTE, true),
+                    texels_buffer.layout(ShaderStages::COMPUTE, true),
+                    rgb_to_spectrum_buffer.layout(ShaderStages::COMPUTE, true)],
             });
         
         let scene_bind_group = device.create_bind_group(&BindGroupDescriptor{
             label: Some("scene bind group"),
             layout: &scene_bind_group_layout,
-            entries: &[spheres_buffer.binding(), materials_buffer.binding(), bvh_buffer.binding()],
+            entries: &[spheres_buffer.binding(), materials_buffer.binding(), bvh_buffer.binding(),
+                bvh_quantized_buffer.binding(), scene_parameters_buffer.binding(),
+                environment_map_buffer.binding(), environment_distribution_buffer.binding(),
+                lights_buffer.binding(), textures_buffer.binding(), texels_buffer.binding(),
+                rgb_to_spectrum_buffer.binding()],
         });
         
         // create the parameters bind group to interact with GPU during runtime
         // this will include the camera controller, the sampling parameters, and the window size
-        let render_parameters= rp.clone();
+        let mut render_parameters= rp.clone();
+        if let Background::Sky(sky) = &scene.background {
+            render_parameters.sky_parameters = Some(*sky);
+        }
         let camera_controller = render_parameters.camera_controller();
         let (width, height) = render_parameters.get_viewport();
         let ar = width as f32 / height as f32;
@@ -184,9 +317,11 @@ impl PathTracer {
             wgpu::include_wgsl!("../shaders/compute_megakernel.wgsl")
         );
         
-        // if I want to pass in override values, I can do it here:
-        // let mut id:HashMap<String, f64> = HashMap::new();
-        // id.insert("stackSize".to_string(), (bvh_tree.nodes.len() - 1) as f64);
+        // the bvh layout is selected through an override constant so the unused
+        // traversal path is compiled out
+        let mut constants: HashMap<String, f64> = HashMap::new();
+        constants.insert("BVH_QUANT_BITS".to_string(), bvh_layout.quantization_bits() as f64);
+        constants.insert("BVH_NODE_STRIDE".to_string(), bvh_layout.node_stride() as f64);
         
         let compute_shader_pipeline = device.create_compute_pipeline(
             &wgpu::ComputePipelineDescriptor {
@@ -194,12 +329,10 @@ impl PathTracer {
                 layout: Some(&ray_tracer_pipeline_layout),
                 module: &shader,
                 entry_point: "main",
-                compilation_options: Default::default(),
-                // PipelineCompilationOptions {
-                //     constants: None, //&id,
-                //     zero_initialize_workgroup_memory: false,
-                //     vertex_pulling_transform: false,
-                // },
+                compilation_options: PipelineCompilationOptions {
+                    constants: &constants,
+                    ..Default::default()
+                },
                 cache: None,
             }
         );
@@ -282,6 +415,18 @@ impl PathTracer {
             spheres_buffer,
             materials_buffer,
             bvh_buffer,
+            bvh_quantized_buffer,
+            scene_parameters_buffer,
+            scene_parameters,
+            lights,
+            environment_map_buffer,
+            environment_distribution_buffer,
+            lights_buffer,
+            textures_buffer,
+            texels_buffer,
+            rgb_to_spectrum_buffer,
+            rebuild_bvh: lbvh.is_some(),
+            lbvh,
             scene_bind_group,
             camera_buffer,
             sampling_parameters_buffer,
@@ -314,6 +459,18 @@ impl PathTracer {
         self.render_parameters = render_parameters
     }
 
+    // moves the spheres of a scene built with the GPU builder; the tree is rebuilt ahead of
+    // the next frame, and the number of spheres has to stay the same
+    pub fn update_spheres(&mut self, queue: &Queue, spheres: &[Sphere]) {
+        if self.lbvh.is_none() {
+            log::warn!("only a bvh_tree built on the GPU can follow the spheres, ignoring the update");
+            return;
+        }
+        self.spheres_buffer.queue_for_gpu(queue, bytemuck::cast_slice(spheres));
+        self.rebuild_bvh = true;
+        self.render_progress.reset();
+    }
+
     pub fn update_buffers(&mut self, queue: &Queue) {
         // if rp is the same as the stored buffer, no need to do anything
         if self.render_parameters == self.last_render_parameters {
@@ -336,6 +493,18 @@ impl PathTracer {
         let gpu_camera = self.render_parameters.camera_controller().get_GPU_camera();
         self.camera_buffer.queue_for_gpu(queue, bytemuck::cast_slice(&[gpu_camera]));
 
+        // update the sky and its sun, the last light, if the user moved them
+        if let Some(sky) = self.render_parameters.sky_parameters() {
+            if self.last_render_parameters.sky_parameters() != Some(sky) {
+                self.scene_parameters.set_sky(sky);
+                if let Some(sun) = self.lights.last_mut() {
+                    *sun = GPULight::from_light(&sky.sun(), 0);
+                }
+                self.scene_parameters_buffer.queue_for_gpu(queue, bytemuck::cast_slice(&[self.scene_parameters]));
+                self.lights_buffer.queue_for_gpu(queue, bytemuck::cast_slice(self.lights.as_slice()));
+            }
+        }
+
         self.render_progress.reset();
     }
 
@@ -355,6 +524,14 @@ impl PathTracer {
                 label: Some("compute kernel encoder"),
             });
 
+        let rebuilt = self.rebuild_bvh;
+        if self.rebuild_bvh {
+            if let Some(lbvh) = &self.lbvh {
+                lbvh.build(&mut encoder);
+            }
+            self.rebuild_bvh = false;
+        }
+
         {
             let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
                 label: Some("compute pass"),
@@ -374,6 +551,15 @@ impl PathTracer {
         }
         queries.resolve(&mut encoder);
         queue.submit(Some(encoder.finish()));
+
+        // the megakernel cannot reach the nodes below its traversal stack
+        if let (true, Some(lbvh)) = (rebuilt, &self.lbvh) {
+            let depth = lbvh.read_depth(device) as usize;
+            if depth > TRAVERSAL_STACK_SIZE {
+                log::error!("the bvh_tree built on the GPU is {} levels deep, the megakernel only traverses {}",
+                            depth, TRAVERSAL_STACK_SIZE);
+            }
+        }
     }
 
     pub fn run_display_kernel(&mut self, surface: &mut Surface,
@@ -63,7 +63,7 @@ impl QueryResults {
 }
 
 impl Queries {
-    pub(crate) fn new(device: &wgpu::Device, num_queries: u64) -> Self {
+    pub fn new(device: &wgpu::Device, num_queries: u64) -> Self {
         Queries {
             set: device.create_query_set(&wgpu::QuerySetDescriptor {
                 label: Some("Timestamp query set"),
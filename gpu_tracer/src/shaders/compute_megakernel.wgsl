@@ -1,9 +1,25 @@
 const EPSILON = 0.001f;
+// world space step of the finite differences of bump maps
+const BUMP_DELTA = 0.001f;
 
 const PI = 3.1415927f;
 const FRAC_1_PI = 0.31830987f;
 const FRAC_PI_2 = 1.5707964f;
 const USE_BVH = true;
+// the visible range of spectral mode in nm, as in spectrum.rs
+const LAMBDA_MIN = 360.0f;
+const LAMBDA_MAX = 830.0f;
+const CIE_Y_INTEGRAL = 106.92207f;
+const WHITE_BALANCE = vec3f(1.2002837, 0.9497977, 0.9081533);
+const RGB_TO_SPECTRUM_RESOLUTION = 16u;
+
+// 0 reads the full precision bvhTree; 8 or 16 reads the quantized copy in bvhQuantized,
+// where each node is BVH_NODE_STRIDE words long
+override BVH_QUANT_BITS: u32 = 0u;
+override BVH_NODE_STRIDE: u32 = 8u;
+// the layout of a quantized node, as in BVHLayout
+const BVH_HEADER_WORDS: u32 = 5u;
+const BVH_LEAF_FLAG: u32 = 1u << 24u;
 
 struct BVHNode {
     aabbMin: vec3f,
@@ -18,11 +34,92 @@ struct Sphere {
     mat_idx: u32,
 }
 
+struct Light {
+    position: vec4f,
+    direction: vec4f,
+    // intensity, or irradiance for directional lights
+    intensity: vec4f,
+    // 0 emissive sphere, 1 point, 2 spot, 3 directional, 4 photometric
+    light_type: u32,
+    sphere_idx: u32,
+    // cosines of the spot angles; directional lights keep the cosine of half their
+    // angular diameter in cos_outer
+    cos_inner: f32,
+    cos_outer: f32,
+    falloff: f32,
+    profile_offset: u32,
+}
+
 struct Material {
     albedo: vec4f,
     fuzz: f32,
     refract_idx: f32,
-    mat_type: u32
+    // as listed over GPUMaterial in material.rs
+    mat_type: u32,
+    emission_strength: f32,
+    // index into the textures that replaces the albedo, or NO_TEXTURE
+    albedo_texture: u32,
+    // GGX alpha of the conductor and rough dielectric; the principled material squares it
+    roughness: f32,
+    metallic: f32,
+    specular: f32,
+    // complex index of refraction of conductors; dispersive dielectrics keep the Cauchy
+    // coefficients in eta.xy with eta.w = 1, or the Sellmeier ones in eta.xyz and k.xyz with
+    // eta.w = 2
+    eta: vec4f,
+    k: vec4f,
+    // the remaining lobes of the principled material
+    transmission: f32,
+    clearcoat: f32,
+    clearcoat_roughness: f32,
+    sheen: f32,
+    // the normal or bump map, or NO_TEXTURE; normal_type is 0 for a tangent-space normal
+    // map and 1 for a bump map, and normal_strength is its strength or scale
+    normal_texture: u32,
+    normal_type: u32,
+    normal_strength: f32,
+    // the priority of dielectrics that overlap
+    priority: u32,
+    // the alpha mask, or NO_TEXTURE, and the opacity that scales it
+    opacity: f32,
+    alpha_texture: u32,
+    // the thin film over a dielectric or conductor, with a thickness of 0 for none
+    film_thickness: f32,
+    film_ior: f32,
+}
+
+struct Texture {
+    // 0 solid, 1 checker, 2 noise, 3 turbulence, 4 marble, 5 image
+    texture_type: u32,
+    // image size, and where its texels start in the texel buffer
+    width: u32,
+    height: u32,
+    texel_offset: u32,
+    // the solid or noise color, or the even checker color
+    color: vec4f,
+    // the odd checker color
+    odd_color: vec4f,
+    scale: f32,
+    depth: u32,
+    // 0 repeat, 1 clamp, 2 mirror
+    wrap: u32,
+}
+
+struct SceneParameters {
+    // 0 solid color, 1 gradient, 2 environment map, 3 Preetham sky
+    background_type: u32,
+    environment_width: u32,
+    environment_height: u32,
+    // 1 to importance sample the environment map at every diffuse bounce
+    environment_sampling: u32,
+    // the solid color, or the bottom and top of the gradient
+    background_bottom: vec4f,
+    background_top: vec4f,
+    // number of entries in the lights buffer
+    light_count: u32,
+    // the Perez coefficients A to E and the zenith values of the sky, each holding
+    // (Y, x, y), and the direction to the sun
+    sky: array<vec4f, 7>,
 }
 
 struct Ray {
@@ -31,11 +128,20 @@ struct Ray {
     invDirection: vec3f,
 }
 
+// the dielectrics a path is inside of, in the order it entered them
+struct MediumStack {
+    materials: array<u32, MAX_NESTING>,
+    count: u32,
+}
+
 struct HitPayload {
     t: f32,
     p: vec3f,
+    // the shading normal, which starts out as the geometric normal ng until a normal map tilts it
     n: vec3f,
     idx: u32,
+    uv: vec2f,
+    ng: vec3f,
 }
 
 struct CameraData {
@@ -49,7 +155,9 @@ struct CameraData {
 struct SamplingParameters {
     samples_per_frame: u32,
     num_bounces: u32,
-    clear_image_buffer: u32
+    clear_image_buffer: u32,
+    // 1 to trace the path at four wavelengths
+    spectral: u32
 }
 
 struct FrameBuffer {
@@ -67,13 +175,34 @@ struct ViewBuffer {
     view: mat4x4<f32>
 }
 
-const STACKSIZE:u32 = 10;
+// as TRAVERSAL_STACK_SIZE in bvh.rs, so trees built for the cpu tracer fit too
+const STACKSIZE:u32 = 32;
+const NO_TEXTURE: u32 = 0xffffffffu;
+// most dielectrics a path can be inside of at once, and the medium outside of any
+const MAX_NESTING: u32 = 4u;
+// most surfaces a path can pass through without them changing its medium; these crossings
+// are not bounces, but a bound keeps a ray from passing through them forever
+const MAX_NULL_CROSSINGS: u32 = 64u;
+const NO_MEDIUM: u32 = 0xffffffffu;
 
 @group(0) @binding(0) var<storage, read_write> image_buffer: array<array<f32, 3>>;
 @group(0) @binding(1) var<uniform> frame_buffer: FrameBuffer;
 @group(1) @binding(0) var<storage, read> spheres: array<Sphere>;
 @group(1) @binding(1) var<storage, read> materials: array<Material>;
 @group(1) @binding(2) var<storage, read> bvhTree: array<BVHNode>;
+@group(1) @binding(3) var<storage, read> bvhQuantized: array<u32>;
+@group(1) @binding(4) var<uniform> scene_parameters: SceneParameters;
+@group(1) @binding(5) var<storage, read> environment_map: array<vec4f>;
+// conditional functions, conditional cdfs, marginal function, marginal cdf and its integral,
+// as laid out by Distribution2D::to_gpu
+@group(1) @binding(6) var<storage, read> environment_distribution: array<f32>;
+// the emissive spheres, then the point, spot and directional lights
+@group(1) @binding(7) var<storage, read> lights: array<Light>;
+@group(1) @binding(8) var<storage, read> textures: array<Texture>;
+// the image textures one after the other
+@group(1) @binding(9) var<storage, read> texels: array<vec4f>;
+// sigmoid polynomial coefficients of rgb colors, as laid out by RGBToSpectrumTable
+@group(1) @binding(10) var<storage, read> rgb_to_spectrum: array<vec4f>;
 @group(2) @binding(0) var<uniform> camera: CameraData;
 @group(2) @binding(1) var<uniform> sampling_parameters: SamplingParameters;
 @group(2) @binding(2) var<uniform> projection_matrix: ProjectionBuffer;
@@ -109,24 +238,1083 @@ fn rayColor(primaryRay: Ray, state: ptr<function, u32>) -> vec3<f32> {
     // rayColor calls traceRay to get a hit, then calls it again
     // with new bounce ray
     var nextRay = primaryRay;
-    var throughput: vec3f = vec3f(1.0);
-    var pixel_color: vec3f = vec3f(0.0);
-    for (var i: u32 = 0; i < sampling_parameters.num_bounces; i++) {
+    // in spectral mode the path carries four wavelengths, the hero first; in rgb mode the
+    // first three entries are the channels
+    var wavelengths: vec4f = vec4f(0.0);
+    if isSpectral() {
+        wavelengths = sampleWavelengths(rngNextFloat(state));
+    }
+    var throughput: vec4f = vec4f(1.0);
+    var pixel_color: vec4f = vec4f(0.0);
+    // set once dispersive glass has split the wavelengths, leaving only the hero
+    var heroOnly = false;
+    // the dielectrics the path is inside of
+    var media = MediumStack();
+    // pdf of the last bounce direction and where it started; the pdf is 0 for camera rays
+    // and specular bounces, which light sampling could never have produced
+    var bouncePdf: f32 = 0.0;
+    var bounceOrigin: vec3f = primaryRay.origin;
+    var nullCrossings: u32 = 0u;
+    var i: u32 = 0u;
+    while i < sampling_parameters.num_bounces {
         var payLoad = HitPayload();
 
         if TraceRay(nextRay, &payLoad) {
             // depending on what kind of material, I need to find the scatter ray and the attenuation
             let mat_idx:u32 = spheres[payLoad.idx].mat_idx;
-            getScatterRay(&nextRay, mat_idx, &payLoad, state);
+            // emissive spheres add their radiance and end the path; a bounce that hits one
+            // shares it with the light sample taken at the previous vertex
+            if materials[mat_idx].mat_type == 3u {
+                var weight: f32 = 1.0;
+                if bouncePdf > 0.0 {
+                    weight = powerHeuristic(bouncePdf, sphereLightPdf(bounceOrigin, payLoad.idx));
+                }
+                pixel_color += throughput * toPathColor(weight * materialAlbedo(mat_idx, payLoad) * materials[mat_idx].emission_strength, wavelengths);
+                break;
+            }
+            // dispersive glass bends each wavelength its own way, so the path follows the
+            // hero alone from here, which carries the share of the others
+            var lambda: f32 = 0.0;
+            if isSpectral() && isDispersive(mat_idx) {
+                lambda = wavelengths.x;
+                if !heroOnly {
+                    throughput = vec4f(4.0 * throughput.x, 0.0, 0.0, 0.0);
+                    heroOnly = true;
+                }
+            }
+            // the surface of a dielectric is only there when crossing it changes the medium,
+            // which is the one with the highest priority the path is inside of
+            var crossed = media;
+            var outsideIndex: f32 = 1.0;
+            if isDielectric(mat_idx) {
+                let entering = dot(payLoad.ng, nextRay.direction) < 0.0;
+                if entering {
+                    pushMedium(&crossed, mat_idx);
+                } else {
+                    removeMedium(&crossed, mat_idx);
+                }
+                let current = currentMedium(&media);
+                let next = currentMedium(&crossed);
+                if current == next {
+                    media = crossed;
+                    nextRay.origin = payLoad.p;
+                    nullCrossings++;
+                    if nullCrossings > MAX_NULL_CROSSINGS {
+                        break;
+                    }
+                    continue;
+                }
+                var outside = next;
+                if entering {
+                    outside = current;
+                }
+                outsideIndex = mediumIndex(outside, wavelengths.x);
+            }
+            payLoad.n = shadingNormal(mat_idx, payLoad, -nextRay.direction);
+            // the last vertex has no bounce left to reach a light, so it does not sample one either
+            if isSampledByLights(mat_idx) && i + 1u < sampling_parameters.num_bounces {
+                let u = vec3f(rngNextFloat(state), rngNextFloat(state), rngNextFloat(state));
+                pixel_color += throughput * toPathColor(directLight(nextRay, payLoad, mat_idx, u), wavelengths);
+                if samplesEnvironment() {
+                    let u = vec2f(rngNextFloat(state), rngNextFloat(state));
+                    pixel_color += throughput * toPathColor(environmentLight(nextRay, payLoad, mat_idx, u), wavelengths);
+                }
+            }
+            let inRay = nextRay;
+            var weight: vec3f = vec3f(0.0);
+            getScatterRay(&nextRay, &weight, mat_idx, lambda, outsideIndex, &payLoad, state);
+            bouncePdf = bsdfPdf(inRay, payLoad, mat_idx, nextRay.direction);
+            bounceOrigin = payLoad.p;
+            // a refraction takes the path into the dielectric or out of it
+            if isDielectric(mat_idx) && (dot(payLoad.ng, nextRay.direction) < 0.0) == (dot(payLoad.ng, inRay.direction) < 0.0) {
+                media = crossed;
+            }
 
-            throughput *= materials[mat_idx].albedo.xyz;
+            throughput *= toPathColor(weight, wavelengths);
+            // microfacet samples that leave on the wrong side of the surface carry nothing
+            if all(throughput == vec4f(0.0)) {
+                break;
+            }
         } else {
-            let a: f32 = 0.5 * (primaryRay.direction.y + 1.0);
-            pixel_color = throughput * ((1.0 - a) * vec3f(1.0, 1.0, 1.0) + a * vec3f(0.5, 0.7, 1.0));
+            // a bounce that reaches the environment shares it with the light sample
+            var weight: f32 = 1.0;
+            if bouncePdf > 0.0 && samplesEnvironment() {
+                weight = powerHeuristic(bouncePdf, environmentPdf(nextRay.direction));
+            }
+            pixel_color += throughput * toPathColor(weight * backgroundColor(nextRay.direction), wavelengths);
             break;
         }
+        i++;
+    }
+    return toRgb(pixel_color, wavelengths);
+}
+
+fn isSpectral() -> bool {
+    return sampling_parameters.spectral == 1u;
+}
+
+fn toPathColor(rgb: vec3f, wavelengths: vec4f) -> vec4f {
+    // an rgb quantity as the path carries it, upsampled to the wavelengths in spectral mode
+    if isSpectral() {
+        return rgbToSpectrum(rgb, wavelengths);
+    }
+    return vec4f(rgb, 0.0);
+}
+
+fn toRgb(color: vec4f, wavelengths: vec4f) -> vec3f {
+    // what the path brought back, through XYZ in spectral mode
+    if isSpectral() {
+        return spectrumToRgb(color, wavelengths);
+    }
+    return color.xyz;
+}
+
+fn cieXYZ(lambda: f32) -> vec3f {
+    // the CIE 1931 matching functions, with the multi-lobe fit of Wyman, Sloan and Shirley
+    return vec3f(
+        1.056 * cieLobe(lambda, 599.8, 37.9, 31.0) + 0.362 * cieLobe(lambda, 442.0, 16.0, 26.7)
+            - 0.065 * cieLobe(lambda, 501.1, 20.4, 26.2),
+        0.821 * cieLobe(lambda, 568.8, 46.9, 40.5) + 0.286 * cieLobe(lambda, 530.9, 16.3, 31.1),
+        1.217 * cieLobe(lambda, 437.0, 11.8, 36.0) + 0.681 * cieLobe(lambda, 459.0, 26.0, 13.8)
+    );
+}
+
+fn cieLobe(lambda: f32, mu: f32, sigmaBelow: f32, sigmaAbove: f32) -> f32 {
+    var t = (lambda - mu) / sigmaAbove;
+    if lambda < mu {
+        t = (lambda - mu) / sigmaBelow;
+    }
+    return exp(-0.5 * t * t);
+}
+
+fn xyzToRgb(xyz: vec3f) -> vec3f {
+    return vec3f(
+        3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+        -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+        0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z
+    ) / WHITE_BALANCE;
+}
+
+fn sampleWavelengths(u: f32) -> vec4f {
+    // the hero wavelength picked by u, and three more spread evenly over the range after it
+    let range = LAMBDA_MAX - LAMBDA_MIN;
+    let hero = u * range;
+    let offsets = vec4f(0.0, 0.25, 0.5, 0.75) * range;
+    return LAMBDA_MIN + (vec4f(hero) + offsets) % range;
+}
+
+fn spectrumToRgb(values: vec4f, wavelengths: vec4f) -> vec3f {
+    var xyz = vec3f(0.0);
+    for (var i: u32 = 0; i < 4u; i++) {
+        xyz += values[i] * cieXYZ(wavelengths[i]);
+    }
+    return xyzToRgb(xyz * 0.25 * (LAMBDA_MAX - LAMBDA_MIN) / CIE_Y_INTEGRAL);
+}
+
+fn rgbToSpectrum(color: vec3f, wavelengths: vec4f) -> vec4f {
+    // colors brighter than 1, such as lights, are scaled down to fit the table and back up
+    let rgb = max(color, vec3f(0.0));
+    var scale = max(rgb.x, max(rgb.y, rgb.z));
+    if scale <= 0.0 {
+        return vec4f(0.0);
+    }
+    if scale > 1.0 {
+        scale = 2.0 * scale;
+    } else {
+        scale = 1.0;
+    }
+    let coefficients = rgbToSpectrumLookup(rgb / scale);
+    let t = (wavelengths - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN);
+    let x = (coefficients.x * t + coefficients.y) * t + coefficients.z;
+    return scale * (0.5 + 0.5 * x / sqrt(1.0 + x * x));
+}
+
+fn rgbToSpectrumLookup(color: vec3f) -> vec4f {
+    // trilinear in the table of the largest channel, indexed by the two others over it and by
+    // the largest channel on its smoothstep grid
+    let rgb = clamp(color, vec3f(0.0), vec3f(1.0));
+    var largest = 2u;
+    if rgb.x >= rgb.y && rgb.x >= rgb.z {
+        largest = 0u;
+    } else if rgb.y >= rgb.z {
+        largest = 1u;
+    }
+    let z = rgb[largest];
+    if z <= 0.0 {
+        return vec4f(0.0, 0.0, -1e4, 0.0);
+    }
+    let res = RGB_TO_SPECTRUM_RESOLUTION;
+    let coords = clamp(vec3f(rgb[(largest + 1u) % 3u] / z, rgb[(largest + 2u) % 3u] / z, inverseZScale(z)), vec3f(0.0), vec3f(1.0))
+        * f32(res - 1u);
+    let cell = min(vec3u(coords), vec3u(res - 2u));
+    let f = coords - vec3f(cell);
+    let base = ((largest * res + cell.z) * res + cell.y) * res + cell.x;
+    let dy = res;
+    let dz = res * res;
+    let bottom = mix(mix(rgb_to_spectrum[base], rgb_to_spectrum[base + 1u], f.x),
+                     mix(rgb_to_spectrum[base + dy], rgb_to_spectrum[base + dy + 1u], f.x), f.y);
+    let top = mix(mix(rgb_to_spectrum[base + dz], rgb_to_spectrum[base + dz + 1u], f.x),
+                  mix(rgb_to_spectrum[base + dz + dy], rgb_to_spectrum[base + dz + dy + 1u], f.x), f.y);
+    return mix(bottom, top, f.z);
+}
+
+fn inverseZScale(z: f32) -> f32 {
+    // where z falls on the grid of the largest channel, smoothstep applied twice
+    return inverseSmoothstep(inverseSmoothstep(z));
+}
+
+fn inverseSmoothstep(y: f32) -> f32 {
+    return 0.5 - sin(asin(1.0 - 2.0 * clamp(y, 0.0, 1.0)) / 3.0);
+}
+
+fn isDielectric(mat_idx: u32) -> bool {
+    return materials[mat_idx].mat_type == 2u || materials[mat_idx].mat_type == 5u;
+}
+
+fn pushMedium(media: ptr<function, MediumStack>, mat_idx: u32) {
+    if (*media).count < MAX_NESTING {
+        (*media).materials[(*media).count] = mat_idx;
+        (*media).count += 1u;
+    }
+}
+
+fn removeMedium(media: ptr<function, MediumStack>, mat_idx: u32) {
+    var last = MAX_NESTING;
+    for (var i: u32 = 0; i < (*media).count; i++) {
+        if (*media).materials[i] == mat_idx {
+            last = i;
+        }
+    }
+    if last == MAX_NESTING {
+        return;
     }
-    return pixel_color;
+    for (var i: u32 = last; i + 1u < (*media).count; i++) {
+        (*media).materials[i] = (*media).materials[i + 1u];
+    }
+    (*media).count -= 1u;
+}
+
+fn currentMedium(media: ptr<function, MediumStack>) -> u32 {
+    // the dielectric with the highest priority, the last one entered among equals
+    var current = NO_MEDIUM;
+    for (var i: u32 = 0; i < (*media).count; i++) {
+        let mat_idx = (*media).materials[i];
+        if current == NO_MEDIUM || materials[mat_idx].priority >= materials[current].priority {
+            current = mat_idx;
+        }
+    }
+    return current;
+}
+
+fn mediumIndex(medium: u32, lambda: f32) -> f32 {
+    // the index of refraction of a medium, at the hero wavelength in spectral mode, and 1
+    // outside of any
+    if medium == NO_MEDIUM {
+        return 1.0;
+    }
+    if isSpectral() && isDispersive(medium) {
+        return dispersiveIndex(medium, lambda);
+    }
+    return materials[medium].refract_idx;
+}
+
+fn isDispersive(mat_idx: u32) -> bool {
+    return materials[mat_idx].mat_type == 2u && materials[mat_idx].eta.w > 0.0;
+}
+
+fn dispersiveIndex(mat_idx: u32, lambda: f32) -> f32 {
+    // Cauchy's or Sellmeier's equation, with the wavelength in micrometers
+    let material = materials[mat_idx];
+    let l2 = (lambda * 1e-3) * (lambda * 1e-3);
+    if material.eta.w == 1.0 {
+        return material.eta.x + material.eta.y / l2;
+    }
+    let terms = material.eta.xyz * l2 / (vec3f(l2) - material.k.xyz);
+    return sqrt(1.0 + terms.x + terms.y + terms.z);
+}
+
+fn backgroundColor(direction: vec3f) -> vec3f {
+    // what a ray leaving the scene in this direction picks up
+    var color: vec3f = scene_parameters.background_bottom.xyz;
+    switch (scene_parameters.background_type) {
+        case 1u {
+            let a: f32 = 0.5 * (normalize(direction).y + 1.0);
+            color = (1.0 - a) * scene_parameters.background_bottom.xyz + a * scene_parameters.background_top.xyz;
+        }
+        case 2u {
+            color = environmentLookup(direction);
+        }
+        case 3u {
+            color = skyRadiance(direction);
+        }
+        default {}
+    }
+    return color;
+}
+
+fn skyRadiance(direction: vec3f) -> vec3f {
+    // Preetham sky: the Perez function of the view and sun angles scales the zenith
+    // (Y, x, y), which is then taken to linear sRGB; below the horizon the horizon repeats
+    let sky = scene_parameters.sky;
+    let d = normalize(direction);
+    let cosTheta = max(d.y, 0.001);
+    let cosGamma = clamp(dot(d, sky[6].xyz), -1.0, 1.0);
+    let gamma = acos(cosGamma);
+    let perez = (vec3f(1.0) + sky[0].xyz * exp(sky[1].xyz / cosTheta))
+        * (vec3f(1.0) + sky[2].xyz * exp(sky[3].xyz * gamma) + sky[4].xyz * cosGamma * cosGamma);
+    let Yxy = sky[5].xyz * perez;
+
+    let XYZ = vec3f(Yxy.y / Yxy.z * Yxy.x, Yxy.x, (1.0 - Yxy.y - Yxy.z) / Yxy.z * Yxy.x);
+    let rgb = vec3f(
+        3.2406 * XYZ.x - 1.5372 * XYZ.y - 0.4986 * XYZ.z,
+        -0.9689 * XYZ.x + 1.8758 * XYZ.y + 0.0415 * XYZ.z,
+        0.0557 * XYZ.x - 0.2040 * XYZ.y + 1.0570 * XYZ.z);
+    return max(rgb, vec3f(0.0));
+}
+
+fn environmentLookup(direction: vec3f) -> vec3f {
+    // bilinear lookup in the equirectangular map, wrapping around horizontally;
+    // v = 0 looks straight up and u = 0.5 looks down -z
+    let width = scene_parameters.environment_width;
+    let height = scene_parameters.environment_height;
+    let d = normalize(direction);
+    let u = 0.5 + atan2(d.x, -d.z) * 0.5 * FRAC_1_PI;
+    let v = acos(clamp(d.y, -1.0, 1.0)) * FRAC_1_PI;
+
+    let x = u * f32(width) - 0.5;
+    let y = clamp(v * f32(height) - 0.5, 0.0, f32(height - 1));
+    let fx = fract(x);
+    let fy = fract(y);
+    let x0 = u32((i32(floor(x)) + i32(width)) % i32(width));
+    let x1 = (x0 + 1) % width;
+    let y0 = u32(floor(y));
+    let y1 = min(y0 + 1, height - 1);
+
+    let top = mix(environment_map[y0 * width + x0].xyz, environment_map[y0 * width + x1].xyz, fx);
+    let bottom = mix(environment_map[y1 * width + x0].xyz, environment_map[y1 * width + x1].xyz, fx);
+    return mix(top, bottom, fy);
+}
+
+fn samplesEnvironment() -> bool {
+    return scene_parameters.background_type == 2u && scene_parameters.environment_sampling == 1u;
+}
+
+fn isSampledByLights(mat_idx: u32) -> bool {
+    // lambertian, fuzzy metal and rough conductor hits; sharp mirrors and glass only see
+    // lights through their single bounce direction, and rough glass mostly transmits
+    let material = materials[mat_idx];
+    return material.mat_type == 0u || (material.mat_type == 1u && material.fuzz > 0.0)
+        || (material.mat_type == 4u && material.roughness > 0.0) || material.mat_type == 6u
+        || material.mat_type == 7u;
+}
+
+fn bsdfPdf(inRay: Ray, hit: HitPayload, mat_idx: u32, direction: vec3f) -> f32 {
+    // pdf of getScatterRay producing this direction; it is 0 for the materials light
+    // sampling skips, so that their bounces keep the whole of what they hit, and light
+    // samples only leave through the outside of both the shading and geometric surfaces
+    let material = materials[mat_idx];
+    if dot(hit.n, direction) <= 0.0 || dot(hit.ng, direction) <= 0.0 {
+        return 0.0;
+    }
+    if material.mat_type == 0u {
+        return max(dot(hit.n, normalize(direction)), 0.0) * FRAC_1_PI;
+    }
+    if material.mat_type == 1u && material.fuzz > 0.0 {
+        return fuzzPdf(reflect(inRay.direction, hit.n), material.fuzz, direction);
+    }
+    if material.mat_type == 4u && material.roughness > 0.0 {
+        return ggxReflectionPdf(-normalize(inRay.direction), normalize(direction), hit.n, material.roughness);
+    }
+    if material.mat_type == 6u {
+        return principledPdf(-normalize(inRay.direction), normalize(direction), hit.n, mat_idx, materialAlbedo(mat_idx, hit));
+    }
+    if material.mat_type == 7u {
+        return layeredPdf(-normalize(inRay.direction), normalize(direction), hit.n, mat_idx, materialAlbedo(mat_idx, hit));
+    }
+    return 0.0;
+}
+
+fn bsdfEval(inRay: Ray, hit: HitPayload, mat_idx: u32, direction: vec3f) -> vec3f {
+    // the bsdf times the cosine toward direction; lambertian and metal scatter the albedo
+    // fraction of the light, so for them it is albedo * pdf
+    if materials[mat_idx].mat_type == 4u {
+        return conductorEval(-normalize(inRay.direction), normalize(direction), hit.n, mat_idx)
+            * materialAlbedo(mat_idx, hit);
+    }
+    if materials[mat_idx].mat_type == 6u {
+        return principledEval(-normalize(inRay.direction), normalize(direction), hit.n, mat_idx, materialAlbedo(mat_idx, hit));
+    }
+    if materials[mat_idx].mat_type == 7u {
+        return layeredEval(-normalize(inRay.direction), normalize(direction), hit.n, mat_idx, materialAlbedo(mat_idx, hit));
+    }
+    return materialAlbedo(mat_idx, hit) * bsdfPdf(inRay, hit, mat_idx, direction);
+}
+
+fn conductorEval(wo: vec3f, wi: vec3f, n: vec3f, mat_idx: u32) -> vec3f {
+    // Cook-Torrance with the GGX distribution, the height-correlated Smith shadowing and
+    // the Fresnel reflectance of the complex index of refraction, times cos(wi)
+    let cosO = dot(n, wo);
+    let cosI = dot(n, wi);
+    if cosO <= 0.0 || cosI <= 0.0 {
+        return vec3f(0.0);
+    }
+    let material = materials[mat_idx];
+    let alpha = material.roughness;
+    let m = normalize(wo + wi);
+    let g2 = 1.0 / (1.0 + smithLambda(cosO, alpha) + smithLambda(cosI, alpha));
+    let fresnel = conductorFresnel(dot(wo, m), mat_idx);
+    return fresnel * ggxD(dot(n, m), alpha) * g2 / (4.0 * cosO);
+}
+
+fn ggxReflectionPdf(wo: vec3f, wi: vec3f, n: vec3f, alpha: f32) -> f32 {
+    // visible normal density of the half vector, G1(wo) D(m) (wo.m) / cos(wo), over
+    // the 4 (wo.m) of the reflection jacobian
+    let cosO = dot(n, wo);
+    if cosO <= 0.0 || dot(n, wi) <= 0.0 {
+        return 0.0;
+    }
+    let m = normalize(wo + wi);
+    let g1 = 1.0 / (1.0 + smithLambda(cosO, alpha));
+    return g1 * ggxD(dot(n, m), alpha) / (4.0 * cosO);
+}
+
+fn ggxD(cosM: f32, alpha: f32) -> f32 {
+    // Trowbridge-Reitz distribution of microfacet normals
+    if cosM <= 0.0 {
+        return 0.0;
+    }
+    // written with sin^2 so that d stays above 0 for the tiny alpha of smooth surfaces
+    let a2 = alpha * alpha;
+    let d = cosM * cosM * a2 + max(1.0 - cosM * cosM, 0.0);
+    return a2 / (PI * d * d);
+}
+
+fn smithLambda(cosTheta: f32, alpha: f32) -> f32 {
+    let cos2 = max(cosTheta * cosTheta, 1e-8);
+    let tan2 = max((1.0 - cos2) / cos2, 0.0);
+    return 0.5 * (sqrt(1.0 + alpha * alpha * tan2) - 1.0);
+}
+
+fn microfacetWeight(wo: vec3f, wi: vec3f, n: vec3f, alpha: f32) -> f32 {
+    // G2 / G1 of visible normal sampling, as the bsdf times the cosine over the pdf
+    let lambdaO = smithLambda(abs(dot(n, wo)), alpha);
+    let lambdaI = smithLambda(abs(dot(n, wi)), alpha);
+    return (1.0 + lambdaO) / (1.0 + lambdaO + lambdaI);
+}
+
+fn sampleGGXVisibleNormal(wo: vec3f, alpha: f32, u: vec2f) -> vec3f {
+    // Heitz 2018: sample the projected disk of the hemisphere stretched to roughness 1,
+    // in the frame where the normal is z, then unstretch the sampled normal
+    let v = normalize(vec3f(alpha * wo.x, alpha * wo.y, wo.z));
+    let lensq = v.x * v.x + v.y * v.y;
+    var t1 = vec3f(1.0, 0.0, 0.0);
+    if lensq > 0.0 {
+        t1 = vec3f(-v.y, v.x, 0.0) / sqrt(lensq);
+    }
+    let t2 = cross(v, t1);
+    let r = sqrt(u.x);
+    let phi = 2.0 * PI * u.y;
+    let p1 = r * cos(phi);
+    let s = 0.5 * (1.0 + v.z);
+    let p2 = (1.0 - s) * sqrt(max(1.0 - p1 * p1, 0.0)) + s * r * sin(phi);
+    let nh = p1 * t1 + p2 * t2 + sqrt(max(1.0 - p1 * p1 - p2 * p2, 0.0)) * v;
+    return normalize(vec3f(alpha * nh.x, alpha * nh.y, max(nh.z, 0.0)));
+}
+
+fn sampleMicrofacetNormal(wo: vec3f, n: vec3f, roughness: f32, u: vec2f) -> vec3f {
+    // visible normal around n in world space; a tiny alpha stands in for a smooth surface
+    let basis = orthonormalBasis(n);
+    let woLocal = vec3f(dot(wo, basis[0]), dot(wo, basis[1]), dot(wo, n));
+    let m = sampleGGXVisibleNormal(woLocal, max(roughness, 1e-4), u);
+    return m.x * basis[0] + m.y * basis[1] + m.z * n;
+}
+
+fn fresnelConductor(cosTheta: f32, eta: vec3f, k: vec3f) -> vec3f {
+    // exact reflectance of a conductor in air for unpolarized light
+    let cosI = clamp(cosTheta, 0.0, 1.0);
+    let cos2 = cosI * cosI;
+    let sin2 = 1.0 - cos2;
+    let t0 = eta * eta - k * k - vec3f(sin2);
+    let a2plusb2 = sqrt(max(t0 * t0 + 4.0 * eta * eta * k * k, vec3f(0.0)));
+    let t1 = a2plusb2 + vec3f(cos2);
+    let a = sqrt(max(0.5 * (a2plusb2 + t0), vec3f(0.0)));
+    let t2 = 2.0 * cosI * a;
+    let rs = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2plusb2 + vec3f(sin2 * sin2);
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    return 0.5 * (rp + rs);
+}
+
+fn conductorFresnel(cosTheta: f32, mat_idx: u32) -> vec3f {
+    let material = materials[mat_idx];
+    if material.film_thickness > 0.0 {
+        return thinFilmReflectance(cosTheta, 1.0, material.film_thickness, material.film_ior, material.eta.xyz, material.k.xyz);
+    }
+    return fresnelConductor(cosTheta, material.eta.xyz, material.k.xyz);
+}
+
+const FILM_WAVELENGTHS: u32 = 16u;
+
+fn thinFilmReflectance(cosTheta: f32, n1: f32, thickness: f32, filmIor: f32, eta: vec3f, k: vec3f) -> vec3f {
+    // the light reflected at both boundaries of a film interferes, so the reflectance is
+    // summed over wavelengths and turned into rgb; eta and k of the substrate are given at
+    // 650, 550 and 450nm
+    var xyz = vec3f(0.0);
+    var white = vec3f(0.0);
+    for (var i = 0u; i < FILM_WAVELENGTHS; i++) {
+        let lambda = 380.0 + (f32(i) + 0.5) * 400.0 / f32(FILM_WAVELENGTHS);
+        let substrate = vec2f(rgbIndex(eta, lambda), rgbIndex(k, lambda));
+        let cie = cieXYZ(lambda);
+        xyz += thinFilmAiry(cosTheta, n1, filmIor, substrate, thickness, lambda) * cie;
+        white += cie;
+    }
+    // a flat reflectance comes out the same in rgb
+    return max(xyzToRgb(xyz) / xyzToRgb(white), vec3f(0.0));
+}
+
+fn thinFilmAiry(cosTheta: f32, n1: f32, n2: f32, n3: vec2f, thickness: f32, lambda: f32) -> f32 {
+    // the Airy summation of the reflections inside the film for unpolarized light; n3 is
+    // the complex index of the substrate
+    let cos1 = clamp(cosTheta, 0.0, 1.0);
+    let sin2 = 1.0 - cos1 * cos1;
+    let sin2Film = (n1 / n2) * (n1 / n2) * sin2;
+    if sin2Film >= 1.0 {
+        return 1.0;
+    }
+    let cos2 = sqrt(1.0 - sin2Film);
+    let cos3 = complexSqrt(vec2f(1.0, 0.0) - sin2 * complexDiv(vec2f(n1 * n1, 0.0), complexMul(n3, n3)));
+
+    let r12s = (n1 * cos1 - n2 * cos2) / (n1 * cos1 + n2 * cos2);
+    let r12p = (n2 * cos1 - n1 * cos2) / (n2 * cos1 + n1 * cos2);
+    let filmS = vec2f(n2 * cos2, 0.0);
+    let substrateS = complexMul(n3, cos3);
+    let r23s = complexDiv(filmS - substrateS, filmS + substrateS);
+    let filmP = n3 * cos2;
+    let substrateP = n2 * cos3;
+    let r23p = complexDiv(filmP - substrateP, filmP + substrateP);
+
+    let phase = 4.0 * PI * n2 * thickness * cos2 / lambda;
+    let shift = vec2f(cos(phase), sin(phase));
+    return 0.5 * (airyTotal(r12s, complexMul(r23s, shift)) + airyTotal(r12p, complexMul(r23p, shift)));
+}
+
+fn airyTotal(r12: f32, r23: vec2f) -> f32 {
+    let r = complexDiv(vec2f(r12, 0.0) + r23, vec2f(1.0, 0.0) + r12 * r23);
+    return dot(r, r);
+}
+
+fn rgbIndex(v: vec3f, lambda: f32) -> f32 {
+    // a value given at 650, 550 and 450nm, interpolated to lambda
+    if lambda < 550.0 {
+        return v.z + (v.y - v.z) * clamp((lambda - 450.0) / 100.0, 0.0, 1.0);
+    }
+    return v.y + (v.x - v.y) * clamp((lambda - 550.0) / 100.0, 0.0, 1.0);
+}
+
+// complex numbers as (real, imaginary)
+fn complexMul(a: vec2f, b: vec2f) -> vec2f {
+    return vec2f(a.x * b.x - a.y * b.y, a.x * b.y + a.y * b.x);
+}
+
+fn complexDiv(a: vec2f, b: vec2f) -> vec2f {
+    return complexMul(a, vec2f(b.x, -b.y)) / dot(b, b);
+}
+
+fn complexSqrt(z: vec2f) -> vec2f {
+    // the root with a positive real part
+    let r = length(z);
+    let re = sqrt(max(0.5 * (r + z.x), 0.0));
+    var im = sqrt(max(0.5 * (r - z.x), 0.0));
+    if z.y < 0.0 {
+        im = -im;
+    }
+    return vec2f(re, im);
+}
+
+fn principledFrame(wo: vec3f, n: vec3f, mat_idx: u32) -> vec4f {
+    // the normal on the side of wo, and the index across the surface over the one wo is in
+    let ior = materials[mat_idx].refract_idx;
+    if dot(n, wo) < 0.0 {
+        return vec4f(-n, 1.0 / ior);
+    }
+    return vec4f(n, ior);
+}
+
+fn principledLobes(wo: vec3f, n: vec3f, mat_idx: u32, baseColor: vec3f) -> vec4f {
+    // chances of sampling the diffuse and sheen, specular, transmission and clearcoat
+    // lobes, roughly in proportion to what each of them reflects toward wo
+    let material = materials[mat_idx];
+    let luminance = vec3f(0.2126, 0.7152, 0.0722);
+    let cosO = dot(n, wo);
+    let coat = material.clearcoat * fresnelDielectric(cosO, 1.5);
+    let dielectric = (1.0 - material.metallic) * (1.0 - coat);
+    let r = (material.refract_idx - 1.0) / (material.refract_idx + 1.0);
+    let f0 = mix(vec3f(min(2.0 * material.specular * r * r, 1.0)), baseColor, material.metallic);
+    let specular = dot(f0 + (1.0 - f0) * pow(1.0 - cosO, 5.0), luminance) * (1.0 - coat);
+    let diffuse = dielectric * (1.0 - material.transmission) * (dot(baseColor, luminance) + material.sheen);
+    let transmission = dielectric * material.transmission * dot(baseColor, luminance);
+    let lobes = vec4f(diffuse, specular, transmission, coat);
+    let total = lobes.x + lobes.y + lobes.z + lobes.w;
+    if total <= 0.0 {
+        return vec4f(1.0, 0.0, 0.0, 0.0);
+    }
+    return lobes / total;
+}
+
+fn principledEval(wo: vec3f, wi: vec3f, normal: vec3f, mat_idx: u32, baseColor: vec3f) -> vec3f {
+    // the bsdf times |cos(wi)|: lambertian diffuse with a sheen at grazing angles and
+    // GGX specular reflection and transmission, under a GGX clearcoat that takes its
+    // Fresnel share of the light off everything below it
+    let material = materials[mat_idx];
+    let frame = principledFrame(wo, normal, mat_idx);
+    let n = frame.xyz;
+    let eta = frame.w;
+    let cosO = dot(n, wo);
+    let cosI = dot(n, wi);
+    if cosO <= 0.0 || cosI == 0.0 {
+        return vec3f(0.0);
+    }
+    let alpha = max(material.roughness * material.roughness, 1e-4);
+    let dielectric = 1.0 - material.metallic;
+    let below = 1.0 - material.clearcoat * fresnelDielectric(cosO, 1.5);
+    let lambdaO = smithLambda(cosO, alpha);
+    let lambdaI = smithLambda(abs(cosI), alpha);
+
+    if cosI < 0.0 {
+        // refraction, through the generalized half vector
+        var m = normalize(wo + eta * wi);
+        if dot(n, m) < 0.0 {
+            m *= -1.0;
+        }
+        let cosOM = dot(wo, m);
+        let cosIM = dot(wi, m);
+        if cosOM <= 0.0 || cosIM >= 0.0 {
+            return vec3f(0.0);
+        }
+        let denom = (cosIM + cosOM / eta) * (cosIM + cosOM / eta);
+        let transmitted = ggxD(dot(n, m), alpha) * (1.0 - fresnelDielectric(cosOM, eta))
+            * abs(cosIM * cosOM) / (cosO * denom * (1.0 + lambdaO + lambdaI));
+        return baseColor * transmitted * dielectric * material.transmission * below;
+    }
+
+    let m = normalize(wo + wi);
+    let cosOM = dot(wo, m);
+    let sheen = material.sheen * pow(1.0 - dot(wi, m), 5.0);
+    let diffuse = (baseColor * FRAC_1_PI + vec3f(sheen)) * cosI * dielectric * (1.0 - material.transmission);
+    let metalFresnel = baseColor + (1.0 - baseColor) * pow(1.0 - cosOM, 5.0);
+    let dielectricFresnel = min(2.0 * material.specular * fresnelDielectric(cosOM, eta), 1.0);
+    let fresnel = mix(vec3f(dielectricFresnel), metalFresnel, material.metallic);
+    let specular = fresnel * ggxD(dot(n, m), alpha) / (4.0 * cosO * (1.0 + lambdaO + lambdaI));
+
+    let coatAlpha = max(material.clearcoat_roughness * material.clearcoat_roughness, 1e-4);
+    let coatG2 = 1.0 / (1.0 + smithLambda(cosO, coatAlpha) + smithLambda(cosI, coatAlpha));
+    let coat = material.clearcoat * fresnelDielectric(cosOM, 1.5) * ggxD(dot(n, m), coatAlpha) * coatG2 / (4.0 * cosO);
+    return (diffuse + specular) * below + vec3f(coat);
+}
+
+fn principledPdf(wo: vec3f, wi: vec3f, normal: vec3f, mat_idx: u32, baseColor: vec3f) -> f32 {
+    // the lobe pdfs weighted by the chances of picking them
+    let material = materials[mat_idx];
+    let frame = principledFrame(wo, normal, mat_idx);
+    let n = frame.xyz;
+    let eta = frame.w;
+    let cosO = dot(n, wo);
+    let cosI = dot(n, wi);
+    if cosO <= 0.0 || cosI == 0.0 {
+        return 0.0;
+    }
+    let lobes = principledLobes(wo, n, mat_idx, baseColor);
+    let alpha = max(material.roughness * material.roughness, 1e-4);
+
+    if cosI < 0.0 {
+        var m = normalize(wo + eta * wi);
+        if dot(n, m) < 0.0 {
+            m *= -1.0;
+        }
+        let cosOM = dot(wo, m);
+        let cosIM = dot(wi, m);
+        if cosOM <= 0.0 || cosIM >= 0.0 {
+            return 0.0;
+        }
+        // visible normal density times the jacobian of the refraction
+        let visible = ggxD(dot(n, m), alpha) * cosOM / (cosO * (1.0 + smithLambda(cosO, alpha)));
+        return lobes.z * visible * abs(cosIM) / ((cosIM + cosOM / eta) * (cosIM + cosOM / eta));
+    }
+
+    let coatAlpha = max(material.clearcoat_roughness * material.clearcoat_roughness, 1e-4);
+    return lobes.x * cosI * FRAC_1_PI
+        + lobes.y * ggxReflectionPdf(wo, wi, n, alpha)
+        + lobes.w * ggxReflectionPdf(wo, wi, n, coatAlpha);
+}
+
+fn principledSample(wo: vec3f, normal: vec3f, mat_idx: u32, baseColor: vec3f, u: vec3f) -> vec3f {
+    // u.z picks the lobe and u.xy samples it; a refraction that does not exist gives zero
+    let material = materials[mat_idx];
+    let frame = principledFrame(wo, normal, mat_idx);
+    let n = frame.xyz;
+    let eta = frame.w;
+    let lobes = principledLobes(wo, n, mat_idx, baseColor);
+    let alpha = max(material.roughness * material.roughness, 1e-4);
+    let coatAlpha = max(material.clearcoat_roughness * material.clearcoat_roughness, 1e-4);
+    var direction = vec3f(0.0);
+    if u.z < lobes.x {
+        let basis = orthonormalBasis(n);
+        let r = sqrt(u.x);
+        let phi = 2.0 * PI * u.y;
+        direction = r * cos(phi) * basis[0] + r * sin(phi) * basis[1] + sqrt(max(1.0 - u.x, 0.0)) * n;
+    } else if u.z < lobes.x + lobes.y {
+        direction = reflect(-wo, sampleMicrofacetNormal(wo, n, alpha, u.xy));
+    } else if u.z < lobes.x + lobes.y + lobes.z {
+        let m = sampleMicrofacetNormal(wo, n, alpha, u.xy);
+        refract(-wo, m, 1.0 / eta, &direction);
+    } else {
+        direction = reflect(-wo, sampleMicrofacetNormal(wo, n, coatAlpha, u.xy));
+    }
+    return direction;
+}
+
+fn layeredCoatChance(wo: vec3f, n: vec3f, mat_idx: u32, baseColor: vec3f) -> f32 {
+    // chance of sampling the coat rather than the base, in proportion to what each of
+    // them reflects toward wo
+    let material = materials[mat_idx];
+    let luminance = vec3f(0.2126, 0.7152, 0.0722);
+    let cosO = dot(n, wo);
+    let coat = fresnelDielectric(cosO, material.refract_idx);
+    var reflectance = baseColor;
+    if material.metallic > 0.0 {
+        reflectance *= conductorFresnel(cosO, mat_idx);
+    }
+    let base = (1.0 - coat) * dot(reflectance, luminance);
+    if coat + base <= 0.0 {
+        return 1.0;
+    }
+    return coat / (coat + base);
+}
+
+fn layeredEval(wo: vec3f, wi: vec3f, n: vec3f, mat_idx: u32, baseColor: vec3f) -> vec3f {
+    // the bsdf times cos(wi): a GGX dielectric coat reflecting its Fresnel share of the
+    // light, over a lambertian or conductor base that sees what the coat transmits on the
+    // way in and on the way out
+    let material = materials[mat_idx];
+    let cosO = dot(n, wo);
+    let cosI = dot(n, wi);
+    if cosO <= 0.0 || cosI <= 0.0 {
+        return vec3f(0.0);
+    }
+    let alpha = max(material.clearcoat_roughness, 1e-4);
+    let m = normalize(wo + wi);
+    let g2 = 1.0 / (1.0 + smithLambda(cosO, alpha) + smithLambda(cosI, alpha));
+    let coat = fresnelDielectric(dot(wo, m), material.refract_idx) * ggxD(dot(n, m), alpha) * g2 / (4.0 * cosO);
+    let transmitted = (1.0 - fresnelDielectric(cosO, material.refract_idx)) * (1.0 - fresnelDielectric(cosI, material.refract_idx));
+    var base = baseColor * cosI * FRAC_1_PI;
+    if material.metallic > 0.0 {
+        base = conductorEval(wo, wi, n, mat_idx) * baseColor;
+    }
+    return base * transmitted + vec3f(coat);
+}
+
+fn layeredPdf(wo: vec3f, wi: vec3f, n: vec3f, mat_idx: u32, baseColor: vec3f) -> f32 {
+    // the coat and base pdfs weighted by the chances of picking them
+    let material = materials[mat_idx];
+    let cosI = dot(n, wi);
+    if dot(n, wo) <= 0.0 || cosI <= 0.0 {
+        return 0.0;
+    }
+    let coat = layeredCoatChance(wo, n, mat_idx, baseColor);
+    var base = cosI * FRAC_1_PI;
+    if material.metallic > 0.0 {
+        base = ggxReflectionPdf(wo, wi, n, material.roughness);
+    }
+    return coat * ggxReflectionPdf(wo, wi, n, max(material.clearcoat_roughness, 1e-4)) + (1.0 - coat) * base;
+}
+
+fn layeredSample(wo: vec3f, n: vec3f, mat_idx: u32, baseColor: vec3f, u: vec3f) -> vec3f {
+    // u.z picks the coat or the base and u.xy samples it
+    let material = materials[mat_idx];
+    if u.z < layeredCoatChance(wo, n, mat_idx, baseColor) {
+        return reflect(-wo, sampleMicrofacetNormal(wo, n, material.clearcoat_roughness, u.xy));
+    }
+    if material.metallic > 0.0 {
+        return reflect(-wo, sampleMicrofacetNormal(wo, n, material.roughness, u.xy));
+    }
+    let basis = orthonormalBasis(n);
+    let r = sqrt(u.x);
+    let phi = 2.0 * PI * u.y;
+    return r * cos(phi) * basis[0] + r * sin(phi) * basis[1] + sqrt(max(1.0 - u.x, 0.0)) * n;
+}
+
+fn fresnelDielectric(cosTheta: f32, eta: f32) -> f32 {
+    // exact reflectance for unpolarized light; eta is the index across the boundary
+    // over the index on the side of cosTheta
+    let cosI = clamp(cosTheta, 0.0, 1.0);
+    let sin2T = (1.0 - cosI * cosI) / (eta * eta);
+    if sin2T >= 1.0 {
+        return 1.0;
+    }
+    let cosT = sqrt(1.0 - sin2T);
+    let rs = (cosI - eta * cosT) / (cosI + eta * cosT);
+    let rp = (eta * cosI - cosT) / (eta * cosI + cosT);
+    return 0.5 * (rs * rs + rp * rp);
+}
+
+fn fuzzPdf(reflected: vec3f, fuzz: f32, direction: vec3f) -> f32 {
+    // fuzzy reflections aim at a uniform point on a sphere of radius fuzz around the
+    // reflected vector; the pdf sums, over the points where the direction crosses that
+    // sphere, the area density 1 / (4 pi fuzz^2) times t^2 / |cos| to turn it into solid angle
+    let w = normalize(direction);
+    let b = dot(w, reflected);
+    let discrim = b * b - dot(reflected, reflected) + fuzz * fuzz;
+    if discrim <= 0.0 {
+        return 0.0;
+    }
+    let root = sqrt(discrim);
+    var pdf: f32 = 0.0;
+    let tNear = b - root;
+    let tFar = b + root;
+    if tNear > 0.0 {
+        pdf += tNear * tNear / (4.0 * PI * fuzz * root);
+    }
+    if tFar > 0.0 {
+        pdf += tFar * tFar / (4.0 * PI * fuzz * root);
+    }
+    return pdf;
+}
+
+fn directLight(inRay: Ray, hit: HitPayload, mat_idx: u32, u: vec3f) -> vec3f {
+    // light sample toward one of the lights, picked uniformly with u.z
+    let lightCount = scene_parameters.light_count;
+    if lightCount == 0u {
+        return vec3f(0.0);
+    }
+    let light = lights[min(u32(u.z * f32(lightCount)), lightCount - 1u)];
+    switch (light.light_type) {
+        case 0u {
+            return sphereLight(inRay, hit, mat_idx, light.sphere_idx, u.xy);
+        }
+        case 3u {
+            return directionalLight(inRay, hit, mat_idx, light, u.xy);
+        }
+        case 4u {
+            // photometric lights are only traced by the CPU tracer for now
+            return vec3f(0.0);
+        }
+        default {
+            return pointLight(inRay, hit, mat_idx, light);
+        }
+    }
+}
+
+fn pointLight(inRay: Ray, hit: HitPayload, mat_idx: u32, light: Light) -> vec3f {
+    // point and spot lights are only ever reached by shadow rays, so the sample needs no
+    // weight; scaling by the light count makes up for picking a single light
+    let toLight = light.position.xyz - hit.p;
+    let distanceSquared = dot(toLight, toLight);
+    let distance = sqrt(distanceSquared);
+    let direction = toLight / distance;
+
+    var intensity = light.intensity.xyz;
+    if light.light_type == 2u {
+        intensity *= spotFalloff(light, -direction);
+    }
+    let pdf = bsdfPdf(inRay, hit, mat_idx, direction);
+    if pdf <= 0.0 || dot(hit.n, direction) <= 0.0 || all(intensity == vec3f(0.0)) {
+        return vec3f(0.0);
+    }
+    if occluded(Ray(hit.p, direction, 1.0 / direction), distance - EPSILON) {
+        return vec3f(0.0);
+    }
+    let lightCount = f32(scene_parameters.light_count);
+    return bsdfEval(inRay, hit, mat_idx, direction) * intensity / distanceSquared * lightCount;
+}
+
+fn spotFalloff(light: Light, direction: vec3f) -> f32 {
+    // direction points away from the light
+    let cosTheta = dot(direction, light.direction.xyz);
+    if cosTheta < light.cos_outer {
+        return 0.0;
+    }
+    let t = (cosTheta - light.cos_outer) / max(light.cos_inner - light.cos_outer, 1e-6);
+    return pow(min(t, 1.0), light.falloff);
+}
+
+fn directionalLight(inRay: Ray, hit: HitPayload, mat_idx: u32, light: Light, u: vec2f) -> vec3f {
+    // uniform over the disk of the sun; its radiance times its solid angle is the
+    // irradiance, so the estimate does not depend on the size of the disk
+    let axis = -light.direction.xyz;
+    let cosTheta = 1.0 - u.x * (1.0 - light.cos_outer);
+    let sinTheta = sqrt(max(1.0 - cosTheta * cosTheta, 0.0));
+    let phi = 2.0 * PI * u.y;
+    let basis = orthonormalBasis(axis);
+    let direction = sinTheta * cos(phi) * basis[0] + sinTheta * sin(phi) * basis[1] + cosTheta * axis;
+
+    let pdf = bsdfPdf(inRay, hit, mat_idx, direction);
+    if pdf <= 0.0 || dot(hit.n, direction) <= 0.0 {
+        return vec3f(0.0);
+    }
+    if occluded(Ray(hit.p, direction, 1.0 / direction), 1e29) {
+        return vec3f(0.0);
+    }
+    let lightCount = f32(scene_parameters.light_count);
+    return bsdfEval(inRay, hit, mat_idx, direction) * light.intensity.xyz * lightCount;
+}
+
+fn sphereLight(inRay: Ray, hit: HitPayload, mat_idx: u32, lightIdx: u32, u: vec2f) -> vec3f {
+    // light sample in the cone the emissive sphere subtends, weighted against the chance
+    // of the bounce ray hitting it
+    let light = spheres[lightIdx];
+    let toCenter = light.center.xyz - hit.p;
+    let distanceSquared = dot(toCenter, toCenter);
+    let radiusSquared = light.radius * light.radius;
+    if distanceSquared <= radiusSquared {
+        return vec3f(0.0);
+    }
+
+    let cosThetaMax = sqrt(max(1.0 - radiusSquared / distanceSquared, 0.0));
+    let cosTheta = 1.0 - u.x * (1.0 - cosThetaMax);
+    let sinTheta = sqrt(max(1.0 - cosTheta * cosTheta, 0.0));
+    let phi = 2.0 * PI * u.y;
+    let axis = toCenter / sqrt(distanceSquared);
+    let basis = orthonormalBasis(axis);
+    let direction = sinTheta * cos(phi) * basis[0] + sinTheta * sin(phi) * basis[1] + cosTheta * axis;
+
+    let pdf = bsdfPdf(inRay, hit, mat_idx, direction);
+    if pdf <= 0.0 || dot(hit.n, direction) <= 0.0 {
+        return vec3f(0.0);
+    }
+    // stop the shadow ray just short of the near side of the light
+    let b = dot(direction, toCenter);
+    let tLight = b - sqrt(max(b * b - distanceSquared + radiusSquared, 0.0));
+    if occluded(Ray(hit.p, direction, 1.0 / direction), tLight - EPSILON) {
+        return vec3f(0.0);
+    }
+
+    let lightPdf = sphereLightPdf(hit.p, lightIdx);
+    // the emission is looked up where the shadow ray meets the light
+    let lightPoint = hit.p + tLight * direction;
+    let lightNormal = normalize(lightPoint - light.center.xyz);
+    let lightHit = HitPayload(tLight, lightPoint, lightNormal, lightIdx, sphereUV(lightNormal), lightNormal);
+    let emitted = materialAlbedo(light.mat_idx, lightHit) * materials[light.mat_idx].emission_strength;
+    return bsdfEval(inRay, hit, mat_idx, direction) * emitted * powerHeuristic(lightPdf, pdf) / lightPdf;
+}
+
+fn sphereLightPdf(origin: vec3f, sphereIdx: u32) -> f32 {
+    // uniform over the cone the sphere subtends from origin, and over the lights
+    let sphere = spheres[sphereIdx];
+    let toCenter = sphere.center.xyz - origin;
+    let distanceSquared = dot(toCenter, toCenter);
+    let radiusSquared = sphere.radius * sphere.radius;
+    let lightCount = scene_parameters.light_count;
+    if distanceSquared <= radiusSquared || lightCount == 0u {
+        return 0.0;
+    }
+    let cosThetaMax = sqrt(max(1.0 - radiusSquared / distanceSquared, 0.0));
+    return 1.0 / (2.0 * PI * (1.0 - cosThetaMax) * f32(lightCount));
+}
+
+fn orthonormalBasis(n: vec3f) -> mat2x3f {
+    // two unit vectors perpendicular to n and to each other (Duff et al. 2017)
+    let sign = select(-1.0, 1.0, n.z >= 0.0);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+    return mat2x3f(vec3f(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+                   vec3f(b, sign + n.y * n.y * a, -n.y));
+}
+
+fn environmentLight(inRay: Ray, hit: HitPayload, mat_idx: u32, u: vec2f) -> vec3f {
+    // light sample of the environment, weighted against the chance of the bounce ray
+    // finding the same direction
+    let lightSample = sampleEnvironment(u);
+    let direction = lightSample.xyz;
+    let lightPdf = lightSample.w;
+    if lightPdf <= 0.0 || dot(hit.n, direction) <= 0.0 {
+        return vec3f(0.0);
+    }
+    let pdf = bsdfPdf(inRay, hit, mat_idx, direction);
+    if pdf <= 0.0 || occluded(Ray(hit.p, direction, 1.0 / direction), 1e29) {
+        return vec3f(0.0);
+    }
+    let weight = powerHeuristic(lightPdf, pdf);
+    return bsdfEval(inRay, hit, mat_idx, direction) * environmentLookup(direction) * weight / lightPdf;
+}
+
+fn sampleEnvironment(u: vec2f) -> vec4f {
+    // picks a row with the marginal cdf and a column with that row's conditional cdf,
+    // then maps the point to a direction; returns the direction and its pdf
+    let width = scene_parameters.environment_width;
+    let height = scene_parameters.environment_height;
+    let conditionalCdf = width * height;
+    let marginalCdf = conditionalCdf + height * (width + 1u) + height;
+    let integral = environment_distribution[marginalCdf + height + 1u];
+    if integral <= 0.0 {
+        return vec4f(0.0);
+    }
+
+    let row = findInterval(marginalCdf, height + 1u, u.y);
+    let dv = segmentOffset(marginalCdf + row, u.y);
+    let rowCdf = conditionalCdf + row * (width + 1u);
+    let col = findInterval(rowCdf, width + 1u, u.x);
+    let du = segmentOffset(rowCdf + col, u.x);
+
+    let theta = PI * (f32(row) + dv) / f32(height);
+    let phi = 2.0 * PI * ((f32(col) + du) / f32(width) - 0.5);
+    let sinTheta = sin(theta);
+    if sinTheta <= 0.0 {
+        return vec4f(0.0);
+    }
+    let direction = vec3f(sinTheta * sin(phi), cos(theta), -sinTheta * cos(phi));
+    // from the (u, v) square to solid angle
+    let pdf = environment_distribution[row * width + col] / integral / (2.0 * PI * PI * sinTheta);
+    return vec4f(direction, pdf);
+}
+
+fn environmentPdf(direction: vec3f) -> f32 {
+    let width = scene_parameters.environment_width;
+    let height = scene_parameters.environment_height;
+    let integral = environment_distribution[width * height + height * (width + 1u) + 2u * height + 1u];
+    let d = normalize(direction);
+    let sinTheta = sqrt(max(1.0 - d.y * d.y, 0.0));
+    if integral <= 0.0 || sinTheta <= 0.0 {
+        return 0.0;
+    }
+    let u = 0.5 + atan2(d.x, -d.z) * 0.5 * FRAC_1_PI;
+    let v = acos(clamp(d.y, -1.0, 1.0)) * FRAC_1_PI;
+    let col = min(u32(u * f32(width)), width - 1u);
+    let row = min(u32(v * f32(height)), height - 1u);
+    return environment_distribution[row * width + col] / integral / (2.0 * PI * PI * sinTheta);
+}
+
+fn findInterval(cdf: u32, size: u32, u: f32) -> u32 {
+    // index of the last of the size cdf entries starting at cdf that is <= u, kept
+    // within the segments
+    var first = 0u;
+    var length = size;
+    while (length > 0u) {
+        let half = length >> 1u;
+        let middle = first + half;
+        if environment_distribution[cdf + middle] <= u {
+            first = middle + 1u;
+            length -= half + 1u;
+        } else {
+            length = half;
+        }
+    }
+    return min(max(first, 1u) - 1u, size - 2u);
+}
+
+fn segmentOffset(segment: u32, u: f32) -> f32 {
+    // where u lies between the cdf values at both ends of a segment
+    let start = environment_distribution[segment];
+    let width = environment_distribution[segment + 1u] - start;
+    if width > 0.0 {
+        return clamp((u - start) / width, 0.0, 1.0);
+    }
+    return 0.0;
+}
+
+fn powerHeuristic(pdf: f32, otherPdf: f32) -> f32 {
+    let a = pdf * pdf;
+    return a / (a + otherPdf * otherPdf);
 }
 
 fn TraceRay(ray: Ray, hit: ptr<function, HitPayload>) -> bool {
@@ -139,15 +1327,17 @@ fn TraceRay(ray: Ray, hit: ptr<function, HitPayload>) -> bool {
 
     if USE_BVH {
         // this is where I will implement the BVH tree search rather than using a full primitive search
-        var stack = array<BVHNode, STACKSIZE>();
+        var stack = array<u32, STACKSIZE>();
         var stackPointer:u32 = 0;
-        var node: BVHNode = bvhTree[0];
+        var nodeIdx: u32 = 0;
         while true {
-            if node.primCount > 0 {
+            let primCount = nodePrimCount(nodeIdx);
+            let leftFirst = nodeLeftFirst(nodeIdx);
+            if primCount > 0 {
                 // this is a leaf and has primitives, so check to see if primitives are hit
-                for (var idx:u32 = 0; idx < node.primCount; idx++) {
+                for (var idx:u32 = 0; idx < primCount; idx++) {
                     var newHitPayload = HitPayload();
-                    if hit(ray, node.leftFirst + idx, 0.001, nearest_hit, &newHitPayload) {
+                    if hit(ray, leftFirst + idx, 0.001, nearest_hit, &newHitPayload) {
                         nearest_hit = newHitPayload.t;
                         tempHitPayload = newHitPayload;
                     }
@@ -159,26 +1349,25 @@ fn TraceRay(ray: Ray, hit: ptr<function, HitPayload>) -> bool {
                 }
                 else {
                     stackPointer--;
-                    node = stack[stackPointer];
+                    nodeIdx = stack[stackPointer];
                     continue;
                 }
             } else {
                 // if not a leaf, check to see if this node's children have been hit
-                var leftChild = bvhTree[node.leftFirst];
-                var rightChild = bvhTree[node.leftFirst + 1];
-                var t_left:f32 = hit_bvh_node(leftChild, ray, nearest_hit);
-                var t_right:f32 = hit_bvh_node(rightChild, ray, nearest_hit);
+                var leftChild = leftFirst;
+                var rightChild = leftFirst + 1;
+                let t_children = hit_bvh_children(nodeIdx, ray, nearest_hit);
+                var t_left:f32 = t_children.x;
+                var t_right:f32 = t_children.y;
 
                 // make sure the left node is always the closer node
-                var swap = false;
                 if t_left > t_right {
                     let temp_t:f32 = t_left;
                     t_left = t_right;
                     t_right = temp_t;
 
-                    var temp = leftChild;
-                    leftChild = rightChild;
-                    rightChild = temp;
+                    leftChild = leftFirst + 1;
+                    rightChild = leftFirst;
                 }
                 // if the left hit is bigger than nearest hit, no need to do anything else here
                 if t_left > nearest_hit {
@@ -186,10 +1375,10 @@ fn TraceRay(ray: Ray, hit: ptr<function, HitPayload>) -> bool {
                         break;
                     } else {
                         stackPointer--;
-                        node = stack[stackPointer];
+                        nodeIdx = stack[stackPointer];
                     }
                 } else {
-                    node = leftChild;
+                    nodeIdx = leftChild;
                     // if the rightChild hit distance is also smaller than nearest_hit, save to the stack
                     if t_right < nearest_hit {
                         stack[stackPointer] = rightChild;
@@ -221,17 +1410,127 @@ fn TraceRay(ray: Ray, hit: ptr<function, HitPayload>) -> bool {
     return false;
 }
 
+fn occluded(ray: Ray, t_max: f32) -> bool {
+    // any hit query for shadow rays; returns true as soon as any sphere is hit between
+    // 0.001 and t_max, without looking for the closest one
+
+    if !USE_BVH {
+        let sphere_count = arrayLength(&spheres);
+        for (var i: u32 = 0; i < sphere_count; i++) {
+            if occludes(ray, i, 0.001, t_max) {
+                return true;
+            }
+        }
+        return false;
+    }
+
+    // children are visited in any order, so there is no need to sort them by distance
+    var stack = array<u32, STACKSIZE>();
+    var stackPointer: u32 = 0;
+    var nodeIdx: u32 = 0;
+    while true {
+        let primCount = nodePrimCount(nodeIdx);
+        let leftFirst = nodeLeftFirst(nodeIdx);
+        if primCount > 0 {
+            for (var idx: u32 = 0; idx < primCount; idx++) {
+                if occludes(ray, leftFirst + idx, 0.001, t_max) {
+                    return true;
+                }
+            }
+        } else {
+            let t_children = hit_bvh_children(nodeIdx, ray, t_max);
+            let hitLeft = t_children.x < t_max;
+            let hitRight = t_children.y < t_max;
+            if hitLeft || hitRight {
+                nodeIdx = select(leftFirst + 1, leftFirst, hitLeft);
+                if hitLeft && hitRight {
+                    stack[stackPointer] = leftFirst + 1;
+                    stackPointer++;
+                }
+                continue;
+            }
+        }
+
+        if stackPointer == 0 {
+            break;
+        }
+        stackPointer--;
+        nodeIdx = stack[stackPointer];
+    }
+    return false;
+}
+
+fn nodeLeftFirst(nodeIdx: u32) -> u32 {
+    if BVH_QUANT_BITS == 0u {
+        return bvhTree[nodeIdx].leftFirst;
+    }
+    return bvhQuantized[nodeIdx * BVH_NODE_STRIDE + 3u];
+}
+
+fn nodePrimCount(nodeIdx: u32) -> u32 {
+    if BVH_QUANT_BITS == 0u {
+        return bvhTree[nodeIdx].primCount;
+    }
+    // leaves are flagged in the exponent word, and keep their count in the first child word
+    let base = nodeIdx * BVH_NODE_STRIDE;
+    if (bvhQuantized[base + 4u] & BVH_LEAF_FLAG) == 0u {
+        return 0u;
+    }
+    return bvhQuantized[base + BVH_HEADER_WORDS];
+}
+
+// returns the entry distances into the left and right child of an interior node
+fn hit_bvh_children(nodeIdx: u32, ray: Ray, nearest_hit: f32) -> vec2f {
+    if BVH_QUANT_BITS == 0u {
+        let leftFirst = bvhTree[nodeIdx].leftFirst;
+        return vec2f(hit_bvh_node(bvhTree[leftFirst], ray, nearest_hit),
+                     hit_bvh_node(bvhTree[leftFirst + 1], ray, nearest_hit));
+    }
+
+    // the node stores its own origin and the biased exponents of a power of two scale per
+    // axis; the child boxes are quantized relative to them in the order left min, left max,
+    // right min, right max
+    let base = nodeIdx * BVH_NODE_STRIDE;
+    let origin = bitcast<vec3f>(vec3u(bvhQuantized[base], bvhQuantized[base + 1u], bvhQuantized[base + 2u]));
+    let exponents = bvhQuantized[base + 4u];
+    let scale = bitcast<vec3f>(vec3u(extractBits(exponents, 0u, 8u), extractBits(exponents, 8u, 8u),
+                                     extractBits(exponents, 16u, 8u)) << vec3u(23u));
+    let leftMin = origin + scale * quantizedVec3(base, 0u);
+    let leftMax = origin + scale * quantizedVec3(base, 3u);
+    let rightMin = origin + scale * quantizedVec3(base, 6u);
+    let rightMax = origin + scale * quantizedVec3(base, 9u);
+    return vec2f(hit_aabb(leftMin, leftMax, ray, nearest_hit),
+                 hit_aabb(rightMin, rightMax, ray, nearest_hit));
+}
+
+fn quantizedVec3(base: u32, first: u32) -> vec3f {
+    return vec3f(f32(quantizedValue(base, first)),
+                 f32(quantizedValue(base, first + 1u)),
+                 f32(quantizedValue(base, first + 2u)));
+}
+
+fn quantizedValue(base: u32, k: u32) -> u32 {
+    // max keeps the full precision pipeline from seeing a division by zero
+    let valuesPerWord = 32u / max(BVH_QUANT_BITS, 8u);
+    let word = bvhQuantized[base + BVH_HEADER_WORDS + k / valuesPerWord];
+    return extractBits(word, (k % valuesPerWord) * BVH_QUANT_BITS, BVH_QUANT_BITS);
+}
+
 fn hit_bvh_node(node: BVHNode, ray: Ray, nearest_hit: f32) -> f32 {
-    let t_x_min = (node.aabbMin.x - ray.origin.x) * ray.invDirection.x;
-    let t_x_max = (node.aabbMax.x - ray.origin.x) * ray.invDirection.x;
+    return hit_aabb(node.aabbMin, node.aabbMax, ray, nearest_hit);
+}
+
+fn hit_aabb(aabbMin: vec3f, aabbMax: vec3f, ray: Ray, nearest_hit: f32) -> f32 {
+    let t_x_min = (aabbMin.x - ray.origin.x) * ray.invDirection.x;
+    let t_x_max = (aabbMax.x - ray.origin.x) * ray.invDirection.x;
     var tmin = min(t_x_min, t_x_max);
     var tmax = max(t_x_min, t_x_max);
-    let t_y_min = (node.aabbMin.y - ray.origin.y) * ray.invDirection.y;
-    let t_y_max = (node.aabbMax.y - ray.origin.y) * ray.invDirection.y;
+    let t_y_min = (aabbMin.y - ray.origin.y) * ray.invDirection.y;
+    let t_y_max = (aabbMax.y - ray.origin.y) * ray.invDirection.y;
     tmin = max(min(t_y_min, t_y_max), tmin);
     tmax = min(max(t_y_min, t_y_max), tmax);
-    let t_z_min = (node.aabbMin.z - ray.origin.z) * ray.invDirection.z;
-    let t_z_max = (node.aabbMax.z - ray.origin.z) * ray.invDirection.z;
+    let t_z_min = (aabbMin.z - ray.origin.z) * ray.invDirection.z;
+    let t_z_max = (aabbMax.z - ray.origin.z) * ray.invDirection.z;
     tmin = max(min(t_z_min, t_z_max), tmin);
     tmax = min(max(t_z_min, t_z_max), tmax);
 
@@ -256,13 +1555,13 @@ fn hit(ray: Ray, sphereIdx: u32, t_min: f32, t_nearest: f32, payload: ptr<functi
 
     if (discrim >= 0) {
         var t: f32 = (-b - sqrt(discrim)) / a;
-        if (t > t_min && t < t_nearest) {
+        if (t > t_min && t < t_nearest && anyHit(ray, sphere, sphereIdx, t)) {
             *payload = hitSphere(t, ray, sphere, sphereIdx);
             return true;
         }
 
         t = (-b + sqrt(discrim)) / a;
-        if (t > t_min && t < t_nearest) {
+        if (t > t_min && t < t_nearest && anyHit(ray, sphere, sphereIdx, t)) {
             *payload = hitSphere(t, ray, sphere, sphereIdx);
             return true;
         }
@@ -270,6 +1569,55 @@ fn hit(ray: Ray, sphereIdx: u32, t_min: f32, t_nearest: f32, payload: ptr<functi
     return false;
 }
 
+fn occludes(ray: Ray, sphereIdx: u32, t_min: f32, t_max: f32) -> bool {
+    // same test as hit, but only reports whether either intersection lies in (t_min, t_max)
+    let sphere: Sphere = spheres[sphereIdx];
+    let oc = ray.origin - sphere.center.xyz;
+    let a: f32 = dot(ray.direction, ray.direction);
+    let b: f32 = dot(ray.direction, oc);
+    let c: f32 = dot(oc, oc) - sphere.radius * sphere.radius;
+    let discrim: f32 = b * b - a * c;
+
+    if (discrim < 0) {
+        return false;
+    }
+    let t_near = (-b - sqrt(discrim)) / a;
+    let t_far = (-b + sqrt(discrim)) / a;
+    return (t_near > t_min && t_near < t_max && anyHit(ray, sphere, sphereIdx, t_near)) ||
+        (t_far > t_min && t_far < t_max && anyHit(ray, sphere, sphereIdx, t_far));
+}
+
+fn anyHit(ray: Ray, sphere: Sphere, sphereIdx: u32, t: f32) -> bool {
+    // called for every intersection found during traversal; a surface with an opacity or
+    // alpha texture is kept with a chance equal to its alpha, and skipped otherwise, so
+    // that shadow rays pass through it as well
+    let material = materials[sphere.mat_idx];
+    if material.opacity >= 1.0 && material.alpha_texture == NO_TEXTURE {
+        return true;
+    }
+    var alpha = material.opacity;
+    if material.alpha_texture != NO_TEXTURE {
+        let hit = hitSphere(t, ray, sphere, sphereIdx);
+        alpha *= dot(textureValue(material.alpha_texture, hit.uv, hit.p), vec3f(1.0 / 3.0));
+    }
+    return alpha > alphaThreshold(ray, sphereIdx, t);
+}
+
+fn alphaThreshold(ray: Ray, sphereIdx: u32, t: f32) -> f32 {
+    // TraceRay has no random numbers to draw from, so the alpha test hashes the ray and the
+    // intersection into a threshold in [0, 1), as pbrt does
+    let origin = bitcast<vec3u>(ray.origin);
+    let direction = bitcast<vec3u>(ray.direction);
+    var hash = jenkinsHash(sphereIdx ^ bitcast<u32>(t));
+    hash = jenkinsHash(hash ^ origin.x);
+    hash = jenkinsHash(hash ^ origin.y);
+    hash = jenkinsHash(hash ^ origin.z);
+    hash = jenkinsHash(hash ^ direction.x);
+    hash = jenkinsHash(hash ^ direction.y);
+    hash = jenkinsHash(hash ^ direction.z);
+    return f32(hash >> 8u) / 16777216.0;
+}
+
 fn hitSphere(t: f32, ray: Ray, sphere: Sphere, idx: u32) -> HitPayload {
     // make the hitPayload struct
     // note that decision here is that normals ALWAYS point out of the sphere
@@ -279,7 +1627,186 @@ fn hitSphere(t: f32, ray: Ray, sphere: Sphere, idx: u32) -> HitPayload {
     let p: vec3f = ray.origin + t * ray.direction;
     let n: vec3f = normalize(p - sphere.center.xyz);
 
-    return HitPayload(t, p, n, idx);
+    return HitPayload(t, p, n, idx, sphereUV(n), n);
+}
+
+fn sphereUV(n: vec3f) -> vec2f {
+    // u turns around the y axis starting from -x, v goes from the bottom (0) to the top (1)
+    let theta = acos(clamp(-n.y, -1.0, 1.0));
+    let phi = atan2(-n.z, n.x) + PI;
+    return vec2f(phi * 0.5 * FRAC_1_PI, theta * FRAC_1_PI);
+}
+
+fn materialAlbedo(mat_idx: u32, hit: HitPayload) -> vec3f {
+    // the albedo texture, if the material has one, replaces the constant albedo
+    let material = materials[mat_idx];
+    if material.albedo_texture == NO_TEXTURE {
+        return material.albedo.xyz;
+    }
+    return textureValue(material.albedo_texture, hit.uv, hit.p);
+}
+
+fn shadingNormal(mat_idx: u32, hit: HitPayload, wo: vec3f) -> vec3f {
+    // the normal or bump map tilts the normal in the tangent frame of the sphere's uvs; a
+    // tilt that turns the surface away from wo is dropped, as the bsdf would then see wo
+    // on the other side of the surface than the ray came from
+    let material = materials[mat_idx];
+    if material.normal_texture == NO_TEXTURE {
+        return hit.n;
+    }
+    // u turns around the y axis, so the frame degenerates at the poles
+    let sinTheta = sqrt(hit.n.x * hit.n.x + hit.n.z * hit.n.z);
+    if sinTheta < 1e-4 {
+        return hit.n;
+    }
+    let tangent = vec3f(hit.n.z, 0.0, -hit.n.x) / sinTheta;
+    let bitangent = cross(hit.n, tangent);
+    var shading: vec3f;
+    if material.normal_type == 0u {
+        let t = 2.0 * textureValue(material.normal_texture, hit.uv, hit.p) - vec3f(1.0);
+        shading = normalize(material.normal_strength * (t.x * tangent + t.y * bitangent) + t.z * hit.n);
+    } else {
+        // slopes of the height along the tangent and bitangent; a step of BUMP_DELTA
+        // covers BUMP_DELTA / (2 pi r sinTheta) in u and BUMP_DELTA / (pi r) in v
+        let radius = abs(spheres[hit.idx].radius);
+        let du = vec2f(BUMP_DELTA / (2.0 * PI * radius * sinTheta), 0.0);
+        let dv = vec2f(0.0, BUMP_DELTA / (PI * radius));
+        let height = bumpHeight(material.normal_texture, hit.uv, hit.p);
+        let slopeU = (bumpHeight(material.normal_texture, hit.uv + du, hit.p + BUMP_DELTA * tangent) - height) / BUMP_DELTA;
+        let slopeV = (bumpHeight(material.normal_texture, hit.uv + dv, hit.p + BUMP_DELTA * bitangent) - height) / BUMP_DELTA;
+        shading = normalize(hit.n - material.normal_strength * (slopeU * tangent + slopeV * bitangent));
+    }
+    if dot(shading, wo) * dot(hit.ng, wo) <= 0.0 {
+        return hit.ng;
+    }
+    return shading;
+}
+
+fn bumpHeight(textureIdx: u32, uv: vec2f, p: vec3f) -> f32 {
+    return dot(textureValue(textureIdx, uv, p), vec3f(1.0 / 3.0));
+}
+
+fn textureValue(textureIdx: u32, uv: vec2f, p: vec3f) -> vec3f {
+    // the same steps as Texture::value on the CPU
+    let texture = textures[textureIdx];
+    let color = texture.color.xyz;
+    switch (texture.texture_type) {
+        case 1u {
+            let cell = floor(p / texture.scale);
+            let sum = cell.x + cell.y + cell.z;
+            if sum - 2.0 * floor(0.5 * sum) < 1.0 {
+                return color;
+            }
+            return texture.odd_color.xyz;
+        }
+        case 2u {
+            return color * 0.5 * (1.0 + perlin(texture.scale * p));
+        }
+        case 3u {
+            return color * turbulence(texture.scale * p, texture.depth);
+        }
+        case 4u {
+            return color * 0.5 * (1.0 + sin(texture.scale * p.z + 10.0 * turbulence(p, 7u)));
+        }
+        case 5u {
+            return imageLookup(texture, uv);
+        }
+        default {
+            return color;
+        }
+    }
+}
+
+fn imageLookup(texture: Texture, uv: vec2f) -> vec3f {
+    // bilinear lookup; texel centers sit at half integers and row 0 is the top of the image
+    let x = uv.x * f32(texture.width) - 0.5;
+    let y = (1.0 - uv.y) * f32(texture.height) - 0.5;
+    let x0 = i32(floor(x));
+    let y0 = i32(floor(y));
+    let tx = x - f32(x0);
+    let ty = y - f32(y0);
+    let top = mix(imageTexel(texture, x0, y0), imageTexel(texture, x0 + 1, y0), tx);
+    let bottom = mix(imageTexel(texture, x0, y0 + 1), imageTexel(texture, x0 + 1, y0 + 1), tx);
+    return mix(top, bottom, ty);
+}
+
+fn imageTexel(texture: Texture, i: i32, j: i32) -> vec3f {
+    let column = wrapTexel(i, texture.width, texture.wrap);
+    let row = wrapTexel(j, texture.height, texture.wrap);
+    return texels[texture.texel_offset + row * texture.width + column].xyz;
+}
+
+fn wrapTexel(i: i32, size: u32, wrap: u32) -> u32 {
+    // 0 repeat, 1 clamp, 2 mirror
+    let n = i32(size);
+    switch (wrap) {
+        case 1u {
+            return u32(clamp(i, 0, n - 1));
+        }
+        case 2u {
+            let period = ((i % (2 * n)) + 2 * n) % (2 * n);
+            if period < n {
+                return u32(period);
+            }
+            return u32(2 * n - 1 - period);
+        }
+        default {
+            return u32(((i % n) + n) % n);
+        }
+    }
+}
+
+fn perlin(p: vec3f) -> f32 {
+    // gradient noise in [-1, 1]; the gradients are hashed from the lattice coordinates
+    let cell = floor(p);
+    let f = p - cell;
+    // Hermite smoothing of the weights
+    let w = f * f * (3.0 - 2.0 * f);
+    let c = vec3i(cell);
+    var accum: f32 = 0.0;
+    for (var dk: i32 = 0; dk < 2; dk++) {
+        for (var dj: i32 = 0; dj < 2; dj++) {
+            for (var di: i32 = 0; di < 2; di++) {
+                let corner = vec3f(f32(di), f32(dj), f32(dk));
+                let gradient = latticeGradient(c + vec3i(di, dj, dk));
+                let weight = corner * w + (1.0 - corner) * (1.0 - w);
+                accum += weight.x * weight.y * weight.z * dot(gradient, f - corner);
+            }
+        }
+    }
+    return accum;
+}
+
+fn turbulence(p: vec3f, depth: u32) -> f32 {
+    var accum: f32 = 0.0;
+    var point = p;
+    var weight: f32 = 1.0;
+    for (var i: u32 = 0; i < depth; i++) {
+        accum += weight * perlin(point);
+        weight *= 0.5;
+        point *= 2.0;
+    }
+    return abs(accum);
+}
+
+fn latticeGradient(cell: vec3i) -> vec3f {
+    // one of the twelve edge directions of the cube of the improved Perlin noise
+    let c = bitcast<vec3u>(cell);
+    let hash = jenkinsHash(c.x ^ jenkinsHash(c.y ^ jenkinsHash(c.z)));
+    switch (hash % 12u) {
+        case 0u { return vec3f(1.0, 1.0, 0.0); }
+        case 1u { return vec3f(-1.0, 1.0, 0.0); }
+        case 2u { return vec3f(1.0, -1.0, 0.0); }
+        case 3u { return vec3f(-1.0, -1.0, 0.0); }
+        case 4u { return vec3f(1.0, 0.0, 1.0); }
+        case 5u { return vec3f(-1.0, 0.0, 1.0); }
+        case 6u { return vec3f(1.0, 0.0, -1.0); }
+        case 7u { return vec3f(-1.0, 0.0, -1.0); }
+        case 8u { return vec3f(0.0, 1.0, 1.0); }
+        case 9u { return vec3f(0.0, -1.0, 1.0); }
+        case 10u { return vec3f(0.0, 1.0, -1.0); }
+        default { return vec3f(0.0, -1.0, -1.0); }
+    }
 }
 
 fn getRay(x: u32, y: u32, state: ptr<function, u32>) -> Ray {
@@ -313,14 +1840,17 @@ fn getRay(x: u32, y: u32, state: ptr<function, u32>) -> Ray {
     return ray;
 }
 
-fn getScatterRay(inRay: ptr<function, Ray>, mat_idx: u32, hit: ptr<function, HitPayload>, state: ptr<function, u32>) {
+fn getScatterRay(inRay: ptr<function, Ray>, weight: ptr<function, vec3f>, mat_idx: u32, lambda: f32, outsideIndex: f32, hit: ptr<function, HitPayload>, state: ptr<function, u32>) {
     // when we show up here, hit.n is necessarily the outward normal of the sphere
     // we need to orient it correctly
     let payLoad = *hit;
     var ray = Ray();
     ray.origin = payLoad.p;
 
-    let mat_type: u32 = materials[mat_idx].mat_type;
+    let material = materials[mat_idx];
+    let mat_type: u32 = material.mat_type;
+    // what the throughput is scaled by; the albedo unless the material says otherwise
+    *weight = materialAlbedo(mat_idx, payLoad);
 
     switch (mat_type) {
         case 0u, default {
@@ -337,7 +1867,13 @@ fn getScatterRay(inRay: ptr<function, Ray>, mat_idx: u32, hit: ptr<function, Hit
             ray.direction = reflect((*inRay).direction, payLoad.n) + fuzz * randomBounce;
         }
         case 2u {
-            let refract_idx: f32 = materials[mat_idx].refract_idx;
+            // lambda is the hero wavelength at dispersive glass in spectral mode, and 0 otherwise
+            var refract_idx: f32 = materials[mat_idx].refract_idx;
+            if lambda > 0.0 {
+                refract_idx = dispersiveIndex(mat_idx, lambda);
+            }
+            // relative to the medium on the outside of the surface
+            refract_idx /= outsideIndex;
             var norm: vec3f = payLoad.n;
             let uv = normalize((*inRay).direction);
             var cosTheta: f32 = min(dot(norm, -uv), 1.0); // as uv represents incoming, -uv is outgoing direction
@@ -360,9 +1896,31 @@ fn getScatterRay(inRay: ptr<function, Ray>, mat_idx: u32, hit: ptr<function, Hit
                 cosTheta *= -1.0;
             }
 
-            let reflectance: f32 = schlick(cosTheta, etaOverEtaPrime);
             var refractDirection: vec3f = vec3f(0.0);
+            if material.film_thickness > 0.0 {
+                // the film colors the reflection, so reflecting is picked with the average of
+                // its reflectance and the weights make up for the rest; the film is seen from
+                // the medium outside, as the glass is
+                var n1: f32 = 1.0;
+                if dot(payLoad.n, uv) > 0.0 {
+                    n1 = refract_idx;
+                }
+                let filmReflectance = thinFilmReflectance(cosTheta, n1, material.film_thickness * outsideIndex,
+                    material.film_ior / outsideIndex, vec3f(n1 / etaOverEtaPrime), vec3f(0.0));
+                let chance = (filmReflectance.x + filmReflectance.y + filmReflectance.z) / 3.0;
+                if !refract(uv, norm, etaOverEtaPrime, &refractDirection) {
+                    ray.direction = reflect(uv, norm);
+                } else if chance > rngNextFloat(state) {
+                    ray.direction = reflect(uv, norm);
+                    *weight *= filmReflectance / chance;
+                } else {
+                    ray.direction = refractDirection;
+                    *weight *= (vec3f(1.0) - filmReflectance) / (1.0 - chance);
+                }
+                break;
+            }
 
+            let reflectance: f32 = schlick(cosTheta, etaOverEtaPrime);
             if refract(uv, norm, etaOverEtaPrime, &refractDirection) {
                 if reflectance > rngNextFloat(state) {
                     ray.direction = reflect(uv, norm);
@@ -373,6 +1931,73 @@ fn getScatterRay(inRay: ptr<function, Ray>, mat_idx: u32, hit: ptr<function, Hit
                 ray.direction = reflect(uv, norm);
             }
         }
+        case 4u {
+            let u = vec2f(rngNextFloat(state), rngNextFloat(state));
+            let wo = -normalize((*inRay).direction);
+            let m = sampleMicrofacetNormal(wo, payLoad.n, material.roughness, u);
+            ray.direction = reflect(-wo, m);
+            if dot(payLoad.n, wo) > 0.0 && dot(payLoad.n, ray.direction) > 0.0 {
+                *weight *= conductorFresnel(dot(wo, m), mat_idx)
+                    * microfacetWeight(wo, ray.direction, payLoad.n, material.roughness);
+            } else {
+                *weight = vec3f(0.0);
+            }
+        }
+        case 5u {
+            let u = vec3f(rngNextFloat(state), rngNextFloat(state), rngNextFloat(state));
+            let wo = -normalize((*inRay).direction);
+            // the normal faces wo and eta is the index across the boundary over the one wo is in
+            var norm: vec3f = payLoad.n;
+            var eta: f32 = material.refract_idx / outsideIndex;
+            if dot(norm, wo) < 0.0 {
+                norm *= -1.0;
+                eta = 1.0 / eta;
+            }
+            let m = sampleMicrofacetNormal(wo, norm, material.roughness, u.xy);
+            // picking reflection with the Fresnel probability cancels it out of the weight
+            let reflected = u.z < fresnelDielectric(dot(wo, m), eta);
+            var refractDirection: vec3f = vec3f(0.0);
+            if reflected || !refract(-wo, m, 1.0 / eta, &refractDirection) {
+                ray.direction = reflect(-wo, m);
+            } else {
+                ray.direction = refractDirection;
+            }
+            if (dot(norm, ray.direction) > 0.0) == reflected {
+                *weight *= microfacetWeight(wo, ray.direction, norm, material.roughness);
+            } else {
+                *weight = vec3f(0.0);
+            }
+        }
+        case 6u {
+            let u = vec3f(rngNextFloat(state), rngNextFloat(state), rngNextFloat(state));
+            let wo = -normalize((*inRay).direction);
+            let baseColor = *weight;
+            ray.direction = principledSample(wo, payLoad.n, mat_idx, baseColor, u);
+            let pdf = principledPdf(wo, ray.direction, payLoad.n, mat_idx, baseColor);
+            if pdf > 0.0 {
+                *weight = principledEval(wo, ray.direction, payLoad.n, mat_idx, baseColor) / pdf;
+            } else {
+                *weight = vec3f(0.0);
+                ray.direction = payLoad.n;
+            }
+        }
+        case 7u {
+            let u = vec3f(rngNextFloat(state), rngNextFloat(state), rngNextFloat(state));
+            let wo = -normalize((*inRay).direction);
+            let baseColor = *weight;
+            ray.direction = layeredSample(wo, payLoad.n, mat_idx, baseColor, u);
+            let pdf = layeredPdf(wo, ray.direction, payLoad.n, mat_idx, baseColor);
+            if pdf > 0.0 {
+                *weight = layeredEval(wo, ray.direction, payLoad.n, mat_idx, baseColor) / pdf;
+            } else {
+                *weight = vec3f(0.0);
+                ray.direction = payLoad.n;
+            }
+        }
+    }
+    // a bounce the shading normal sends through the geometric surface would leak light
+    if (dot(payLoad.ng, ray.direction) > 0.0) != (dot(payLoad.n, ray.direction) > 0.0) {
+        *weight = vec3f(0.0);
     }
     ray.invDirection = 1.0 / ray.direction;
     *inRay = ray;
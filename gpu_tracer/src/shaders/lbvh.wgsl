@@ -0,0 +1,274 @@
+// linear bvh construction (Karras 2012); the tree is written in the same layout the cpu
+// builder produces: root at 0, an unused node at 1, and the two children of a node
+// stored next to each other at leftFirst and leftFirst + 1
+// internal node i (in Karras' numbering) places its children in slots 2 + 2i and 3 + 2i,
+// and every leaf holds a single sphere, referenced by its index in the unsorted array
+const WORKGROUP_SIZE = 256u;
+const MAX_U32 = 0xffffffffu;
+
+struct BVHNode {
+    aabbMin: vec3f,
+    leftFirst: u32,
+    aabbMax: vec3f,
+    primCount: u32,
+}
+
+struct Sphere {
+    center: vec4f,
+    radius: f32,
+    mat_idx: u32,
+}
+
+struct BuildParameters {
+    numPrims: u32,
+    numBlocks: u32,
+}
+
+// spheres and bvhTree use the same bindings as in the megakernel's scene bind group
+@group(0) @binding(0) var<storage, read> spheres: array<Sphere>;
+// min xyz then max xyz of the scene, as order preserving uints, then the depth of the tree
+@group(0) @binding(1) var<storage, read_write> sceneBounds: array<atomic<u32>, 7>;
+@group(0) @binding(2) var<storage, read_write> bvhTree: array<BVHNode>;
+// the same, for every node of the tree
+@group(0) @binding(3) var<storage, read_write> nodeBounds: array<atomic<u32>>;
+@group(0) @binding(4) var<storage, read_write> mortonCodes: array<u32>;
+@group(0) @binding(5) var<storage, read_write> primIndices: array<u32>;
+@group(0) @binding(6) var<storage, read_write> leafSlots: array<u32>;
+@group(0) @binding(7) var<storage, read_write> internalSlots: array<u32>;
+@group(0) @binding(8) var<uniform> params: BuildParameters;
+
+// flips the bits of a float so that unsigned comparisons follow the float order
+fn floatToOrderedUint(f: f32) -> u32 {
+    let bits = bitcast<u32>(f);
+    if ((bits & 0x80000000u) != 0u) {
+        return ~bits;
+    }
+    return bits | 0x80000000u;
+}
+
+fn orderedUintToFloat(u: u32) -> f32 {
+    if ((u & 0x80000000u) != 0u) {
+        return bitcast<f32>(u & 0x7fffffffu);
+    }
+    return bitcast<f32>(~u);
+}
+
+fn sphereMin(sphere: Sphere) -> vec3f {
+    return sphere.center.xyz - vec3f(sphere.radius);
+}
+
+fn sphereMax(sphere: Sphere) -> vec3f {
+    return sphere.center.xyz + vec3f(sphere.radius);
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn reset(@builtin(global_invocation_id) id: vec3u) {
+    let idx = id.x;
+    if (idx == 0u) {
+        for (var axis = 0u; axis < 3u; axis++) {
+            atomicStore(&sceneBounds[axis], MAX_U32);
+            atomicStore(&sceneBounds[axis + 3u], 0u);
+        }
+        atomicStore(&sceneBounds[6], 0u);
+    }
+    if (idx >= 2u * params.numPrims) {
+        return;
+    }
+    for (var axis = 0u; axis < 3u; axis++) {
+        atomicStore(&nodeBounds[6u * idx + axis], MAX_U32);
+        atomicStore(&nodeBounds[6u * idx + axis + 3u], 0u);
+    }
+    // the placeholder node is never written by the hierarchy
+    if (idx == 1u) {
+        bvhTree[1] = BVHNode(vec3f(0.0), 0u, vec3f(0.0), 0u);
+    }
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn scene_bounds(@builtin(global_invocation_id) id: vec3u) {
+    let idx = id.x;
+    if (idx >= params.numPrims) {
+        return;
+    }
+    let aabbMin = sphereMin(spheres[idx]);
+    let aabbMax = sphereMax(spheres[idx]);
+    for (var axis = 0u; axis < 3u; axis++) {
+        atomicMin(&sceneBounds[axis], floatToOrderedUint(aabbMin[axis]));
+        atomicMax(&sceneBounds[axis + 3u], floatToOrderedUint(aabbMax[axis]));
+    }
+}
+
+// spreads the lower 10 bits of v so there are two zero bits between each of them
+fn expandBits(v: u32) -> u32 {
+    var x = v & 0x3ffu;
+    x = (x | (x << 16u)) & 0x030000ffu;
+    x = (x | (x << 8u)) & 0x0300f00fu;
+    x = (x | (x << 4u)) & 0x030c30c3u;
+    x = (x | (x << 2u)) & 0x09249249u;
+    return x;
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn morton_codes(@builtin(global_invocation_id) id: vec3u) {
+    let idx = id.x;
+    if (idx >= params.numPrims) {
+        return;
+    }
+    var sceneMin: vec3f;
+    var sceneMax: vec3f;
+    for (var axis = 0u; axis < 3u; axis++) {
+        sceneMin[axis] = orderedUintToFloat(atomicLoad(&sceneBounds[axis]));
+        sceneMax[axis] = orderedUintToFloat(atomicLoad(&sceneBounds[axis + 3u]));
+    }
+    let extent = max(sceneMax - sceneMin, vec3f(1e-20));
+    let p = clamp((spheres[idx].center.xyz - sceneMin) / extent, vec3f(0.0), vec3f(1.0));
+    let q = vec3u(min(p * 1024.0, vec3f(1023.0)));
+    mortonCodes[idx] = (expandBits(q.x) << 2u) | (expandBits(q.y) << 1u) | expandBits(q.z);
+    primIndices[idx] = idx;
+}
+
+// length of the common prefix of the sorted keys i and j, or -1 if j is out of range;
+// equal codes are told apart by their position so every key is unique
+fn delta(i: i32, j: i32) -> i32 {
+    if (j < 0 || j >= i32(params.numPrims)) {
+        return -1;
+    }
+    let a = mortonCodes[i];
+    let b = mortonCodes[j];
+    if (a == b) {
+        return 32 + i32(countLeadingZeros(u32(i) ^ u32(j)));
+    }
+    return i32(countLeadingZeros(a ^ b));
+}
+
+fn placeChild(child: u32, isLeaf: bool, slot: u32) {
+    if (isLeaf) {
+        bvhTree[slot].leftFirst = primIndices[child];
+        bvhTree[slot].primCount = 1u;
+        leafSlots[child] = slot;
+    } else {
+        bvhTree[slot].leftFirst = 2u + 2u * child;
+        bvhTree[slot].primCount = 0u;
+        internalSlots[child] = slot;
+    }
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn hierarchy(@builtin(global_invocation_id) id: vec3u) {
+    let n = params.numPrims;
+    // a single sphere makes the root a leaf
+    if (n == 1u && id.x == 0u) {
+        bvhTree[0].leftFirst = primIndices[0];
+        bvhTree[0].primCount = 1u;
+        leafSlots[0] = 0u;
+        return;
+    }
+    if (id.x + 1u >= n) {
+        return;
+    }
+    let i = i32(id.x);
+
+    // the direction of the range covered by node i
+    let d = select(-1, 1, delta(i, i + 1) - delta(i, i - 1) > 0);
+
+    // upper bound for the length of the range, then the exact other end of it
+    let deltaMin = delta(i, i - d);
+    var lMax = 2;
+    while (delta(i, i + lMax * d) > deltaMin) {
+        lMax *= 2;
+    }
+    var l = 0;
+    for (var t = lMax / 2; t >= 1; t /= 2) {
+        if (delta(i, i + (l + t) * d) > deltaMin) {
+            l += t;
+        }
+    }
+    let j = i + l * d;
+
+    // find where the keys in the range stop sharing the node's prefix
+    let deltaNode = delta(i, j);
+    var s = 0;
+    var t = l;
+    loop {
+        t = (t + 1) / 2;
+        if (delta(i, i + (s + t) * d) > deltaNode) {
+            s += t;
+        }
+        if (t <= 1) {
+            break;
+        }
+    }
+    let split = u32(i + s * d + min(d, 0));
+    let first = u32(min(i, j));
+    let last = u32(max(i, j));
+
+    if (i == 0) {
+        bvhTree[0].leftFirst = 2u;
+        bvhTree[0].primCount = 0u;
+        internalSlots[0] = 0u;
+    }
+    placeChild(split, first == split, 2u + 2u * u32(i));
+    placeChild(split + 1u, last == split + 1u, 3u + 2u * u32(i));
+}
+
+// grows the bounds of a node; returns false if they already held the box
+fn growNode(slot: u32, aabbMin: vec3f, aabbMax: vec3f) -> bool {
+    var grown = false;
+    for (var axis = 0u; axis < 3u; axis++) {
+        let qMin = floatToOrderedUint(aabbMin[axis]);
+        let qMax = floatToOrderedUint(aabbMax[axis]);
+        grown = (atomicMin(&nodeBounds[6u * slot + axis], qMin) > qMin) || grown;
+        grown = (atomicMax(&nodeBounds[6u * slot + axis + 3u], qMax) < qMax) || grown;
+    }
+    return grown;
+}
+
+// every leaf walks up to the root growing the bounds of its ancestors; a walk can stop
+// as soon as a node already holds the box, as whichever walk grew it that far will carry
+// the same values further up
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn propagate_bounds(@builtin(global_invocation_id) id: vec3u) {
+    if (id.x >= params.numPrims) {
+        return;
+    }
+    let sphere = spheres[primIndices[id.x]];
+    let aabbMin = sphereMin(sphere);
+    let aabbMax = sphereMax(sphere);
+
+    var slot = leafSlots[id.x];
+    growNode(slot, aabbMin, aabbMax);
+    while (slot != 0u) {
+        slot = internalSlots[(slot - 2u) / 2u];
+        if (!growNode(slot, aabbMin, aabbMax)) {
+            break;
+        }
+    }
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn write_bounds(@builtin(global_invocation_id) id: vec3u) {
+    let slot = id.x;
+    if (slot >= 2u * params.numPrims || slot == 1u) {
+        return;
+    }
+    for (var axis = 0u; axis < 3u; axis++) {
+        bvhTree[slot].aabbMin[axis] = orderedUintToFloat(atomicLoad(&nodeBounds[6u * slot + axis]));
+        bvhTree[slot].aabbMax[axis] = orderedUintToFloat(atomicLoad(&nodeBounds[6u * slot + axis + 3u]));
+    }
+}
+
+// the depth of the deepest leaf, counting the root as the first level; Karras trees over
+// clustered codes can be deeper than the traversal stack of the megakernel
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn tree_depth(@builtin(global_invocation_id) id: vec3u) {
+    if (id.x >= params.numPrims) {
+        return;
+    }
+    var slot = leafSlots[id.x];
+    var depth = 1u;
+    while (slot != 0u) {
+        slot = internalSlots[(slot - 2u) / 2u];
+        depth++;
+    }
+    atomicMax(&sceneBounds[6], depth);
+}
@@ -0,0 +1,105 @@
+// least significant digit radix sort of (key, value) pairs, 4 bits per pass; each pass
+// counts the digits of every block, scans the counts, and scatters the pairs in a stable
+// way to the output buffers
+const WORKGROUP_SIZE = 256u;
+const RADIX = 16u;
+
+struct SortParameters {
+    numKeys: u32,
+    numBlocks: u32,
+    shift: u32,
+}
+
+@group(0) @binding(0) var<storage, read> keysIn: array<u32>;
+@group(0) @binding(1) var<storage, read> valuesIn: array<u32>;
+@group(0) @binding(2) var<storage, read_write> keysOut: array<u32>;
+@group(0) @binding(3) var<storage, read_write> valuesOut: array<u32>;
+// digit counts, digit major: histogram[digit * numBlocks + block]
+@group(0) @binding(4) var<storage, read_write> histogram: array<u32>;
+@group(0) @binding(5) var<uniform> params: SortParameters;
+
+var<workgroup> digitCounts: array<atomic<u32>, RADIX>;
+var<workgroup> blockDigits: array<u32, WORKGROUP_SIZE>;
+var<workgroup> partialSums: array<u32, WORKGROUP_SIZE>;
+
+fn digitOf(key: u32) -> u32 {
+    return (key >> params.shift) & (RADIX - 1u);
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn count_digits(@builtin(local_invocation_id) lid: vec3u, @builtin(workgroup_id) wid: vec3u) {
+    if (lid.x < RADIX) {
+        atomicStore(&digitCounts[lid.x], 0u);
+    }
+    workgroupBarrier();
+
+    let idx = wid.x * WORKGROUP_SIZE + lid.x;
+    if (idx < params.numKeys) {
+        atomicAdd(&digitCounts[digitOf(keysIn[idx])], 1u);
+    }
+    workgroupBarrier();
+
+    if (lid.x < RADIX) {
+        histogram[lid.x * params.numBlocks + wid.x] = atomicLoad(&digitCounts[lid.x]);
+    }
+}
+
+// exclusive scan of the whole histogram by a single workgroup; each invocation sums a
+// contiguous chunk, the chunk sums are scanned, and then each chunk is written out
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn scan_histogram(@builtin(local_invocation_id) lid: vec3u) {
+    let length = RADIX * params.numBlocks;
+    let chunk = (length + WORKGROUP_SIZE - 1u) / WORKGROUP_SIZE;
+    let start = lid.x * chunk;
+    let end = min(start + chunk, length);
+
+    var sum = 0u;
+    for (var k = start; k < end; k++) {
+        sum += histogram[k];
+    }
+    partialSums[lid.x] = sum;
+    workgroupBarrier();
+
+    // Hillis Steele inclusive scan of the chunk sums
+    for (var offset = 1u; offset < WORKGROUP_SIZE; offset *= 2u) {
+        var value = partialSums[lid.x];
+        if (lid.x >= offset) {
+            value += partialSums[lid.x - offset];
+        }
+        workgroupBarrier();
+        partialSums[lid.x] = value;
+        workgroupBarrier();
+    }
+
+    var running = partialSums[lid.x] - sum;
+    for (var k = start; k < end; k++) {
+        let count = histogram[k];
+        histogram[k] = running;
+        running += count;
+    }
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn scatter(@builtin(local_invocation_id) lid: vec3u, @builtin(workgroup_id) wid: vec3u) {
+    let idx = wid.x * WORKGROUP_SIZE + lid.x;
+    var digit = RADIX;
+    if (idx < params.numKeys) {
+        digit = digitOf(keysIn[idx]);
+    }
+    blockDigits[lid.x] = digit;
+    workgroupBarrier();
+
+    if (idx >= params.numKeys) {
+        return;
+    }
+    // pairs with the same digit earlier in the block keep going first
+    var rank = 0u;
+    for (var k = 0u; k < lid.x; k++) {
+        if (blockDigits[k] == digit) {
+            rank++;
+        }
+    }
+    let destination = histogram[digit * params.numBlocks + wid.x] + rank;
+    keysOut[destination] = keysIn[idx];
+    valuesOut[destination] = valuesIn[idx];
+}